@@ -0,0 +1,161 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Authentication to rsync daemons.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use md4::{Digest, Md4};
+
+use crate::redact::RSYNC_PASSWORD_ENV;
+use crate::{Options, Result};
+
+/// Returns the password for a daemon: from `options.password_file` if it's
+/// set, and otherwise from `$RSYNC_PASSWORD`, as in rsync.
+pub(crate) fn daemon_password(options: &Options) -> Result<Option<String>> {
+    if let Some(path) = &options.password_file {
+        read_password_file(path).map(Some)
+    } else {
+        Ok(std::env::var(RSYNC_PASSWORD_ENV).ok())
+    }
+}
+
+/// Returns the user name to authenticate as, if none was given in the
+/// daemon address: `$USER` or `$LOGNAME`, as in rsync.
+pub(crate) fn default_user() -> Result<String> {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .context("No user name given for daemon authentication, and $USER is not set")
+}
+
+/// Read a password from the first line of a file, as for rsync's
+/// `--password-file`.
+///
+/// On Unix, like rsync, the file must not be readable by other users.
+pub(crate) fn read_password_file(path: &Path) -> Result<String> {
+    let metadata =
+        fs::metadata(path).with_context(|| format!("Failed to stat password file {:?}", path))?;
+    check_permissions(path, &metadata)?;
+    let content =
+        fs::read(path).with_context(|| format!("Failed to read password file {:?}", path))?;
+    let line = content.split(|&c| c == b'\n').next().unwrap_or_default();
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8(line.to_vec()).with_context(|| format!("Password in {:?} is not UTF-8", path))
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path, metadata: &fs::Metadata) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if metadata.permissions().mode() & 0o044 != 0 {
+        bail!(
+            "Password file {:?} must not be readable by group or other users",
+            path
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path, _metadata: &fs::Metadata) -> Result<()> {
+    Ok(())
+}
+
+/// Returns the response to a daemon's authentication challenge.
+///
+/// Before protocol 30, this is the MD4 of a zero seed, the password, and
+/// the challenge, in base64 without padding.
+pub(crate) fn challenge_response(password: &str, challenge: &str) -> String {
+    let mut hasher = Md4::new();
+    hasher.input(0i32.to_le_bytes());
+    hasher.input(password.as_bytes());
+    hasher.input(challenge.as_bytes());
+    base64_unpadded(&hasher.result())
+}
+
+/// Encode bytes as base64, without `=` padding, as rsync does for
+/// authentication responses.
+fn base64_unpadded(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let mut buf = [0u8; 3];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let n = u32::from(buf[0]) << 16 | u32::from(buf[1]) << 8 | u32::from(buf[2]);
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn base64_without_padding() {
+        assert_eq!(base64_unpadded(b""), "");
+        assert_eq!(base64_unpadded(b"f"), "Zg");
+        assert_eq!(base64_unpadded(b"fo"), "Zm8");
+        assert_eq!(base64_unpadded(b"foo"), "Zm9v");
+        assert_eq!(base64_unpadded(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn challenge_response_is_unpadded_md4() {
+        let response = challenge_response("hunter2", "Ah8kyqYBQoG2KFjh6ZR6Sw");
+        assert_eq!(response.len(), 22);
+        assert_ne!(
+            response,
+            challenge_response("hunter3", "Ah8kyqYBQoG2KFjh6ZR6Sw")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_private_password_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_password_file")
+            .tempdir()
+            .unwrap();
+        let path = tmp.path().join("password");
+        fs::write(&path, b"hunter2\r\nignored\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(read_password_file(&path).unwrap(), "hunter2");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn world_readable_password_file_is_rejected() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_password_file")
+            .tempdir()
+            .unwrap();
+        let path = tmp.path().join("password");
+        fs::write(&path, b"hunter2\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let err = read_password_file(&path).unwrap_err();
+        assert!(
+            err.to_string().contains("must not be readable"),
+            "unexpected error {:?}",
+            err
+        );
+    }
+}
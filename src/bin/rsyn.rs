@@ -14,7 +14,10 @@
 
 //! Command-line program for rsyn, an rsync client in Rust.
 
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use fern::colors::{Color, ColoredLevelConfig};
@@ -22,7 +25,32 @@ use fern::colors::{Color, ColoredLevelConfig};
 use log::{debug, error, info, trace, warn};
 use structopt::StructOpt;
 
-use rsyn::{Client, LocalTree, Options, Result};
+use rsyn::{
+    changes_to_json, Client, DeleteMode, Error, FileEntry, FilterSource, LocalTree, Options,
+    Result, Summary, SymlinkFallback,
+};
+
+// Exit codes, chosen to match rsync's, as listed under "EXIT VALUES" in
+// rsync(1). Any other error exits with 1.
+
+/// The server's protocol is too old.
+const EXIT_PROTOCOL_INCOMPATIBLE: i32 = 2;
+/// The daemon refused the connection, for example because authentication
+/// failed.
+const EXIT_STARTING_PROTOCOL: i32 = 5;
+/// Reading from or writing to the connection failed, including if it was
+/// closed.
+const EXIT_IO: i32 = 10;
+/// Reading or writing a local file failed.
+const EXIT_FILE_IO: i32 = 11;
+/// Data from the server couldn't be understood.
+const EXIT_PROTOCOL_STREAM: i32 = 12;
+/// The transfer was cancelled.
+const EXIT_CANCELLED: i32 = 20;
+/// The transfer finished, but some files weren't transferred.
+const EXIT_PARTIAL_TRANSFER: i32 = 23;
+/// The connection timed out.
+const EXIT_TIMEOUT: i32 = 30;
 
 #[derive(Debug, StructOpt)]
 #[structopt()]
@@ -52,49 +80,573 @@ struct Opt {
     #[structopt(long, short = "r")]
     recursive: bool,
 
-    /// List files, don't copy them.
+    /// Don't cross filesystem boundaries.
+    #[structopt(long, short = "x")]
+    one_file_system: bool,
+
+    /// Keep the whole source path in the names received.
+    #[structopt(long, short = "R")]
+    relative: bool,
+
+    /// With --relative, don't send the parent directories in the source path.
+    #[structopt(long)]
+    no_implied_dirs: bool,
+
+    /// Receive symlinks as symlinks.
+    #[structopt(long = "links", short = "l")]
+    preserve_links: bool,
+
+    /// What to do if a symlink can't be created: fail with an `error`,
+    /// `skip` it, or `copy` the file it points to.
+    #[structopt(
+        long,
+        value_name = "POLICY",
+        possible_values = &["error", "skip", "copy"],
+        default_value = "error"
+    )]
+    symlink_fallback: String,
+
+    /// Receive the permissions of each file.
+    #[structopt(long = "perms", short = "p")]
+    preserve_perms: bool,
+
+    /// Receive the files pointed to by symlinks that point outside the tree.
+    #[structopt(long)]
+    copy_unsafe_links: bool,
+
+    /// Receive the owner of each file.
+    #[structopt(long = "owner", short = "o")]
+    preserve_uid: bool,
+
+    /// Receive the group of each file.
+    #[structopt(long = "group", short = "g")]
+    preserve_gid: bool,
+
+    /// Don't map user and group ids by name.
+    #[structopt(long)]
+    numeric_ids: bool,
+
+    /// Send the path to the server without it being interpreted by the
+    /// remote shell.
+    #[structopt(long, short = "s")]
+    protect_args: bool,
+
+    /// Limit the transfer to KBPS KiB per second.
+    #[structopt(long, value_name = "KBPS")]
+    bwlimit: Option<u32>,
+
+    /// Refuse to allocate more than BYTES at once for data from the server,
+    /// and pass the same limit to the server.
+    #[structopt(long, value_name = "BYTES")]
+    max_alloc: Option<usize>,
+
+    /// Fail if no data is received for this many seconds.
+    #[structopt(long)]
+    timeout: Option<u64>,
+
+    /// Fail if no data is received for this many seconds while receiving the
+    /// file list. Defaults to --timeout.
+    #[structopt(long, value_name = "SECS")]
+    flist_timeout: Option<u64>,
+
+    /// Fail if no data is received for this many seconds while transferring
+    /// files. Defaults to --timeout.
+    #[structopt(long, value_name = "SECS")]
+    transfer_timeout: Option<u64>,
+
+    /// Exclude files matching PATTERN. May be repeated.
+    #[structopt(long, number_of_values = 1, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Don't exclude files matching PATTERN. May be repeated.
+    #[structopt(long, number_of_values = 1, value_name = "PATTERN")]
+    include: Vec<String>,
+
+    /// Read exclude patterns from FILE. May be repeated.
+    #[structopt(long, number_of_values = 1, value_name = "FILE")]
+    exclude_from: Vec<PathBuf>,
+
+    /// Read include patterns from FILE. May be repeated.
+    #[structopt(long, number_of_values = 1, value_name = "FILE")]
+    include_from: Vec<PathBuf>,
+
+    /// Exclude files in the same way CVS does, after all other rules.
+    #[structopt(long, short = "C")]
+    cvs_exclude: bool,
+
+    /// Skip files that match in size, even if their modification times differ.
+    #[structopt(long)]
+    size_only: bool,
+
+    /// Transfer files even if their size and modification time match.
+    #[structopt(long, short = "I")]
+    ignore_times: bool,
+
+    /// Use blocks of BYTES for delta transfers, rather than choosing a size
+    /// for each file.
+    #[structopt(long, short = "B", value_name = "BYTES")]
+    block_size: Option<u32>,
+
+    /// Ask the server to use SEED as the checksum seed, making transfers
+    /// reproducible.
+    #[structopt(long, value_name = "SEED")]
+    checksum_seed: Option<i32>,
+
+    /// Skip files that are newer on the destination.
+    #[structopt(long, short = "u")]
+    update: bool,
+
+    /// Delete files from the destination that aren't on the source.
+    #[structopt(long)]
+    delete: bool,
+
+    /// Delete before transferring files. This is the default.
+    #[structopt(long)]
+    delete_before: bool,
+
+    /// Delete after transferring files.
+    #[structopt(long, conflicts_with = "delete-before")]
+    delete_after: bool,
+
+    /// Rename files to a backup name before replacing or deleting them.
+    #[structopt(long, short = "b")]
+    backup: bool,
+
+    /// Suffix for backups, by default `~`.
+    #[structopt(long = "suffix", value_name = "SUFFIX")]
+    backup_suffix: Option<String>,
+
+    /// Put backups in DIR, which implies `--backup`.
+    #[structopt(long, value_name = "DIR")]
+    backup_dir: Option<PathBuf>,
+
+    /// Put all received files in place at the end of the transfer.
+    #[structopt(long)]
+    delay_updates: bool,
+
+    /// Leave holes in received files rather than writing runs of zeros.
+    #[structopt(long, short = "S")]
+    sparse: bool,
+
+    /// Give received files this owner and group, as USER:GROUP.
+    #[structopt(long, value_name = "USER:GROUP")]
+    chown: Option<String>,
+
+    /// Make everything received owned by USER, and by GROUP or else USER's
+    /// primary group.
+    #[structopt(long, value_name = "USER[:GROUP]")]
+    copy_as: Option<String>,
+
+    /// Replace destination entries of a different type, such as a directory
+    /// where the source has a file.
+    #[structopt(long)]
+    force: bool,
+
+    /// Also delete excluded files from the destination.
+    #[structopt(long, conflicts_with = "delete-after")]
+    delete_excluded: bool,
+
+    /// Compression algorithm: zstd, lz4, zlibx, zlib, or none.
+    ///
+    /// Only zlib is implemented: the others transfer uncompressed, with a
+    /// warning.
+    #[structopt(long, alias = "zc", value_name = "ALGORITHM")]
+    compress_choice: Option<String>,
+
+    /// Checksum algorithm: xxh128, xxh3, xxh64, md5, md4, sha1, or none.
+    ///
+    /// Only md4 is supported yet.
+    #[structopt(long, alias = "cc", value_name = "ALGORITHM")]
+    checksum_choice: Option<String>,
+
+    /// Log a line in FORMAT for each file received, like `%n %l`.
+    #[structopt(long, alias = "log-format", value_name = "FORMAT")]
+    out_format: Option<String>,
+
+    /// Read the daemon password from FILE.
+    #[structopt(long, value_name = "FILE")]
+    password_file: Option<PathBuf>,
+
+    /// Show sizes like `1.2M`.
+    #[structopt(long)]
+    human_readable: bool,
+
+    /// Leave non-ASCII characters in file names unescaped.
+    #[structopt(long = "8-bit-output", short = "8")]
+    eight_bit_output: bool,
+
+    /// Print a summary of the changes to each file.
+    #[structopt(long, short = "i")]
+    itemize_changes: bool,
+
+    /// Print statistics about the transfer, like rsync.
+    #[structopt(long)]
+    stats: bool,
+
+    /// Print the itemized changes as a JSON array.
+    #[structopt(long, requires = "itemize-changes")]
+    json: bool,
+
+    /// Show what would change, without changing anything.
+    #[structopt(long, short = "n")]
+    dry_run: bool,
+
+    /// Delete files even if the server reports IO errors.
+    #[structopt(long)]
+    ignore_errors: bool,
+
+    /// Filter rules from `--exclude`, `--include`, `--exclude-from` and
+    /// `--include-from`, in command-line order.
+    #[structopt(skip)]
+    filter_rules: Vec<FilterSource>,
+
+    /// List files, don't copy them, even if a destination is given.
     #[structopt(long)]
     list_only: bool,
 
+    /// Format of listings: `ls`, like `ls -l`, or `porcelain`, with
+    /// tab-separated octal mode, size, mtime in seconds since the epoch, and
+    /// name.
+    #[structopt(
+        long,
+        value_name = "FORMAT",
+        possible_values = &["ls", "porcelain"],
+        default_value = "ls"
+    )]
+    list_format: String,
+
+    /// Time listing SOURCE, and then downloading it into a new temporary
+    /// directory, and print the throughput of each.
+    #[structopt(long, hidden = true, conflicts_with_all = &["destination", "list-only"])]
+    benchmark: bool,
+
     /// Be more verbose.
     #[structopt(short = "v", parse(from_occurrences))]
     verbose: u32,
 }
 
 impl Opt {
+    /// Parse command-line arguments.
+    ///
+    /// Like `from_iter`, but also keeps the relative order of `--exclude`,
+    /// `--include`, and the files of patterns, which matters to how they're
+    /// applied.
+    fn from_iter_ordered<I>(args: I) -> Opt
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString> + Clone,
+    {
+        let matches = Opt::clap().get_matches_from(args);
+        let mut opt = Opt::from_clap(&matches);
+        let mut rules: Vec<(usize, FilterSource)> = Vec::new();
+        let mut add = |name, sources: Vec<FilterSource>| {
+            if let Some(indices) = matches.indices_of(name) {
+                rules.extend(indices.zip(sources));
+            }
+        };
+        add(
+            "exclude",
+            opt.exclude
+                .iter()
+                .map(|p| format!("- {}", p).into())
+                .collect(),
+        );
+        add(
+            "include",
+            opt.include
+                .iter()
+                .map(|p| format!("+ {}", p).into())
+                .collect(),
+        );
+        add(
+            "exclude-from",
+            opt.exclude_from
+                .iter()
+                .cloned()
+                .map(FilterSource::ExcludeFrom)
+                .collect(),
+        );
+        add(
+            "include-from",
+            opt.include_from
+                .iter()
+                .cloned()
+                .map(FilterSource::IncludeFrom)
+                .collect(),
+        );
+        rules.sort_by_key(|(i, _)| *i);
+        opt.filter_rules = rules.into_iter().map(|(_, rule)| rule).collect();
+        opt
+    }
+
     /// Convert command-line options to protocol options.
     fn to_options(&self) -> Options {
         Options {
             recursive: self.recursive,
-            list_only: self.list_only,
             verbose: self.verbose,
+            one_file_system: self.one_file_system,
+            relative: self.relative,
+            no_implied_dirs: self.no_implied_dirs,
+            protect_args: self.protect_args,
+            preserve_uid: self.preserve_uid,
+            preserve_gid: self.preserve_gid,
+            numeric_ids: self.numeric_ids,
+            preserve_links: self.preserve_links,
+            symlink_fallback: match self.symlink_fallback.as_str() {
+                "skip" => SymlinkFallback::SkipWithWarning,
+                "copy" => SymlinkFallback::CopyReferent,
+                _ => SymlinkFallback::Error,
+            },
+            preserve_perms: self.preserve_perms,
+            copy_unsafe_links: self.copy_unsafe_links,
+            timeout: self.timeout.map(Duration::from_secs),
+            flist_timeout: self.flist_timeout.map(Duration::from_secs),
+            transfer_timeout: self.transfer_timeout.map(Duration::from_secs),
+            bwlimit: self.bwlimit,
+            max_alloc: self.max_alloc,
+            filter_rules: self.filter_rules.clone(),
+            cvs_exclude: self.cvs_exclude,
+            out_format: self.out_format.clone(),
+            human_readable: self.human_readable,
+            eight_bit_output: self.eight_bit_output,
+            password_file: self.password_file.clone(),
+            compress_choice: self.compress_choice.clone(),
+            checksum_choice: self.checksum_choice.clone(),
+            size_only: self.size_only,
+            ignore_times: self.ignore_times,
+            block_size: self.block_size,
+            checksum_seed: self.checksum_seed,
+            update: self.update,
+            itemize: self.itemize_changes,
+            force: self.force,
+            backup: self.backup,
+            backup_suffix: self.backup_suffix.clone(),
+            backup_dir: self.backup_dir.clone(),
+            delay_updates: self.delay_updates,
+            sparse: self.sparse,
+            dry_run: self.dry_run,
+            ignore_errors: self.ignore_errors,
+            chown: self.chown.clone(),
+            copy_as: self.copy_as.clone(),
+            delete: if self.delete_excluded {
+                Some(DeleteMode::Excluded)
+            } else if self.delete_after {
+                Some(DeleteMode::After)
+            } else if self.delete || self.delete_before {
+                Some(DeleteMode::Before)
+            } else {
+                None
+            },
             rsync_command: self.rsync_path.as_ref().map(|p| {
-                shell_words::split(&p).expect("Failed to split shell words from rsync_command")
+                shell_words::split(p).expect("Failed to split shell words from rsync_command")
             }),
             ssh_command: self.rsh.as_ref().map(|p| {
-                shell_words::split(&p).expect("Failed to split shell words from ssh_command")
+                shell_words::split(p).expect("Failed to split shell words from ssh_command")
             }),
+            ..Options::default()
         }
     }
 }
 
-fn main() -> Result<()> {
-    let opt = Opt::from_args();
+fn main() {
+    let opt = Opt::from_iter_ordered(std::env::args_os());
+    let result = run(&opt);
+    let code = exit_code(&result);
+    match result {
+        Err(err) => eprintln!("rsyn: {:#} (code {})", err, code),
+        Ok(_) if code != 0 => eprintln!("rsyn: Some files were not transferred (code {})", code),
+        Ok(_) => (),
+    }
+    std::process::exit(code);
+}
 
-    configure_logging(&opt)?;
+/// Do whatever the options ask, and return the summary of the transfer.
+fn run(opt: &Opt) -> Result<Summary> {
+    configure_logging(opt)?;
 
     let mut client = Client::from_str(&opt.source).expect("Failed to parse path");
     *client.mut_options() = opt.to_options();
-    if let Some(destination) = opt.destination {
-        let (_file_list, _summary) = client.download(&mut LocalTree::new(&destination))?;
+    let summary = if opt.benchmark {
+        benchmark(&client)?
+    } else if let (Some(destination), false) = (&opt.destination, opt.list_only) {
+        let mut local_tree = if may_be_file(opt, destination) {
+            LocalTree::file_or_directory(destination)
+        } else {
+            LocalTree::new(destination)
+        };
+        let (file_list, summary) = client.download(&mut local_tree)?;
+        if opt.json {
+            println!("{}", changes_to_json(&summary.changes));
+        } else {
+            for (entry, change) in &summary.changes {
+                println!("{} {}", change, entry.name_escaped(opt.eight_bit_output));
+            }
+        }
+        print_stats(opt, &file_list, &summary);
+        summary
     } else {
-        let (file_list, _summary) = client.list_files()?;
-        for entry in file_list {
-            println!("{}", &entry)
+        let (file_list, summary) = client.list_files()?;
+        if let Some(hint) = recursion_hint(opt, &file_list) {
+            warn!("{}", hint);
         }
-    }
+        for entry in &file_list {
+            if opt.list_format == "porcelain" {
+                println!("{}", porcelain_line(entry, opt.eight_bit_output))
+            } else {
+                println!(
+                    "{}",
+                    entry.to_ls_line(opt.human_readable, opt.eight_bit_output)
+                )
+            }
+        }
+        print_stats(opt, &file_list, &summary);
+        summary
+    };
     debug!("That's all folks!");
-    Ok(())
+    Ok(summary)
+}
+
+/// Returns the rsync-compatible exit code for the outcome of `run`.
+///
+/// A transfer that finishes, but with files the server couldn't read or
+/// that failed their checksum, is a partial transfer, as in rsync.
+fn exit_code(result: &Result<Summary>) -> i32 {
+    match result {
+        Ok(summary)
+            if summary.server_flist_io_error_count != 0
+                || !summary.files_not_sent.is_empty()
+                || summary.whole_file_sum_mismatch_count != 0 =>
+        {
+            EXIT_PARTIAL_TRANSFER
+        }
+        Ok(_) => 0,
+        Err(err) => err.chain().find_map(cause_exit_code).unwrap_or(1),
+    }
+}
+
+/// Returns the exit code for one error in a chain, if it has a specific one.
+fn cause_exit_code(cause: &(dyn std::error::Error + 'static)) -> Option<i32> {
+    if let Some(err) = cause.downcast_ref::<Error>() {
+        match err {
+            Error::ProtocolTooOld { .. } => Some(EXIT_PROTOCOL_INCOMPATIBLE),
+            Error::DaemonRefused { .. } => Some(EXIT_STARTING_PROTOCOL),
+            Error::ChecksumSeedMismatch { .. } => Some(EXIT_PROTOCOL_STREAM),
+            Error::Cancelled => Some(EXIT_CANCELLED),
+            Error::LocalIo { .. } => Some(EXIT_FILE_IO),
+            // Decided by why it was interrupted, further down the chain.
+            Error::PartialFileList { .. } => None,
+        }
+    } else if let Some(err) = cause.downcast_ref::<io::Error>() {
+        match err.kind() {
+            io::ErrorKind::TimedOut => Some(EXIT_TIMEOUT),
+            _ => Some(EXIT_IO),
+        }
+    } else {
+        None
+    }
+}
+
+/// With `--stats`, print statistics after a blank line, as rsync does.
+/// List and then download the source into a temporary directory, and print
+/// how fast each went.
+fn benchmark(client: &Client) -> Result<Summary> {
+    let start = Instant::now();
+    let (file_list, _summary) = client.list_files()?;
+    println!(
+        "{}",
+        throughput("Listed", file_list.len() as u64, "entries", start.elapsed())
+    );
+
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_benchmark")
+        .tempdir()
+        .context("Failed to create temporary directory")?;
+    let start = Instant::now();
+    let (_file_list, summary) = client.download(&mut LocalTree::new(tmp.path()))?;
+    println!(
+        "{}",
+        throughput(
+            "Downloaded",
+            summary.literal_bytes_received as u64,
+            "bytes",
+            start.elapsed()
+        )
+    );
+    Ok(summary)
+}
+
+/// Describe how many things were done in how long, like
+/// `Listed 10 entries in 0.500s: 20 entries/s`.
+fn throughput(verb: &str, count: u64, unit: &str, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    format!(
+        "{} {} {} in {:.3}s: {:.0} {}/s",
+        verb,
+        count,
+        unit,
+        secs,
+        count as f64 / secs.max(f64::EPSILON),
+        unit
+    )
+}
+
+fn print_stats(opt: &Opt, file_list: &[FileEntry], summary: &Summary) {
+    if !opt.stats {
+        return;
+    }
+    println!();
+    if opt.human_readable {
+        println!("{:#}", summary.rsync_stats(file_list));
+    } else {
+        println!("{}", summary.rsync_stats(file_list));
+    }
+}
+
+/// Format an entry for `--list-format=porcelain`, as tab-separated fields
+/// that don't change with the locale or time zone.
+///
+/// The name is escaped as usual, but can contain tabs, so it comes last.
+fn porcelain_line(entry: &FileEntry, eight_bit_output: bool) -> String {
+    format!(
+        "{:o}\t{}\t{}\t{}",
+        entry.mode,
+        entry.file_len,
+        entry.unix_mtime(),
+        entry.name_escaped(eight_bit_output)
+    )
+}
+
+/// Returns true if the destination might name the file to write a single
+/// source file to, rather than a directory to copy into.
+///
+/// As in rsync, this is so if the destination has no trailing slash and
+/// isn't an existing directory, and the source has no trailing slash. It's
+/// then a file if the download turns out to be a single file.
+fn may_be_file(opt: &Opt, destination: &Path) -> bool {
+    !(destination.to_string_lossy().ends_with('/')
+        || destination.is_dir()
+        || opt.source.ends_with('/'))
+}
+
+/// Returns a suggestion to use `-r`, if a listing without it shows only the
+/// source directory itself, which is often not what was wanted.
+///
+/// A local source is checked directly; for a remote source, the listing
+/// being a single directory is a good sign.
+fn recursion_hint(opt: &Opt, file_list: &[FileEntry]) -> Option<String> {
+    if opt.recursive || opt.source.ends_with('/') {
+        return None;
+    }
+    let only_a_directory = matches!(file_list, [entry] if entry.is_dir());
+    if only_a_directory || Path::new(&opt.source).is_dir() {
+        Some(format!(
+            "{:?} is a directory: add a trailing slash to list its contents, \
+             or -r to list them recursively",
+            opt.source
+        ))
+    } else {
+        None
+    }
 }
 
 // Configure the logger: send everything to the log file (if there is one), and
@@ -150,6 +702,61 @@ fn configure_logging(opt: &Opt) -> Result<()> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use rsyn::Tree;
+
+    #[test]
+    fn throughput_per_second() {
+        assert_eq!(
+            throughput("Listed", 10, "entries", Duration::from_millis(500)),
+            "Listed 10 entries in 0.500s: 20 entries/s"
+        );
+        assert!(throughput("Downloaded", 0, "bytes", Duration::from_secs(0))
+            .starts_with("Downloaded 0 bytes in 0.000s: 0 bytes/s"));
+    }
+
+    #[test]
+    fn partial_transfer_exits_with_23() {
+        assert_eq!(exit_code(&Ok(Summary::default())), 0);
+        let not_sent = Summary {
+            files_not_sent: vec![1],
+            ..Summary::default()
+        };
+        assert_eq!(exit_code(&Ok(not_sent)), 23);
+        let io_errors = Summary {
+            server_flist_io_error_count: 1,
+            ..Summary::default()
+        };
+        assert_eq!(exit_code(&Ok(io_errors)), 23);
+        let mismatched = Summary {
+            whole_file_sum_mismatch_count: 2,
+            ..Summary::default()
+        };
+        assert_eq!(exit_code(&Ok(mismatched)), 23);
+    }
+
+    #[test]
+    fn errors_exit_with_rsync_codes() {
+        let refused: anyhow::Error = Error::DaemonRefused {
+            message: "auth failed on module m".to_owned(),
+        }
+        .into();
+        assert_eq!(exit_code(&Err(refused)), 5);
+        let closed = anyhow::Error::new(io::Error::from(io::ErrorKind::UnexpectedEof))
+            .context("Failed to read file list");
+        assert_eq!(exit_code(&Err(closed)), 10);
+        let local = LocalTree::new("/nonexistent/rsyn")
+            .create_dir(b"sub")
+            .unwrap_err();
+        assert_eq!(exit_code(&Err(local)), 11);
+        let interrupted: anyhow::Error = Error::PartialFileList {
+            entries: Vec::new(),
+            source: io::Error::from(io::ErrorKind::TimedOut).into(),
+        }
+        .into();
+        assert_eq!(exit_code(&Err(interrupted)), 30);
+        assert_eq!(exit_code(&Err(Error::Cancelled.into())), 20);
+        assert_eq!(exit_code(&Err(anyhow::anyhow!("Something else"))), 1);
+    }
 
     #[test]
     fn rsync_path_option() {
@@ -170,6 +777,43 @@ mod test {
         );
     }
 
+    #[test]
+    fn destination_directory_is_not_a_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        for dest in &[dir.to_owned(), "newdir/".to_owned()] {
+            let opt = Opt::from_iter(&["rsyn", "/nonexistent/source", dest]);
+            assert!(!may_be_file(&opt, Path::new(dest)));
+        }
+        let opt = Opt::from_iter(&["rsyn", "/nonexistent/source/", "newname"]);
+        assert!(!may_be_file(&opt, Path::new("newname")));
+        let opt = Opt::from_iter(&["rsyn", "/nonexistent/source", "newname"]);
+        assert!(may_be_file(&opt, Path::new("newname")));
+    }
+
+    #[test]
+    fn porcelain_listing_parses_back() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("a file"), b"hello").unwrap();
+        std::fs::write(tmp.path().join("new\nline"), b"").unwrap();
+        let opt = Opt::from_iter(&["rsyn", "--list-format=porcelain", "/example"]);
+        assert_eq!(opt.list_format, "porcelain");
+        let file_list = LocalTree::new(tmp.path()).scan(&opt.to_options()).unwrap();
+        let mut names = Vec::new();
+        for entry in &file_list {
+            let line = porcelain_line(entry, false);
+            let fields: Vec<&str> = line.splitn(4, '\t').collect();
+            assert_eq!(fields.len(), 4, "{:?}", line);
+            assert_eq!(u32::from_str_radix(fields[0], 8).unwrap(), entry.mode);
+            assert_eq!(fields[1].parse::<u64>().unwrap(), entry.file_len);
+            assert_eq!(fields[2].parse::<u32>().unwrap(), entry.unix_mtime());
+            names.push(fields[3].to_owned());
+        }
+        assert_eq!(names, [".", "a file", r"new\#012line"]);
+        assert!(porcelain_line(&file_list[1], false).starts_with("100"));
+        assert!(porcelain_line(&file_list[1], false).contains("\t5\t"));
+    }
+
     #[test]
     fn rsh_option() {
         let opt = Opt::from_iter(&["rsyn", "--rsh=ssh -OFoo -OBar=123 -v -A", "-vv", "/example"]);
@@ -181,4 +825,59 @@ mod test {
             ["ssh", "-OFoo", "-OBar=123", "-v", "-A"]
         );
     }
+
+    #[test]
+    fn exclude_and_include_options_keep_their_order() {
+        let opt = Opt::from_iter_ordered(&[
+            "rsyn",
+            "--exclude=*.o",
+            "--include",
+            "*.c",
+            "-r",
+            "--exclude-from=excludes",
+            "--exclude",
+            "*",
+            "--include-from",
+            "includes",
+            "/example",
+        ]);
+        assert_eq!(
+            opt.to_options().filter_rules,
+            [
+                "- *.o".into(),
+                "+ *.c".into(),
+                FilterSource::ExcludeFrom("excludes".into()),
+                "- *".into(),
+                FilterSource::IncludeFrom("includes".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn suggest_recursion_for_local_directory() {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_recursion_hint")
+            .tempdir()
+            .unwrap();
+        let dir = tmp.path().to_str().unwrap();
+
+        let hint = recursion_hint(&Opt::from_iter(&["rsyn", dir]), &[]).unwrap();
+        assert!(hint.contains("-r"), "{}", hint);
+
+        let with_slash = format!("{}/", dir);
+        assert_eq!(
+            recursion_hint(&Opt::from_iter(&["rsyn", &with_slash]), &[]),
+            None
+        );
+        assert_eq!(
+            recursion_hint(&Opt::from_iter(&["rsyn", "-r", dir]), &[]),
+            None
+        );
+        let file = tmp.path().join("a");
+        std::fs::write(&file, b"a").unwrap();
+        assert_eq!(
+            recursion_hint(&Opt::from_iter(&["rsyn", file.to_str().unwrap()]), &[]),
+            None
+        );
+    }
 }
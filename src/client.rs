@@ -14,24 +14,54 @@
 
 //! A client that connects to an rsync server.
 
+use std::collections::BTreeMap;
 use std::ffi::OsString;
+use std::io::prelude::*;
 use std::path::Path;
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
 
-use anyhow::Context;
+use anyhow::{bail, Context};
+use crossbeam::thread;
 use lazy_static::lazy_static;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use regex::Regex;
 
 use crate::connection::Connection;
-use crate::{FileList, LocalTree, Options, Result, Summary};
+#[cfg(unix)]
+use crate::daemon::{daemon_handshake, ShutdownOnDrop};
+use crate::filter::{self, FilterSource};
+use crate::localtree::path_bytes;
+use crate::redact::{Redactor, RSYNC_PASSWORD_ENV};
+use crate::{
+    FileEntry, FileList, LocalTree, Options, ProtocolInfo, Result, Summary, TransferMode, Tree,
+    MD4_SUM_LENGTH,
+};
 
 /// SSH command name, to start it as a subprocess.
 const DEFAULT_SSH_COMMAND: &str = "ssh";
 /// rsync command name, to start it as a subprocess either locally or remotely.
 const DEFAULT_RSYNC_COMMAND: &str = "rsync";
 
+/// Quote a path to be parsed by the remote shell as a single word.
+///
+/// A leading `~` or `~user` is left unquoted, so that the shell still expands
+/// it to a home directory.
+fn quote_remote_path(path: &str) -> String {
+    let (home, rest) = if path.starts_with('~') {
+        path.split_at(path.find('/').unwrap_or(path.len()))
+    } else {
+        ("", path)
+    };
+    if rest.is_empty() {
+        home.to_owned()
+    } else {
+        format!("{}{}", home, shell_words::quote(rest))
+    }
+}
+
 /// A client for an rsync server.
 ///
 /// The client is built with information about the location of the server and
@@ -47,10 +77,22 @@ const DEFAULT_RSYNC_COMMAND: &str = "rsync";
 /// ```
 /// let client = rsyn::Client::local("./src");
 /// let client = rsyn::Client::ssh(Some("user"), "host.example.com", "./src");
+/// let client = rsyn::Client::daemon_module("rsync.example.com", "module")
+///     .with_path("sub/dir/");
 /// ```
+///
+/// As in rsync, a trailing slash on a source directory's path is significant:
+/// `src/` means the contents of the directory, which are named relative to
+/// it (`.`, `lib.rs`, ...), and are downloaded directly into the destination.
+/// Without the slash, `src` means the directory itself, whose contents are
+/// named as `src`, `src/lib.rs`, ..., and which is downloaded as a
+/// subdirectory of the destination.
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub struct Client {
     /// Root path to pass to the server.
+    ///
+    /// For a daemon, this is the path within the module, which is empty for
+    /// the whole module.
     path: OsString,
 
     /// How to start the SSH transport, if applicable.
@@ -70,8 +112,33 @@ pub struct Client {
 #[derive(Clone, Eq, PartialEq, Debug)]
 struct Daemon {
     user: Option<String>,
-    host: String,
-    port: Option<u16>,
+    address: DaemonAddress,
+    /// The name of the module, without the path within it.
+    module: String,
+}
+
+/// Where to find an rsync daemon.
+#[derive(Clone, Eq, PartialEq, Debug)]
+enum DaemonAddress {
+    /// Connect over TCP.
+    Tcp { host: String, port: Option<u16> },
+    /// Connect to a Unix-domain socket on this machine.
+    #[cfg(unix)]
+    UnixSocket(std::path::PathBuf),
+}
+
+/// How a connection reaches the server.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Transport {
+    /// An `rsync --server` subprocess on this machine.
+    #[default]
+    Local,
+
+    /// An rsync server started over SSH.
+    Ssh,
+
+    /// An rsync daemon, over TCP, a Unix-domain socket, or SSH.
+    Daemon,
 }
 
 /// Describes how to start an SSH subprocess.
@@ -81,6 +148,36 @@ struct Ssh {
     host: String,
 }
 
+/// Split a daemon path like `module/sub/dir` into the module name and the
+/// path within the module.
+fn split_module(path: &str) -> (String, OsString) {
+    match path.find('/') {
+        Some(pos) => (path[..pos].to_owned(), path[pos + 1..].into()),
+        None => (path.to_owned(), OsString::new()),
+    }
+}
+
+/// Check that a daemon module name is usable.
+pub(crate) fn check_module_name(module: &str) -> Result<()> {
+    if module.is_empty() {
+        bail!("No daemon module name given; listing modules isn't supported yet");
+    } else if module.contains('/') {
+        bail!("Daemon module name {:?} contains a slash", module);
+    }
+    Ok(())
+}
+
+/// Returns the first line of the output of `rsync --version`, checking that
+/// it looks like a version.
+fn parse_version_output(stdout: &[u8]) -> Result<String> {
+    let stdout = String::from_utf8_lossy(stdout);
+    let first_line = stdout.lines().next().unwrap_or_default().trim_end();
+    if !first_line.starts_with("rsync") || !first_line.contains("version") {
+        bail!("Unexpected output from rsync --version: {:?}", first_line);
+    }
+    Ok(first_line.to_owned())
+}
+
 impl Client {
     /// Builds a `Client` that, when connected, starts an `rsync --server` subprocess
     /// on the local machine.
@@ -116,6 +213,78 @@ impl Client {
         }
     }
 
+    /// Builds a `Client` that will connect to an rsync daemon listening on a
+    /// Unix-domain socket on this machine.
+    ///
+    /// `module` is the name of the daemon module, optionally followed by a
+    /// path within it, like `module/subdir/`.
+    #[cfg(unix)]
+    pub fn unix_socket<P: AsRef<Path>>(socket_path: P, module: &str) -> Client {
+        let (module, path) = split_module(module);
+        Client {
+            path,
+            ssh: None,
+            daemon: Some(Daemon {
+                user: None,
+                address: DaemonAddress::UnixSocket(socket_path.as_ref().to_owned()),
+                module,
+            }),
+            options: Options::default(),
+        }
+    }
+
+    /// Builds a `Client` that will connect to an rsync daemon over TCP, on
+    /// the default port, and transfer the whole of `module`.
+    ///
+    /// To transfer a path within the module, use
+    /// [`with_path`](#method.with_path).
+    pub fn daemon_module(host: &str, module: &str) -> Client {
+        Client {
+            path: OsString::new(),
+            ssh: None,
+            daemon: Some(Daemon {
+                user: None,
+                address: DaemonAddress::Tcp {
+                    host: host.into(),
+                    port: None,
+                },
+                module: module.into(),
+            }),
+            options: Options::default(),
+        }
+    }
+
+    /// Returns this client with a different path. For a daemon, the path is
+    /// within the module.
+    pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> Client {
+        self.path = path.as_ref().as_os_str().into();
+        self
+    }
+
+    /// Change the daemon module, keeping the path within it.
+    ///
+    /// Fails if this client doesn't connect to a daemon, or if the name is
+    /// empty or contains a slash.
+    pub fn set_daemon_module(&mut self, module: &str) -> Result<&mut Self> {
+        check_module_name(module)?;
+        match &mut self.daemon {
+            Some(daemon) => daemon.module = module.into(),
+            None => bail!("Can't set a daemon module on a client that doesn't use a daemon"),
+        }
+        Ok(self)
+    }
+
+    /// Returns the daemon module, if this client connects to a daemon.
+    pub fn module(&self) -> Option<&str> {
+        self.daemon.as_ref().map(|daemon| daemon.module.as_str())
+    }
+
+    /// Returns the path on the server: for a daemon, this is within the
+    /// module.
+    pub fn path(&self) -> &Path {
+        Path::new(&self.path)
+    }
+
     /// Mutably borrow this client's `Options`.
     pub fn mut_options(&mut self) -> &mut Options {
         &mut self.options
@@ -141,7 +310,39 @@ impl Client {
 
     /// Builds the arguments to start a connection subcommand, including the
     /// command name.
-    fn build_args(&self) -> Vec<OsString> {
+    pub(crate) fn build_args(&self, mode: TransferMode) -> Vec<OsString> {
+        let mut v = self.rsync_command_args();
+        let mut push_str = |s: &str| v.push(s.into());
+        if self.options.protect_args {
+            // The rest of the arguments are sent over the connection, by
+            // `protected_args`.
+            push_str("--server");
+            push_str("--sender");
+            push_str("-s");
+        } else {
+            let mut server_args = self.server_args(mode);
+            if self.ssh.is_some() {
+                // The remote shell parses the command line again, so the
+                // path must be a single word to it.
+                let path = server_args.pop().expect("No path in server args");
+                server_args.push(quote_remote_path(&path.to_string_lossy()).into());
+            }
+            v.extend(server_args);
+        }
+        v
+    }
+
+    /// Builds the arguments to ask the rsync that would be run as the
+    /// server for its version.
+    fn version_args(&self) -> Vec<OsString> {
+        let mut v = self.rsync_command_args();
+        v.push("--version".into());
+        v
+    }
+
+    /// Builds the arguments to run rsync, locally or over ssh, up to and
+    /// including the rsync command itself.
+    fn rsync_command_args(&self) -> Vec<OsString> {
         let mut v = Vec::<OsString>::new();
         let mut push_str = |s: &str| v.push(s.into());
         if let Some(ref ssh) = self.ssh {
@@ -165,8 +366,40 @@ impl Client {
         } else {
             push_str(DEFAULT_RSYNC_COMMAND)
         }
-        push_str("--server");
-        push_str("--sender");
+        v
+    }
+
+    /// Builds the arguments for `rsync --server`, starting from `--server`.
+    ///
+    /// These are either passed on the server's command line, or sent to
+    /// a daemon.
+    fn server_args(&self, mode: TransferMode) -> Vec<OsString> {
+        let mut v: Vec<OsString> = vec!["--server".into(), "--sender".into()];
+        v.extend(self.transfer_args(mode));
+        v
+    }
+
+    /// Builds the arguments sent over the connection with `protect_args`,
+    /// as rsync's `send_protected_args` does.
+    ///
+    /// They're the server arguments that aren't on its command line, after
+    /// a new `argv[0]`. Each is terminated by a NUL, and an empty argument
+    /// ends the list.
+    fn protected_args(&self, mode: TransferMode) -> Vec<u8> {
+        let mut buf = b"rsync\0".to_vec();
+        for arg in self.transfer_args(mode) {
+            buf.extend_from_slice(&path_bytes(Path::new(&arg)));
+            buf.push(0);
+        }
+        buf.push(0);
+        buf
+    }
+
+    /// Builds the server arguments after `--server --sender`, describing the
+    /// transfer and ending with the path.
+    fn transfer_args(&self, mode: TransferMode) -> Vec<OsString> {
+        let mut v = Vec::<OsString>::new();
+        let mut push_str = |s: &str| v.push(s.into());
         if self.options.verbose > 0 {
             let mut o = "-".to_string();
             for _ in 0..self.options.verbose {
@@ -174,47 +407,369 @@ impl Client {
             }
             push_str(&o);
         }
-        if self.options.list_only {
+        if mode == TransferMode::List {
             push_str("--list-only")
         }
         if self.options.recursive {
             push_str("-r")
         }
-        if self.path.is_empty() {
-            push_str(".")
-        } else {
-            v.push(self.path.clone())
+        if self.options.one_file_system {
+            push_str("-x")
+        }
+        if self.options.relative {
+            push_str("-R")
+        }
+        if self.options.no_implied_dirs {
+            push_str("--no-implied-dirs")
+        }
+        if self.options.preserve_uid {
+            push_str("-o")
+        }
+        if self.options.preserve_gid {
+            push_str("-g")
+        }
+        if self.options.numeric_ids {
+            push_str("--numeric-ids")
+        }
+        if self.options.preserve_links {
+            push_str("-l")
         }
+        if self.options.preserve_perms {
+            push_str("-p")
+        }
+        if self.options.copy_unsafe_links {
+            push_str("--copy-unsafe-links")
+        }
+        if self.options.size_only {
+            push_str("--size-only")
+        }
+        if self.options.ignore_times {
+            push_str("-I")
+        }
+        if self.options.update {
+            push_str("-u")
+        }
+        if let Some(block_size) = self.options.block_size {
+            push_str(&format!("-B{}", block_size))
+        }
+        // Other algorithms can't be chosen in protocol 27.
+        if self.options.compress_choice.as_deref() == Some("zlib") {
+            push_str("-z")
+        }
+        if let Some(checksum_seed) = self.options.checksum_seed {
+            push_str(&format!("--checksum-seed={}", checksum_seed))
+        }
+        if self.options.dry_run {
+            push_str("-n")
+        }
+        if let Some(timeout) = self.options.effective_timeout() {
+            // rsync's timeout is in whole seconds, and 0 means none.
+            let secs = timeout.as_secs() + (timeout.subsec_nanos() > 0) as u64;
+            push_str(&format!("--timeout={}", secs));
+        }
+        if let Some(bwlimit) = self.options.bwlimit {
+            push_str(&format!("--bwlimit={}", bwlimit));
+        }
+        if let Some(max_alloc) = self.options.max_alloc {
+            push_str(&format!("--max-alloc={}", max_alloc));
+        }
+        for arg in &self.options.extra_server_args {
+            push_str(arg)
+        }
+        // The server's first argument is the directory to start from, and
+        // the remaining arguments are the files to send, relative to it.
+        // Like rsync, pass the whole path as a single file argument, so that
+        // the server treats it the same way, including any trailing slash.
+        push_str(".");
+        v.push(self.remote_path());
         v
     }
 
-    /// List files from the remote server.
+    /// Returns the path to send to the server. For a daemon, it starts with
+    /// the module name.
+    fn remote_path(&self) -> OsString {
+        match &self.daemon {
+            Some(daemon) => {
+                let mut path = OsString::from(format!("{}/", daemon.module));
+                path.push(&self.path);
+                path
+            }
+            None if self.path.is_empty() => ".".into(),
+            None => self.path.clone(),
+        }
+    }
+
+    /// Describe a command line for logging, without any secrets.
+    fn describe_command(&self, args: &[OsString]) -> String {
+        self.describe_command_hiding(args, std::env::var(RSYNC_PASSWORD_ENV).ok())
+    }
+
+    /// Describe a command line for logging, hiding `password`, which is
+    /// normally taken from the environment.
+    fn describe_command_hiding(&self, args: &[OsString], password: Option<String>) -> String {
+        Redactor::for_password(&self.options, password).redact(&format!("{:?}", args))
+    }
+
+    /// List files from the remote server, without transferring their
+    /// contents.
     ///
-    /// This implicitly sets the `list_only` option.
-    pub fn list_files(&mut self) -> Result<(FileList, Summary)> {
-        self.download(&mut LocalTree::new("/dev/null")) // TODO: Clean LocalTree::null()
+    /// Without the `recursive` option, this lists just the immediate contents
+    /// of a directory whose path has a trailing slash, like `ls`. Without the
+    /// trailing slash, just the directory itself is listed.
+    ///
+    /// The list is sorted by name, with any duplicates removed, as rsync
+    /// does. This is the order in which the server numbers files. To see
+    /// the order the server sent them in, use
+    /// [`list_files_in_wire_order`](#method.list_files_in_wire_order).
+    pub fn list_files(&self) -> Result<(FileList, Summary)> {
+        self.connect(TransferMode::List)
+            .context("Failed to connect")?
+            .receive(&mut LocalTree::new("/dev/null")) // TODO: Clean LocalTree::null()
+            .context("Failed to list files")
     }
 
-    /// Download from the server into a local tree.
-    pub fn download(&mut self, local_tree: &mut LocalTree) -> Result<(FileList, Summary)> {
-        self.connect()
+    /// List files like [`list_files`](#method.list_files), but in the order
+    /// the server sent them, without sorting or removing duplicates.
+    ///
+    /// This is mostly useful for debugging the protocol: file indexes refer
+    /// to the sorted list, not to positions in this one.
+    pub fn list_files_in_wire_order(&self) -> Result<(FileList, Summary)> {
+        self.connect(TransferMode::List)
             .context("Failed to connect")?
-            .receive(local_tree)
+            .list_files_in_wire_order()
             .context("Failed to list files")
     }
 
-    /// Opens a connection using the previously configured destination and options.
+    /// List several paths from the same server, with up to `parallelism`
+    /// connections open at once.
+    ///
+    /// Each path is listed as if it replaced this client's path, with the
+    /// same server and options. For a daemon, the paths are within the same
+    /// module. The file lists are returned keyed by path.
+    ///
+    /// If any listing fails, no more are started, and the error is returned
+    /// once the connections that are already open have finished.
+    pub fn list_many(
+        &self,
+        paths: &[&str],
+        parallelism: usize,
+    ) -> Result<BTreeMap<String, FileList>> {
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let file_lists = Mutex::new(BTreeMap::new());
+        let list_remaining = || -> Result<()> {
+            while !failed.load(Ordering::Relaxed) {
+                let path = match paths.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    Some(path) => path,
+                    None => break,
+                };
+                let client = Client {
+                    path: path.into(),
+                    ..self.clone()
+                };
+                let (file_list, _summary) = client
+                    .list_files()
+                    .with_context(|| format!("Failed to list {:?}", path))
+                    .inspect_err(|_| failed.store(true, Ordering::Relaxed))?;
+                file_lists
+                    .lock()
+                    .unwrap()
+                    .insert(path.to_string(), file_list);
+            }
+            Ok(())
+        };
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..parallelism.max(1).min(paths.len()))
+                .map(|i| {
+                    scope
+                        .builder()
+                        .name(format!("rsyn_list_{}", i))
+                        .spawn(|_| list_remaining())
+                        .expect("Failed to spawn listing thread")
+                })
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("Listing thread panicked"))
+        })
+        .unwrap()?;
+        Ok(file_lists.into_inner().unwrap())
+    }
+
+    /// Connect to the server and do just the protocol handshake, without
+    /// listing or transferring any files.
+    ///
+    /// This is a cheap way to check that the server is reachable and
+    /// compatible, for example for monitoring.
+    pub fn probe(&self) -> Result<ProtocolInfo> {
+        self.connect(TransferMode::List)
+            .context("Failed to connect")?
+            .probe()
+    }
+
+    /// Returns the first line of `rsync --version` from the command that
+    /// would be run as the server, like
+    /// `rsync  version 3.2.3  protocol version 31`.
+    ///
+    /// The server doesn't report its version in the protocol, so this runs
+    /// it again, in the same way, locally or over ssh. This shows which rsync
+    /// is actually reached, for example after `rsync_command` or the remote
+    /// `PATH` is changed. It's not possible for a daemon.
+    pub fn remote_rsync_version(&self) -> Result<String> {
+        if self.daemon.is_some() {
+            bail!("Can't ask an rsync daemon for its version");
+        }
+        let args = self.version_args();
+        info!("Run version command {}", self.describe_command(&args));
+        let mut command = self.subprocess_command(args);
+        command.stdin(Stdio::null());
+        let output = command
+            .output()
+            .with_context(|| format!("Failed to run {:?}", command))?;
+        if !output.status.success() {
+            bail!(
+                "{:?} failed with {}: {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        parse_version_output(&output.stdout)
+    }
+
+    /// Download from the server into a tree, such as a `LocalTree`.
+    pub fn download(&self, tree: &mut dyn Tree) -> Result<(FileList, Summary)> {
+        self.connect(TransferMode::Download)
+            .context("Failed to connect")?
+            .receive(tree)
+            .context("Failed to download files")
+    }
+
+    /// Returns the MD4 of the content of every regular file, in file list
+    /// order, for comparison with digests computed elsewhere.
+    ///
+    /// Nothing is written locally, but the server sends the whole content
+    /// of every file. See
+    /// [`Connection::checksum_manifest`](struct.Connection.html#method.checksum_manifest).
+    pub fn checksum_manifest(&self) -> Result<Vec<(FileEntry, [u8; MD4_SUM_LENGTH])>> {
+        self.connect(TransferMode::Download)
+            .context("Failed to connect")?
+            .checksum_manifest()
+            .context("Failed to checksum files")
+    }
+
+    /// Download one regular text file into a string, without writing
+    /// anything locally.
+    ///
+    /// `remote_name` is relative to the top of the source, as in the file
+    /// list, so a file in a subdirectory needs the `recursive` option. It's an
+    /// error if the content isn't UTF-8.
+    pub fn download_file_to_string(&self, remote_name: &str) -> Result<String> {
+        let content = self
+            .connect(TransferMode::Download)
+            .context("Failed to connect")?
+            .fetch_file(remote_name.as_bytes())
+            .with_context(|| format!("Failed to download {:?}", remote_name))?;
+        String::from_utf8(content).with_context(|| format!("{:?} is not UTF-8", remote_name))
+    }
+
+    /// Opens a connection using the previously configured destination and
+    /// options, to do a transfer of the given kind.
     ///
     /// The `Client` can be opened any number of times, but each `Connection`
     /// can only do a single operation.
-    fn connect(&self) -> Result<Connection> {
-        if self.daemon.is_some() {
-            todo!("daemon mode is not implemented yet");
+    ///
+    /// Most callers can use the higher-level methods on `Client` instead.
+    pub fn connect(&self, mode: TransferMode) -> Result<Connection> {
+        if mode == TransferMode::Upload {
+            // TODO: Once rsyn can act as the sender, add
+            // `Options::remove_source_files`, removing each regular file (never
+            // a directory) from the source tree after the server has received
+            // it. For downloads, the server does the removing, but it relies on
+            // success messages from the client, which rsyn doesn't send.
+            bail!("Uploading files is not implemented yet");
+        }
+        self.options.check()?;
+        // Read any filter rule files before connecting, so that errors are
+        // found early, and the connection has just one list of rules.
+        let mut options = self.options.clone();
+        options.filter_rules = filter::all_rules(&self.options)?
+            .into_iter()
+            .map(FilterSource::Rule)
+            .collect();
+        options.cvs_exclude = false;
+
+        let mut connection = match &self.daemon {
+            None => self.connect_subprocess(mode, options),
+            #[cfg(unix)]
+            Some(Daemon {
+                address: DaemonAddress::UnixSocket(socket_path),
+                ..
+            }) => self.connect_unix_socket(socket_path, mode, options),
+            Some(_) => bail!("Connecting to rsync daemons over TCP is not implemented yet"),
+        }?;
+        connection.set_transport(self.transport());
+        Ok(connection)
+    }
+
+    /// Returns how connections from this client reach the server.
+    ///
+    /// A daemon reached over SSH counts as `Transport::Daemon`.
+    pub fn transport(&self) -> Transport {
+        match (&self.daemon, &self.ssh) {
+            (Some(_), _) => Transport::Daemon,
+            (None, Some(_)) => Transport::Ssh,
+            (None, None) => Transport::Local,
         }
-        let mut args = self.build_args();
-        info!("Run connection command {:?}", &args);
+    }
+
+    /// Connect to a daemon listening on a Unix-domain socket.
+    #[cfg(unix)]
+    fn connect_unix_socket(
+        &self,
+        socket_path: &Path,
+        mode: TransferMode,
+        options: Options,
+    ) -> Result<Connection> {
+        use std::os::unix::net::UnixStream;
+
+        info!("Connect to daemon socket {:?}", socket_path);
+        let stream = UnixStream::connect(socket_path)
+            .with_context(|| format!("Failed to connect to daemon socket {:?}", socket_path))?;
+        let r = Box::new(stream.try_clone()?);
+        let w = Box::new(ShutdownOnDrop(stream));
+        let daemon = self.daemon.as_ref().expect("Not a daemon client");
+        daemon_handshake(
+            r,
+            w,
+            &daemon.module,
+            &self.server_args(mode),
+            daemon.user.as_deref(),
+            mode,
+            options,
+        )
+    }
+
+    /// Build a command to run locally, with the environment from the options.
+    fn subprocess_command(&self, mut args: Vec<OsString>) -> Command {
         let mut command = Command::new(args.remove(0));
         command.args(args);
+        if self.options.subprocess_env_clear {
+            command.env_clear();
+        }
+        for (name, value) in &self.options.subprocess_env {
+            // Values can be secrets, such as RSYNC_PASSWORD, so aren't logged.
+            debug!("Set {} for subprocess", name);
+            command.env(name, value);
+        }
+        command
+    }
+
+    /// Start the server as a subprocess, either locally or over ssh.
+    fn connect_subprocess(&self, mode: TransferMode, options: Options) -> Result<Connection> {
+        let args = self.build_args(mode);
+        info!("Run connection command {}", self.describe_command(&args));
+        let mut command = self.subprocess_command(args);
         command.stdin(Stdio::piped());
         command.stdout(Stdio::piped());
         let mut child = command
@@ -222,9 +777,17 @@ impl Client {
             .with_context(|| format!("Failed to launch rsync subprocess {:?}", command))?;
 
         let r = Box::new(child.stdout.take().expect("Child has no stdout"));
-        let w = Box::new(child.stdin.take().expect("Child has no stdin"));
+        let mut w = Box::new(child.stdin.take().expect("Child has no stdin"));
+        if options.protect_args {
+            debug!(
+                "Send protected arguments {}",
+                self.describe_command(&self.transfer_args(mode))
+            );
+            w.write_all(&self.protected_args(mode))
+                .context("Failed to send protected arguments")?;
+        }
 
-        Connection::handshake(r, w, child, self.options.clone())
+        Connection::handshake(r, w, Some(child), mode, options)
     }
 
     /// Builds a Client from a path, URL, or SFTP-like path.
@@ -260,24 +823,32 @@ impl Client {
             .unwrap();
         }
         if let Some(caps) = URL_RE.captures(s) {
+            let (module, path) = split_module(&caps["path"]);
             Ok(Client {
                 daemon: Some(Daemon {
-                    host: caps["host"].into(),
                     user: caps.name("user").map(|m| m.as_str().to_string()),
-                    port: caps.name("port").map(|p| p.as_str().parse().unwrap()),
+                    address: DaemonAddress::Tcp {
+                        host: caps["host"].into(),
+                        port: caps.name("port").map(|p| p.as_str().parse().unwrap()),
+                    },
+                    module,
                 }),
-                path: caps["path"].into(),
+                path,
                 ssh: None,
                 options: Options::default(),
             })
         } else if let Some(caps) = SFTP_RE.captures(s) {
             if caps.name("colon").is_some() {
+                let (module, path) = split_module(&caps["path"]);
                 Ok(Client {
-                    path: caps["path"].into(),
+                    path,
                     daemon: Some(Daemon {
                         user: caps.name("user").map(|m| m.as_str().to_string()),
-                        host: caps["host"].into(),
-                        port: None,
+                        address: DaemonAddress::Tcp {
+                            host: caps["host"].into(),
+                            port: None,
+                        },
+                        module,
                     }),
                     ssh: None,
                     options: Options::default(),
@@ -309,6 +880,21 @@ impl Client {
 mod test {
     use super::*;
 
+    #[test]
+    fn transport_from_client() {
+        assert_eq!(Client::local("/tmp").transport(), Transport::Local);
+        assert_eq!(
+            Client::from_str("bilbo:/home/www").unwrap().transport(),
+            Transport::Ssh
+        );
+        assert_eq!(
+            Client::from_str("rsync://rsync.samba.org/foo")
+                .unwrap()
+                .transport(),
+            Transport::Daemon
+        );
+    }
+
     #[test]
     fn parse_sftp_style_without_user() {
         let client = Client::from_str("bilbo:/home/www").unwrap();
@@ -349,12 +935,15 @@ mod test {
         assert_eq!(
             client,
             Client {
-                path: "foo".into(),
+                path: "".into(),
                 ssh: None,
                 daemon: Some(Daemon {
-                    host: "rsync.samba.org".into(),
                     user: None,
-                    port: None,
+                    address: DaemonAddress::Tcp {
+                        host: "rsync.samba.org".into(),
+                        port: None,
+                    },
+                    module: "foo".into(),
                 }),
                 options: Options::default(),
             }
@@ -367,12 +956,15 @@ mod test {
         assert_eq!(
             client,
             Client {
-                path: "meat/bread/wine".into(),
+                path: "bread/wine".into(),
                 ssh: None,
                 daemon: Some(Daemon {
-                    host: "rsync.samba.org".into(),
                     user: Some("rsync".into()),
-                    port: None,
+                    address: DaemonAddress::Tcp {
+                        host: "rsync.samba.org".into(),
+                        port: None,
+                    },
+                    module: "meat".into(),
                 }),
                 options: Options::default(),
             }
@@ -385,30 +977,45 @@ mod test {
         assert_eq!(
             client,
             Client {
-                path: "foo".into(),
+                path: "".into(),
                 ssh: None,
                 daemon: Some(Daemon {
-                    host: "rsync.samba.org".into(),
                     user: None,
-                    port: None,
+                    address: DaemonAddress::Tcp {
+                        host: "rsync.samba.org".into(),
+                        port: None,
+                    },
+                    module: "foo".into(),
                 }),
                 options: Options::default(),
             }
         );
     }
 
+    #[test]
+    fn tcp_daemon_is_an_error() {
+        let client = Client::from_str("rsync://rsync.samba.org/foo").unwrap();
+        assert_eq!(
+            format!("{:#}", client.list_files().unwrap_err()),
+            "Failed to connect: Connecting to rsync daemons over TCP is not implemented yet"
+        );
+    }
+
     #[test]
     fn parse_rsync_url_with_username() {
         let client = Client::from_str("rsync://anon@rsync.samba.org/foo").unwrap();
         assert_eq!(
             client,
             Client {
-                path: "foo".into(),
+                path: "".into(),
                 ssh: None,
                 daemon: Some(Daemon {
-                    host: "rsync.samba.org".into(),
                     user: Some("anon".into()),
-                    port: None,
+                    address: DaemonAddress::Tcp {
+                        host: "rsync.samba.org".into(),
+                        port: None,
+                    },
+                    module: "foo".into(),
                 }),
                 options: Options::default(),
             }
@@ -422,18 +1029,60 @@ mod test {
         assert_eq!(
             client,
             Client {
-                path: "alpha/beta/gamma".into(),
+                path: "beta/gamma".into(),
                 ssh: None,
                 daemon: Some(Daemon {
-                    host: "rsync.samba.org".into(),
                     user: Some("anon".into()),
-                    port: Some(8370),
+                    address: DaemonAddress::Tcp {
+                        host: "rsync.samba.org".into(),
+                        port: Some(8370),
+                    },
+                    module: "alpha".into(),
                 }),
                 options: Options::default(),
             }
         );
     }
 
+    #[test]
+    fn daemon_module_and_path_are_separate() {
+        let client = Client::from_str("rsync://rsync.samba.org/module/sub/dir/").unwrap();
+        assert_eq!(client.module(), Some("module"));
+        assert_eq!(client.path(), Path::new("sub/dir/"));
+        assert_eq!(
+            client,
+            Client::daemon_module("rsync.samba.org", "module").with_path("sub/dir/")
+        );
+        assert_eq!(
+            client.server_args(TransferMode::Download).last().unwrap(),
+            "module/sub/dir/"
+        );
+
+        let whole_module = Client::from_str("rsync.samba.org::module").unwrap();
+        assert_eq!(whole_module.module(), Some("module"));
+        assert_eq!(whole_module.path(), Path::new(""));
+        assert_eq!(
+            whole_module
+                .server_args(TransferMode::Download)
+                .last()
+                .unwrap(),
+            "module/"
+        );
+
+        assert_eq!(Client::local("/tmp").module(), None);
+    }
+
+    #[test]
+    fn set_daemon_module_keeps_the_path() {
+        let mut client = Client::from_str("rsync://rsync.samba.org/old/sub").unwrap();
+        client.set_daemon_module("new").unwrap();
+        assert_eq!(client.module(), Some("new"));
+        assert_eq!(client.path(), Path::new("sub"));
+        assert!(client.set_daemon_module("").is_err());
+        assert!(client.set_daemon_module("a/b").is_err());
+        assert!(Client::local("/tmp").set_daemon_module("new").is_err());
+    }
+
     #[test]
     fn parse_simple_path() {
         let client = Client::from_str("/usr/local/foo").unwrap();
@@ -450,8 +1099,13 @@ mod test {
 
     #[test]
     fn build_local_args() {
-        let args = Client::local("./src").set_recursive(true).build_args();
-        assert_eq!(args, vec!["rsync", "--server", "--sender", "-r", "./src"],);
+        let args = Client::local("./src")
+            .set_recursive(true)
+            .build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            vec!["rsync", "--server", "--sender", "-r", ".", "./src"],
+        );
     }
 
     #[test]
@@ -461,10 +1115,16 @@ mod test {
                 rsync_command: Some(vec!["/opt/rsync/rsync-3.1415".to_owned()]),
                 ..Options::default()
             })
-            .build_args();
+            .build_args(TransferMode::Download);
         assert_eq!(
             args,
-            ["/opt/rsync/rsync-3.1415", "--server", "--sender", "testdir"],
+            [
+                "/opt/rsync/rsync-3.1415",
+                "--server",
+                "--sender",
+                ".",
+                "testdir"
+            ],
         );
     }
 
@@ -472,8 +1132,89 @@ mod test {
     fn build_local_args_verbose() {
         let mut client = Client::local("./src");
         client.set_verbose(3);
-        let args = client.build_args();
-        assert_eq!(args, ["rsync", "--server", "--sender", "-vvv", "./src"],);
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            ["rsync", "--server", "--sender", "-vvv", ".", "./src"],
+        );
+    }
+
+    #[test]
+    fn build_local_args_timeout() {
+        let mut client = Client::local("./src");
+        client.mut_options().timeout = Some(std::time::Duration::from_millis(2500));
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            ["rsync", "--server", "--sender", "--timeout=3", ".", "./src"]
+        );
+    }
+
+    #[test]
+    fn build_local_args_bwlimit() {
+        let mut client = Client::local("./src");
+        client.mut_options().bwlimit = Some(100);
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            [
+                "rsync",
+                "--server",
+                "--sender",
+                "--bwlimit=100",
+                ".",
+                "./src"
+            ]
+        );
+    }
+
+    #[test]
+    fn build_local_args_max_alloc() {
+        let mut client = Client::local("./src");
+        client.mut_options().max_alloc = Some(1 << 20);
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            [
+                "rsync",
+                "--server",
+                "--sender",
+                "--max-alloc=1048576",
+                ".",
+                "./src"
+            ]
+        );
+    }
+
+    #[test]
+    fn build_local_args_extra_server_args() {
+        let mut client = Client::local("./src");
+        client.mut_options().recursive = true;
+        client.mut_options().extra_server_args = vec!["-t".to_owned(), "--size-only".to_owned()];
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            [
+                "rsync",
+                "--server",
+                "--sender",
+                "-r",
+                "-t",
+                "--size-only",
+                ".",
+                "./src"
+            ]
+        );
+    }
+
+    /// The trailing slash is passed through, to control whether the directory
+    /// or its contents are transferred.
+    #[test]
+    fn build_local_args_trailing_slash() {
+        let args = Client::local("src/").build_args(TransferMode::Download);
+        assert_eq!(args, ["rsync", "--server", "--sender", ".", "src/"]);
+        let args = Client::local("src").build_args(TransferMode::Download);
+        assert_eq!(args, ["rsync", "--server", "--sender", ".", "src"]);
     }
 
     #[test]
@@ -482,7 +1223,7 @@ mod test {
         // at least check the command lines are plausible.
 
         let client = Client::ssh(None, "samba.org", "/home/mbp");
-        let args = client.build_args();
+        let args = client.build_args(TransferMode::Download);
         assert_eq!(
             args,
             [
@@ -491,20 +1232,96 @@ mod test {
                 "rsync",
                 "--server",
                 "--sender",
+                ".",
                 "/home/mbp"
             ],
         );
     }
 
+    #[test]
+    fn build_version_args() {
+        assert_eq!(
+            Client::local("./src").version_args(),
+            ["rsync", "--version"]
+        );
+        let mut client = Client::ssh(Some("mbp"), "samba.org", "/home/mbp");
+        client.set_options(Options {
+            rsync_command: Some(vec!["/opt/rsync".to_owned()]),
+            ..Options::default()
+        });
+        assert_eq!(
+            client.version_args(),
+            ["ssh", "-l", "mbp", "samba.org", "/opt/rsync", "--version"]
+        );
+    }
+
+    /// A stand-in for rsync that reports an environment variable as its
+    /// version.
+    #[cfg(unix)]
+    #[test]
+    fn subprocess_env_reaches_the_command() {
+        let version_with = |options: Options| {
+            let mut client = Client::local("./src");
+            client.set_options(Options {
+                rsync_command: Some(vec![
+                    "/bin/sh".to_owned(),
+                    "-c".to_owned(),
+                    "echo \"rsync  version ${RSYN_TEST_VAR:-unset}${HOME:+ home}\"".to_owned(),
+                    "sh".to_owned(),
+                ]),
+                ..options
+            });
+            client.remote_rsync_version().unwrap()
+        };
+        // The shell makes up its own PATH, but HOME is only inherited.
+        let home = if std::env::var_os("HOME").is_some() {
+            " home"
+        } else {
+            ""
+        };
+        let env = vec![("RSYN_TEST_VAR".to_owned(), "injected".to_owned())];
+        assert_eq!(
+            version_with(Options::default()),
+            format!("rsync  version unset{}", home)
+        );
+        assert_eq!(
+            version_with(Options {
+                subprocess_env: env.clone(),
+                ..Options::default()
+            }),
+            format!("rsync  version injected{}", home)
+        );
+        assert_eq!(
+            version_with(Options {
+                subprocess_env: env,
+                subprocess_env_clear: true,
+                ..Options::default()
+            }),
+            "rsync  version injected"
+        );
+    }
+
+    #[test]
+    fn parse_version() {
+        assert_eq!(
+            parse_version_output(
+                b"rsync  version 3.2.3  protocol version 31\nCopyright (C) 1996-2020\n"
+            )
+            .unwrap(),
+            "rsync  version 3.2.3  protocol version 31"
+        );
+        assert!(parse_version_output(b"").is_err());
+        assert!(parse_version_output(b"bash: rsync: command not found\n").is_err());
+    }
+
     #[test]
     fn build_ssh_args_with_user() {
         let mut client = Client::ssh(Some("mbp"), "samba.org", "/home/mbp");
         {
-            let mut options = client.mut_options();
+            let options = client.mut_options();
             options.recursive = true;
-            options.list_only = true;
         }
-        let args = client.build_args();
+        let args = client.build_args(TransferMode::List);
         assert_eq!(
             args,
             [
@@ -517,6 +1334,7 @@ mod test {
                 "--sender",
                 "--list-only",
                 "-r",
+                ".",
                 "/home/mbp"
             ],
         );
@@ -534,7 +1352,7 @@ mod test {
                 ssh_command: Some(ssh_args),
                 ..Options::default()
             })
-            .build_args();
+            .build_args(TransferMode::Download);
         assert_eq!(
             args,
             [
@@ -547,18 +1365,99 @@ mod test {
                 "rsync",
                 "--server",
                 "--sender",
+                ".",
                 "/home/www",
             ]
         );
     }
 
+    /// The remote shell sees the path as one word, so spaces and wildcards
+    /// aren't interpreted.
+    #[test]
+    fn build_ssh_args_quotes_path() {
+        let args = Client::from_str("bilbo:/home/my files/*.txt")
+            .unwrap()
+            .build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            [
+                "ssh",
+                "bilbo",
+                "rsync",
+                "--server",
+                "--sender",
+                ".",
+                "'/home/my files/*.txt'"
+            ]
+        );
+    }
+
+    /// Local paths are passed straight to rsync, without a shell.
+    #[test]
+    fn build_local_args_does_not_quote_path() {
+        let args = Client::local("/home/my files/*.txt").build_args(TransferMode::Download);
+        assert_eq!(args.last().unwrap(), "/home/my files/*.txt");
+    }
+
+    #[test]
+    fn quote_remote_paths() {
+        assert_eq!(quote_remote_path("/plain/path"), "/plain/path");
+        assert_eq!(quote_remote_path("it's"), r"'it'\''s'");
+        assert_eq!(quote_remote_path("~/my dir"), "~'/my dir'");
+        assert_eq!(quote_remote_path("~mbp"), "~mbp");
+        assert_eq!(quote_remote_path("~mbp/a;b"), "~mbp'/a;b'");
+    }
+
+    /// With `protect_args`, the path and options are sent over the
+    /// connection, not on the command line.
+    #[test]
+    fn build_ssh_args_protect_args() {
+        let mut client = Client::from_str("bilbo:/home/my files/*").unwrap();
+        client.mut_options().protect_args = true;
+        client.mut_options().recursive = true;
+        assert_eq!(
+            client.build_args(TransferMode::Download),
+            ["ssh", "bilbo", "rsync", "--server", "--sender", "-s"]
+        );
+        assert_eq!(
+            client.protected_args(TransferMode::Download),
+            b"rsync\0-r\0.\0/home/my files/*\0\0"
+        );
+    }
+
+    /// If a password is given in the environment, it's not logged, even if
+    /// it's on the command line.
+    #[test]
+    fn command_description_redacts_password() {
+        let mut client = Client::from_str("bilbo:/home/www").unwrap();
+        client.mut_options().ssh_command = Some(vec![
+            "sshpass".to_owned(),
+            "-p".to_owned(),
+            "open-sesame".to_owned(),
+            "ssh".to_owned(),
+        ]);
+        let password = Some("open-sesame".to_owned());
+        let args = client.build_args(TransferMode::Download);
+        let description = client.describe_command_hiding(&args, password.clone());
+        assert!(!description.contains("open-sesame"), "{}", description);
+        assert!(
+            description.contains(r#""-p", "***", "ssh""#),
+            "{}",
+            description
+        );
+
+        client.mut_options().log_secrets = true;
+        assert!(client
+            .describe_command_hiding(&args, password)
+            .contains("open-sesame"));
+    }
+
     /// SSH with no path should say '.', typically to look in the home
     /// directory.
     #[test]
     fn build_ssh_args_for_default_directory() {
-        let mut client = Client::from_str("example-host:").unwrap();
-        client.mut_options().list_only = true;
-        let args = client.build_args();
+        let client = Client::from_str("example-host:").unwrap();
+        let args = client.build_args(TransferMode::List);
         assert_eq!(
             args,
             [
@@ -568,18 +1467,330 @@ mod test {
                 "--server",
                 "--sender",
                 "--list-only",
+                ".",
                 "."
             ],
         );
     }
 
+    #[test]
+    fn build_local_args_one_file_system() {
+        let mut client = Client::local("./src");
+        client.set_recursive(true).mut_options().one_file_system = true;
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            ["rsync", "--server", "--sender", "-r", "-x", ".", "./src"],
+        );
+    }
+
+    #[test]
+    fn build_local_args_relative_no_implied_dirs() {
+        let mut client = Client::local("a/b/c");
+        client.mut_options().relative = true;
+        client.mut_options().no_implied_dirs = true;
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            [
+                "rsync",
+                "--server",
+                "--sender",
+                "-R",
+                "--no-implied-dirs",
+                ".",
+                "a/b/c"
+            ],
+        );
+    }
+
+    #[test]
+    fn build_local_args_copy_unsafe_links() {
+        let mut client = Client::local("./src");
+        client.mut_options().preserve_links = true;
+        client.mut_options().copy_unsafe_links = true;
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            [
+                "rsync",
+                "--server",
+                "--sender",
+                "-l",
+                "--copy-unsafe-links",
+                ".",
+                "./src"
+            ],
+        );
+    }
+
+    #[test]
+    fn build_local_args_size_only() {
+        let mut client = Client::local("./src");
+        client.mut_options().size_only = true;
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            ["rsync", "--server", "--sender", "--size-only", ".", "./src"],
+        );
+    }
+
+    #[test]
+    fn build_local_args_compress() {
+        let mut client = Client::local("./src");
+        client.mut_options().compress_choice = Some("zlib".to_owned());
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(args, ["rsync", "--server", "--sender", "-z", ".", "./src"]);
+        client.mut_options().compress_choice = Some("zstd".to_owned());
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(args, ["rsync", "--server", "--sender", ".", "./src"]);
+    }
+
+    #[test]
+    fn build_local_args_block_size() {
+        let mut client = Client::local("./src");
+        client.mut_options().block_size = Some(4096);
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            ["rsync", "--server", "--sender", "-B4096", ".", "./src"],
+        );
+    }
+
+    #[test]
+    fn build_local_args_checksum_seed() {
+        let mut client = Client::local("./src");
+        client.mut_options().checksum_seed = Some(-7);
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            [
+                "rsync",
+                "--server",
+                "--sender",
+                "--checksum-seed=-7",
+                ".",
+                "./src"
+            ],
+        );
+    }
+
+    #[test]
+    fn build_local_args_dry_run() {
+        let mut client = Client::local("./src");
+        client.mut_options().dry_run = true;
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(args, ["rsync", "--server", "--sender", "-n", ".", "./src"]);
+    }
+
+    #[test]
+    fn build_local_args_ignore_times_and_update() {
+        let mut client = Client::local("./src");
+        client.mut_options().ignore_times = true;
+        client.mut_options().update = true;
+        let args = client.build_args(TransferMode::Download);
+        assert_eq!(
+            args,
+            ["rsync", "--server", "--sender", "-I", "-u", ".", "./src"]
+        );
+    }
+
+    /// Only listing asks the server not to send file contents.
+    #[test]
+    fn list_mode_passes_list_only() {
+        let client = Client::local("./src");
+        assert_eq!(
+            client.build_args(TransferMode::List),
+            ["rsync", "--server", "--sender", "--list-only", ".", "./src"],
+        );
+        assert_eq!(
+            client.build_args(TransferMode::Download),
+            ["rsync", "--server", "--sender", ".", "./src"],
+        );
+    }
+
+    #[test]
+    fn upload_is_not_implemented() {
+        let mut client = Client::local("./src");
+        client.mut_options().rsync_command = Some(vec!["/nonexistent/rsync".to_owned()]);
+        let err = client.connect(TransferMode::Upload).err().unwrap();
+        assert!(
+            err.to_string().contains("not implemented"),
+            "unexpected error {:?}",
+            err
+        );
+    }
+
+    /// List a module from a stub daemon listening on a Unix socket.
+    #[cfg(unix)]
+    #[test]
+    fn list_over_unix_socket() {
+        use std::io::prelude::*;
+        use std::os::unix::net::UnixListener;
+
+        use crate::connection::test::ServerScript;
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_client_unix_socket")
+            .tempdir()
+            .unwrap();
+        let socket_path = tmp.path().join("rsyncd.sock");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b".", 0, 0o040755)
+            .file_entry(b"a", 5, 0o100644)
+            .end_file_list()
+            .finish();
+        let response = script.daemon_stream(&["@RSYNCD: 31.0", "Welcome!", "@RSYNCD: OK"]);
+        let server = std::thread::spawn(move || {
+            let (mut stream, _addr) = listener.accept().unwrap();
+            stream.write_all(&response).unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let client = Client::unix_socket(&socket_path, "module/");
+        let (file_list, _summary) = client.list_files().unwrap();
+        let names: Vec<_> = file_list.iter().map(|e| e.name_lossy_string()).collect();
+        assert_eq!(names, [".", "a"]);
+
+        let received = server.join().unwrap();
+        let expected_text = "@RSYNCD: 27\nmodule\n--server\n--sender\n--list-only\n.\nmodule/\n\n";
+        assert_eq!(
+            String::from_utf8_lossy(&received[..expected_text.len()]),
+            expected_text
+        );
+    }
+
+    /// Download a file from a stub daemon, checking that the checksum seed
+    /// is read from the right place, so the file's MD4 matches.
+    #[cfg(unix)]
+    #[test]
+    fn download_over_unix_socket() {
+        use std::io::prelude::*;
+        use std::os::unix::net::UnixListener;
+
+        use crate::connection::test::ServerScript;
+        use crate::MemoryTree;
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_client_unix_socket_download")
+            .tempdir()
+            .unwrap();
+        let socket_path = tmp.path().join("rsyncd.sock");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let mut script = ServerScript::new(27, 0x5eed);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x5eed)
+            .finish();
+        let response = script.daemon_stream(&["@RSYNCD: 31.0", "@RSYNCD: OK"]);
+        let server = std::thread::spawn(move || {
+            let (mut stream, _addr) = listener.accept().unwrap();
+            stream.write_all(&response).unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+        });
+
+        let tree = MemoryTree::new();
+        let (_file_list, summary) = Client::unix_socket(&socket_path, "module/")
+            .download(&mut tree.clone())
+            .unwrap();
+        server.join().unwrap();
+        assert_eq!(summary.files_received, 1);
+        assert_eq!(summary.whole_file_sum_mismatch_count, 0);
+        assert_eq!(tree.file_content(b"a").unwrap(), b"hello");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn download_file_to_string_over_unix_socket() {
+        use std::io::prelude::*;
+        use std::os::unix::net::UnixListener;
+
+        use crate::connection::test::ServerScript;
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_client_download_file_to_string")
+            .tempdir()
+            .unwrap();
+        let download = |content: &[u8]| {
+            let socket_path = tmp.path().join("rsyncd.sock");
+            let _ = std::fs::remove_file(&socket_path);
+            let listener = UnixListener::bind(&socket_path).unwrap();
+            let mut script = ServerScript::new(27, 0x5eed);
+            script
+                .file_entry(b"config.txt", content.len() as i64, 0o100644)
+                .end_file_list()
+                .file_data(0, content, 0x5eed)
+                .finish();
+            let response = script.daemon_stream(&["@RSYNCD: 31.0", "@RSYNCD: OK"]);
+            let server = std::thread::spawn(move || {
+                let (mut stream, _addr) = listener.accept().unwrap();
+                stream.write_all(&response).unwrap();
+                let mut received = Vec::new();
+                stream.read_to_end(&mut received).unwrap();
+            });
+            let result =
+                Client::unix_socket(&socket_path, "module/").download_file_to_string("config.txt");
+            server.join().unwrap();
+            result
+        };
+
+        assert_eq!(download(b"key = \"value\"\n").unwrap(), "key = \"value\"\n");
+        assert_eq!(
+            download(b"\xff\xfe").unwrap_err().to_string(),
+            "\"config.txt\" is not UTF-8"
+        );
+    }
+
+    /// Once one listing fails, no more are started, even by connections
+    /// that are succeeding.
+    #[cfg(unix)]
+    #[test]
+    fn list_many_stops_after_an_error() {
+        use crate::connection::test::ServerScript;
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_list_many_stops")
+            .tempdir()
+            .unwrap();
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"x", 0, 0o100644)
+            .end_file_list()
+            .finish();
+        let script_path = tmp.path().join("script");
+        std::fs::write(&script_path, script.server_sent()).unwrap();
+        let log_path = tmp.path().join("log");
+        // A server that records the path it was asked for, and then fails
+        // straight away for "a", and lists the others after a while.
+        let server = format!(
+            r#"for path; do :; done; echo "$path" >>'{}'
+            [ "$path" = a ] && exit 1
+            sleep 0.2; cat '{}'; cat >/dev/null"#,
+            log_path.display(),
+            script_path.display()
+        );
+        let mut client = Client::local(".");
+        client.mut_options().rsync_command = Some(vec!["sh".to_owned(), "-c".to_owned(), server]);
+
+        let err = client.list_many(&["a", "b", "c", "d"], 2).unwrap_err();
+        assert_eq!(format!("{}", err), "Failed to list \"a\"");
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        assert!(log.lines().count() <= 2, "{}", log);
+    }
+
     /// Daemon mode is not implemented yet.
     #[test]
     #[should_panic]
     fn daemon_connection_unimplemented() {
         Client::from_str("rsync.example.com::example")
             .unwrap()
-            .connect()
+            .connect(TransferMode::List)
             .unwrap();
     }
 }
@@ -16,43 +16,124 @@
 
 #![allow(unused_imports)]
 
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::io;
 use std::io::prelude::*;
 use std::io::ErrorKind;
 use std::path::Path;
 use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use crossbeam::thread;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use md4::{Digest, Md4};
 
-use crate::flist::{read_file_list, FileEntry, FileList};
+use crate::counting::WireCounters;
+use crate::deflate::DeflatedTokens;
+use crate::delete::{delete_extraneous, resolve_type_conflicts};
+use crate::error::LocalIoContext;
+use crate::filter;
+use crate::flist::{
+    link_referent, read_file_list, read_file_list_raw, sort_and_dedupe, FileEntry, FileList,
+};
+use crate::ids::set_owners;
+use crate::itemize::{count_changes, itemize};
 use crate::mux::DemuxRead;
+use crate::outformat::format_entry;
+use crate::quickcheck::{clock_skew_warning, should_transfer, Decision};
 use crate::sums::SumHead;
+use crate::timeout::{TimeoutReader, TimeoutSetting};
+use crate::tree::{backup, write_sparse};
 use crate::varint::{ReadVarint, WriteVarint};
-use crate::{LocalTree, Options, ServerStatistics, Summary};
+use crate::{
+    DeleteMode, Error, FileWriter, Options, PendingFile, ServerStatistics, Summary,
+    SymlinkFallback, TransferMode, Transport, Tree, MD4_SUM_LENGTH,
+};
 
-const MY_PROTOCOL_VERSION: i32 = 27;
+pub(crate) const MY_PROTOCOL_VERSION: i32 = 27;
+
+/// Protocol versions above this are taken to be something other than rsync
+/// talking, such as text printed by the remote shell.
+const MAX_PLAUSIBLE_PROTOCOL_VERSION: i32 = 1000;
+
+/// What was learned about a server from the protocol handshake.
+///
+/// Returned by [`Client::probe`](struct.Client.html#method.probe).
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ProtocolInfo {
+    /// The mutually-agreed protocol version that would be used for transfers.
+    pub protocol_version: i32,
+
+    /// The newest protocol version supported by the server.
+    pub remote_protocol_version: i32,
+
+    /// Lines of the message of the day sent by an rsync daemon.
+    ///
+    /// Always empty for connections that don't use the daemon protocol.
+    pub motd: Vec<String>,
+}
 
 /// Connection to an rsync server.
 ///
+/// Connections are opened by [`Client::connect`](struct.Client.html#method.connect).
+///
 /// Due to the protocol definition, only one transfer (list, send, or receive)
-/// can be done per connection.
-pub(crate) struct Connection {
-    rv: ReadVarint,
+/// can be done per connection, so the transfer methods consume the connection.
+pub struct Connection {
+    /// The child process carrying this connection, if any.
+    ///
+    /// This is first so that it's dropped first, and then the stream to the
+    /// server: killing the child, or closing our side, closes the stream
+    /// that a timeout reader's thread is waiting on.
+    child: ChildGuard,
+
     wv: WriteVarint,
+    rv: ReadVarint,
 
     /// Mutually-agreed rsync protocol version number.
     protocol_version: i32,
 
+    /// The protocol version offered by the server.
+    remote_protocol_version: i32,
+
     /// Permutation to checksums, pushed as a le i32 at the start of file MD4s.
     checksum_seed: i32,
 
-    /// The child process carrying this connection.
-    child: Child,
+    /// File data is compressed with zlib.
+    compress: bool,
+
+    /// Message of the day sent by an rsync daemon.
+    motd: Vec<String>,
+
+    /// Bytes moved over the transport, as measured by the client.
+    counters: WireCounters,
+
+    /// The current timeout for reads from the server.
+    timeout: TimeoutSetting,
+
+    /// What kind of transfer the server was started for.
+    mode: TransferMode,
+
+    /// How the connection reaches the server.
+    transport: Transport,
+
+    /// IO errors the server reports in `MSG_IO_ERROR` messages.
+    ///
+    /// rsync only sends these from protocol 30, so at protocol 27 this stays
+    /// zero. The messages are decoded anyway, since they can be told apart
+    /// at any version, so that they're counted rather than misread if a
+    /// server does send one.
+    io_error: Arc<AtomicI32>,
+
+    /// Indexes of files the server says it won't send, in `MSG_NO_SEND`.
+    ///
+    /// Like `io_error`, rsync only sends these from protocol 30.
+    no_send: Arc<Mutex<Vec<i32>>>,
 
     /// Connection options, corresponding to a subset of rsync command-line options.
     ///
@@ -67,111 +148,687 @@ impl Connection {
     pub(crate) fn handshake(
         r: Box<dyn Read + Send>,
         w: Box<dyn Write + Send>,
-        child: Child,
+        child: Option<Child>,
+        mode: TransferMode,
         options: Options,
     ) -> Result<Connection> {
-        let mut wv = WriteVarint::new(w);
+        let counters = WireCounters::default();
+        let mut wv = WriteVarint::new(counters.count_write(w));
+        let (r, timeout) = with_timeout(counters.count_read(r), &options);
         let mut rv = ReadVarint::new(r);
 
         wv.write_i32(MY_PROTOCOL_VERSION)?;
-        let remote_protocol_version = rv.read_i32().unwrap();
-        if remote_protocol_version < MY_PROTOCOL_VERSION {
+        let remote_protocol_version = rv
+            .read_i32()
+            .context("Failed to read server protocol version")?;
+        if remote_protocol_version <= 0 || remote_protocol_version > MAX_PLAUSIBLE_PROTOCOL_VERSION
+        {
+            bail!(
+                "Server sent {:?} rather than an rsync protocol version: perhaps \
+                 the remote shell printed a message before starting rsync",
+                String::from_utf8_lossy(&remote_protocol_version.to_le_bytes())
+            );
+        }
+        Connection::start(
+            rv,
+            wv,
+            remote_protocol_version,
+            Vec::new(),
+            counters,
+            timeout,
+            child,
+            mode,
+            options,
+        )
+    }
+
+    /// Continue starting a connection after the protocol versions are
+    /// exchanged, either in binary or by an rsync daemon greeting.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn start(
+        mut rv: ReadVarint,
+        wv: WriteVarint,
+        remote_protocol_version: i32,
+        motd: Vec<String>,
+        counters: WireCounters,
+        timeout: TimeoutSetting,
+        child: Option<Child>,
+        mode: TransferMode,
+        options: Options,
+    ) -> Result<Connection> {
+        // Binary handshakes already reject garbage, but a daemon's greeting
+        // can still carry any number.
+        if remote_protocol_version <= 0 || remote_protocol_version > MAX_PLAUSIBLE_PROTOCOL_VERSION
+        {
             bail!(
-                "server protocol version {} is too old",
+                "Server sent implausible protocol version {}",
                 remote_protocol_version
             );
+        } else if remote_protocol_version < MY_PROTOCOL_VERSION {
+            // TODO: Suggest a lower version here, if rsyn learns to speak
+            // older protocols.
+            return Err(Error::ProtocolTooOld {
+                server_version: remote_protocol_version,
+                minimum_version: MY_PROTOCOL_VERSION,
+            }
+            .into());
         }
         // The server and client agree to use the minimum supported version,
         // which will now be ours, because we refuse to accept anything
         // older.
+        let protocol_version = std::cmp::min(MY_PROTOCOL_VERSION, remote_protocol_version);
+        debug!("Agreed protocol version {}", protocol_version);
 
-        let checksum_seed = rv.read_i32().unwrap();
+        let checksum_seed = rv.read_i32().context("Failed to read checksum seed")?;
         debug!(
             "Connected to server version {}, checksum_seed {:#x}",
             remote_protocol_version, checksum_seed
         );
-        let protocol_version = std::cmp::min(MY_PROTOCOL_VERSION, remote_protocol_version);
-        debug!("Agreed protocol version {}", protocol_version);
+        match options.checksum_seed {
+            Some(wanted) if wanted != checksum_seed => bail!(
+                "Server sent checksum seed {:#x}, but checksum_seed {:#x} was requested",
+                checksum_seed,
+                wanted
+            ),
+            _ => (),
+        }
+        // The client asked for compression on the server's command line, if
+        // it's going to be used.
+        let compress = options.negotiate_compression() == Some("zlib");
+        // Before checksums are negotiated by name, in protocol 30, the only
+        // one is MD4. rsyn doesn't negotiate names yet even in later
+        // versions.
+        // TODO: Read the server's list once rsyn sends CF_VARINT_FLIST_FLAGS.
+        let checksum = options.negotiate_checksum(&["md4"])?;
+        debug!("Using checksum {}", checksum);
+        options.check_block_size(protocol_version)?;
 
         // Server-to-client is multiplexed; client-to-server is not.
         // Pull back the underlying stream and wrap it in a demuxed varint
         // encoder.
-        let rv = ReadVarint::new(Box::new(DemuxRead::new(rv.take())));
+        let mut demux = DemuxRead::new(rv.take());
+        demux.set_max_alloc(options.max_alloc);
+        let io_error = demux.io_error();
+        let no_send = demux.no_send();
+        let mut rv = ReadVarint::new(Box::new(demux));
+        rv.set_max_alloc(options.max_alloc);
 
         Ok(Connection {
-            rv,
+            child: ChildGuard(child),
             wv,
+            rv,
             protocol_version,
+            remote_protocol_version,
             checksum_seed,
-            child,
+            compress,
+            motd,
+            counters,
+            timeout,
+            mode,
+            transport: Transport::Local,
+            io_error,
+            no_send,
             options,
         })
     }
 
-    /// Receive files from the server to the given LocalTree.
-    pub fn receive(mut self, local_tree: &mut LocalTree) -> Result<(FileList, Summary)> {
-        // Analogous to rsync/receiver.c recv_files().
-        // let max_phase = if self.protocol_version >= 29 { 2 } else { 1 };
-        let max_phase = 2;
-        let mut summary = Summary::default();
+    /// Close the connection straight after the handshake, returning what was
+    /// learned about the server.
+    pub(crate) fn probe(self) -> Result<ProtocolInfo> {
+        let info = ProtocolInfo {
+            protocol_version: self.protocol_version,
+            remote_protocol_version: self.remote_protocol_version,
+            motd: self.motd.clone(),
+        };
+        let Connection { rv, wv, child, .. } = self;
+        drop(wv);
+        if let Some(mut child) = child.take() {
+            // The server is now waiting for the exclusion list, and there's no
+            // polite way to say goodbye at this point in the protocol, so just
+            // stop it.
+            child.kill().context("Failed to kill child process")?;
+            let child_exit_status = child.wait()?;
+            debug!("Child process exited: {}", child_exit_status);
+        }
+        drop(rv);
+        Ok(info)
+    }
+
+    /// Returns the kind of transfer this connection was opened for.
+    pub fn mode(&self) -> TransferMode {
+        self.mode
+    }
+
+    /// Returns how this connection reaches the server.
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Record how this connection reaches the server, which is decided by
+    /// the `Client`.
+    pub(crate) fn set_transport(&mut self, transport: Transport) {
+        self.transport = transport;
+    }
+
+    /// Receive files from the server into the given tree, such as a
+    /// `LocalTree`.
+    ///
+    /// On a connection opened for `TransferMode::List`, this just returns the
+    /// file list, and the tree is untouched.
+    pub fn receive(self, tree: &mut dyn Tree) -> Result<(FileList, Summary)> {
+        let (file_list, mut summary, child) = self.receive_into_child(tree)?;
+        wait_for_child(child, &mut summary)?;
+        Ok((file_list, summary))
+    }
+
+    /// Receive files like [`receive`](#method.receive), but after the
+    /// protocol is complete, return the child process carrying the
+    /// connection, if there is one, rather than waiting for it to exit.
+    ///
+    /// This lets the caller manage the child, for example to wait for it
+    /// with a timeout, or kill it. `Summary::child_exit_status` is None.
+    ///
+    /// If the transfer fails, the child isn't returned: it's killed and
+    /// waited for, or if the protocol had already finished, just waited
+    /// for.
+    pub fn receive_into_child(
+        self,
+        tree: &mut dyn Tree,
+    ) -> Result<(FileList, Summary, Option<Child>)> {
+        self.receive_into_tree(tree, None)
+    }
+
+    /// Receive only the regular files at the given indexes in the file list
+    /// into the tree, whether or not they're already up to date.
+    ///
+    /// The indexes are into the sorted list that's returned, which is the
+    /// same as from an earlier listing of the same source with the same
+    /// options. Directories and symlinks are still created, but nothing is
+    /// deleted. It's an error if any index is out of range or isn't a
+    /// regular file.
+    ///
+    /// This consumes the connection, like [`receive`](#method.receive).
+    pub fn request_indexes(
+        self,
+        indexes: &[usize],
+        tree: &mut dyn Tree,
+    ) -> Result<(FileList, Summary)> {
+        let (file_list, mut summary, child) = self.receive_into_tree(tree, Some(indexes))?;
+        wait_for_child(child, &mut summary)?;
+        // The indexes are checked before any are requested, unless the list
+        // was empty.
+        check_indexes(&file_list, indexes)?;
+        Ok((file_list, summary))
+    }
+
+    /// Receive files into the tree: either those that aren't up to date, or
+    /// `only` the regular files at the given indexes.
+    fn receive_into_tree(
+        self,
+        tree: &mut dyn Tree,
+        only: Option<&[usize]>,
+    ) -> Result<(FileList, Summary, Option<Child>)> {
+        let options = self.options.clone();
+        let mode = self.mode;
+        let mut writer: Option<Box<dyn FileWriter>> = None;
+        // With `delay_updates`, completed files that are waiting to be
+        // committed when the transfer succeeds.
+        let mut delayed: Vec<(Vec<u8>, Box<dyn PendingFile>)> = Vec::new();
+        let received = self.receive_with_sink(&mut *tree, only, &mut |tree, entry, received| {
+            match received {
+                Received::Start => writer = Some(tree.create_file(entry)?),
+                Received::Data(data) => {
+                    let writer = writer.as_mut().expect("File is open for writing");
+                    if options.sparse {
+                        write_sparse(&mut **writer, data)
+                    } else {
+                        writer.write_all(data)
+                    }
+                    .local_context(|| format!("Failed to write {:?}", entry.name_lossy_string()))?
+                }
+                Received::End { sum_matches: true } => {
+                    let writer = writer.take().expect("File is open for writing");
+                    if options.delay_updates {
+                        delayed.push((entry.name_bytes().to_vec(), writer.close()?));
+                    } else {
+                        backup(tree, entry.name_bytes(), &options)?;
+                        writer.commit()?
+                    }
+                }
+                // Discard the file, leaving anything that was there before.
+                Received::End { sum_matches: false } => writer = None,
+            }
+            Ok(())
+        })?;
+        if !delayed.is_empty() {
+            debug!("Commit {} delayed updates", delayed.len());
+        }
+        for (name, writer) in delayed {
+            backup(tree, &name, &options)?;
+            writer.commit()?;
+        }
+        if options.symlink_fallback == SymlinkFallback::CopyReferent
+            && mode == TransferMode::Download
+            && !options.dry_run
+            && !tree.is_single_file()
+        {
+            copy_referents(tree, &received.0);
+        }
+        if (options.chown.is_some() || options.copy_as.is_some()) && !options.dry_run {
+            set_owners(tree, &received.0);
+        }
+        // After changing the owner, which can clear set-id bits.
+        if options.preserve_perms && !options.dry_run {
+            set_modes(tree, &received.0);
+        }
+        Ok(received)
+    }
+
+    /// Receive files into the tree, passing their content to `sink`, along
+    /// with the tree.
+    ///
+    /// Directories and symlinks are created in the tree before files are
+    /// requested. With `only`, just the files at those indexes are requested.
+    fn receive_with_sink(
+        self,
+        tree: &mut dyn Tree,
+        only: Option<&[usize]>,
+        sink: &mut (dyn FnMut(&mut dyn Tree, &FileEntry, Received<'_>) -> Result<()> + Send),
+    ) -> Result<(FileList, Summary, Option<Child>)> {
+        let options = self.options.clone();
+        let delete = match self.mode {
+            TransferMode::List => None,
+            // As in rsync, there's nothing to delete beside a single file,
+            // which is checked once the file list is known.
+            TransferMode::Download if options.dry_run || only.is_some() => None,
+            TransferMode::Download => options.delete,
+            TransferMode::Upload => bail!("Can't receive on a connection opened for upload"),
+        };
+        let mode = self.mode;
+        let io_error = self.io_error.clone();
+        // As in rsync, don't delete files that might only be missing from
+        // the file list because the server couldn't read them.
+        let may_delete = |summary: &Summary| {
+            let errors = summary.server_flist_io_error_count | io_error.load(Ordering::Relaxed);
+            if errors != 0 && !options.ignore_errors {
+                warn!("Server reported IO errors; not deleting files");
+                false
+            } else {
+                true
+            }
+        };
+        // The tree is used by the receiver thread while files are
+        // transferred, and from this thread before and after; so is the
+        // sink, which also writes empty files before the transfer.
+        let tree = Mutex::new(tree);
+        let sink = Mutex::new(sink);
+        self.receive_selected(
+            None,
+            |file_list, summary| {
+                if mode == TransferMode::List {
+                    return Ok(Vec::new());
+                }
+                if let Some(only) = only {
+                    check_indexes(file_list, only)?;
+                }
+                let mut guard = tree.lock().unwrap();
+                let tree: &mut dyn Tree = &mut **guard;
+                tree.prepare(file_list)?;
+                if options.dry_run {
+                    // Work out what would change, but leave the tree alone
+                    // and request nothing.
+                    let wanted = files_to_request(tree, file_list, &options);
+                    record_changes(tree, file_list, &wanted, &options, summary);
+                    return Ok(Vec::new());
+                }
+                if let Some(DeleteMode::Before) | Some(DeleteMode::Excluded) = delete {
+                    if !tree.is_single_file() && may_delete(summary) {
+                        summary.files_deleted += delete_extraneous(tree, file_list, &options)?;
+                    }
+                }
+                let skipped: HashSet<usize> =
+                    resolve_type_conflicts(tree, file_list, &options, summary)?
+                        .into_iter()
+                        .collect();
+                let mut wanted = match only {
+                    Some(only) => {
+                        let mut only = only.to_vec();
+                        only.sort_unstable();
+                        only.dedup();
+                        only
+                    }
+                    None => files_to_request(tree, file_list, &options),
+                };
+                wanted.retain(|idx| !skipped.contains(idx));
+                record_changes(tree, file_list, &wanted, &options, summary);
+                if !tree.is_single_file() {
+                    create_dirs_and_symlinks(tree, file_list, &skipped, &options)?;
+                }
+                // Empty files have no content to request, so just create them.
+                let (empty, wanted): (Vec<usize>, Vec<usize>) = wanted
+                    .into_iter()
+                    .partition(|&idx| file_list[idx].file_len == 0);
+                let mut sink = sink.lock().unwrap();
+                for idx in empty {
+                    let entry = &file_list[idx];
+                    debug!("Create empty file {:?}", entry.name_lossy_string());
+                    sink(tree, entry, Received::Start)?;
+                    sink(tree, entry, Received::End { sum_matches: true })?;
+                }
+                Ok(wanted)
+            },
+            &mut |entry, received| {
+                (sink.lock().unwrap())(&mut **tree.lock().unwrap(), entry, received)
+            },
+            |file_list, summary| {
+                let mut guard = tree.lock().unwrap();
+                if let (Some(DeleteMode::After), false) = (delete, guard.is_single_file()) {
+                    if may_delete(summary) {
+                        summary.files_deleted +=
+                            delete_extraneous(&mut **guard, file_list, &options)?;
+                    }
+                }
+                Ok(())
+            },
+        )
+    }
+
+    /// Receive the file list, without transferring any files, and return it
+    /// in the order the server sent it, including any duplicate names.
+    ///
+    /// This is mostly useful for debugging. The server refers to files by
+    /// their index in the list sorted by name, as returned by
+    /// [`receive`](#method.receive), not by their position in this list.
+    pub fn list_files_in_wire_order(self) -> Result<(FileList, Summary)> {
+        let mut wire_order = FileList::new();
+        let (_sorted, mut summary, child) = self.receive_selected(
+            Some(&mut wire_order),
+            |_file_list, _summary| Ok(Vec::new()),
+            &mut |_entry, _received| Ok(()),
+            |_file_list, _summary| Ok(()),
+        )?;
+        wait_for_child(child, &mut summary)?;
+        Ok((wire_order, summary))
+    }
+
+    /// Receive every regular file, without storing it, and return the MD4 of
+    /// each one's content, in file list order.
+    ///
+    /// The digests don't depend on the checksum seed, so they can be compared
+    /// to ones computed anywhere else. The server still sends the whole
+    /// content of every file, which is also checked against the server's
+    /// own whole-file sum as usual. It's an error if any file fails that
+    /// check, or isn't sent.
+    pub fn checksum_manifest(self) -> Result<Vec<(FileEntry, [u8; MD4_SUM_LENGTH])>> {
+        let mut manifest = Vec::new();
+        let mut hasher = Md4::new();
+        let (_file_list, mut summary, child) = self.receive_selected(
+            None,
+            |file_list, _summary| {
+                Ok((0..file_list.len())
+                    .filter(|&idx| file_list[idx].is_file())
+                    .collect())
+            },
+            &mut |entry, received| {
+                match received {
+                    Received::Start => hasher.reset(),
+                    Received::Data(data) => hasher.input(data),
+                    Received::End { sum_matches: true } => {
+                        let mut digest = [0; MD4_SUM_LENGTH];
+                        digest.copy_from_slice(&hasher.result_reset());
+                        manifest.push((entry.clone(), digest));
+                    }
+                    Received::End { sum_matches: false } => (),
+                }
+                Ok(())
+            },
+            |file_list, summary| {
+                let files = file_list.iter().filter(|entry| entry.is_file()).count();
+                if summary.whole_file_sum_mismatch_count > 0 {
+                    bail!(
+                        "MD4 mismatch for {} of {} files",
+                        summary.whole_file_sum_mismatch_count,
+                        files
+                    );
+                } else if summary.files_received < files {
+                    bail!(
+                        "Server sent only {} of {} files",
+                        summary.files_received,
+                        files
+                    );
+                }
+                Ok(())
+            },
+        )?;
+        wait_for_child(child, &mut summary)?;
+        // Files are normally sent in the order they're requested.
+        manifest.sort_by(|(a, _), (b, _)| a.name_bytes().cmp(b.name_bytes()));
+        Ok(manifest)
+    }
+
+    /// Fetch up to `len` bytes starting at `offset` from one regular file.
+    ///
+    /// `entry` is found by name in the file list sent by the server, so it
+    /// would typically come from an earlier listing of the same source.
+    ///
+    /// The result is shorter than `len` if the file ends sooner.
+    ///
+    /// This is currently no cheaper than fetching the whole file: the server
+    /// sends all of it, and everything outside the range is discarded. Only
+    /// the requested range is kept in memory.
+    pub fn fetch_range(self, entry: &FileEntry, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let end = offset.saturating_add(len as u64);
+        let mut buf = Vec::new();
+        self.receive_range_to(entry.name_bytes(), offset, end, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Fetch the whole content of the regular file called `name` into memory.
+    ///
+    /// `name` is relative to the top of the source, as in the file list, and
+    /// nothing is written to the local filesystem.
+    pub fn fetch_file(self, name: &[u8]) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.receive_file_to(name, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Receive the content of the regular file called `name`, writing it to
+    /// `out` as it arrives.
+    ///
+    /// `out` can be anything, such as a socket or a compressor. The content
+    /// is written before its MD4 can be checked, so if an error is returned,
+    /// `out` might have received some or all of the content anyway.
+    pub fn receive_file_to(self, name: &[u8], out: &mut (dyn Write + Send)) -> Result<Summary> {
+        self.receive_range_to(name, 0, u64::MAX, out)
+    }
+
+    /// Receive the bytes from `offset` up to `end` of the regular file
+    /// `name`, writing them to `out`.
+    fn receive_range_to(
+        self,
+        name: &[u8],
+        offset: u64,
+        end: u64,
+        out: &mut (dyn Write + Send),
+    ) -> Result<Summary> {
+        let mut pos: u64 = 0;
+        let (file_list, mut summary, child) = self.receive_selected(
+            None,
+            |file_list, _summary| {
+                Ok(file_list
+                    .iter()
+                    .position(|e| e.is_file() && e.name_bytes() == name)
+                    .into_iter()
+                    .collect())
+            },
+            &mut |_entry, received| {
+                let data = match received {
+                    Received::Data(data) => data,
+                    _ => return Ok(()),
+                };
+                let start = pos;
+                pos += data.len() as u64;
+                let (lo, hi) = (start.max(offset), pos.min(end));
+                if lo < hi {
+                    out.write_all(&data[(lo - start) as usize..(hi - start) as usize])
+                        .context("Failed to write received data")?;
+                }
+                Ok(())
+            },
+            |_file_list, _summary| Ok(()),
+        )?;
+        wait_for_child(child, &mut summary)?;
+        let lossy_name = String::from_utf8_lossy(name);
+        if !file_list
+            .iter()
+            .any(|e| e.is_file() && e.name_bytes() == name)
+        {
+            bail!("{:?} is not a regular file on the server", lossy_name);
+        } else if summary.files_received == 0 {
+            bail!("Server didn't send {:?}", lossy_name);
+        } else if summary.whole_file_sum_mismatch_count > 0 {
+            bail!("MD4 mismatch for {:?}", lossy_name);
+        }
+        Ok(summary)
+    }
 
-        send_empty_exclusions(&mut self.wv)?;
-        let file_list = read_file_list(&mut self.rv)?;
-        // TODO: With -o, get uid list.
-        // TODO: With -g, get gid list.
+    /// Receive the file list, then the files whose indexes are returned by
+    /// `plan`, passing their content to `sink` as it arrives.
+    ///
+    /// If `wire_order` is given, the file list is also stored there, in the
+    /// order it was sent, before it's sorted.
+    ///
+    /// `plan` can also do anything else that must happen before the
+    /// transfer, like deleting extraneous files, and `finish` is called after
+    /// all the files are received.
+    ///
+    /// The child process, if any, is returned without waiting for it.
+    fn receive_selected<P, F>(
+        mut self,
+        wire_order: Option<&mut FileList>,
+        plan: P,
+        sink: &mut (dyn FnMut(&FileEntry, Received<'_>) -> Result<()> + Send),
+        finish: F,
+    ) -> Result<(FileList, Summary, Option<Child>)>
+    where
+        P: FnOnce(&[FileEntry], &mut Summary) -> Result<Vec<usize>>,
+        F: FnOnce(&[FileEntry], &mut Summary) -> Result<()>,
+    {
+        // Analogous to rsync/receiver.c recv_files(). Each phase ends when
+        // both sides have sent a -1 marker. Files are transferred in the
+        // first phase, and the second is for redoing files that failed their
+        // checksum, which we never ask for.
+        const PHASES: i32 = 2;
+        let start = Instant::now();
+        let mut summary = Summary {
+            transport: self.transport,
+            ..Summary::default()
+        };
 
-        if self.protocol_version < 30 {
-            let io_error_count = self
-                .rv
-                .read_i32()
-                .context("Failed to read server error count")?;
-            if io_error_count > 0 {
-                warn!("Server reports {} IO errors", io_error_count);
+        self.timeout.set(self.options.effective_flist_timeout());
+        send_filter_rules(&mut self.wv, &filter::all_rules(&self.options)?)?;
+        let flist_start = self.counters.bytes_read();
+        let read = match wire_order {
+            None => read_file_list(&mut self.rv, &self.options),
+            Some(wire_order) => {
+                read_file_list_raw(&mut self.rv, &self.options).map(|(raw, io_error_count)| {
+                    let mut file_list = raw.clone();
+                    sort_and_dedupe(&mut file_list);
+                    *wire_order = raw;
+                    (file_list, io_error_count)
+                })
+            }
+        };
+        let (file_list, io_error_count) = read.map_err(|err| self.kill_if_cancelled(err))?;
+        summary.file_list_bytes = self.counters.bytes_read() - flist_start;
+        if io_error_count != 0 {
+            warn!("Server reports IO errors {:#x}", io_error_count);
+        }
+        summary.server_flist_io_error_count = io_error_count;
+        if !self.options.size_only && !self.options.ignore_times {
+            if let Some(warning) = clock_skew_warning(&file_list, SystemTime::now()) {
+                warn!("{}", warning);
             }
-            summary.server_flist_io_error_count = io_error_count;
         }
 
-        // Server stops here if there were no files.
+        self.timeout.set(self.options.effective_transfer_timeout());
+
+        // Server stops here if there were no files: in every protocol version
+        // it exits without waiting for anything more from us, and rsync's
+        // client sends nothing more either.
         if file_list.is_empty() {
             info!("Server returned no files, so we're done");
-            // TODO: Maybe write one -1 here?
-            self.shutdown(&mut summary)?;
-            return Ok((file_list, summary));
+            summary.elapsed = start.elapsed();
+            let child = self.shutdown(&mut summary)?;
+            return Ok((file_list, summary, child));
         }
 
-        for phase in 1..=max_phase {
+        let wanted = plan(&file_list, &mut summary)?;
+        for phase in 1..=PHASES {
             debug!("Start phase {}", phase);
-            if phase == 1 && !self.options.list_only {
-                self.receive_files(&file_list, local_tree, &mut summary)?;
+            if phase == 1 && !wanted.is_empty() {
+                self.receive_files(&file_list, &wanted, sink, &mut summary)
+                    .map_err(|err| self.kill_if_cancelled(err))?;
             } else {
-                self.wv
-                    .write_i32(-1)
-                    .context("Failed to send phase transition")?;
-                assert_eq!(self.rv.read_i32()?, -1);
+                self.end_phase(phase)?;
             }
         }
+        finish(&file_list, &mut summary)?;
 
-        debug!("Send end of sequence");
-        self.wv
-            .write_i32(-1)
-            .context("Failed to send end-of-sequence marker")?;
-        // TODO: In later versions (which?) read an end-of-sequence marker?
-        summary.server_stats = read_server_statistics(&mut self.rv, self.protocol_version)
-            .context("Failed to read server statistics")?;
+        // The server sends its statistics without waiting for our goodbye,
+        // so these can be in either order.
+        debug!("Send final goodbye");
+        write_ndx_done(&mut self.wv).context("Failed to send final goodbye")?;
+        summary.server_stats =
+            read_server_statistics(&mut self.rv).context("Failed to read server statistics")?;
+        let listed_size = total_file_size(&file_list);
+        if listed_size != summary.server_stats.total_file_size {
+            // Unless entries were dropped from the list, this suggests it
+            // was misread.
+            warn!(
+                "Server's total file size {} doesn't match the file list's {}",
+                summary.server_stats.total_file_size, listed_size
+            );
+        }
 
-        // TODO: In later versions, send a final -1 marker.
-        self.shutdown(&mut summary)?;
+        let checksum_seed = self.checksum_seed;
+        summary.elapsed = start.elapsed();
+        let child = self.shutdown(&mut summary)?;
         info!("{:#?}", summary);
-        Ok((file_list, summary))
+        if let Err(err) = check_for_seed_mismatch(&summary, checksum_seed) {
+            wait_for_child(child, &mut summary)?;
+            return Err(err);
+        }
+        Ok((file_list, summary, child))
     }
 
-    /// Download all regular files.
+    /// End a phase in which we don't want any files: send the end-of-phase
+    /// marker, and expect the sender to send one straight back.
+    fn end_phase(&mut self, phase: i32) -> Result<()> {
+        write_ndx_done(&mut self.wv).context("Failed to send phase transition")?;
+        match self
+            .rv
+            .read_i32()
+            .context("Failed to read phase transition")?
+        {
+            -1 => Ok(()),
+            marker => bail!(
+                "Expected the end of phase {} from the sender, but received {}",
+                phase,
+                marker
+            ),
+        }
+    }
+
+    /// Download the regular files at the `wanted` indexes.
     ///
     /// Includes sending requests for them (with no basis) and receiving the data.
     fn receive_files(
         &mut self,
         file_list: &[FileEntry],
-        local_tree: &mut LocalTree,
+        wanted: &[usize],
+        sink: &mut (dyn FnMut(&FileEntry, Received<'_>) -> Result<()> + Send),
         summary: &mut Summary,
     ) -> Result<()> {
         // compare to `recv_generator` in generator.c.
@@ -179,76 +836,427 @@ impl Connection {
         let rv = &mut self.rv;
         let wv = &mut self.wv;
         let checksum_seed = self.checksum_seed;
-        thread::scope(|scope| {
-            scope
+        let compress = self.compress;
+        let options = &self.options;
+        let received = thread::scope(|scope| {
+            let receiver = scope
                 .builder()
                 .name("rsyn_receiver".to_owned())
-                .spawn(|_| receive_offered_files(rv, checksum_seed, file_list, local_tree, summary))
+                .spawn(|_| {
+                    receive_offered_files(
+                        rv,
+                        checksum_seed,
+                        compress,
+                        file_list,
+                        options,
+                        sink,
+                        summary,
+                    )
+                })
                 .expect("Failed to spawn receiver thread");
-            generate_files(wv, file_list).unwrap();
+            let generated = generate_files(wv, file_list, wanted);
+            let received = receiver.join().expect("Receiver thread panicked");
+            generated.and(received)
         })
         .unwrap();
         debug!("receive_files done");
-        Ok(()) // TODO: Handle errors from threads correctly
+        received
+    }
+
+    /// If `err` is from the `cancel` token, kill the server, which would
+    /// otherwise carry on sending the rest of the transfer. Returns `err`.
+    fn kill_if_cancelled(&mut self, err: anyhow::Error) -> anyhow::Error {
+        if let Some(Error::Cancelled) = err.downcast_ref::<Error>() {
+            if self.child.0.is_some() {
+                info!("Kill child process after cancellation");
+                self.child.kill();
+            }
+        }
+        err
     }
 
-    /// Shut down this connection, consuming the object.
+    /// Shut down this connection, consuming the object, and returning the
+    /// child process, if any, without waiting for it.
     ///
     /// This isn't the drop method, because it only makes sense to do after
     /// the protocol has reached the natural end.
-    fn shutdown(self, summary: &mut Summary) -> Result<()> {
+    fn shutdown(self, summary: &mut Summary) -> Result<Option<Child>> {
         let Connection {
-            rv,
+            child,
             wv,
-            protocol_version: _,
+            rv,
+            protocol_version,
+            remote_protocol_version: _,
             checksum_seed: _,
-            mut child,
+            compress: _,
+            motd: _,
+            counters,
+            timeout: _,
+            mode: _,
+            transport: _,
+            io_error,
+            no_send,
             options: _,
         } = self;
 
-        rv.check_for_eof()?;
+        // Close our side first, so that the server can't be left waiting for
+        // input while we wait for it to finish.
         drop(wv);
+        check_for_trailing_data(rv, protocol_version)?;
+        summary.server_flist_io_error_count |= io_error.load(Ordering::Relaxed);
+        summary.files_not_sent = no_send
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|&idx| idx as usize)
+            .collect();
+        summary.bytes_read = counters.bytes_read();
+        summary.bytes_written = counters.bytes_written();
+        Ok(child.take())
+    }
+}
+
+/// The child process carrying a connection, if any.
+///
+/// If this is dropped while it still holds the child, because the transfer
+/// failed or the connection was abandoned, the child is killed and waited
+/// for, rather than left running.
+struct ChildGuard(Option<Child>);
+
+impl ChildGuard {
+    /// Take the child, which is then no longer killed on drop.
+    fn take(mut self) -> Option<Child> {
+        self.0.take()
+    }
+
+    /// Kill the child, if there is one, and wait for it to exit.
+    fn kill(&mut self) {
+        if let Some(mut child) = self.0.take() {
+            if let Err(err) = child.kill() {
+                warn!("Failed to kill child process: {}", err);
+            } else if let Ok(status) = child.wait() {
+                debug!("Child process exited: {}", status);
+            }
+        }
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        if self.0.is_some() {
+            debug!("Kill child process of an unfinished connection");
+            self.kill();
+        }
+    }
+}
+
+/// Check that the server sent nothing more after the end of the protocol.
+///
+/// Anything left over means the two ends disagree about the protocol, for
+/// example about which statistics are sent, so the start of it is logged to
+/// help find what was misread.
+fn check_for_trailing_data(rv: ReadVarint, protocol_version: i32) -> Result<()> {
+    const SHOWN: u64 = 32;
+    let mut trailing = Vec::new();
+    rv.take()
+        .take(SHOWN)
+        .read_to_end(&mut trailing)
+        .context("Error looking for end of stream")?;
+    if !trailing.is_empty() {
+        warn!(
+            "Unexpected data from the server after the end of protocol {}: {}",
+            protocol_version,
+            hex::encode(&trailing)
+        );
+        bail!("Data found when we expected end of stream");
+    }
+    Ok(())
+}
+
+/// Count what will change in the tree, given the sorted indexes of files that
+/// will be requested, and with `itemize`, keep the description of each change.
+fn record_changes(
+    tree: &dyn Tree,
+    file_list: &[FileEntry],
+    wanted: &[usize],
+    options: &Options,
+    summary: &mut Summary,
+) {
+    let changes = itemize(tree, file_list, wanted);
+    count_changes(&changes, summary);
+    if options.itemize {
+        summary.changes = changes;
+    }
+}
 
+/// Wait for the child process carrying a connection, if there is one, and
+/// record its exit status.
+fn wait_for_child(child: Option<Child>, summary: &mut Summary) -> Result<()> {
+    if let Some(mut child) = child {
         // TODO: Should we timeout after a while?
         // TODO: Map rsync return codes to messages.
         let child_exit_status = child.wait()?;
         summary.child_exit_status = Some(child_exit_status);
         info!("Child process exited: {}", child_exit_status);
+    }
+    Ok(())
+}
+
+/// Wrap a stream from the server so reads fail after the configured timeout.
+///
+/// The returned setting starts as `options.timeout`, and can be changed
+/// later for each phase of the protocol.
+pub(crate) fn with_timeout(
+    r: Box<dyn Read + Send>,
+    options: &Options,
+) -> (Box<dyn Read + Send>, TimeoutSetting) {
+    let setting = TimeoutSetting::new(options.effective_timeout());
+    if options.has_timeouts() {
+        (Box::new(TimeoutReader::new(r, setting.clone())), setting)
+    } else {
+        (r, setting)
+    }
+}
 
-        Ok(())
+/// If every file failed its whole-file checksum, the seed (or the protocol)
+/// is probably wrong, rather than every file being corrupt.
+///
+/// A single mismatching file is not enough evidence to say so.
+fn check_for_seed_mismatch(summary: &Summary, checksum_seed: i32) -> Result<()> {
+    if summary.files_received >= 2
+        && summary.whole_file_sum_mismatch_count == summary.files_received
+    {
+        let err = Error::ChecksumSeedMismatch {
+            files: summary.files_received,
+            checksum_seed,
+        };
+        error!("{}", err);
+        return Err(err.into());
     }
+    Ok(())
 }
 
-fn read_server_statistics(rv: &mut ReadVarint, protocol_version: i32) -> Result<ServerStatistics> {
+/// Read the statistics the server sends at the end, like rsync's
+/// `handle_stats`.
+///
+/// At protocol 27 these are three longs. The file list times are only sent
+/// from protocol 29.
+fn read_server_statistics(rv: &mut ReadVarint) -> Result<ServerStatistics> {
     Ok(ServerStatistics {
         total_bytes_read: rv.read_i64()?,
         total_bytes_written: rv.read_i64()?,
         total_file_size: rv.read_i64()?,
-        flist_build_time: if protocol_version >= 29 {
-            Some(rv.read_i64()?)
-        } else {
-            None
-        },
-        flist_xfer_time: if protocol_version >= 29 {
-            Some(rv.read_i64()?)
-        } else {
-            None
-        },
+        flist_build_time: None,
+        flist_xfer_time: None,
     })
 }
 
-fn send_empty_exclusions(wv: &mut WriteVarint) -> Result<()> {
-    wv.write_i32(0).context("Failed to send exclusion list")
+/// Returns the total size of a file list, counted like
+/// `ServerStatistics::total_file_size`: regular files and symlinks, but not
+/// directories or special files.
+fn total_file_size(file_list: &[FileEntry]) -> i64 {
+    file_list
+        .iter()
+        .filter(|entry| entry.is_file() || entry.is_symlink())
+        .map(|entry| entry.file_len as i64)
+        .sum()
+}
+
+/// Send filter rules to the server, which will apply them as it builds the file list.
+fn send_filter_rules(wv: &mut WriteVarint, rules: &[String]) -> Result<()> {
+    // Compare to rsync `send_filter_list`. Before protocol 29, only the
+    // short `+ ` and `- ` prefixes are understood, but that's all we send.
+    for rule in rules {
+        debug!("Send filter rule {:?}", rule);
+        wv.write_i32(rule.len().try_into().context("Filter rule too long")?)
+            .and_then(|_| wv.write_byte_string(rule.as_bytes()))
+            .context("Failed to send filter rule")?;
+    }
+    wv.write_i32(0)
+        .context("Failed to send end of filter rules")
+}
+
+/// Create the directories and symlinks in the file list that aren't already
+/// in the tree, except those at the `skipped` indexes.
+///
+/// The list is sorted, so each directory is created before its contents.
+/// With `relative`, missing parents that aren't in the list are created too.
+fn create_dirs_and_symlinks(
+    tree: &mut dyn Tree,
+    file_list: &[FileEntry],
+    skipped: &HashSet<usize>,
+    options: &Options,
+) -> Result<()> {
+    for (idx, entry) in file_list.iter().enumerate() {
+        let name = entry.name_bytes();
+        if skipped.contains(&idx) || tree.stat(name).is_some() {
+            continue;
+        }
+        if options.relative {
+            create_implied_dirs(tree, name)?;
+        }
+        if entry.is_dir() {
+            debug!("Create directory {:?}", entry.name_lossy_string());
+            tree.create_dir(name)?;
+        } else if let Some(target) = entry.link_target() {
+            // The server should already have sent the referents of these, but
+            // if it didn't, don't create links out of the destination. They
+            // stay in the list, since files are requested by their index.
+            if options.copy_unsafe_links && entry.is_unsafe_symlink() {
+                warn!(
+                    "Skipping unsafe symlink {:?} from server",
+                    entry.name_lossy_string()
+                );
+                continue;
+            }
+            debug!("Create symlink {:?}", entry.name_lossy_string());
+            match (tree.symlink(name, target), options.symlink_fallback) {
+                (Ok(()), _) => (),
+                (Err(err), SymlinkFallback::Error) => return Err(err),
+                (Err(err), SymlinkFallback::SkipWithWarning) => {
+                    warn!("{:#}; skipping it", err)
+                }
+                (Err(err), SymlinkFallback::CopyReferent) => {
+                    warn!("{:#}; copying what it points to instead", err)
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Create the parents of `name` that don't exist, with default permissions.
+///
+/// These are only missing if the server didn't send them, as with
+/// `no_implied_dirs`, so their attributes are never set from the source.
+fn create_implied_dirs(tree: &mut dyn Tree, name: &[u8]) -> Result<()> {
+    for (pos, _) in name.iter().enumerate().filter(|(_, &b)| b == b'/') {
+        let parent = &name[..pos];
+        if !parent.is_empty() && tree.stat(parent).is_none() {
+            debug!(
+                "Create implied directory {:?}",
+                String::from_utf8_lossy(parent)
+            );
+            tree.create_dir(parent)?;
+        }
+    }
+    Ok(())
+}
+
+/// Put a copy of the file each symlink points to in place of symlinks that
+/// couldn't be created.
+///
+/// Only links to regular files in the file list are copied; others are
+/// skipped with a warning.
+fn copy_referents(tree: &mut dyn Tree, file_list: &[FileEntry]) {
+    for entry in file_list {
+        let (name, target) = match entry.link_target() {
+            Some(target) if tree.stat(entry.name_bytes()).is_none() => (entry.name_bytes(), target),
+            _ => continue,
+        };
+        let referent = link_referent(name, target).filter(|referent| {
+            file_list
+                .binary_search_by(|e| e.name_bytes().cmp(referent))
+                .is_ok_and(|idx| file_list[idx].is_file())
+        });
+        let result = match referent {
+            Some(referent) => tree.copy_file(&referent, name),
+            None => Err(anyhow!(
+                "Symlink {:?} doesn't point to a regular file in the transfer",
+                entry.name_lossy_string()
+            )),
+        };
+        match result {
+            Ok(()) => debug!(
+                "Copied the referent of symlink {:?}",
+                entry.name_lossy_string()
+            ),
+            Err(err) => warn!("{:#}; skipping it", err),
+        }
+    }
+}
+
+/// Set the permissions of everything in the file list that's in the tree with
+/// the same type, except symlinks.
+///
+/// If it fails, a warning is logged and no more permissions are changed.
+fn set_modes(tree: &mut dyn Tree, file_list: &[FileEntry]) {
+    for entry in file_list.iter().filter(|entry| !entry.is_symlink()) {
+        let name = entry.name_bytes();
+        match tree.stat(name) {
+            Some(local) if local.mode & 0o170000 == entry.mode & 0o170000 => {
+                if local.mode & 0o7777 == entry.mode & 0o7777 {
+                    continue;
+                }
+            }
+            _ => continue,
+        }
+        if let Err(err) = tree.set_mode(name, entry.mode) {
+            warn!("{:#}; not changing the permissions of any more files", err);
+            return;
+        }
+    }
+}
+
+/// Returns the indexes of the regular files that should be requested,
+/// skipping those that are already up to date in the local tree.
+fn files_to_request(
+    local_tree: &dyn Tree,
+    file_list: &[FileEntry],
+    options: &Options,
+) -> Vec<usize> {
+    file_list
+        .iter()
+        .enumerate()
+        .filter(|(_idx, entry)| entry.is_file())
+        .filter(|(_idx, entry)| {
+            let local = local_tree.stat(entry.name_bytes());
+            match should_transfer(entry, local.as_ref(), options) {
+                Decision::Transfer => true,
+                Decision::Skip(reason) => {
+                    debug!("Skip {:?}: {}", entry.name_lossy_string(), reason);
+                    false
+                }
+            }
+        })
+        .map(|(idx, _entry)| idx)
+        .collect()
+}
+
+/// Check that every index is of a regular file in the list.
+fn check_indexes(file_list: &[FileEntry], indexes: &[usize]) -> Result<()> {
+    for &idx in indexes {
+        match file_list.get(idx) {
+            None => bail!(
+                "Index {} is out of range for a file list of {} entries",
+                idx,
+                file_list.len()
+            ),
+            Some(entry) if !entry.is_file() => bail!(
+                "Entry {}, {:?}, is not a regular file",
+                idx,
+                entry.name_lossy_string()
+            ),
+            Some(_) => (),
+        }
+    }
+    Ok(())
 }
 
-fn generate_files(wv: &mut WriteVarint, file_list: &[FileEntry]) -> Result<()> {
-    for (idx, entry) in file_list.iter().enumerate().filter(|(_idx, e)| e.is_file()) {
+fn generate_files(wv: &mut WriteVarint, file_list: &[FileEntry], wanted: &[usize]) -> Result<()> {
+    for &idx in wanted {
+        let entry = &file_list[idx];
         debug!(
             "Send request for file idx {}, name {:?}",
             idx,
             entry.name_lossy_string()
         );
         wv.write_i32(idx.try_into().unwrap())?;
+        // TODO: `--append` and `--append-verify` would send a SumHead covering
+        // the length of the existing destination file, with no block sums, and
+        // then write only the data after it. `--append-verify` also feeds the
+        // existing prefix into the whole-file checksum, and retries the file
+        // in full in phase 2 if that doesn't match.
         SumHead::zero().write(wv)?;
     }
     debug!("Generator done");
@@ -257,29 +1265,66 @@ fn generate_files(wv: &mut WriteVarint, file_list: &[FileEntry]) -> Result<()> {
     Ok(())
 }
 
+/// Part of a received file, passed to a sink as it arrives.
+enum Received<'a> {
+    /// The sender started sending the file.
+    Start,
+
+    /// Some of the file's content.
+    Data(&'a [u8]),
+
+    /// The whole file was received, and its whole-file checksum did or
+    /// didn't match.
+    End { sum_matches: bool },
+}
+
+/// Send the `NDX_DONE` marker that ends a phase or the run, which is -1 as
+/// an int in the protocols rsyn speaks.
+///
+/// After the server's statistics, nothing more is read.
+///
+/// TODO: If rsyn speaks newer protocols: from protocol 29 there's a third
+/// phase, in which the sender finishes its own bookkeeping, and from 31 the
+/// server answers the final goodbye, which must be read.
+fn write_ndx_done(wv: &mut WriteVarint) -> Result<()> {
+    wv.write_i32(-1)?;
+    Ok(())
+}
+
 /// Receive files from the sender until it sends an end-of-phase marker.
 fn receive_offered_files(
     rv: &mut ReadVarint,
     checksum_seed: i32,
+    compress: bool,
     file_list: &[FileEntry],
-    local_tree: &mut LocalTree,
+    options: &Options,
+    sink: &mut (dyn FnMut(&FileEntry, Received<'_>) -> Result<()> + Send),
     summary: &mut Summary,
 ) -> Result<()> {
     // Files normally return in the order the receiver requests them, but this isn't guaranteed.
     // And if the sender fails to open the file, it just doesn't send any message, it just
     // continues to the next one.
     loop {
+        options.check_cancelled()?;
         let remote_idx = rv.read_i32()?;
         if remote_idx == -1 {
             debug!("Received end-of-phase marker");
             return Ok(());
         }
         let idx = remote_idx as usize;
-        if idx >= file_list.len() {
+        if remote_idx < 0 || idx >= file_list.len() {
             summary.invalid_file_index_count += 1;
-            error!("Remote file index {} is out of range", remote_idx)
+            bail!("Remote file index {} is out of range", remote_idx)
         }
-        receive_file(rv, checksum_seed, &file_list[idx], local_tree, summary)?;
+        receive_file(
+            rv,
+            checksum_seed,
+            compress,
+            &file_list[idx],
+            options,
+            sink,
+            summary,
+        )?;
         summary.files_received += 1;
     }
 }
@@ -287,8 +1332,10 @@ fn receive_offered_files(
 fn receive_file(
     rv: &mut ReadVarint,
     checksum_seed: i32,
+    compress: bool,
     entry: &FileEntry,
-    _local_tree: &LocalTree,
+    options: &Options,
+    sink: &mut (dyn FnMut(&FileEntry, Received<'_>) -> Result<()> + Send),
     summary: &mut Summary,
 ) -> Result<()> {
     // Like |receive_data|.
@@ -298,26 +1345,47 @@ fn receive_file(
     trace!("Got sums for {:?}: {:?}", name, sums);
     let mut hasher = Md4::new();
     hasher.input(checksum_seed.to_le_bytes());
-    loop {
-        // TODO: Specially handle data for deflate mode.
-        // Like rsync |simple_recv_token|.
-        let t = rv.read_i32()?;
-        if t == 0 {
-            break;
-        } else if t < 0 {
-            todo!("Block copy reference")
-        } else {
-            let t = t.try_into().unwrap();
-            let content = rv.read_byte_string(t)?;
-            assert_eq!(content.len(), t);
-            summary.literal_bytes_received += content.len();
-            hasher.input(content);
-            // TODO: Write it to the local tree.
+    let mut bytes_received: u64 = 0;
+    sink(entry, Received::Start)?;
+    let mut receive_data = |content: &[u8]| {
+        summary.literal_bytes_received += content.len();
+        bytes_received += content.len() as u64;
+        hasher.input(content);
+        sink(entry, Received::Data(content))
+    };
+    if compress {
+        let mut tokens = DeflatedTokens::new();
+        let mut content = Vec::new();
+        loop {
+            let more = tokens.read(rv, &mut content)?;
+            if !content.is_empty() {
+                receive_data(&content)?;
+                content.clear();
+            }
+            if !more {
+                break;
+            }
+        }
+    } else {
+        loop {
+            // Like rsync |simple_recv_token|.
+            let t = rv.read_i32()?;
+            if t == 0 {
+                break;
+            } else if t < 0 {
+                // TODO: Copy the block, once rsyn sends block sums.
+                bail!("Received a block reference, but rsyn sent no block sums")
+            } else {
+                let content = rv.read_byte_string(t as usize)?;
+                receive_data(&content)?;
+            }
         }
     }
     let remote_md4 = rv.read_byte_string(crate::MD4_SUM_LENGTH)?;
     let local_md4 = hasher.result();
-    if local_md4[..] != remote_md4[..] {
+    let sum_matches = local_md4[..] == remote_md4[..];
+    sink(entry, Received::End { sum_matches })?;
+    if !sum_matches {
         // TODO: Remember the error, but don't bail out. Try again in phase 2.
         summary.whole_file_sum_mismatch_count += 1;
         error!(
@@ -332,6 +1400,2524 @@ fn receive_file(
             name,
             hex::encode(&remote_md4)
         );
+        if let Some(out_format) = &options.out_format {
+            info!(
+                "{}",
+                format_entry(out_format, entry, bytes_received, options)
+            );
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::cell::RefCell;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::{mpsc, Arc, Once};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{Client, LocalTree, MemoryTree};
+
+    thread_local! {
+        /// Messages logged on this thread, while it's inside `logs_from`.
+        static LOGS: RefCell<Option<Vec<(log::Level, String)>>> = const { RefCell::new(None) };
+    }
+
+    /// Records messages of every level logged by threads inside
+    /// `logs_from`.
+    struct CapturingLogger;
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            LOGS.with(|logs| {
+                if let Some(logs) = logs.borrow_mut().as_mut() {
+                    logs.push((record.level(), record.args().to_string()));
+                }
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Run `f`, and return its result along with everything it logged on
+    /// this thread, down to trace level.
+    pub(crate) fn logs_from<R>(f: impl FnOnce() -> R) -> (R, Vec<(log::Level, String)>) {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CapturingLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        LOGS.with(|logs| *logs.borrow_mut() = Some(Vec::new()));
+        let result = f();
+        (result, LOGS.with(|logs| logs.borrow_mut().take().unwrap()))
+    }
+
+    /// Run `f`, and return its result along with the warnings it logged on
+    /// this thread.
+    fn warnings_from<R>(f: impl FnOnce() -> R) -> (R, Vec<String>) {
+        let (result, logs) = logs_from(f);
+        let warnings = logs
+            .into_iter()
+            .filter(|(level, _)| *level <= log::Level::Warn)
+            .map(|(_, message)| message)
+            .collect();
+        (result, warnings)
+    }
+
+    /// A `Write` that records everything written, and can be cloned to read it back.
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedBuf(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Builds the byte stream that a server would send, to drive a
+    /// `Connection` without a real server.
+    pub(crate) struct ServerScript {
+        /// Sent before multiplexing starts.
+        greeting: Vec<u8>,
+        /// Sent in multiplexed data packets.
+        body: Vec<u8>,
+        /// Other multiplexed messages, each with its tag and the offset in
+        /// `body` before which it's sent.
+        messages: Vec<(usize, u8, Vec<u8>)>,
+        /// Everything sent by the client.
+        client_sent: SharedBuf,
+    }
+
+    impl ServerScript {
+        pub(crate) fn new(protocol_version: i32, checksum_seed: i32) -> ServerScript {
+            let mut greeting = Vec::new();
+            greeting.extend_from_slice(&protocol_version.to_le_bytes());
+            greeting.extend_from_slice(&checksum_seed.to_le_bytes());
+            ServerScript {
+                greeting,
+                body: Vec::new(),
+                messages: Vec::new(),
+                client_sent: SharedBuf::default(),
+            }
+        }
+
+        pub(crate) fn i32(&mut self, v: i32) -> &mut Self {
+            self.body.extend_from_slice(&v.to_le_bytes());
+            self
+        }
+
+        pub(crate) fn i64(&mut self, v: i64) -> &mut Self {
+            if v >= 0 && v <= i32::MAX as i64 {
+                self.i32(v as i32)
+            } else {
+                self.i32(-1);
+                self.body.extend_from_slice(&v.to_le_bytes());
+                self
+            }
+        }
+
+        pub(crate) fn bytes(&mut self, b: &[u8]) -> &mut Self {
+            self.body.extend_from_slice(b);
+            self
+        }
+
+        /// Send a file list entry with no fields repeated from the previous
+        /// entry.
+        pub(crate) fn file_entry(&mut self, name: &[u8], len: i64, mode: u32) -> &mut Self {
+            self.bytes(&[crate::flist::STATUS_LONG_NAME])
+                .i32(name.len() as i32)
+                .bytes(name)
+                .i64(len)
+                .i32(1588429517)
+                .i32(mode as i32)
+        }
+
+        /// End the file list, and send a zero IO error count.
+        pub(crate) fn end_file_list(&mut self) -> &mut Self {
+            self.end_file_list_with_io_error(0)
+        }
+
+        /// End the file list, and send an IO error count.
+        pub(crate) fn end_file_list_with_io_error(&mut self, io_error: i32) -> &mut Self {
+            self.bytes(&[0]).i32(io_error)
+        }
+
+        /// Send the whole content of a file as literal data, followed by
+        /// an MD4 computed from `md4_seed`.
+        pub(crate) fn file_data(&mut self, idx: i32, content: &[u8], md4_seed: i32) -> &mut Self {
+            self.i32(idx);
+            for _ in 0..4 {
+                self.i32(0);
+            }
+            if !content.is_empty() {
+                self.i32(content.len() as i32).bytes(content);
+            }
+            self.i32(0);
+            let mut hasher = Md4::new();
+            hasher.input(md4_seed.to_le_bytes());
+            hasher.input(content);
+            self.bytes(&hasher.result())
+        }
+
+        /// Send a multiplexed message, between the data sent so far and
+        /// whatever follows.
+        pub(crate) fn message(&mut self, tag: u8, payload: &[u8]) -> &mut Self {
+            self.messages.push((self.body.len(), tag, payload.to_vec()));
+            self
+        }
+
+        /// Send the whole content of a file compressed with zlib, as for
+        /// `-z`, in packets of at most `packet_len` bytes, followed by an MD4
+        /// computed from `md4_seed`.
+        pub(crate) fn deflated_file_data(
+            &mut self,
+            idx: i32,
+            content: &[u8],
+            packet_len: usize,
+            md4_seed: i32,
+        ) -> &mut Self {
+            self.i32(idx);
+            for _ in 0..4 {
+                self.i32(0);
+            }
+            self.bytes(&crate::deflate::deflate_for_test(content, packet_len));
+            let mut hasher = Md4::new();
+            hasher.input(md4_seed.to_le_bytes());
+            hasher.input(content);
+            self.bytes(&hasher.result())
+        }
+
+        /// Send the end-of-phase markers and the server statistics for
+        /// protocol 27.
+        pub(crate) fn finish(&mut self) -> &mut Self {
+            self.i32(-1).i32(-1).i64(0).i64(0).i64(0)
+        }
+
+        /// Returns the body of the script, in multiplexed packets.
+        fn mux_body(&self) -> Vec<u8> {
+            fn packet(stream: &mut Vec<u8>, tag: u8, payload: &[u8]) {
+                stream
+                    .extend_from_slice(&(payload.len() as u32 | (tag as u32) << 24).to_le_bytes());
+                stream.extend_from_slice(payload);
+            }
+            let mut stream = Vec::new();
+            let mut start = 0;
+            let ends = self.messages.iter().map(Some).chain(Some(None));
+            for message in ends {
+                let end = message.map_or(self.body.len(), |(offset, _, _)| *offset);
+                for chunk in self.body[start..end].chunks(0xff_ffff) {
+                    packet(&mut stream, 7, chunk);
+                }
+                start = end;
+                if let Some((_, tag, payload)) = message {
+                    packet(&mut stream, *tag, payload);
+                }
+            }
+            stream
+        }
+
+        /// Returns what an rsync daemon would send for this script: the given
+        /// greeting lines, then the checksum seed, then the multiplexed body.
+        pub(crate) fn daemon_stream(&self, lines: &[&str]) -> Vec<u8> {
+            let mut stream = Vec::new();
+            for line in lines {
+                stream.extend_from_slice(line.as_bytes());
+                stream.push(b'\n');
+            }
+            stream.extend_from_slice(&self.greeting[4..8]);
+            stream.extend_from_slice(&self.mux_body());
+            stream
+        }
+
+        /// Returns everything the server sends, for a non-daemon connection.
+        pub(crate) fn server_sent(&self) -> Vec<u8> {
+            let mut stream = self.greeting.clone();
+            stream.extend_from_slice(&self.mux_body());
+            stream
+        }
+
+        /// Open a connection for a download that will read this script.
+        pub(crate) fn connect(&self, options: Options) -> Result<Connection> {
+            self.connect_for(TransferMode::Download, options)
+        }
+
+        /// Open a connection for the given kind of transfer that will read
+        /// this script.
+        pub(crate) fn connect_for(
+            &self,
+            mode: TransferMode,
+            options: Options,
+        ) -> Result<Connection> {
+            Connection::handshake(
+                Box::new(io::Cursor::new(self.server_sent())),
+                Box::new(self.client_sent.clone()),
+                None,
+                mode,
+                options,
+            )
+        }
+
+        /// Returns everything the client has sent so far, including the
+        /// protocol version.
+        pub(crate) fn client_sent(&self) -> Vec<u8> {
+            self.client_sent.0.lock().unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn probe() {
+        let info = ServerScript::new(31, 0x1234)
+            .connect(Options::default())
+            .unwrap()
+            .probe()
+            .unwrap();
+        assert_eq!(
+            info,
+            ProtocolInfo {
+                protocol_version: 27,
+                remote_protocol_version: 31,
+                motd: Vec::new(),
+            }
+        );
+    }
+
+    /// A reader that returns everything in `data`, and then blocks until the
+    /// returned sender is dropped.
+    fn stall_after(data: Vec<u8>) -> (mpsc::Sender<()>, Box<dyn Read + Send>) {
+        struct Stall(mpsc::Receiver<()>);
+
+        impl Read for Stall {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                let _ = self.0.recv();
+                Ok(0)
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        (tx, Box::new(io::Cursor::new(data).chain(Stall(rx))))
+    }
+
+    /// Receive from a server that stops sending after `data`, and return
+    /// the error.
+    ///
+    /// Like a real server, it stops stalling once the client closes its
+    /// side of the connection.
+    fn receive_until_stall(data: Vec<u8>, options: Options) -> anyhow::Error {
+        struct Unstall {
+            _sender: mpsc::Sender<()>,
+        }
+
+        impl Write for Unstall {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (unstall, r) = stall_after(data);
+        Connection::handshake(
+            r,
+            Box::new(Unstall { _sender: unstall }),
+            None,
+            TransferMode::List,
+            options,
+        )
+        .unwrap()
+        .receive(&mut LocalTree::new("/dev/null"))
+        .unwrap_err()
+    }
+
+    /// A server that stops while sending the file list is caught by the
+    /// file list timeout, not the transfer timeout.
+    #[test]
+    fn flist_timeout_applies_to_file_list() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script.file_entry(b"a", 3, 0o100644);
+        let options = Options {
+            flist_timeout: Some(Duration::from_millis(50)),
+            transfer_timeout: Some(Duration::from_secs(3600)),
+            ..Options::default()
+        };
+        let err = receive_until_stall(script.server_sent(), options);
+        assert!(
+            format!("{:#}", err).contains("No data received for 50ms"),
+            "unexpected error {:#}",
+            err
+        );
+    }
+
+    /// Once the file list is received, the transfer timeout applies.
+    #[test]
+    fn transfer_timeout_applies_after_file_list() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script.file_entry(b"a", 3, 0o100644).end_file_list();
+        let options = Options {
+            flist_timeout: Some(Duration::from_secs(3600)),
+            transfer_timeout: Some(Duration::from_millis(50)),
+            ..Options::default()
+        };
+        let err = receive_until_stall(script.server_sent(), options);
+        assert!(
+            format!("{:#}", err).contains("No data received for 50ms"),
+            "unexpected error {:#}",
+            err
+        );
+    }
+
+    /// Text from the remote shell, before the protocol version, gets a
+    /// clear diagnosis.
+    #[test]
+    fn text_before_protocol_version() {
+        let mut server_sent = b"Welcome to bilbo!\n".to_vec();
+        server_sent.extend_from_slice(&ServerScript::new(31, 0x1234).server_sent());
+        let err = Connection::handshake(
+            Box::new(io::Cursor::new(server_sent)),
+            Box::new(SharedBuf::default()),
+            None,
+            TransferMode::List,
+            Options::default(),
+        )
+        .err()
+        .expect("handshake succeeded");
+        let message = err.to_string();
+        assert!(message.contains("\"Welc\""), "{}", message);
+        assert!(message.contains("remote shell printed"), "{}", message);
+    }
+
+    #[test]
+    fn receive_empty_file_list() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script.end_file_list();
+        let (file_list, summary) = script
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut LocalTree::new("/dev/null"))
+            .unwrap();
+        assert!(file_list.is_empty());
+        assert_eq!(summary.files_received, 0);
+        // The client sends its version and an empty exclusion list, and nothing more.
+        assert_eq!(script.client_sent(), [27, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn send_filter_rules_in_order() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script.end_file_list();
+        let options = Options {
+            filter_rules: vec!["- *.o".into(), "+ src/".into()],
+            ..Options::default()
+        };
+        script
+            .connect(options)
+            .unwrap()
+            .receive(&mut LocalTree::new("/dev/null"))
+            .unwrap();
+        let mut expected = vec![27, 0, 0, 0];
+        expected.extend_from_slice(&[5, 0, 0, 0]);
+        expected.extend_from_slice(b"- *.o");
+        expected.extend_from_slice(&[6, 0, 0, 0]);
+        expected.extend_from_slice(b"+ src/");
+        expected.extend_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(script.client_sent(), expected);
+    }
+
+    #[test]
+    fn receive_two_files() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .file_entry(b"b", 3, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .file_data(1, b"bye", 0x1234)
+            .finish();
+        let (file_list, summary) = script
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut MemoryTree::new())
+            .unwrap();
+        assert_eq!(file_list.len(), 2);
+        assert_eq!(summary.files_received, 2);
+        assert_eq!(summary.bytes_read, script.server_sent().len() as u64);
+        assert_eq!(summary.bytes_written, script.client_sent().len() as u64);
+        assert_eq!(summary.whole_file_sum_mismatch_count, 0);
+        assert_eq!(summary.literal_bytes_received, 8);
+        let rate = summary.rate_bytes_per_sec();
+        assert!(rate.is_finite() && rate > 0.0, "rate {}", rate);
+    }
+
+    #[test]
+    fn fixed_checksum_seed_makes_transfers_reproducible() {
+        let options = Options {
+            checksum_seed: Some(0x5eed),
+            ..Options::default()
+        };
+        let transfer = || {
+            let mut script = ServerScript::new(27, 0x5eed);
+            script
+                .file_entry(b"a", 5, 0o100644)
+                .file_entry(b"b", 3, 0o100644)
+                .end_file_list()
+                .file_data(0, b"hello", 0x5eed)
+                .file_data(1, b"bye", 0x5eed)
+                .finish();
+            let (_file_list, summary) = script
+                .connect(options.clone())
+                .unwrap()
+                .receive(&mut MemoryTree::new())
+                .unwrap();
+            assert_eq!(summary.files_received, 2);
+            assert_eq!(summary.whole_file_sum_mismatch_count, 0);
+            (script.server_sent(), script.client_sent())
+        };
+        assert_eq!(transfer(), transfer());
+    }
+
+    #[test]
+    fn different_checksum_seed_from_server_is_an_error() {
+        let options = Options {
+            checksum_seed: Some(0x5eed),
+            ..Options::default()
+        };
+        let err = ServerScript::new(27, 0x1234)
+            .connect(options)
+            .err()
+            .expect("Different seed accepted");
+        assert_eq!(
+            err.to_string(),
+            "Server sent checksum seed 0x1234, but checksum_seed 0x5eed was requested"
+        );
+    }
+
+    #[test]
+    fn files_the_server_wont_send_are_recorded() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .file_entry(b"b", 3, 0o100644)
+            .end_file_list()
+            .message(crate::mux::TAG_NO_SEND, &0i32.to_le_bytes())
+            .file_data(1, b"bye", 0x1234)
+            .finish();
+        let mut tree = MemoryTree::new();
+        let (_file_list, summary) = script
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut tree)
+            .unwrap();
+        assert_eq!(summary.files_received, 1);
+        assert_eq!(summary.files_not_sent, [0]);
+        assert!(tree.stat(b"a").is_none());
+        assert!(tree.stat(b"b").is_some());
+    }
+
+    /// Files received into a `MemoryTree` keep the source's mtime, so a
+    /// second transfer finds them up to date.
+    #[test]
+    fn received_files_pass_the_quick_check() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .finish();
+        let mut tree = MemoryTree::new();
+        let (file_list, _summary) = script
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut tree)
+            .unwrap();
+        assert!(files_to_request(&tree, &file_list, &Options::default()).is_empty());
+    }
+
+    #[test]
+    fn interrupted_file_list_keeps_the_entries_received() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"b", 5, 0o100644)
+            .file_entry(b"a", 3, 0o100644)
+            .file_entry(b"c", 3, 0o100644)
+            // The start of another entry, cut off in its name.
+            .bytes(&[crate::flist::STATUS_LONG_NAME])
+            .i32(10)
+            .bytes(b"dd");
+        let err = script
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut MemoryTree::new())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "File list was interrupted after 3 entries");
+        match err.downcast_ref::<Error>() {
+            Some(Error::PartialFileList { entries, source }) => {
+                let names: Vec<&[u8]> = entries.iter().map(FileEntry::name_bytes).collect();
+                assert_eq!(names, [&b"a"[..], b"b", b"c"]);
+                assert!(source.root_cause().is::<io::Error>());
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+
+        // Cut off before any entries, there's nothing to keep.
+        let err = ServerScript::new(27, 0x1234)
+            .bytes(&[crate::flist::STATUS_LONG_NAME])
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut MemoryTree::new())
+            .unwrap_err();
+        assert!(err.downcast_ref::<Error>().is_none(), "{:?}", err);
+    }
+
+    #[test]
+    fn request_even_indexes() {
+        let names: [&[u8]; 5] = [b"a", b"b", b"c", b"d", b"e"];
+        let mut script = ServerScript::new(27, 0x1234);
+        for name in &names {
+            script.file_entry(name, 1, 0o100644);
+        }
+        script.end_file_list();
+        for idx in &[0, 2, 4] {
+            script.file_data(*idx, names[*idx as usize], 0x1234);
+        }
+        script.finish();
+        let mut tree = MemoryTree::new();
+        let (file_list, summary) = script
+            .connect(Options::default())
+            .unwrap()
+            .request_indexes(&[4, 0, 2], &mut tree)
+            .unwrap();
+        assert_eq!(file_list.len(), 5);
+        assert_eq!(summary.files_received, 3);
+        assert_eq!(summary.literal_bytes_received, 3);
+        for (idx, name) in names.iter().enumerate() {
+            assert_eq!(
+                tree.file_content(name).is_some(),
+                idx % 2 == 0,
+                "{:?}",
+                name
+            );
+        }
+        // The requests are in order, each followed by an empty SumHead.
+        let sent = script.client_sent();
+        let requests: Vec<u8> = [0, 2, 4, -1]
+            .iter()
+            .flat_map(|idx: &i32| {
+                let mut request = idx.to_le_bytes().to_vec();
+                if *idx >= 0 {
+                    request.extend_from_slice(&[0; 16]);
+                }
+                request
+            })
+            .collect();
+        assert!(sent
+            .windows(requests.len())
+            .any(|window| window == &requests[..]));
+    }
+
+    #[test]
+    fn request_indexes_must_be_regular_files() {
+        for (idx, message) in &[
+            (1, "Entry 1, \"sub\", is not a regular file"),
+            (3, "Index 3 is out of range for a file list of 3 entries"),
+        ] {
+            let mut script = ServerScript::new(27, 0x1234);
+            script
+                .file_entry(b"a", 1, 0o100644)
+                .file_entry(b"sub", 0, 0o040755)
+                .file_entry(b"sub/b", 1, 0o100644)
+                .end_file_list();
+            let err = script
+                .connect(Options {
+                    recursive: true,
+                    ..Options::default()
+                })
+                .unwrap()
+                .request_indexes(&[0, *idx], &mut MemoryTree::new())
+                .unwrap_err();
+            assert_eq!(err.to_string(), *message);
+        }
+    }
+
+    #[test]
+    fn empty_files_are_created_without_requesting_them() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b".", 0, 0o040755)
+            .file_entry(b"empty1", 0, 0o100644)
+            .file_entry(b"empty2", 0, 0o100644)
+            .file_entry(b"sub", 0, 0o040755)
+            .file_entry(b"sub/empty3", 0, 0o100644)
+            .end_file_list()
+            .finish();
+        let options = Options {
+            recursive: true,
+            ..Options::default()
+        };
+        let mut tree = MemoryTree::new();
+        let (file_list, summary) = script.connect(options).unwrap().receive(&mut tree).unwrap();
+        assert_eq!(file_list.len(), 5);
+        for name in &[&b"empty1"[..], b"empty2", b"sub/empty3"] {
+            assert_eq!(tree.file_content(name).unwrap(), b"", "{:?}", name);
+        }
+        assert_eq!(summary.literal_bytes_received, 0);
+        assert_eq!(summary.files_received, 0);
+        // Nothing was requested: after the filter rules, the client only
+        // sent the ends of the phases and the final goodbye.
+        let sent = script.client_sent();
+        assert!(sent.ends_with(&[
+            0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff
+        ]));
+    }
+
+    #[test]
+    fn cancel_while_reading_a_large_file_list() {
+        /// Cancels the token once `after` bytes have been read, and
+        /// counts how many were read in the end.
+        struct CancelAfter {
+            inner: io::Cursor<Vec<u8>>,
+            after: u64,
+            cancel: crate::CancelToken,
+            bytes_read: Arc<AtomicU64>,
+        }
+
+        impl Read for CancelAfter {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.inner.position() >= self.after {
+                    self.cancel.cancel();
+                }
+                // Return a little at a time, like a slow server.
+                let len = buf.len().min(64);
+                let read = self.inner.read(&mut buf[..len])?;
+                self.bytes_read.fetch_add(read as u64, Ordering::SeqCst);
+                Ok(read)
+            }
+        }
+
+        let mut script = ServerScript::new(27, 0x1234);
+        for i in 0..10_000 {
+            script.file_entry(format!("file{:05}", i).as_bytes(), 1, 0o100644);
+        }
+        script.end_file_list().finish();
+        let server_sent = script.server_sent();
+        let cancel = crate::CancelToken::new();
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let reader = CancelAfter {
+            inner: io::Cursor::new(server_sent.clone()),
+            after: 1000,
+            cancel: cancel.clone(),
+            bytes_read: bytes_read.clone(),
+        };
+
+        let options = Options {
+            cancel: Some(cancel.clone()),
+            ..Options::default()
+        };
+        let err = Connection::handshake(
+            Box::new(reader),
+            Box::new(SharedBuf::default()),
+            None,
+            TransferMode::List,
+            options,
+        )
+        .unwrap()
+        .receive(&mut MemoryTree::new())
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Cancelled)
+        ));
+        assert!(cancel.is_cancelled());
+        let bytes_read = bytes_read.load(Ordering::SeqCst);
+        assert!(
+            bytes_read < 2000,
+            "Read {} of {} bytes after cancelling",
+            bytes_read,
+            server_sent.len()
+        );
+    }
+
+    #[test]
+    fn literal_data_longer_than_max_alloc_is_refused() {
+        // The data is never sent: the length alone is refused.
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 2 << 20, 0o100644)
+            .end_file_list()
+            .i32(0)
+            .i32(0)
+            .i32(0)
+            .i32(0)
+            .i32(0)
+            .i32(2 << 20);
+        let options = Options {
+            max_alloc: Some(1 << 20),
+            ..Options::default()
+        };
+        let err = script
+            .connect(options)
+            .unwrap()
+            .receive(&mut MemoryTree::new())
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains(
+                "Refusing to allocate 2097152 bytes for data from the server, \
+                more than max_alloc of 1048576"
+            ),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn old_protocol_versions_are_explained() {
+        let err = ServerScript::new(17, 0x1234)
+            .connect(Options::default())
+            .err()
+            .expect("Connecting to an old server should fail");
+        match err.downcast_ref::<Error>() {
+            Some(Error::ProtocolTooOld {
+                server_version: 17,
+                minimum_version: 27,
+            }) => (),
+            other => panic!("Unexpected error {:?}", other),
+        }
+        let message = err.to_string();
+        assert!(
+            message.starts_with(
+                "Server protocol version 17 is too old: rsyn needs at least protocol 27"
+            ),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("Upgrade rsync on the server"),
+            "{}",
+            message
+        );
+
+        // Garbage isn't mistaken for an old version.
+        let err = Connection::handshake(
+            Box::new(io::Cursor::new(b"Welc".to_vec())),
+            Box::new(SharedBuf::default()),
+            None,
+            TransferMode::List,
+            Options::default(),
+        )
+        .err()
+        .expect("Handshake with garbage should fail");
+        assert!(err.downcast_ref::<Error>().is_none());
+        assert!(
+            err.to_string()
+                .contains("rather than an rsync protocol version"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn wire_order_differs_from_index_order() {
+        let script_for = |data: &[(i32, &[u8])]| {
+            let mut script = ServerScript::new(27, 0x1234);
+            script
+                .file_entry(b"b", 1, 0o100644)
+                .file_entry(b"a", 1, 0o100644)
+                .file_entry(b"c", 1, 0o100644)
+                .end_file_list();
+            for (idx, content) in data {
+                script.file_data(*idx, content, 0x1234);
+            }
+            script.finish();
+            script
+        };
+        let names = |file_list: &[FileEntry]| -> Vec<String> {
+            file_list
+                .iter()
+                .map(|e| e.name_lossy_string().into_owned())
+                .collect()
+        };
+
+        let (wire_order, _summary) = script_for(&[])
+            .connect_for(TransferMode::List, Options::default())
+            .unwrap()
+            .list_files_in_wire_order()
+            .unwrap();
+        assert_eq!(names(&wire_order), ["b", "a", "c"]);
+
+        // Index 0 is the first name in sorted order, not the first sent.
+        let mut tree = MemoryTree::new();
+        let (sorted, _summary) = script_for(&[(0, b"A")])
+            .connect(Options::default())
+            .unwrap()
+            .request_indexes(&[0], &mut tree)
+            .unwrap();
+        assert_eq!(names(&sorted), ["a", "b", "c"]);
+        assert_eq!(tree.file_content(b"a").unwrap(), b"A");
+        assert!(tree.file_content(b"b").is_none());
+        let mut resorted = wire_order.clone();
+        sort_and_dedupe(&mut resorted);
+        assert_eq!(resorted, sorted);
+    }
+
+    #[test]
+    fn checksum_choice_other_than_md4_fails_to_connect() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script.end_file_list().finish();
+        let options = Options {
+            checksum_choice: Some("xxh64".to_owned()),
+            ..Options::default()
+        };
+        let err = script.connect(options).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Checksum algorithm \"xxh64\" is not supported by the server, which offers: md4"
+        );
+        let options = Options {
+            checksum_choice: Some("md4".to_owned()),
+            ..Options::default()
+        };
+        assert!(script.connect(options).is_ok());
+    }
+
+    /// A tree that records the owners it's asked to set.
+    struct OwnerTree {
+        inner: MemoryTree,
+        owners: Vec<(Vec<u8>, Option<u32>, Option<u32>)>,
+    }
+
+    impl Tree for OwnerTree {
+        fn stat(&self, name: &[u8]) -> Option<FileEntry> {
+            self.inner.stat(name)
+        }
+
+        fn scan(&self, options: &Options) -> Result<FileList> {
+            self.inner.scan(options)
+        }
+
+        fn create_file(&mut self, entry: &FileEntry) -> Result<Box<dyn FileWriter>> {
+            self.inner.create_file(entry)
+        }
+
+        fn create_dir(&mut self, name: &[u8]) -> Result<()> {
+            self.inner.create_dir(name)
+        }
+
+        fn symlink(&mut self, name: &[u8], target: &[u8]) -> Result<()> {
+            self.inner.symlink(name, target)
+        }
+
+        fn rename(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+            self.inner.rename(from, to)
+        }
+
+        fn remove(&mut self, name: &[u8], is_dir: bool) -> Result<()> {
+            self.inner.remove(name, is_dir)
+        }
+
+        fn remove_all(&mut self, name: &[u8], is_dir: bool) -> Result<()> {
+            self.inner.remove_all(name, is_dir)
+        }
+
+        fn set_owner(&mut self, name: &[u8], uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+            self.owners.push((name.to_vec(), uid, gid));
+            Ok(())
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_as_owns_everything_received() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b".", 0, 0o040755)
+            .file_entry(b"a", 3, 0o100644)
+            .file_entry(b"sub", 0, 0o040755)
+            .end_file_list()
+            .file_data(1, b"aaa", 0x1234)
+            .finish();
+        let options = Options {
+            recursive: true,
+            copy_as: Some("1000:1001".to_owned()),
+            ..Options::default()
+        };
+        let mut tree = OwnerTree {
+            inner: MemoryTree::new(),
+            owners: Vec::new(),
+        };
+        let (file_list, _summary) = script.connect(options).unwrap().receive(&mut tree).unwrap();
+        assert!(file_list
+            .iter()
+            .all(|entry| entry.uid() == Some(1000) && entry.gid() == Some(1001)));
+        let names: Vec<&[u8]> = tree
+            .owners
+            .iter()
+            .map(|(name, uid, gid)| {
+                assert_eq!((*uid, *gid), (Some(1000), Some(1001)));
+                &name[..]
+            })
+            .collect();
+        assert_eq!(names, [&b"."[..], b"a", b"sub"]);
+    }
+
+    /// A tree that can't have symlinks, like Windows without the privilege
+    /// to create them.
+    struct NoSymlinkTree(MemoryTree);
+
+    impl Tree for NoSymlinkTree {
+        fn stat(&self, name: &[u8]) -> Option<FileEntry> {
+            self.0.stat(name)
+        }
+
+        fn scan(&self, options: &Options) -> Result<FileList> {
+            self.0.scan(options)
+        }
+
+        fn create_file(&mut self, entry: &FileEntry) -> Result<Box<dyn FileWriter>> {
+            self.0.create_file(entry)
+        }
+
+        fn create_dir(&mut self, name: &[u8]) -> Result<()> {
+            self.0.create_dir(name)
+        }
+
+        fn symlink(&mut self, name: &[u8], _target: &[u8]) -> Result<()> {
+            bail!(
+                "Can't create symlink {:?} here",
+                String::from_utf8_lossy(name)
+            )
+        }
+
+        fn rename(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+            self.0.rename(from, to)
+        }
+
+        fn remove(&mut self, name: &[u8], is_dir: bool) -> Result<()> {
+            self.0.remove(name, is_dir)
+        }
+
+        fn remove_all(&mut self, name: &[u8], is_dir: bool) -> Result<()> {
+            self.0.remove_all(name, is_dir)
+        }
+
+        fn copy_file(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+            self.0.copy_file(from, to)
+        }
+    }
+
+    #[test]
+    fn symlink_fallback_policies() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b".", 0, 0o040755)
+            .file_entry(b"a", 5, 0o100644)
+            .file_entry(b"link", 1, 0o120777)
+            .i32(1)
+            .bytes(b"a")
+            .file_entry(b"outside", 5, 0o120777)
+            .i32(5)
+            .bytes(b"../..")
+            .end_file_list()
+            .file_data(1, b"hello", 0x1234)
+            .finish();
+        let receive = |symlink_fallback| {
+            let options = Options {
+                recursive: true,
+                preserve_links: true,
+                symlink_fallback,
+                ..Options::default()
+            };
+            let memory_tree = MemoryTree::new();
+            let result = script
+                .connect(options)
+                .unwrap()
+                .receive(&mut NoSymlinkTree(memory_tree.clone()));
+            (result, memory_tree)
+        };
+
+        let (result, _tree) = receive(SymlinkFallback::Error);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Can't create symlink \"link\" here"
+        );
+
+        let (result, tree) = receive(SymlinkFallback::SkipWithWarning);
+        assert_eq!(result.unwrap().1.files_received, 1);
+        assert_eq!(tree.file_content(b"a").unwrap(), b"hello");
+        assert!(tree.stat(b"link").is_none());
+
+        let (result, tree) = receive(SymlinkFallback::CopyReferent);
+        assert_eq!(result.unwrap().1.files_received, 1);
+        assert_eq!(tree.file_content(b"link").unwrap(), b"hello");
+        assert!(tree.stat(b"outside").is_none());
+    }
+
+    #[test]
+    fn fetch_range_of_one_file() {
+        let content: Vec<u8> = (0..100).collect();
+        let mut list_script = ServerScript::new(27, 0x1234);
+        list_script
+            .file_entry(b"a", 3, 0o100644)
+            .file_entry(b"big", 100, 0o100644)
+            .end_file_list()
+            .finish();
+        let (file_list, _summary) = list_script
+            .connect_for(TransferMode::List, Options::default())
+            .unwrap()
+            .receive(&mut LocalTree::new("/dev/null"))
+            .unwrap();
+
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 3, 0o100644)
+            .file_entry(b"big", 100, 0o100644)
+            .end_file_list()
+            .file_data(1, &content, 0x1234)
+            .finish();
+        let head = script
+            .connect(Options::default())
+            .unwrap()
+            .fetch_range(&file_list[1], 0, 16)
+            .unwrap();
+        assert_eq!(head, &content[..16]);
+
+        // Only the one file was requested.
+        let mut expected = vec![27, 0, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(&[1, 0, 0, 0]);
+        expected.extend_from_slice(&[0; 16]);
+        expected.extend_from_slice(&[0xff; 12]);
+        assert_eq!(script.client_sent(), expected);
+    }
+
+    #[test]
+    fn fetch_range_past_end_of_file() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .finish();
+        let entry = FileEntry::for_test(b"a", 0o100644, 5, 0);
+        let tail = script
+            .connect(Options::default())
+            .unwrap()
+            .fetch_range(&entry, 3, 16)
+            .unwrap();
+        assert_eq!(tail, b"lo");
+    }
+
+    #[test]
+    fn chown_overrides_ids_and_failure_is_not_fatal() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 3, 0o100644)
+            .end_file_list()
+            .file_data(0, b"aaa", 0x1234)
+            .finish();
+        let tree = MemoryTree::new();
+        let options = Options {
+            chown: Some("1000:1000".to_owned()),
+            ..Options::default()
+        };
+        // MemoryTree can't set owners, so this only warns.
+        let (file_list, summary) = script
+            .connect(options)
+            .unwrap()
+            .receive(&mut tree.clone())
+            .unwrap();
+        assert_eq!(summary.files_received, 1);
+        assert_eq!(file_list[0].uid(), Some(1000));
+        assert_eq!(file_list[0].gid(), Some(1000));
+        assert_eq!(tree.file_content(b"a").unwrap(), b"aaa");
+    }
+
+    #[test]
+    fn checksum_manifest_of_every_file() {
+        let files: &[(&[u8], &[u8])] = &[(b"a", b"hello"), (b"empty", b""), (b"sub/b", b"bye")];
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .file_entry(b"empty", 0, 0o100644)
+            .file_entry(b"sub", 0, 0o040755)
+            .file_entry(b"sub/b", 3, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .file_data(1, b"", 0x1234)
+            .file_data(3, b"bye", 0x1234)
+            .finish();
+        let options = Options {
+            recursive: true,
+            ..Options::default()
+        };
+        let manifest = script
+            .connect(options)
+            .unwrap()
+            .checksum_manifest()
+            .unwrap();
+        assert_eq!(manifest.len(), files.len());
+        for ((entry, digest), (name, content)) in manifest.iter().zip(files) {
+            assert_eq!(entry.name_bytes(), *name);
+            assert_eq!(digest[..], Md4::digest(content)[..]);
+        }
+        // Independent of the seed, this is the well-known MD4 of "".
+        assert_eq!(
+            hex::encode(manifest[1].1),
+            "31d6cfe0d16ae931b73c59d7e0c089c0"
+        );
+    }
+
+    #[test]
+    fn checksum_manifest_fails_on_mismatch() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .file_entry(b"b", 3, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .file_data(1, b"bye", 0x9999)
+            .finish();
+        let err = script
+            .connect(Options::default())
+            .unwrap()
+            .checksum_manifest()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "MD4 mismatch for 1 of 2 files");
+    }
+
+    #[test]
+    fn fetch_whole_file() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .file_entry(b"b", 3, 0o100644)
+            .end_file_list()
+            .file_data(1, b"bee", 0x1234)
+            .finish();
+        let content = script
+            .connect(Options::default())
+            .unwrap()
+            .fetch_file(b"b")
+            .unwrap();
+        assert_eq!(content, b"bee");
+    }
+
+    #[test]
+    fn receive_file_to_writer() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .finish();
+        let mut out: Vec<u8> = Vec::new();
+        let summary = script
+            .connect(Options::default())
+            .unwrap()
+            .receive_file_to(b"a", &mut out)
+            .unwrap();
+        assert_eq!(out, b"hello");
+        assert_eq!(summary.files_received, 1);
+        assert_eq!(summary.whole_file_sum_mismatch_count, 0);
+    }
+
+    #[test]
+    fn receive_file_to_fails_on_md4_mismatch() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x4321)
+            .finish();
+        assert!(script
+            .connect(Options::default())
+            .unwrap()
+            .receive_file_to(b"a", &mut io::sink())
+            .is_err());
+    }
+
+    #[test]
+    fn fetch_range_of_missing_file() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .end_file_list()
+            .finish();
+        let entry = FileEntry::for_test(b"b", 0o100644, 5, 0);
+        assert!(script
+            .connect(Options::default())
+            .unwrap()
+            .fetch_range(&entry, 0, 16)
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sparse_file_has_holes() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_connection_sparse")
+            .tempdir()
+            .unwrap();
+        let mut content = b"start".to_vec();
+        content.resize(4 << 20, 0);
+        content.extend_from_slice(b"middle");
+        // Ends in a hole, which must still count in the length.
+        content.resize(8 << 20, 0);
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"sparse", content.len() as i64, 0o100644)
+            .end_file_list()
+            .file_data(0, &content, 0x1234)
+            .finish();
+        let options = Options {
+            sparse: true,
+            ..Options::default()
+        };
+        script
+            .connect(options)
+            .unwrap()
+            .receive(&mut LocalTree::new(tmp.path()))
+            .unwrap();
+        let path = tmp.path().join("sparse");
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.len(), content.len() as u64);
+        assert!(
+            metadata.blocks() * 512 < metadata.len(),
+            "{} blocks allocated",
+            metadata.blocks()
+        );
+    }
+
+    /// The `.` entry's permissions go on the destination directory, rather
+    /// than anything called `.`.
+    #[cfg(unix)]
+    #[test]
+    fn permissions_of_root_entry_apply_to_destination() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_connection_root_perms")
+            .tempdir()
+            .unwrap();
+        let dest = tmp.path().join("dest");
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b".", 0, 0o040750)
+            .file_entry(b"a", 5, 0o100600)
+            .end_file_list()
+            .file_data(1, b"hello", 0x1234)
+            .finish();
+        let options = Options {
+            recursive: true,
+            preserve_perms: true,
+            ..Options::default()
+        };
+        script
+            .connect(options)
+            .unwrap()
+            .receive(&mut LocalTree::new(&dest))
+            .unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&dest), 0o750);
+        assert_eq!(mode(&dest.join("a")), 0o600);
+        let names: Vec<_> = std::fs::read_dir(&dest)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["a"]);
+    }
+
+    /// With `-R --no-implied-dirs`, the server sends only the entry named on
+    /// the command line: a parent that exists locally keeps its permissions,
+    /// and a missing one is created.
+    #[cfg(unix)]
+    #[test]
+    fn no_implied_dirs_keeps_existing_parent_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_connection_no_implied_dirs")
+            .tempdir()
+            .unwrap();
+        let usr = tmp.path().join("usr");
+        std::fs::create_dir(&usr).unwrap();
+        std::fs::set_permissions(&usr, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"usr/local/hello", 5, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .finish();
+        let options = Options {
+            relative: true,
+            no_implied_dirs: true,
+            preserve_perms: true,
+            ..Options::default()
+        };
+        script
+            .connect(options)
+            .unwrap()
+            .receive(&mut LocalTree::new(tmp.path()))
+            .unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&usr), 0o700);
+        assert!(usr.join("local").is_dir());
+        assert_eq!(std::fs::read(usr.join("local/hello")).unwrap(), b"hello");
+        assert_eq!(mode(&usr.join("local/hello")), 0o644);
+    }
+
+    #[test]
+    fn delete_two_extraneous_files() {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_connection_delete")
+            .tempdir()
+            .unwrap();
+        for name in &["a", "x", "y"] {
+            std::fs::write(tmp.path().join(name), b"old").unwrap();
+        }
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b".", 0, 0o040755)
+            .file_entry(b"a", 5, 0o100644)
+            .end_file_list()
+            .file_data(1, b"hello", 0x1234)
+            .finish();
+        let options = Options {
+            recursive: true,
+            delete: Some(DeleteMode::Before),
+            ..Options::default()
+        };
+        let (_file_list, summary) = script
+            .connect(options)
+            .unwrap()
+            .receive(&mut LocalTree::new(tmp.path()))
+            .unwrap();
+        assert_eq!(summary.files_deleted, 2);
+        assert!(tmp.path().join("a").exists());
+        assert!(!tmp.path().join("x").exists());
+        assert!(!tmp.path().join("y").exists());
+    }
+
+    #[test]
+    fn delete_is_skipped_after_io_errors_unless_ignored() {
+        for ignore_errors in [false, true] {
+            let tmp = tempfile::Builder::new()
+                .prefix("rsyn_connection_delete_io_error")
+                .tempdir()
+                .unwrap();
+            std::fs::write(tmp.path().join("x"), b"old").unwrap();
+            let mut script = ServerScript::new(27, 0x1234);
+            script
+                .file_entry(b".", 0, 0o040755)
+                .file_entry(b"a", 5, 0o100644)
+                .end_file_list_with_io_error(1)
+                .file_data(1, b"hello", 0x1234)
+                .finish();
+            let options = Options {
+                recursive: true,
+                delete: Some(DeleteMode::Before),
+                ignore_errors,
+                ..Options::default()
+            };
+            let (_file_list, summary) = script
+                .connect(options)
+                .unwrap()
+                .receive(&mut LocalTree::new(tmp.path()))
+                .unwrap();
+            assert_eq!(summary.server_flist_io_error_count, 1);
+            assert_eq!(summary.files_deleted, ignore_errors as usize);
+            assert_eq!(tmp.path().join("x").exists(), !ignore_errors);
+            assert!(tmp.path().join("a").exists());
+        }
+    }
+
+    /// Receive one file, with an extraneous file in the destination, and
+    /// return whether the extraneous file existed while the transfer was
+    /// in progress and afterwards.
+    fn extraneous_file_exists_during_and_after(delete: DeleteMode) -> (bool, bool) {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_connection_delete_mode")
+            .tempdir()
+            .unwrap();
+        let extraneous = tmp.path().join("x");
+        std::fs::write(&extraneous, b"old").unwrap();
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b".", 0, 0o040755)
+            .file_entry(b"a", 5, 0o100644)
+            .end_file_list()
+            .file_data(1, b"hello", 0x1234)
+            .finish();
+        let options = Options {
+            recursive: true,
+            delete: Some(delete),
+            ..Options::default()
+        };
+        let mut during = None;
+        let (_file_list, summary, _child) = script
+            .connect(options)
+            .unwrap()
+            .receive_with_sink(
+                &mut LocalTree::new(tmp.path()),
+                None,
+                &mut |_tree, _entry, _data| {
+                    during = Some(extraneous.exists());
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(summary.files_deleted, 1);
+        (during.unwrap(), extraneous.exists())
+    }
+
+    #[test]
+    fn delete_before_transfer() {
+        assert_eq!(
+            extraneous_file_exists_during_and_after(DeleteMode::Before),
+            (false, false)
+        );
+    }
+
+    #[test]
+    fn delete_after_transfer() {
+        assert_eq!(
+            extraneous_file_exists_during_and_after(DeleteMode::After),
+            (true, false)
+        );
+    }
+
+    #[test]
+    fn receive_compressed_files() {
+        let text: Vec<u8> = (0..2000)
+            .flat_map(|i| format!("{} bottles of beer\n", i).into_bytes())
+            .collect();
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", text.len() as i64, 0o100644)
+            .file_entry(b"b", 3, 0o100644)
+            .file_entry(b"empty", 0, 0o100644)
+            .end_file_list()
+            .deflated_file_data(0, &text, 1000, 0x1234)
+            .deflated_file_data(1, b"bye", 1000, 0x1234)
+            .deflated_file_data(2, b"", 1000, 0x1234)
+            .finish();
+        let options = Options {
+            compress_choice: Some("zlib".to_owned()),
+            ..Options::default()
+        };
+        let mut tree = MemoryTree::new();
+        let (_file_list, summary) = script.connect(options).unwrap().receive(&mut tree).unwrap();
+        assert_eq!(tree.file_content(b"a").unwrap(), text);
+        assert_eq!(tree.file_content(b"b").unwrap(), b"bye");
+        assert_eq!(tree.file_content(b"empty").unwrap(), b"");
+        assert_eq!(summary.files_received, 3);
+        assert_eq!(summary.whole_file_sum_mismatch_count, 0);
+        assert_eq!(summary.literal_bytes_received, text.len() + 3);
+    }
+
+    /// If the server goes away while files are being requested, the error
+    /// is returned, rather than panicking.
+    #[test]
+    fn failure_to_request_files_is_an_error() {
+        /// A stream to the server that breaks after `limit` bytes.
+        struct BreaksAfter {
+            limit: usize,
+        }
+
+        impl Write for BreaksAfter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if buf.len() > self.limit {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                self.limit -= buf.len();
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .finish();
+        // The handshake, and then the end of the (empty) filter rules, get
+        // through, but not the request for the file.
+        script.connect(Options::default()).unwrap();
+        let limit = script.client_sent().len() + 4;
+        let err = Connection::handshake(
+            Box::new(io::Cursor::new(script.server_sent())),
+            Box::new(BreaksAfter { limit }),
+            None,
+            TransferMode::Download,
+            Options::default(),
+        )
+        .unwrap()
+        .receive(&mut MemoryTree::new())
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(io::ErrorKind::BrokenPipe),
+            "{:#}",
+            err
+        );
+    }
+
+    /// rsyn sends no block sums, so a block reference in an uncompressed
+    /// file is an error, as it is when compressed.
+    #[test]
+    fn block_reference_is_an_error() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .end_file_list()
+            .i32(0)
+            .i32(0)
+            .i32(0)
+            .i32(0)
+            .i32(0)
+            .i32(-1)
+            .finish();
+        let err = script
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut MemoryTree::new())
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err)
+                .contains("Received a block reference, but rsyn sent no block sums"),
+            "{:#}",
+            err
+        );
+    }
+
+    /// Only zlib compression is implemented, so asking for zstd doesn't ask
+    /// the server to compress, logs a warning, and transfers the file
+    /// correctly, uncompressed.
+    #[test]
+    fn compress_choice_falls_back_to_uncompressed() {
+        let content = vec![b'z'; 10_000];
+        let mut script = ServerScript::new(31, 0x1234);
+        script
+            .file_entry(b"big", content.len() as i64, 0o100644)
+            .end_file_list()
+            .file_data(0, &content, 0x1234)
+            .finish();
+        let options = Options {
+            compress_choice: Some("zstd".to_owned()),
+            ..Options::default()
+        };
+        let mut client = Client::local("./src");
+        *client.mut_options() = options.clone();
+        let args = client.build_args(TransferMode::Download);
+        assert!(!args.iter().any(|arg| arg == "-z"), "{:?}", args);
+
+        let (connection, warnings) = warnings_from(|| script.connect(options).unwrap());
+        assert_eq!(
+            warnings,
+            ["rsyn doesn't implement \"zstd\" compression; transferring uncompressed"]
+        );
+        let mut tree = MemoryTree::new();
+        let (_file_list, summary) = connection.receive(&mut tree).unwrap();
+        assert_eq!(tree.file_content(b"big").unwrap(), content);
+        assert_eq!(summary.whole_file_sum_mismatch_count, 0);
+        assert_eq!(summary.literal_bytes_received, content.len());
+    }
+
+    /// A download creates directories, files and symlinks in the tree, which
+    /// here is in memory, and discards files that fail their checksum.
+    #[test]
+    fn download_into_memory_tree() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b".", 0, 0o040755)
+            .file_entry(b"sub", 0, 0o040755)
+            .file_entry(b"sub/a", 5, 0o100644)
+            .file_entry(b"sub/bad", 3, 0o100644)
+            .file_entry(b"sub/link", 1, 0o120777)
+            .i32(1)
+            .bytes(b"a")
+            .end_file_list()
+            .file_data(2, b"hello", 0x1234)
+            .file_data(3, b"bad", 0x4321)
+            .finish();
+        let options = Options {
+            recursive: true,
+            preserve_links: true,
+            ..Options::default()
+        };
+        let mut tree = MemoryTree::new();
+        let (_file_list, summary) = script.connect(options).unwrap().receive(&mut tree).unwrap();
+        assert_eq!(summary.files_received, 2);
+        assert_eq!(summary.whole_file_sum_mismatch_count, 1);
+        assert!(tree.stat(b"sub").unwrap().is_dir());
+        assert_eq!(tree.file_content(b"sub/a").unwrap(), b"hello");
+        assert!(tree.stat(b"sub/bad").is_none());
+        assert_eq!(tree.link_target(b"sub/link").unwrap(), b"a");
+    }
+
+    /// With `backup`, a file that's overwritten is first renamed with the
+    /// backup suffix.
+    #[test]
+    fn backup_overwritten_file() {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_connection_backup")
+            .tempdir()
+            .unwrap();
+        std::fs::write(tmp.path().join("a"), b"old").unwrap();
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .finish();
+        let options = Options {
+            backup: true,
+            ..Options::default()
+        };
+        script
+            .connect(options)
+            .unwrap()
+            .receive(&mut LocalTree::new(tmp.path()))
+            .unwrap();
+        assert_eq!(std::fs::read(tmp.path().join("a")).unwrap(), b"hello");
+        assert_eq!(std::fs::read(tmp.path().join("a~")).unwrap(), b"old");
+    }
+
+    /// With `backup_dir`, the old file goes at the same relative path under
+    /// the backup directory.
+    #[test]
+    fn backup_overwritten_file_to_backup_dir() {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_connection_backup_dir")
+            .tempdir()
+            .unwrap();
+        std::fs::create_dir(tmp.path().join("sub")).unwrap();
+        std::fs::write(tmp.path().join("sub/a"), b"old").unwrap();
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b".", 0, 0o040755)
+            .file_entry(b"sub", 0, 0o040755)
+            .file_entry(b"sub/a", 5, 0o100644)
+            .end_file_list()
+            .file_data(2, b"hello", 0x1234)
+            .finish();
+        let options = Options {
+            recursive: true,
+            backup_dir: Some("./backups/".into()),
+            delete: Some(DeleteMode::After),
+            ..Options::default()
+        };
+        script
+            .connect(options)
+            .unwrap()
+            .receive(&mut LocalTree::new(tmp.path()))
+            .unwrap();
+        assert_eq!(std::fs::read(tmp.path().join("sub/a")).unwrap(), b"hello");
+        assert_eq!(
+            std::fs::read(tmp.path().join("backups/sub/a")).unwrap(),
+            b"old"
+        );
+        assert!(!tmp.path().join("sub/a~").exists());
+    }
+
+    /// A tree that records which files were already in place each time a
+    /// file is created.
+    struct ObservedTree {
+        inner: MemoryTree,
+        present: Vec<Vec<Vec<u8>>>,
+    }
+
+    impl Tree for ObservedTree {
+        fn stat(&self, name: &[u8]) -> Option<FileEntry> {
+            self.inner.stat(name)
+        }
+
+        fn scan(&self, options: &Options) -> Result<FileList> {
+            self.inner.scan(options)
+        }
+
+        fn create_file(&mut self, entry: &FileEntry) -> Result<Box<dyn FileWriter>> {
+            let present = self.inner.scan(&Options::default())?;
+            self.present.push(
+                present
+                    .iter()
+                    .filter(|entry| entry.is_file())
+                    .map(|entry| entry.name_bytes().to_vec())
+                    .collect(),
+            );
+            self.inner.create_file(entry)
+        }
+
+        fn create_dir(&mut self, name: &[u8]) -> Result<()> {
+            self.inner.create_dir(name)
+        }
+
+        fn symlink(&mut self, name: &[u8], target: &[u8]) -> Result<()> {
+            self.inner.symlink(name, target)
+        }
+
+        fn rename(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+            self.inner.rename(from, to)
+        }
+
+        fn remove(&mut self, name: &[u8], is_dir: bool) -> Result<()> {
+            self.inner.remove(name, is_dir)
+        }
+
+        fn remove_all(&mut self, name: &[u8], is_dir: bool) -> Result<()> {
+            self.inner.remove_all(name, is_dir)
+        }
+    }
+
+    fn files_present_during_transfer(delay_updates: bool) -> Vec<Vec<Vec<u8>>> {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .file_entry(b"b", 3, 0o100644)
+            .file_entry(b"c", 3, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .file_data(1, b"bye", 0x1234)
+            .file_data(2, b"end", 0x1234)
+            .finish();
+        let options = Options {
+            delay_updates,
+            ..Options::default()
+        };
+        let mut tree = ObservedTree {
+            inner: MemoryTree::new(),
+            present: Vec::new(),
+        };
+        script.connect(options).unwrap().receive(&mut tree).unwrap();
+        for name in &[&b"a"[..], b"b", b"c"] {
+            assert!(tree.inner.file_content(name).is_some());
+        }
+        tree.present
+    }
+
+    #[test]
+    fn files_are_updated_as_they_arrive() {
+        let empty: Vec<Vec<u8>> = Vec::new();
+        assert_eq!(
+            files_present_during_transfer(false),
+            [
+                empty,
+                vec![b"a".to_vec()],
+                vec![b"a".to_vec(), b"b".to_vec()]
+            ]
+        );
+    }
+
+    /// With `delay_updates`, no file is in place until the transfer is done.
+    #[test]
+    fn delay_updates_until_transfer_completes() {
+        let present = files_present_during_transfer(true);
+        assert_eq!(present.len(), 3);
+        assert!(present.iter().all(Vec::is_empty));
+    }
+
+    /// With `delay_updates`, nothing is put in place if the transfer fails.
+    #[test]
+    fn delayed_updates_are_discarded_on_failure() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .file_entry(b"b", 3, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .i32(7);
+        let options = Options {
+            delay_updates: true,
+            ..Options::default()
+        };
+        let mut tree = MemoryTree::new();
+        assert!(script.connect(options).unwrap().receive(&mut tree).is_err());
+        assert!(tree.file_content(b"a").is_none());
+    }
+
+    /// The summary reports the transport set by the client.
+    #[test]
+    fn summary_reports_transport() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 3, 0o100644)
+            .end_file_list()
+            .finish();
+        let mut connection = script
+            .connect_for(TransferMode::List, Options::default())
+            .unwrap();
+        assert_eq!(connection.transport(), Transport::Local);
+        connection.set_transport(Transport::Daemon);
+        let (_file_list, summary) = connection.receive(&mut MemoryTree::new()).unwrap();
+        assert_eq!(summary.transport, Transport::Daemon);
+    }
+
+    /// A download requests each regular file, starting with index 0 after
+    /// the protocol version and the empty filter list.
+    #[test]
+    fn download_requests_file_data() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 3, 0o100644)
+            .end_file_list()
+            .file_data(0, b"abc", 0x1234)
+            .finish();
+        let connection = script.connect(Options::default()).unwrap();
+        assert_eq!(connection.mode(), TransferMode::Download);
+        let (_file_list, summary) = connection.receive(&mut MemoryTree::new()).unwrap();
+        assert_eq!(summary.files_received, 1);
+        assert_eq!(script.client_sent()[8..12], 0i32.to_le_bytes());
+    }
+
+    /// Changes are described in the summary with `itemize`, even without
+    /// any textual format.
+    #[test]
+    fn itemized_changes_in_summary() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 3, 0o100644)
+            .end_file_list()
+            .file_data(0, b"abc", 0x1234)
+            .finish();
+        let options = Options {
+            itemize: true,
+            ..Options::default()
+        };
+        assert!(options.out_format.is_none());
+        let (_file_list, summary) = script
+            .connect(options)
+            .unwrap()
+            .receive(&mut MemoryTree::new())
+            .unwrap();
+        assert_eq!(summary.changes.len(), 1);
+        let (entry, change) = &summary.changes[0];
+        assert_eq!(entry.name_bytes(), b"a");
+        assert!(change.created && change.transferred);
+        assert_eq!(change.to_string(), ">f+++++++++");
+    }
+
+    /// Entries are counted by the kind of change, whether or not they're
+    /// itemized.
+    #[cfg(unix)]
+    #[test]
+    fn count_changes_by_kind() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_connection_count_changes")
+            .tempdir()
+            .unwrap();
+        let write = |name: &str, content: &[u8], mode: u32, mtime: u64| {
+            let path = tmp.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+                .unwrap();
+        };
+        write("same", b"abc", 0o644, 1588429517);
+        write("old", b"xyz", 0o644, 1588429000);
+        write("meta", b"abc", 0o600, 1588429517);
+        write("gone", b"abc", 0o644, 1588429517);
+        // The temporary directory is 0o700, so the root's metadata changes
+        // too.
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b".", 0, 0o040755)
+            .file_entry(b"meta", 3, 0o100644)
+            .file_entry(b"new", 3, 0o100644)
+            .file_entry(b"old", 3, 0o100644)
+            .file_entry(b"same", 3, 0o100644)
+            .file_entry(b"sub", 0, 0o040755)
+            .end_file_list()
+            .file_data(2, b"new", 0x1234)
+            .file_data(3, b"abc", 0x1234)
+            .finish();
+        let options = Options {
+            recursive: true,
+            delete: Some(DeleteMode::Before),
+            ..Options::default()
+        };
+        let (_file_list, summary) = script
+            .connect(options)
+            .unwrap()
+            .receive(&mut LocalTree::new(tmp.path()))
+            .unwrap();
+        assert!(summary.changes.is_empty());
+        assert_eq!(summary.files_received, 2);
+        assert_eq!(summary.files_created, 2, "new and sub");
+        assert_eq!(summary.files_updated, 1, "old");
+        assert_eq!(summary.files_metadata_only, 2, "meta and the root");
+        assert_eq!(summary.files_deleted, 1, "gone");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dry_run_itemizes_without_changing_anything() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_connection_dry_run")
+            .tempdir()
+            .unwrap();
+        for name in &["changed", "same"] {
+            let path = tmp.path().join(name);
+            std::fs::write(&path, b"old").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(1588429517))
+                .unwrap();
+        }
+        // Nothing is requested, so the server sends no file data.
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"changed", 5, 0o100644)
+            .file_entry(b"same", 3, 0o100644)
+            .end_file_list()
+            .finish();
+        let options = Options {
+            itemize: true,
+            dry_run: true,
+            ..Options::default()
+        };
+        let (_file_list, summary) = script
+            .connect(options)
+            .unwrap()
+            .receive(&mut LocalTree::new(tmp.path()))
+            .unwrap();
+        assert_eq!(summary.files_received, 0);
+        assert_eq!(
+            crate::changes_to_json(&summary.changes),
+            r#"[{"name":"changed","change":">f.s......."}]"#
+        );
+        assert_eq!(std::fs::read(tmp.path().join("changed")).unwrap(), b"old");
+    }
+
+    #[test]
+    fn rsync_stats_for_small_transfer() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .file_entry(b"b", 3, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .file_data(1, b"bye", 0x1234)
+            .i32(-1)
+            .i32(-1)
+            .i64(10)
+            .i64(20)
+            .i64(8);
+        let (file_list, summary) = script
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut MemoryTree::new())
+            .unwrap();
+        // For each entry a status byte, a long name with its length, and the
+        // small size, mtime and mode as i32s; then the end marker and error
+        // count; and one multiplexing header.
+        assert_eq!(summary.file_list_bytes, 2 * (1 + 4 + 1 + 4 + 4 + 4) + 5 + 4);
+        let stats = summary.rsync_stats(&file_list).to_string();
+        let expected = format!(
+            "Number of files: 2\n\
+             Number of regular files transferred: 2\n\
+             Total file size: 8 bytes\n\
+             Literal data: 8 bytes\n\
+             Matched data: 0 bytes\n\
+             File list size: 45\n\
+             Total bytes sent: {}\n\
+             Total bytes received: {}",
+            summary.bytes_written, summary.bytes_read
+        );
+        assert_eq!(stats, expected);
+    }
+
+    #[test]
+    fn total_file_size_counts_files_and_symlinks() {
+        let file_list = [
+            FileEntry::for_test(b".", 0o040755, 4096, 0),
+            FileEntry::for_test(b"a", 0o100644, 100, 0),
+            FileEntry::for_test(b"fifo", 0o010644, 0, 0),
+            FileEntry::for_test(b"link", 0o120777, 7, 0),
+        ];
+        assert_eq!(total_file_size(&file_list), 107);
+    }
+
+    #[test]
+    fn no_itemized_changes_by_default() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 3, 0o100644)
+            .end_file_list()
+            .file_data(0, b"abc", 0x1234)
+            .finish();
+        let (_file_list, summary) = script
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut MemoryTree::new())
+            .unwrap();
+        assert!(summary.changes.is_empty());
+    }
+
+    /// A connection opened for upload can't be used to receive.
+    #[test]
+    fn upload_connection_does_not_receive() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 3, 0o100644)
+            .end_file_list()
+            .finish();
+        let err = script
+            .connect_for(TransferMode::Upload, Options::default())
+            .unwrap()
+            .receive(&mut LocalTree::new("/dev/null"))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't receive on a connection opened for upload"
+        );
+        assert_eq!(script.client_sent(), [27, 0, 0, 0]);
+    }
+
+    /// A listing requests no files, even though there's one in the list:
+    /// each of the two phases at protocol 27 is just an exchange of -1
+    /// markers, and then the client ends the sequence.
+    #[test]
+    fn exchange_two_empty_phases() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 3, 0o100644)
+            .end_file_list()
+            .finish();
+        let (file_list, _summary) = script
+            .connect_for(TransferMode::List, Options::default())
+            .unwrap()
+            .receive(&mut LocalTree::new("/dev/null"))
+            .unwrap();
+        assert_eq!(file_list.len(), 1);
+        let mut expected = vec![27, 0, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(&[0xff; 12]);
+        assert_eq!(script.client_sent(), expected);
+    }
+
+    /// Statistics are read field by field, leaving nothing behind.
+    #[test]
+    fn server_statistics_are_read_exactly() {
+        let mut encoded = Vec::new();
+        for v in 1..=3i32 {
+            encoded.extend_from_slice(&v.to_le_bytes());
+        }
+        let mut rv = ReadVarint::new(Box::new(io::Cursor::new(encoded)));
+        let stats = read_server_statistics(&mut rv).unwrap();
+        assert_eq!(stats.total_bytes_read, 1);
+        assert_eq!(stats.total_bytes_written, 2);
+        assert_eq!(stats.total_file_size, 3);
+        assert_eq!(stats.flist_build_time, None);
+        assert_eq!(stats.flist_xfer_time, None);
+        check_for_trailing_data(rv, 27).unwrap();
+    }
+
+    #[test]
+    fn trailing_data_is_an_error() {
+        let rv = ReadVarint::new(Box::new(io::Cursor::new(vec![1, 2, 3])));
+        assert_eq!(
+            check_for_trailing_data(rv, 27).unwrap_err().to_string(),
+            "Data found when we expected end of stream"
+        );
+    }
+
+    /// Owners are mapped to local users by name, but groups whose names aren't
+    /// known locally keep their ids.
+    #[cfg(unix)]
+    #[test]
+    fn map_owner_and_group_by_name() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 3, 0o100644)
+            .i32(1000)
+            .i32(5005)
+            .bytes(&[0])
+            .i32(1000)
+            .bytes(&[4])
+            .bytes(b"root")
+            .i32(0)
+            .i32(5005)
+            .bytes(&[22])
+            .bytes(b"no-such-group-for-rsyn")
+            .i32(0)
+            .i32(0)
+            .finish();
+        let options = Options {
+            preserve_uid: true,
+            preserve_gid: true,
+            ..Options::default()
+        };
+        let (file_list, _summary) = script
+            .connect_for(TransferMode::List, options)
+            .unwrap()
+            .receive(&mut LocalTree::new("/dev/null"))
+            .unwrap();
+        assert_eq!(file_list[0].uid(), Some(0));
+        assert_eq!(file_list[0].gid(), Some(5005));
+    }
+
+    /// Symlink targets are received with `preserve_links`, including links
+    /// out of the tree, which stay in the list with `copy_unsafe_links`.
+    #[test]
+    fn receive_symlink_targets() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"in", 4, 0o120777)
+            .i32(4)
+            .bytes(b"file")
+            .file_entry(b"out", 4, 0o120777)
+            .i32(4)
+            .bytes(b"../x")
+            .end_file_list()
+            .finish();
+        let options = Options {
+            preserve_links: true,
+            copy_unsafe_links: true,
+            ..Options::default()
+        };
+        let (file_list, _summary) = script
+            .connect_for(TransferMode::List, options)
+            .unwrap()
+            .receive(&mut LocalTree::new("/dev/null"))
+            .unwrap();
+        assert_eq!(file_list.len(), 2);
+        assert_eq!(file_list[0].name_bytes(), b"in");
+        assert_eq!(file_list[0].link_target(), Some(&b"file"[..]));
+        assert_eq!(file_list[1].link_target(), Some(&b"../x"[..]));
+    }
+
+    /// An unsafe symlink that's skipped keeps its place in the list, so the
+    /// files after it are still requested by the right index.
+    #[cfg(unix)]
+    #[test]
+    fn skipped_unsafe_symlink_keeps_later_indexes() {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_connection_unsafe_symlink")
+            .tempdir()
+            .unwrap();
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 9, 0o120777)
+            .i32(9)
+            .bytes(b"/etc/motd")
+            .file_entry(b"b", 5, 0o100644)
+            .end_file_list()
+            .file_data(1, b"hello", 0x1234)
+            .finish();
+        let options = Options {
+            preserve_links: true,
+            copy_unsafe_links: true,
+            ..Options::default()
+        };
+        script
+            .connect(options)
+            .unwrap()
+            .receive(&mut LocalTree::new(tmp.path()))
+            .unwrap();
+        assert!(std::fs::symlink_metadata(tmp.path().join("a")).is_err());
+        assert_eq!(std::fs::read(tmp.path().join("b")).unwrap(), b"hello");
+    }
+
+    /// Whether the destination is a file is decided by the file list of the
+    /// download itself.
+    #[test]
+    fn file_or_directory_destination() {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_connection_file_or_directory")
+            .tempdir()
+            .unwrap();
+
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .finish();
+        let dest = tmp.path().join("renamed");
+        script
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut LocalTree::file_or_directory(&dest))
+            .unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"hello");
+
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .file_entry(b"b", 3, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .file_data(1, b"bye", 0x1234)
+            .finish();
+        let dest = tmp.path().join("dir");
+        std::fs::create_dir(&dest).unwrap();
+        script
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut LocalTree::file_or_directory(&dest))
+            .unwrap();
+        assert_eq!(std::fs::read(dest.join("a")).unwrap(), b"hello");
+        assert_eq!(std::fs::read(dest.join("b")).unwrap(), b"bye");
+
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .file_entry(b"b", 3, 0o100644)
+            .end_file_list()
+            .finish();
+        let err = script
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut LocalTree::single_file(tmp.path().join("one")))
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("Can't write 2 entries to the single file destination"),
+            "{:#}",
+            err
+        );
+    }
+
+    /// With `receive_into_child`, the caller can wait for the child itself.
+    #[cfg(unix)]
+    #[test]
+    fn receive_into_child_returns_child() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 3, 0o100644)
+            .end_file_list()
+            .finish();
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_receive_into_child")
+            .tempdir()
+            .unwrap();
+        let script_path = tmp.path().join("script");
+        std::fs::write(&script_path, script.server_sent()).unwrap();
+        // Send the script, then exit once the client closes its side.
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(r#"cat "$0"; cat >/dev/null; exit 3"#)
+            .arg(&script_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let r = Box::new(child.stdout.take().unwrap());
+        let w = Box::new(child.stdin.take().unwrap());
+
+        let (file_list, summary, child) =
+            Connection::handshake(r, w, Some(child), TransferMode::List, Options::default())
+                .unwrap()
+                .receive_into_child(&mut LocalTree::new("/dev/null"))
+                .unwrap();
+        assert_eq!(file_list.len(), 1);
+        assert_eq!(summary.child_exit_status, None);
+        let status = child.expect("No child returned").wait().unwrap();
+        assert_eq!(status.code(), Some(3));
+    }
+
+    /// After a timeout, the stalled child is killed and reaped.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn timeout_kills_child() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script.file_entry(b"a", 3, 0o100644);
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_timeout_kills_child")
+            .tempdir()
+            .unwrap();
+        let script_path = tmp.path().join("script");
+        std::fs::write(&script_path, script.server_sent()).unwrap();
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(r#"cat "$0"; exec sleep 60"#)
+            .arg(&script_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let proc_path = format!("/proc/{}", child.id());
+        let r = Box::new(child.stdout.take().unwrap());
+        let w = Box::new(child.stdin.take().unwrap());
+        let options = Options {
+            timeout: Some(Duration::from_millis(50)),
+            ..Options::default()
+        };
+
+        let err = Connection::handshake(r, w, Some(child), TransferMode::List, options)
+            .unwrap()
+            .receive(&mut LocalTree::new("/dev/null"))
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("No data received for 50ms"),
+            "{:#}",
+            err
+        );
+        assert!(!Path::new(&proc_path).exists());
+    }
+
+    /// If the transfer fails, the child is killed and reaped, even if it
+    /// would otherwise keep running.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failed_transfer_kills_child() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script.bytes(&[crate::flist::STATUS_LONG_NAME]).i32(-1);
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_failed_transfer_kills_child")
+            .tempdir()
+            .unwrap();
+        let script_path = tmp.path().join("script");
+        std::fs::write(&script_path, script.server_sent()).unwrap();
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(r#"cat "$0"; exec sleep 60"#)
+            .arg(&script_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let proc_path = format!("/proc/{}", child.id());
+        let r = Box::new(child.stdout.take().unwrap());
+        let w = Box::new(child.stdin.take().unwrap());
+
+        let err = Connection::handshake(r, w, Some(child), TransferMode::List, Options::default())
+            .unwrap()
+            .receive_into_child(&mut LocalTree::new("/dev/null"))
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("negative name length"),
+            "{:#}",
+            err
+        );
+        assert!(!Path::new(&proc_path).exists());
+    }
+
+    #[test]
+    fn unexpected_phase_marker_is_an_error() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 3, 0o100644)
+            .end_file_list()
+            .i32(-1)
+            .i32(0);
+        let err = script
+            .connect_for(TransferMode::List, Options::default())
+            .unwrap()
+            .receive(&mut LocalTree::new("/dev/null"))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expected the end of phase 2 from the sender, but received 0"
+        );
+    }
+
+    #[test]
+    fn wrong_checksum_seed_is_diagnosed() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .file_entry(b"b", 3, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x4321)
+            .file_data(1, b"bye", 0x4321)
+            .finish();
+        let err = script
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut MemoryTree::new())
+            .unwrap_err();
+        match err.downcast_ref::<Error>() {
+            Some(Error::ChecksumSeedMismatch {
+                files: 2,
+                checksum_seed: 0x1234,
+            }) => (),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn single_mismatch_is_not_blamed_on_seed() {
+        let mut script = ServerScript::new(27, 0x1234);
+        script
+            .file_entry(b"a", 5, 0o100644)
+            .file_entry(b"b", 3, 0o100644)
+            .end_file_list()
+            .file_data(0, b"hello", 0x1234)
+            .file_data(1, b"bye", 0x4321)
+            .finish();
+        let (_file_list, summary) = script
+            .connect(Options::default())
+            .unwrap()
+            .receive(&mut MemoryTree::new())
+            .unwrap();
+        assert_eq!(summary.files_received, 2);
+        assert_eq!(summary.whole_file_sum_mismatch_count, 1);
+    }
+
+    #[test]
+    fn quick_check_compares_size_and_mtime() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_quick_check")
+            .tempdir()
+            .unwrap();
+        let path = tmp.path().join("a");
+        std::fs::write(&path, b"hello").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1588429517))
+            .unwrap();
+        let local_tree = LocalTree::new(tmp.path());
+        let same = [FileEntry::for_test(b"a", 0o100644, 5, 1588429517)];
+        let newer = [FileEntry::for_test(b"a", 0o100644, 5, 1588429600)];
+        let longer = [FileEntry::for_test(b"a", 0o100644, 6, 1588429517)];
+
+        let mut options = Options::default();
+        assert!(files_to_request(&local_tree, &same, &options).is_empty());
+        assert_eq!(files_to_request(&local_tree, &newer, &options), [0]);
+        assert_eq!(files_to_request(&local_tree, &longer, &options), [0]);
+
+        options.size_only = true;
+        assert!(files_to_request(&local_tree, &newer, &options).is_empty());
+        assert_eq!(files_to_request(&local_tree, &longer, &options), [0]);
+
+        // The local file is newer than this one.
+        let older = [FileEntry::for_test(b"a", 0o100644, 6, 1588429000)];
+        let mut options = Options::default();
+        assert_eq!(files_to_request(&local_tree, &older, &options), [0]);
+        options.update = true;
+        assert!(files_to_request(&local_tree, &older, &options).is_empty());
+        assert_eq!(files_to_request(&local_tree, &newer, &options), [0]);
+    }
+}
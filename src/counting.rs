@@ -0,0 +1,108 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Count the bytes read from and written to a transport.
+
+use std::io;
+use std::io::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Counters of the bytes moved over a connection's transport, measured by
+/// the client.
+///
+/// Clones share the same counters, so they can be read while the streams
+/// are owned elsewhere.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WireCounters {
+    read: Arc<AtomicU64>,
+    written: Arc<AtomicU64>,
+}
+
+impl WireCounters {
+    /// Wrap a stream so that bytes read from it are counted.
+    pub(crate) fn count_read(&self, r: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+        Box::new(CountingRead {
+            r,
+            count: self.read.clone(),
+        })
+    }
+
+    /// Wrap a stream so that bytes written to it are counted.
+    pub(crate) fn count_write(&self, w: Box<dyn Write + Send>) -> Box<dyn Write + Send> {
+        Box::new(CountingWrite {
+            w,
+            count: self.written.clone(),
+        })
+    }
+
+    pub(crate) fn bytes_read(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bytes_written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+}
+
+struct CountingRead {
+    r: Box<dyn Read + Send>,
+    count: Arc<AtomicU64>,
+}
+
+impl Read for CountingRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.r.read(buf)?;
+        self.count.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+}
+
+struct CountingWrite {
+    w: Box<dyn Write + Send>,
+    count: Arc<AtomicU64>,
+}
+
+impl Write for CountingWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.w.write(buf)?;
+        self.count.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn count_reads_and_writes() {
+        let counters = WireCounters::default();
+        let mut r = counters.count_read(Box::new(&b"hello world"[..]));
+        let mut buf = [0; 5];
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(counters.bytes_read(), 5);
+        r.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(counters.bytes_read(), 11);
+
+        let mut w = counters.count_write(Box::new(io::sink()));
+        w.write_all(b"abc").unwrap();
+        assert_eq!(counters.bytes_written(), 3);
+        assert_eq!(counters.clone().bytes_written(), 3);
+    }
+}
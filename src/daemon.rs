@@ -0,0 +1,390 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The rsync daemon protocol.
+//!
+//! A daemon connection starts with a text exchange, in which the client and
+//! server exchange protocol versions, the client chooses a module, and then
+//! sends the arguments that would otherwise be on the server's command line.
+//! The usual binary protocol follows, starting from the checksum seed.
+
+// So far, daemons can only be reached over Unix sockets.
+#![cfg_attr(not(unix), allow(dead_code))]
+
+use std::ffi::OsString;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::auth::{challenge_response, daemon_password, default_user};
+use crate::client::check_module_name;
+use crate::connection::{with_timeout, Connection, MY_PROTOCOL_VERSION};
+use crate::counting::WireCounters;
+use crate::localtree::path_bytes;
+use crate::redact::{Redactor, RSYNC_PASSWORD_ENV};
+use crate::varint::{ReadVarint, WriteVarint};
+use crate::{Error, Options, Result, TransferMode};
+
+/// Starts every line of the daemon's protocol messages.
+const DAEMON_PREFIX: &str = "@RSYNCD: ";
+
+/// Longest line accepted from the daemon, to avoid reading forever from
+/// something that isn't an rsync daemon.
+const MAX_LINE_LENGTH: usize = 8192;
+
+/// Do the daemon handshake over a stream, and then start the usual protocol.
+///
+/// `module` is the name of the module, without any path within it.
+/// `server_args` are the arguments that would be given to `rsync --server`.
+/// `user` is the name to authenticate as, if the module asks for a password.
+pub(crate) fn daemon_handshake(
+    r: Box<dyn Read + Send>,
+    w: Box<dyn Write + Send>,
+    module: &str,
+    server_args: &[OsString],
+    user: Option<&str>,
+    mode: TransferMode,
+    options: Options,
+) -> Result<Connection> {
+    // Compare to rsync `start_inband_exchange`.
+    let counters = WireCounters::default();
+    let (mut r, timeout) = with_timeout(counters.count_read(r), &options);
+    let mut w = counters.count_write(w);
+    let mut redactor = Redactor::for_options(&options);
+
+    send_line(
+        &mut w,
+        format!("{}{}", DAEMON_PREFIX, MY_PROTOCOL_VERSION).as_bytes(),
+    )
+    .context("Failed to send daemon greeting")?;
+    let greeting = read_line(&mut r).context("Failed to read daemon greeting")?;
+    let remote_protocol_version = parse_greeting(&greeting)?;
+    debug!("Daemon greeting {:?}", greeting);
+
+    check_module_name(module)?;
+    send_line(&mut w, module.as_bytes()).context("Failed to send module name")?;
+
+    let mut motd = Vec::new();
+    loop {
+        let line = read_line(&mut r).context("Failed to read daemon response")?;
+        if line == "@RSYNCD: OK" {
+            break;
+        } else if let Some(challenge) = line.strip_prefix("@RSYNCD: AUTHREQD ") {
+            let password = daemon_password(&options)?.with_context(|| {
+                format!(
+                    "Daemon module {:?} requires a password, but no password file \
+                     or ${} was given",
+                    module, RSYNC_PASSWORD_ENV
+                )
+            })?;
+            let user = match user {
+                Some(user) => user.to_owned(),
+                None => default_user()?,
+            };
+            let response = challenge_response(&password, challenge);
+            if !options.log_secrets {
+                redactor.add_secret(password);
+                redactor.add_secret(response.as_str());
+            }
+            let auth_line = format!("{} {}", user, response);
+            debug!("Send authentication {:?}", redactor.redact(&auth_line));
+            send_line(&mut w, auth_line.as_bytes()).context("Failed to send authentication")?;
+        } else if line == "@RSYNCD: EXIT" {
+            bail!("Daemon closed the connection");
+        } else if let Some(message) = line.strip_prefix("@ERROR") {
+            return Err(Error::DaemonRefused {
+                message: error_message(message).to_owned(),
+            }
+            .into());
+        } else {
+            info!("Daemon: {}", line);
+            motd.push(line);
+        }
+    }
+
+    debug!(
+        "Send daemon arguments {}",
+        redactor.redact(&format!("{:?}", server_args))
+    );
+    // Before protocol 30, arguments are terminated by newlines rather than
+    // NULs, and an empty line ends the list.
+    for arg in server_args {
+        send_line(&mut w, &path_bytes(Path::new(arg))).context("Failed to send argument")?;
+    }
+    send_line(&mut w, b"").context("Failed to send end of arguments")?;
+
+    // If the daemon rejects the arguments, it says so in a line of text
+    // instead of starting the binary protocol. Check for that, rather than
+    // taking the text as the checksum seed, which would make every file fail
+    // its checksum.
+    let mut r = io::BufReader::new(r);
+    if r.fill_buf()
+        .context("Failed to read daemon response to arguments")?
+        .starts_with(b"@ERROR")
+    {
+        let line = read_line(&mut r)?;
+        return Err(Error::DaemonRefused {
+            message: error_message(&line["@ERROR".len()..]).to_owned(),
+        }
+        .into());
+    }
+
+    Connection::start(
+        ReadVarint::new(Box::new(r)),
+        WriteVarint::new(w),
+        remote_protocol_version,
+        motd,
+        counters,
+        timeout,
+        None,
+        mode,
+        options,
+    )
+}
+
+/// Parse a greeting like `@RSYNCD: 31.0`, returning the protocol version.
+///
+/// Newer daemons may follow the version with a sub-version, and a list of
+/// checksum algorithms, which are ignored.
+fn parse_greeting(greeting: &str) -> Result<i32> {
+    greeting
+        .strip_prefix(DAEMON_PREFIX)
+        .and_then(|rest| {
+            rest.split(|c: char| !c.is_ascii_digit())
+                .next()
+                .and_then(|v| v.parse().ok())
+        })
+        .with_context(|| format!("Unexpected daemon greeting {:?}", greeting))
+}
+
+/// Returns the message following `@ERROR` in a line from the daemon.
+fn error_message(rest: &str) -> &str {
+    rest.trim_start_matches(':').trim_start()
+}
+
+fn send_line(w: &mut dyn Write, line: &[u8]) -> io::Result<()> {
+    w.write_all(line)?;
+    w.write_all(b"\n")?;
+    w.flush()
+}
+
+/// Read one line from the daemon, without the line ending.
+///
+/// This reads one byte at a time, so as not to consume any of the binary
+/// protocol that follows.
+fn read_line(r: &mut dyn Read) -> Result<String> {
+    let mut line = Vec::new();
+    loop {
+        let mut b = [0u8];
+        r.read_exact(&mut b)?;
+        match b[0] {
+            b'\n' => break,
+            b'\r' => (),
+            c => line.push(c),
+        }
+        if line.len() > MAX_LINE_LENGTH {
+            bail!("Line from daemon is too long");
+        }
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// Wraps a Unix socket for writing, and shuts down its sending side when
+/// dropped.
+///
+/// The socket is also open for reading, so just dropping this end wouldn't
+/// tell the server we're done.
+#[cfg(unix)]
+pub(crate) struct ShutdownOnDrop(pub(crate) std::os::unix::net::UnixStream);
+
+#[cfg(unix)]
+impl Write for ShutdownOnDrop {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ShutdownOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown(std::net::Shutdown::Write);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_greetings() {
+        assert_eq!(parse_greeting("@RSYNCD: 27").unwrap(), 27);
+        assert_eq!(parse_greeting("@RSYNCD: 31.0").unwrap(), 31);
+        assert_eq!(parse_greeting("@RSYNCD: 31.0 sha512 md5 md4").unwrap(), 31);
+        assert!(parse_greeting("SSH-2.0-OpenSSH").is_err());
+        assert!(parse_greeting("@RSYNCD: ").is_err());
+    }
+
+    #[test]
+    fn read_lines() {
+        let mut r = io::Cursor::new(b"one\r\ntwo\nrest".to_vec());
+        assert_eq!(read_line(&mut r).unwrap(), "one");
+        assert_eq!(read_line(&mut r).unwrap(), "two");
+        // The rest is untouched.
+        assert_eq!(r.position(), 9);
+        assert!(read_line(&mut r).is_err());
+    }
+
+    #[test]
+    fn error_instead_of_checksum_seed() {
+        let server_sent = b"@RSYNCD: 27\n@RSYNCD: OK\n@ERROR: unknown option\n".to_vec();
+        let err = daemon_handshake(
+            Box::new(io::Cursor::new(server_sent)),
+            Box::new(io::sink()),
+            "module",
+            &[],
+            None,
+            TransferMode::Download,
+            Options::default(),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "Daemon error: unknown option");
+    }
+
+    #[test]
+    fn refused_authentication_is_a_distinct_error() {
+        let server_sent = b"@RSYNCD: 27\n@ERROR: auth failed on module module\n".to_vec();
+        let err = daemon_handshake(
+            Box::new(io::Cursor::new(server_sent)),
+            Box::new(io::sink()),
+            "module",
+            &[],
+            None,
+            TransferMode::Download,
+            Options::default(),
+        )
+        .err()
+        .unwrap();
+        match err.downcast_ref::<Error>() {
+            Some(Error::DaemonRefused { message }) => {
+                assert_eq!(message, "auth failed on module module")
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn authenticate_with_password_file() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        use crate::connection::test::{ServerScript, SharedBuf};
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_daemon_auth")
+            .tempdir()
+            .unwrap();
+        let password_file = tmp.path().join("password");
+        fs::write(&password_file, b"hunter2\n").unwrap();
+        fs::set_permissions(&password_file, fs::Permissions::from_mode(0o600)).unwrap();
+        let server_sent = ServerScript::new(27, 0x1234).daemon_stream(&[
+            "@RSYNCD: 27",
+            "@RSYNCD: AUTHREQD c2FsdHlzYWx0",
+            "@RSYNCD: OK",
+        ]);
+        let client_sent = SharedBuf::default();
+        let options = Options {
+            password_file: Some(password_file),
+            ..Options::default()
+        };
+        daemon_handshake(
+            Box::new(io::Cursor::new(server_sent)),
+            Box::new(client_sent.clone()),
+            "module",
+            &[],
+            Some("me"),
+            TransferMode::Download,
+            options,
+        )
+        .unwrap();
+        let expected = format!(
+            "@RSYNCD: 27\nmodule\nme {}\n\n",
+            challenge_response("hunter2", "c2FsdHlzYWx0")
+        );
+        assert_eq!(
+            String::from_utf8(client_sent.0.lock().unwrap().clone()).unwrap(),
+            expected
+        );
+    }
+
+    /// Neither the password nor the response to the challenge is logged,
+    /// even at trace level.
+    #[cfg(unix)]
+    #[test]
+    fn authentication_is_redacted_in_logs() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        use crate::connection::test::{logs_from, ServerScript};
+
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_daemon_auth_logs")
+            .tempdir()
+            .unwrap();
+        let password_file = tmp.path().join("password");
+        fs::write(&password_file, b"hunter2\n").unwrap();
+        fs::set_permissions(&password_file, fs::Permissions::from_mode(0o600)).unwrap();
+        let server_sent = ServerScript::new(27, 0x1234).daemon_stream(&[
+            "@RSYNCD: 27",
+            "@RSYNCD: AUTHREQD c2FsdHlzYWx0",
+            "@RSYNCD: OK",
+        ]);
+        let options = Options {
+            password_file: Some(password_file),
+            ..Options::default()
+        };
+        let (result, logs) = logs_from(|| {
+            daemon_handshake(
+                Box::new(io::Cursor::new(server_sent)),
+                Box::new(io::sink()),
+                "module",
+                &[],
+                Some("me"),
+                TransferMode::Download,
+                options,
+            )
+        });
+        result.unwrap();
+        let logs: Vec<String> = logs.into_iter().map(|(_, message)| message).collect();
+        let response = challenge_response("hunter2", "c2FsdHlzYWx0");
+        for message in &logs {
+            assert!(!message.contains("hunter2"), "{:?}", message);
+            assert!(!message.contains(&response), "{:?}", message);
+        }
+        assert!(
+            logs.iter().any(|message| message.contains("***")),
+            "{:?}",
+            logs
+        );
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! File data compressed with zlib, as rsync sends it with `-z`, like
+//! `recv_deflated_token` in rsync's `token.c`.
+//!
+//! Each file's data is a raw deflate stream, cut into packets that start with
+//! a flag byte. The sender flushes the stream before every token that isn't
+//! data, and strips the `00 00 ff ff` that ends each flush, so the receiver
+//! puts it back.
+
+use anyhow::{bail, Context};
+use flate2::{Decompress, FlushDecompress};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::varint::ReadVarint;
+use crate::Result;
+
+/// The end of the file's data.
+const END_FLAG: u8 = 0;
+/// Followed by a 32-bit block number.
+const TOKEN_LONG: u8 = 0x20;
+/// Compressed data: the low 6 bits are the high bits of the length, and a
+/// byte with the low bits follows.
+const DEFLATED_DATA: u8 = 0x40;
+
+/// The bytes at the end of a sync flush, which the sender leaves out.
+const SYNC_MARKER: [u8; 4] = [0, 0, 0xff, 0xff];
+
+/// How much to inflate at a time.
+const INFLATE_CHUNK: usize = 32 << 10;
+
+/// Reads the compressed data of one file.
+pub(crate) struct DeflatedTokens {
+    decompress: Decompress,
+    /// Data has been inflated since the last flush.
+    inflated: bool,
+}
+
+impl DeflatedTokens {
+    /// Start reading a new file's data.
+    pub(crate) fn new() -> DeflatedTokens {
+        DeflatedTokens {
+            // A raw stream, with no zlib header, as rsync's `inflateInit2(-15)`.
+            decompress: Decompress::new(false),
+            inflated: false,
+        }
+    }
+
+    /// Read the next packet, appending any data it inflates to `out`.
+    ///
+    /// Returns false at the end of the file's data.
+    pub(crate) fn read(&mut self, rv: &mut ReadVarint, out: &mut Vec<u8>) -> Result<bool> {
+        let flag = rv.read_u8()?;
+        if flag & 0xc0 == DEFLATED_DATA {
+            let len = ((flag & 0x3f) as usize) << 8 | rv.read_u8()? as usize;
+            let input = rv.read_byte_string(len)?;
+            self.inflate(&input, FlushDecompress::None, out)?;
+            self.inflated = true;
+            return Ok(true);
+        }
+        if self.inflated {
+            self.inflate(&SYNC_MARKER, FlushDecompress::Sync, out)?;
+            self.inflated = false;
+        }
+        match flag {
+            END_FLAG => Ok(false),
+            // TODO: Feed the matched block into the inflater's history, like
+            // rsync's `see_deflate_token`, once rsyn sends block sums.
+            flag if flag & 0x80 != 0 || flag & 0xe0 == TOKEN_LONG => {
+                bail!("Received a block reference, but rsyn sent no block sums")
+            }
+            flag => bail!("Unexpected compressed token flag {:#04x}", flag),
+        }
+    }
+
+    /// Inflate all of `input`, and whatever output it makes available.
+    fn inflate(
+        &mut self,
+        mut input: &[u8],
+        flush: FlushDecompress,
+        out: &mut Vec<u8>,
+    ) -> Result<()> {
+        loop {
+            out.reserve(INFLATE_CHUNK);
+            let total_in = self.decompress.total_in();
+            let total_out = self.decompress.total_out();
+            self.decompress
+                .decompress_vec(input, out, flush)
+                .context("Failed to inflate compressed file data")?;
+            let consumed = (self.decompress.total_in() - total_in) as usize;
+            let produced = self.decompress.total_out() - total_out;
+            input = &input[consumed..];
+            // Stop once the input is used up and the output wasn't limited by
+            // the space available, or if no progress can be made.
+            if (input.is_empty() && out.len() < out.capacity()) || (consumed == 0 && produced == 0)
+            {
+                break;
+            }
+        }
+        if !input.is_empty() {
+            bail!("Compressed file data continues after the end of the stream");
+        }
+        Ok(())
+    }
+}
+
+/// Compress `content` as rsync's sender does for a whole file with no
+/// matched blocks, in packets of at most `packet_len` bytes.
+#[cfg(test)]
+pub(crate) fn deflate_for_test(content: &[u8], packet_len: usize) -> Vec<u8> {
+    use flate2::{Compress, Compression, FlushCompress};
+
+    let mut compress = Compress::new(Compression::default(), false);
+    let mut compressed = Vec::with_capacity(content.len() + 64);
+    loop {
+        let consumed = compress.total_in() as usize;
+        compressed.reserve(INFLATE_CHUNK);
+        compress
+            .compress_vec(&content[consumed..], &mut compressed, FlushCompress::Sync)
+            .unwrap();
+        if compress.total_in() as usize == content.len() && compressed.len() < compressed.capacity()
+        {
+            break;
+        }
+    }
+    assert!(compressed.ends_with(&SYNC_MARKER));
+    compressed.truncate(compressed.len() - SYNC_MARKER.len());
+    let mut stream = Vec::new();
+    for packet in compressed.chunks(packet_len) {
+        stream.push(DEFLATED_DATA | (packet.len() >> 8) as u8);
+        stream.push(packet.len() as u8);
+        stream.extend_from_slice(packet);
+    }
+    stream.push(END_FLAG);
+    stream
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::*;
+
+    fn inflate_stream(stream: Vec<u8>) -> Result<Vec<u8>> {
+        let mut rv = ReadVarint::new(Box::new(io::Cursor::new(stream)));
+        let mut tokens = DeflatedTokens::new();
+        let mut content = Vec::new();
+        while tokens.read(&mut rv, &mut content)? {}
+        Ok(content)
+    }
+
+    #[test]
+    fn inflate_in_packets_of_any_size() {
+        let text: Vec<u8> = (0..5000)
+            .flat_map(|i| format!("line {} of some text\n", i).into_bytes())
+            .collect();
+        // The length of a packet has 14 bits.
+        for &packet_len in &[1, 7, 300, 16383] {
+            assert_eq!(
+                inflate_stream(deflate_for_test(&text, packet_len)).unwrap(),
+                text,
+                "packet_len {}",
+                packet_len
+            );
+        }
+        assert_eq!(inflate_stream(deflate_for_test(b"", 100)).unwrap(), b"");
+        assert_eq!(inflate_stream(vec![END_FLAG]).unwrap(), b"");
+    }
+
+    #[test]
+    fn block_references_are_errors() {
+        for stream in [vec![0x81], vec![TOKEN_LONG, 1, 0, 0, 0]] {
+            assert_eq!(
+                inflate_stream(stream).unwrap_err().to_string(),
+                "Received a block reference, but rsyn sent no block sums"
+            );
+        }
+    }
+
+    #[test]
+    fn corrupt_data_is_an_error() {
+        let err = inflate_stream(vec![DEFLATED_DATA, 3, 0xff, 0xff, 0xff, END_FLAG]).unwrap_err();
+        assert_eq!(err.to_string(), "Failed to inflate compressed file data");
+    }
+}
@@ -0,0 +1,415 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Delete files from the destination that aren't on the source, as with
+//! rsync's `--delete`.
+
+use std::collections::HashSet;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::filter;
+use crate::tree::backup;
+use crate::{DeleteMode, FileEntry, FilterSet, Options, Result, Summary, Tree};
+
+/// Delete entries from the local tree that aren't in the file list received
+/// from the server, returning the number deleted.
+///
+/// Only the contents of directories in the file list are considered, so
+/// transferring one subdirectory leaves the rest of the tree alone. The
+/// contents of deleted directories are deleted and counted too.
+///
+/// Entries excluded by the filter rules, and everything inside excluded
+/// directories, are kept, unless the delete mode is `Excluded`.
+///
+/// If backups are enabled, files are renamed to their backup names rather
+/// than deleted, and existing backups are kept, including everything in
+/// `options.backup_dir` if it's inside the destination.
+///
+/// Failures to delete are logged and skipped.
+pub(crate) fn delete_extraneous(
+    local_tree: &mut dyn Tree,
+    file_list: &[FileEntry],
+    options: &Options,
+) -> Result<usize> {
+    let filters = if options.delete == Some(DeleteMode::Excluded) {
+        FilterSet::default()
+    } else {
+        FilterSet::parse(filter::all_rules(options)?)?
+    };
+    let remote: HashSet<&[u8]> = file_list.iter().map(FileEntry::name_bytes).collect();
+    let remote_dirs: HashSet<&[u8]> = file_list
+        .iter()
+        .filter(|entry| entry.is_dir())
+        .map(FileEntry::name_bytes)
+        .collect();
+    let local = local_tree.scan(options)?;
+    let backup_suffix = options.effective_backup_suffix().as_bytes();
+    let backup_dir = options.backup_dir_name();
+
+    let mut doomed = Vec::new();
+    for entry in local.iter().filter(|entry| entry.name_bytes() != b".") {
+        let name = entry.name_bytes();
+        let self_and_ancestors = self_and_ancestors(name);
+        if options.backs_up()
+            && ((!backup_suffix.is_empty() && name.ends_with(backup_suffix))
+                || matches!(&backup_dir, Some(dir) if self_and_ancestors.contains(&&dir[..])))
+        {
+            trace!("Keep backup {:?}", entry.name_lossy_string());
+            continue;
+        }
+        if self_and_ancestors
+            .iter()
+            .any(|&path| filters.matches(path, path != name || entry.is_dir()) == Some(false))
+        {
+            trace!("Keep excluded {:?}", entry.name_lossy_string());
+            continue;
+        }
+        if self_and_ancestors
+            .iter()
+            .any(|&path| !remote.contains(path) && remote_dirs.contains(parent(path)))
+        {
+            doomed.push(entry);
+        }
+    }
+
+    // The list is sorted, so directories come before their contents, and
+    // deleting in reverse order empties each directory before it's removed.
+    let mut deleted = 0;
+    for entry in doomed.iter().rev() {
+        let name = entry.name_lossy_string();
+        let result = if options.backs_up() && !entry.is_dir() {
+            backup(local_tree, entry.name_bytes(), options)
+        } else {
+            local_tree.remove(entry.name_bytes(), entry.is_dir())
+        };
+        match result {
+            Ok(()) => {
+                info!("Deleted {:?}", name);
+                deleted += 1;
+            }
+            Err(err) => warn!("Failed to delete {:?}: {:#}", name, err),
+        }
+    }
+    Ok(deleted)
+}
+
+/// Handle local entries whose type differs from the entry of the same name in
+/// the file list, such as a directory where the server has a file.
+///
+/// With `options.force`, the local entry is removed, including everything in
+/// it if it's a directory, so that it can be replaced. Otherwise, it's kept,
+/// and the remote entry and anything inside it are skipped; these are
+/// counted in `summary.type_conflict_count`.
+///
+/// Returns the indexes of the skipped entries in the file list.
+pub(crate) fn resolve_type_conflicts(
+    local_tree: &mut dyn Tree,
+    file_list: &[FileEntry],
+    options: &Options,
+    summary: &mut Summary,
+) -> Result<Vec<usize>> {
+    let mut skipped = Vec::new();
+    let mut skipped_dirs: HashSet<&[u8]> = HashSet::new();
+    for (idx, entry) in file_list.iter().enumerate() {
+        let name = entry.name_bytes();
+        if name == b"." {
+            continue;
+        }
+        if self_and_ancestors(name)
+            .iter()
+            .skip(1)
+            .any(|dir| skipped_dirs.contains(dir))
+        {
+            skipped.push(idx);
+            continue;
+        }
+        let local = match local_tree.stat(name) {
+            Some(local) if local.mode & 0o170000 != entry.mode & 0o170000 => local,
+            _ => continue,
+        };
+        let name_str = entry.name_lossy_string();
+        if options.force {
+            info!("Remove {:?} to replace it with a different type", name_str);
+            local_tree.remove_all(name, local.is_dir())?;
+        } else {
+            warn!(
+                "Not replacing {:?} with a different type without --force",
+                name_str
+            );
+            summary.type_conflict_count += 1;
+            skipped.push(idx);
+            if entry.is_dir() {
+                skipped_dirs.insert(name);
+            }
+        }
+    }
+    Ok(skipped)
+}
+
+/// Returns the path, and then the path of each of its parent directories.
+fn self_and_ancestors(path: &[u8]) -> Vec<&[u8]> {
+    let mut v = vec![path];
+    v.extend(
+        path.iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, &c)| c == b'/')
+            .map(|(i, _)| &path[..i]),
+    );
+    v
+}
+
+/// Returns the directory containing `path`, which is `.` for a top-level name.
+fn parent(path: &[u8]) -> &[u8] {
+    match path.iter().rposition(|&c| c == b'/') {
+        Some(pos) => &path[..pos],
+        None => b".",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::LocalTree;
+
+    fn remote_list(entries: &[(&str, u32)]) -> Vec<FileEntry> {
+        entries
+            .iter()
+            .map(|(name, mode)| FileEntry::for_test(name.as_bytes(), *mode, 0, 1588429517))
+            .collect()
+    }
+
+    fn make_tree(root: &Path, files: &[&str], dirs: &[&str]) {
+        for dir in dirs {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"content").unwrap();
+        }
+    }
+
+    #[test]
+    fn ancestors() {
+        assert_eq!(
+            self_and_ancestors(b"a/b/c"),
+            [&b"a/b/c"[..], &b"a/b"[..], &b"a"[..]]
+        );
+        assert_eq!(parent(b"a/b/c"), b"a/b");
+        assert_eq!(parent(b"a"), b".");
+    }
+
+    #[test]
+    fn delete_extraneous_files_and_directories() {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_delete_extraneous")
+            .tempdir()
+            .unwrap();
+        make_tree(
+            tmp.path(),
+            &["a", "x", "sub/b", "old/c", "old/d"],
+            &["old/e"],
+        );
+        let file_list = remote_list(&[
+            (".", 0o040755),
+            ("a", 0o100644),
+            ("sub", 0o040755),
+            ("sub/b", 0o100644),
+        ]);
+        let options = Options {
+            recursive: true,
+            ..Options::default()
+        };
+        let deleted =
+            delete_extraneous(&mut LocalTree::new(tmp.path()), &file_list, &options).unwrap();
+        // x, old, old/c, old/d, old/e
+        assert_eq!(deleted, 5);
+        assert!(tmp.path().join("a").exists());
+        assert!(tmp.path().join("sub/b").exists());
+        assert!(!tmp.path().join("x").exists());
+        assert!(!tmp.path().join("old").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn delete_symlink_to_directory_but_not_its_target() {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_delete_symlink")
+            .tempdir()
+            .unwrap();
+        let dest = tmp.path().join("dest");
+        let outside = tmp.path().join("outside");
+        make_tree(&dest, &["a"], &[]);
+        make_tree(&outside, &["precious", "deeper/also"], &[]);
+        std::os::unix::fs::symlink(&outside, dest.join("link")).unwrap();
+        let file_list = remote_list(&[(".", 0o040755), ("a", 0o100644)]);
+        let options = Options {
+            recursive: true,
+            ..Options::default()
+        };
+        let deleted = delete_extraneous(&mut LocalTree::new(&dest), &file_list, &options).unwrap();
+        assert_eq!(deleted, 1);
+        assert!(fs::symlink_metadata(dest.join("link")).is_err());
+        assert!(dest.join("a").exists());
+        assert!(outside.join("precious").exists());
+        assert!(outside.join("deeper/also").exists());
+    }
+
+    #[test]
+    fn only_delete_within_transferred_directories() {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_delete_scope")
+            .tempdir()
+            .unwrap();
+        make_tree(tmp.path(), &["other", "src/a", "src/stale"], &[]);
+        // A transfer of `src`, without a trailing slash.
+        let file_list = remote_list(&[("src", 0o040755), ("src/a", 0o100644)]);
+        let options = Options {
+            recursive: true,
+            ..Options::default()
+        };
+        let deleted =
+            delete_extraneous(&mut LocalTree::new(tmp.path()), &file_list, &options).unwrap();
+        assert_eq!(deleted, 1);
+        assert!(tmp.path().join("other").exists());
+        assert!(!tmp.path().join("src/stale").exists());
+    }
+
+    #[test]
+    fn excluded_files_are_kept() {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_delete_excluded")
+            .tempdir()
+            .unwrap();
+        make_tree(
+            tmp.path(),
+            &["keep.o", "stale", "build/out", "old/x.o", "old/y"],
+            &[],
+        );
+        let file_list = remote_list(&[(".", 0o040755)]);
+        let options = Options {
+            recursive: true,
+            filter_rules: vec!["- *.o".into(), "- build/".into()],
+            ..Options::default()
+        };
+        let deleted =
+            delete_extraneous(&mut LocalTree::new(tmp.path()), &file_list, &options).unwrap();
+        // `stale` and `old/y` are deleted; `old` can't be, because it still
+        // holds the excluded `old/x.o`.
+        assert_eq!(deleted, 2);
+        assert!(tmp.path().join("keep.o").exists());
+        assert!(tmp.path().join("build/out").exists());
+        assert!(tmp.path().join("old/x.o").exists());
+        assert!(!tmp.path().join("old/y").exists());
+        assert!(!tmp.path().join("stale").exists());
+    }
+
+    #[test]
+    fn delete_excluded() {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_delete_excluded_mode")
+            .tempdir()
+            .unwrap();
+        make_tree(tmp.path(), &["keep.o", "stale", "build/out"], &[]);
+        let file_list = remote_list(&[(".", 0o040755)]);
+        let options = Options {
+            recursive: true,
+            filter_rules: vec!["- *.o".into(), "- build/".into()],
+            delete: Some(DeleteMode::Excluded),
+            ..Options::default()
+        };
+        let deleted =
+            delete_extraneous(&mut LocalTree::new(tmp.path()), &file_list, &options).unwrap();
+        assert_eq!(deleted, 4);
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 0);
+    }
+
+    /// A local directory where the server has a file, and a local file
+    /// where the server has a directory.
+    fn type_conflicts(force: bool) -> (tempfile::TempDir, Vec<usize>, Summary) {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_type_conflicts")
+            .tempdir()
+            .unwrap();
+        make_tree(tmp.path(), &["was_dir/inner", "was_file", "same"], &[]);
+        let file_list = remote_list(&[
+            (".", 0o040755),
+            ("same", 0o100644),
+            ("was_dir", 0o100644),
+            ("was_file", 0o040755),
+            ("was_file/a", 0o100644),
+        ]);
+        let options = Options {
+            force,
+            ..Options::default()
+        };
+        let mut summary = Summary::default();
+        let skipped = resolve_type_conflicts(
+            &mut LocalTree::new(tmp.path()),
+            &file_list,
+            &options,
+            &mut summary,
+        )
+        .unwrap();
+        (tmp, skipped, summary)
+    }
+
+    #[test]
+    fn type_conflicts_are_skipped_without_force() {
+        let (tmp, skipped, summary) = type_conflicts(false);
+        assert_eq!(skipped, [2, 3, 4]);
+        assert_eq!(summary.type_conflict_count, 2);
+        assert!(tmp.path().join("was_dir/inner").is_file());
+        assert!(tmp.path().join("was_file").is_file());
+    }
+
+    #[test]
+    fn type_conflicts_are_replaced_with_force() {
+        let (tmp, skipped, summary) = type_conflicts(true);
+        assert!(skipped.is_empty());
+        assert_eq!(summary.type_conflict_count, 0);
+        assert!(!tmp.path().join("was_dir").exists());
+        assert!(!tmp.path().join("was_file").exists());
+        assert!(tmp.path().join("same").is_file());
+    }
+
+    #[test]
+    fn deleted_files_are_backed_up() {
+        let tmp = tempfile::Builder::new()
+            .prefix("rsyn_delete_backup")
+            .tempdir()
+            .unwrap();
+        make_tree(tmp.path(), &["a", "stale", "older.bak"], &[]);
+        let file_list = remote_list(&[(".", 0o040755), ("a", 0o100644)]);
+        let options = Options {
+            recursive: true,
+            backup: true,
+            backup_suffix: Some(".bak".to_owned()),
+            delete: Some(DeleteMode::Before),
+            ..Options::default()
+        };
+        let deleted =
+            delete_extraneous(&mut LocalTree::new(tmp.path()), &file_list, &options).unwrap();
+        assert_eq!(deleted, 1);
+        assert!(!tmp.path().join("stale").exists());
+        assert!(tmp.path().join("stale.bak").is_file());
+        assert!(tmp.path().join("older.bak").is_file());
+    }
+}
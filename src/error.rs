@@ -0,0 +1,144 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Errors that callers may want to distinguish from general failures.
+
+use std::fmt;
+
+/// Specific errors from rsyn.
+///
+/// rsyn APIs return `anyhow::Error`; errors of this type can be recovered
+/// from it with `downcast_ref::<rsyn::Error>()`.
+#[derive(Debug)]
+pub enum Error {
+    /// Every file received failed its whole-file checksum.
+    ///
+    /// Individual mismatches can be caused by files changing while they're
+    /// read, but if every one fails it's much more likely the checksum seed
+    /// was misread, or the two ends disagree on the protocol.
+    ChecksumSeedMismatch {
+        /// The number of files received, all of which mismatched.
+        files: usize,
+        /// The checksum seed received from the server.
+        checksum_seed: i32,
+    },
+
+    /// The transfer was stopped by `CancelToken::cancel`.
+    Cancelled,
+
+    /// The daemon refused the connection with an `@ERROR` line, for example
+    /// because authentication failed, or the module doesn't exist.
+    DaemonRefused {
+        /// The daemon's message.
+        message: String,
+    },
+
+    /// Reading or writing a local file failed, as opposed to reading from
+    /// or writing to the connection.
+    ///
+    /// The underlying error is available from `source()`.
+    LocalIo {
+        /// What was being done, including the path.
+        message: String,
+        /// Why it failed.
+        source: std::io::Error,
+    },
+
+    /// The server only speaks protocol versions older than rsyn supports.
+    ProtocolTooOld {
+        /// The newest protocol version the server offered.
+        server_version: i32,
+        /// The oldest protocol version rsyn supports.
+        minimum_version: i32,
+    },
+
+    /// The connection was interrupted partway through the file list, for
+    /// example because it was closed or timed out.
+    ///
+    /// The entries received before then are kept, so that callers can use
+    /// them, or work out where to resume. The cause is available from
+    /// `source()`.
+    PartialFileList {
+        /// The entries received, sorted and without duplicates.
+        ///
+        /// User and group ids are as the server sent them, since the names
+        /// to map them come after the list.
+        entries: crate::FileList,
+        /// Why the list was cut off.
+        source: anyhow::Error,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ChecksumSeedMismatch {
+                files,
+                checksum_seed,
+            } => write!(
+                f,
+                "All {} received files failed their MD4 check: \
+                this probably indicates a protocol or checksum seed ({:#x}) mismatch, \
+                rather than corrupt files",
+                files, checksum_seed
+            ),
+            Error::Cancelled => write!(f, "Transfer was cancelled"),
+            Error::DaemonRefused { message } => write!(f, "Daemon error: {}", message),
+            Error::LocalIo { message, .. } => write!(f, "{}", message),
+            Error::ProtocolTooOld {
+                server_version,
+                minimum_version,
+            } => write!(
+                f,
+                "Server protocol version {} is too old: rsyn needs at least \
+                protocol {}, from rsync 2.6.0 or later. Upgrade rsync on the \
+                server, or use --rsync-path to run a newer rsync there",
+                server_version, minimum_version
+            ),
+            Error::PartialFileList { entries, .. } => write!(
+                f,
+                "File list was interrupted after {} entries",
+                entries.len()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::PartialFileList { source, .. } => Some(source.as_ref()),
+            Error::LocalIo { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Describes an `io::Error` from a local file, making it an
+/// `Error::LocalIo`.
+pub(crate) trait LocalIoContext<T> {
+    fn local_context<F: FnOnce() -> String>(self, describe: F) -> crate::Result<T>;
+}
+
+impl<T> LocalIoContext<T> for std::io::Result<T> {
+    fn local_context<F: FnOnce() -> String>(self, describe: F) -> crate::Result<T> {
+        self.map_err(|source| {
+            Error::LocalIo {
+                message: describe(),
+                source,
+            }
+            .into()
+        })
+    }
+}
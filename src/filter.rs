@@ -0,0 +1,585 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Filter rules, controlling which files are transferred.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::{Options, Result};
+
+/// Prefix for a rule that excludes files.
+pub(crate) const EXCLUDE_PREFIX: &str = "- ";
+/// Prefix for a rule that includes files.
+pub(crate) const INCLUDE_PREFIX: &str = "+ ";
+
+/// Patterns excluded by `cvs_exclude`, the same as rsync's built-in list.
+const CVS_EXCLUDES: &str = "RCS SCCS CVS CVS.adm RCSLOG cvslog.* tags TAGS \
+    .make.state .nse_depinfo *~ #* .#* ,* _$* *$ *.old *.bak *.BAK *.orig *.rej \
+    .del-* *.a *.olb *.o *.obj *.so *.exe *.Z *.elc *.ln core .svn/ .git/ .hg/ .bzr/";
+
+/// Where filter rules come from, in
+/// [`Options::filter_rules`](struct.Options.html#structfield.filter_rules).
+///
+/// Files of patterns are read before connecting, and their rules take the
+/// place of the file in the list.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FilterSource {
+    /// A single rule, such as `- *.o`, as for `--exclude`, `--include` or
+    /// `--filter`.
+    Rule(String),
+    /// A file of patterns to exclude, one per line, as for `--exclude-from`.
+    ///
+    /// Blank lines and lines starting with `#` or `;` are ignored. A line
+    /// starting with `+ ` or `- ` is an include or exclude rule regardless.
+    ExcludeFrom(PathBuf),
+    /// A file of patterns to include, in the same format, as for
+    /// `--include-from`.
+    IncludeFrom(PathBuf),
+}
+
+impl From<&str> for FilterSource {
+    fn from(rule: &str) -> FilterSource {
+        FilterSource::Rule(rule.to_owned())
+    }
+}
+
+impl From<String> for FilterSource {
+    fn from(rule: String) -> FilterSource {
+        FilterSource::Rule(rule)
+    }
+}
+
+/// A single include or exclude rule, like those given to rsync's `--filter`.
+///
+/// Patterns follow rsync's rules:
+///
+/// * A leading `/` anchors the pattern to the root of the transfer.
+/// * A trailing `/` matches only directories.
+/// * A pattern containing `/` (apart from a trailing one) or `**` is matched
+///   against the whole path, or, if it isn't anchored, any trailing part of
+///   it that starts at a directory boundary. Other patterns are matched
+///   against just the last component of the path.
+/// * `*` matches anything except `/`, `**` matches anything including `/`,
+///   `?` matches any single character except `/`, and `[...]` matches a
+///   character class. A backslash escapes the next character.
+/// * A pattern ending in `/***` matches the directory and everything in it.
+///
+/// The `Display` trait formats the rule in the short `+ `/`- ` form understood
+/// by all rsync versions.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FilterRule {
+    include: bool,
+    /// The pattern as given, without the rule prefix.
+    pattern: String,
+    /// The pattern to match, without anchoring or trailing slashes.
+    glob: Vec<u8>,
+    anchored: bool,
+    dir_only: bool,
+    /// True if the pattern is matched against the full path rather than
+    /// just the last component.
+    full_path: bool,
+    /// True if the pattern ended in `/***`, so it also matches everything
+    /// inside the directory.
+    and_contents: bool,
+}
+
+impl FilterRule {
+    /// Parse a rule from a string like `- *.o` or `include src/`.
+    pub fn parse(rule: &str) -> Result<FilterRule> {
+        let (include, pattern) = if let Some(pattern) = rule.strip_prefix(INCLUDE_PREFIX) {
+            (true, pattern)
+        } else if let Some(pattern) = rule.strip_prefix(EXCLUDE_PREFIX) {
+            (false, pattern)
+        } else if let Some(pattern) = rule.strip_prefix("include ") {
+            (true, pattern)
+        } else if let Some(pattern) = rule.strip_prefix("exclude ") {
+            (false, pattern)
+        } else {
+            bail!("Unsupported filter rule {:?}", rule);
+        };
+
+        let mut glob = pattern;
+        let anchored = glob.starts_with('/');
+        glob = glob.trim_start_matches('/');
+        let and_contents = glob.ends_with("/***");
+        if and_contents {
+            glob = &glob[..glob.len() - 4];
+        }
+        let dir_only = glob.ends_with('/');
+        glob = glob.trim_end_matches('/');
+        if glob.is_empty() {
+            bail!("Filter rule {:?} has an empty pattern", rule);
+        }
+        Ok(FilterRule {
+            include,
+            pattern: pattern.to_owned(),
+            full_path: anchored || and_contents || glob.contains('/') || glob.contains("**"),
+            glob: glob.as_bytes().to_vec(),
+            anchored,
+            dir_only,
+            and_contents,
+        })
+    }
+
+    /// True for an include rule; false for an exclude rule.
+    pub fn is_include(&self) -> bool {
+        self.include
+    }
+
+    /// The pattern, as given, without the rule prefix.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// True if this rule's pattern matches the path.
+    ///
+    /// `path` is relative to the root of the transfer, with `/` separators.
+    pub fn matches<P: AsRef<[u8]>>(&self, path: P, is_dir: bool) -> bool {
+        let path = path.as_ref();
+        if self.and_contents && self.match_from_any_start(path, true) {
+            return true;
+        }
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if !self.full_path {
+            let name = match path.iter().rposition(|&c| c == b'/') {
+                Some(pos) => &path[pos + 1..],
+                None => path,
+            };
+            return wildmatch(&self.glob, name);
+        }
+        self.match_from_any_start(path, false)
+    }
+
+    /// Match the whole path, or if the pattern's not anchored, any tail of it
+    /// starting after a `/`. With `prefix`, match anything inside a directory
+    /// matching the pattern.
+    fn match_from_any_start(&self, path: &[u8], prefix: bool) -> bool {
+        let matches = |tail: &[u8]| {
+            if prefix {
+                tail.iter()
+                    .enumerate()
+                    .filter(|(_, &c)| c == b'/')
+                    .any(|(i, _)| wildmatch(&self.glob, &tail[..i]))
+            } else {
+                wildmatch(&self.glob, tail)
+            }
+        };
+        if matches(path) {
+            return true;
+        }
+        !self.anchored
+            && path
+                .iter()
+                .enumerate()
+                .filter(|(_, &c)| c == b'/')
+                .any(|(i, _)| matches(&path[i + 1..]))
+    }
+}
+
+impl fmt::Display for FilterRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prefix = if self.include {
+            INCLUDE_PREFIX
+        } else {
+            EXCLUDE_PREFIX
+        };
+        write!(f, "{}{}", prefix, self.pattern)
+    }
+}
+
+/// An ordered list of filter rules, where the first matching rule wins.
+///
+/// As in rsync, rules are matched against each path separately: excluding a
+/// directory only excludes its contents if the directory isn't descended into.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FilterSet {
+    rules: Vec<FilterRule>,
+}
+
+impl FilterSet {
+    /// Parse a list of rules, in order.
+    pub fn parse<I, S>(rules: I) -> Result<FilterSet>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Ok(FilterSet {
+            rules: rules
+                .into_iter()
+                .map(|rule| FilterRule::parse(rule.as_ref()))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Add a rule, matched after all the existing rules.
+    pub fn push(&mut self, rule: FilterRule) {
+        self.rules.push(rule)
+    }
+
+    /// The rules, in order.
+    pub fn rules(&self) -> &[FilterRule] {
+        &self.rules
+    }
+
+    /// Returns `Some(true)` if the first rule matching the path is an include
+    /// rule, `Some(false)` if it's an exclude rule, or `None` if no rule
+    /// matches.
+    ///
+    /// `path` is relative to the root of the transfer, with `/` separators.
+    pub fn matches<P: AsRef<[u8]>>(&self, path: P, is_dir: bool) -> Option<bool> {
+        let path = path.as_ref();
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(path, is_dir))
+            .map(FilterRule::is_include)
+    }
+}
+
+/// Match a shell-style wildcard pattern against the whole of `text`.
+fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            let rest = trim_stars(rest);
+            (0..=text.len()).any(|i| wildmatch(rest, &text[i..]))
+        }
+        [b'*', rest @ ..] => {
+            for i in 0..=text.len() {
+                if wildmatch(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        [b'?', rest @ ..] => match text {
+            [c, text_rest @ ..] if *c != b'/' => wildmatch(rest, text_rest),
+            _ => false,
+        },
+        [b'[', class @ ..] => match text {
+            [] => false,
+            [c, text_rest @ ..] => match match_class(class, *c) {
+                Some((matched, rest)) => matched && wildmatch(rest, text_rest),
+                // No closing bracket, so it's literal.
+                None => *c == b'[' && wildmatch(class, text_rest),
+            },
+        },
+        [b'\\', c, rest @ ..] | [c, rest @ ..] => match text {
+            [t, text_rest @ ..] if t == c => wildmatch(rest, text_rest),
+            _ => false,
+        },
+    }
+}
+
+fn trim_stars(mut pattern: &[u8]) -> &[u8] {
+    while let [b'*', rest @ ..] = pattern {
+        pattern = rest;
+    }
+    pattern
+}
+
+/// Match a character class, starting after the opening `[`.
+///
+/// Returns whether `c` matched, and the rest of the pattern after the
+/// closing `]`, or None if the class isn't closed.
+fn match_class(class: &[u8], c: u8) -> Option<(bool, &[u8])> {
+    let (negated, mut i) = match class.first() {
+        Some(b'!') | Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let lo = *class.get(i)?;
+        if lo == b']' && !first {
+            break;
+        }
+        first = false;
+        match class.get(i + 1..i + 3) {
+            Some(&[b'-', hi]) if hi != b']' => {
+                matched |= lo <= c && c <= hi;
+                i += 3;
+            }
+            _ => {
+                matched |= c == lo;
+                i += 1;
+            }
+        }
+    }
+    Some((c != b'/' && matched != negated, &class[i + 1..]))
+}
+
+/// Returns all the filter rules from the options, in the order they apply:
+/// `filter_rules`, with the rules read from each file in place of the file,
+/// and finally the CVS excludes.
+///
+/// Rules are checked, and returned in the short form understood by every
+/// version of rsync.
+pub(crate) fn all_rules(options: &Options) -> Result<Vec<String>> {
+    let mut rules = Vec::new();
+    for source in &options.filter_rules {
+        match source {
+            FilterSource::Rule(rule) => rules.push(rule.clone()),
+            FilterSource::ExcludeFrom(path) => rules.extend(read_rules_file(path, EXCLUDE_PREFIX)?),
+            FilterSource::IncludeFrom(path) => rules.extend(read_rules_file(path, INCLUDE_PREFIX)?),
+        }
+    }
+    if options.cvs_exclude {
+        rules.extend(
+            CVS_EXCLUDES
+                .split_whitespace()
+                .map(|pattern| format!("{}{}", EXCLUDE_PREFIX, pattern)),
+        );
+    }
+    rules
+        .iter()
+        .map(|rule| FilterRule::parse(rule).map(|rule| rule.to_string()))
+        .collect()
+}
+
+/// Read a file of patterns, like those given to `--exclude-from`, one per line.
+///
+/// Blank lines, and lines starting with `#` or `;`, are ignored. Lines that
+/// already start with `+ ` or `- ` keep that prefix, and otherwise
+/// `default_prefix` is added.
+fn read_rules_file(path: &Path, default_prefix: &str) -> Result<Vec<String>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read filter rules from {:?}", path))?;
+    let rules = parse_rules(&content, default_prefix);
+    debug!("Read {} filter rules from {:?}", rules.len(), path);
+    Ok(rules)
+}
+
+fn parse_rules(content: &str, default_prefix: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !(line.trim().is_empty() || line.starts_with('#') || line.starts_with(';')))
+        .map(|line| {
+            if line.starts_with(INCLUDE_PREFIX) || line.starts_with(EXCLUDE_PREFIX) {
+                line.to_owned()
+            } else {
+                format!("{}{}", default_prefix, line)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_rules_skips_comments_and_blanks() {
+        let content = "# Build products\n*.o\n\n   \n; more\r\ntarget/\r\n+ keep.o\n";
+        assert_eq!(
+            parse_rules(content, EXCLUDE_PREFIX),
+            ["- *.o", "- target/", "+ keep.o"]
+        );
+    }
+
+    #[test]
+    fn all_rules_reads_files_in_place() {
+        let tempdir = tempfile::Builder::new()
+            .prefix("rsyn_filter_all_rules")
+            .tempdir()
+            .unwrap();
+        let exclude_path = tempdir.path().join("exclude");
+        fs::write(&exclude_path, "# Comment\n\n*.o\n*.tmp\n").unwrap();
+        let include_path = tempdir.path().join("include");
+        fs::write(&include_path, "src/\n").unwrap();
+        let options = Options {
+            filter_rules: vec![
+                FilterSource::ExcludeFrom(exclude_path),
+                "- *.bak".into(),
+                FilterSource::IncludeFrom(include_path),
+                "- *".into(),
+            ],
+            ..Options::default()
+        };
+        assert_eq!(
+            all_rules(&options).unwrap(),
+            ["- *.o", "- *.tmp", "- *.bak", "+ src/", "- *"]
+        );
+    }
+
+    #[test]
+    fn cvs_excludes_come_last() {
+        let options = Options {
+            filter_rules: vec!["+ keep.o".into()],
+            cvs_exclude: true,
+            ..Options::default()
+        };
+        let rules = all_rules(&options).unwrap();
+        assert_eq!(rules[0], "+ keep.o");
+        assert!(rules[1..].contains(&"- *.o".to_owned()));
+        assert!(rules[1..].contains(&"- .git/".to_owned()));
+        assert_eq!(rules.last().unwrap(), "- .bzr/");
+    }
+
+    #[test]
+    fn long_form_rules_are_sent_short() {
+        let options = Options {
+            filter_rules: vec!["include *.c".into(), "exclude *".into()],
+            ..Options::default()
+        };
+        assert_eq!(all_rules(&options).unwrap(), ["+ *.c", "- *"]);
+    }
+
+    #[test]
+    fn bad_rule_is_an_error() {
+        let options = Options {
+            filter_rules: vec!["*.o".into()],
+            ..Options::default()
+        };
+        assert!(all_rules(&options).is_err());
+    }
+
+    #[test]
+    fn parse_filter_rules() {
+        let rule = FilterRule::parse("+ /src/").unwrap();
+        assert!(rule.is_include());
+        assert_eq!(rule.pattern(), "/src/");
+        assert_eq!(rule.to_string(), "+ /src/");
+
+        let rule = FilterRule::parse("exclude *.o").unwrap();
+        assert!(!rule.is_include());
+        assert_eq!(rule.pattern(), "*.o");
+        assert_eq!(rule.to_string(), "- *.o");
+
+        for bad in &["", "*.o", "+", "+ ", "- /", "! foo", "-foo"] {
+            assert!(FilterRule::parse(bad).is_err(), "{:?} should fail", bad);
+        }
+    }
+
+    fn rule_matches(rule: &str, path: &str, is_dir: bool) -> bool {
+        FilterRule::parse(&format!("- {}", rule))
+            .unwrap()
+            .matches(path, is_dir)
+    }
+
+    #[test]
+    fn unanchored_pattern_matches_last_component() {
+        assert!(rule_matches("*.o", "foo.o", false));
+        assert!(rule_matches("*.o", "a/b/foo.o", false));
+        assert!(rule_matches("foo", "a/foo", true));
+        assert!(!rule_matches("*.o", "foo.o/bar", false));
+        assert!(!rule_matches("foo", "foobar", false));
+        assert!(!rule_matches("a", "a/b", false));
+    }
+
+    #[test]
+    fn anchored_pattern_matches_from_root() {
+        assert!(rule_matches("/foo", "foo", false));
+        assert!(!rule_matches("/foo", "a/foo", false));
+        assert!(rule_matches("/a/*.c", "a/x.c", false));
+        assert!(!rule_matches("/a/*.c", "b/a/x.c", false));
+    }
+
+    #[test]
+    fn pattern_with_slash_matches_any_tail() {
+        assert!(rule_matches("a/*.c", "a/x.c", false));
+        assert!(rule_matches("a/*.c", "src/a/x.c", false));
+        assert!(!rule_matches("a/*.c", "ba/x.c", false));
+        assert!(!rule_matches("a/*.c", "a/b/x.c", false));
+    }
+
+    #[test]
+    fn directory_only_pattern() {
+        assert!(rule_matches("build/", "build", true));
+        assert!(rule_matches("build/", "src/build", true));
+        assert!(!rule_matches("build/", "build", false));
+        assert!(rule_matches("/src/build/", "src/build", true));
+        assert!(!rule_matches("/src/build/", "src/build", false));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        assert!(rule_matches("a/**/z", "a/b/c/z", false));
+        assert!(rule_matches("a/**/z", "a/b/z", false));
+        assert!(!rule_matches("a/**/z", "a/z", false));
+        assert!(rule_matches("/a/**", "a/b/c", false));
+        assert!(rule_matches("**.o", "a/b/c.o", false));
+        assert!(!rule_matches("a/*/z", "a/b/c/z", false));
+    }
+
+    #[test]
+    fn triple_star_matches_directory_and_contents() {
+        assert!(rule_matches("/a/***", "a", true));
+        assert!(rule_matches("/a/***", "a/b", false));
+        assert!(rule_matches("/a/***", "a/b/c", true));
+        assert!(!rule_matches("/a/***", "ab", true));
+        assert!(!rule_matches("/a/***", "x/a/b", false));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(rule_matches("?.c", "a.c", false));
+        assert!(!rule_matches("?.c", "ab.c", false));
+        assert!(rule_matches("[abc].c", "b.c", false));
+        assert!(!rule_matches("[abc].c", "d.c", false));
+        assert!(rule_matches("[a-c].c", "b.c", false));
+        assert!(rule_matches("[!a-c].c", "d.c", false));
+        assert!(!rule_matches("[!a-c].c", "a.c", false));
+        assert!(rule_matches("[]].c", "].c", false));
+        assert!(rule_matches("[.c", "[.c", false));
+        assert!(rule_matches("\\*.c", "*.c", false));
+        assert!(!rule_matches("\\*.c", "a.c", false));
+        assert!(rule_matches("*", "anything", false));
+        assert!(rule_matches("a*b*c", "aXXbYYc", false));
+        assert!(!rule_matches("a*b*c", "aXXbYY", false));
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let set = FilterSet::parse(["+ keep.o", "- *.o", "- /build/", "+ */", "- *"]).unwrap();
+        assert_eq!(set.matches("keep.o", false), Some(true));
+        assert_eq!(set.matches("a/keep.o", false), Some(true));
+        assert_eq!(set.matches("a/foo.o", false), Some(false));
+        assert_eq!(set.matches("build", true), Some(false));
+        assert_eq!(set.matches("src", true), Some(true));
+        assert_eq!(set.matches("src/main.c", false), Some(false));
+        assert_eq!(FilterSet::default().matches("foo", false), None);
+        assert_eq!(
+            FilterSet::parse(["- *.o"]).unwrap().matches("foo.c", false),
+            None
+        );
+    }
+
+    #[test]
+    fn filter_set_rejects_bad_rules() {
+        assert!(FilterSet::parse(["- *.o", "bad"]).is_err());
+    }
+
+    #[test]
+    fn missing_rules_file_is_an_error() {
+        let options = Options {
+            filter_rules: vec![FilterSource::ExcludeFrom(
+                "/nonexistent/rsyn/excludes".into(),
+            )],
+            ..Options::default()
+        };
+        assert!(all_rules(&options).is_err());
+    }
+}
@@ -16,24 +16,32 @@
 
 use std::convert::TryInto;
 use std::fmt;
+use std::fs;
+use std::io;
+use std::time::UNIX_EPOCH;
 
 use anyhow::{bail, Context};
-use chrono::{Local, TimeZone};
+use chrono::{Local, TimeZone, Utc};
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
+use crate::ids::{override_ids, receive_id_lists};
+use crate::statistics::human_bytes;
 use crate::varint::ReadVarint;
-use crate::Result;
+use crate::{Error, Options, Result};
 
 // const STATUS_TOP_LEVEL_DIR: u8 = 0x01;
 const STATUS_REPEAT_MODE: u8 = 0x02;
-// const STATUS_REPEAT_UID: u8 = 0x08;
-// const STATUS_REPEAT_GID: u8 = 0x08;
+const STATUS_REPEAT_UID: u8 = 0x08;
+const STATUS_REPEAT_GID: u8 = 0x10;
 const STATUS_REPEAT_PARTIAL_NAME: u8 = 0x20;
-const STATUS_LONG_NAME: u8 = 0x40;
+pub(crate) const STATUS_LONG_NAME: u8 = 0x40;
 const STATUS_REPEAT_MTIME: u8 = 0x80;
 
+/// The longest name that can be received, plus one, as in rsync.
+const MAXPATHLEN: usize = 4096;
+
 type ByteString = Vec<u8>;
 
 /// Description of a single file (or directory or symlink etc).
@@ -55,13 +63,61 @@ pub struct FileEntry {
     /// Modification time, in seconds since the Unix epoch.
     mtime: u32,
 
+    /// Nanoseconds after `mtime`, if the server sent them.
+    mtime_nsec: u32,
+
     /// If this is a symlink, the target.
     link_target: Option<ByteString>,
+
+    /// The owner's user id, if `preserve_uid` is set.
+    pub(crate) uid: Option<u32>,
+
+    /// The group id, if `preserve_gid` is set.
+    pub(crate) gid: Option<u32>,
     // TODO: Other file_struct fields.
     // TODO: Work out what |basedir| is and maybe include that.
 }
 
 impl FileEntry {
+    /// Describes a local file, from its name relative to the root of the tree
+    /// and its metadata.
+    pub(crate) fn from_metadata(name: Vec<u8>, metadata: &fs::Metadata) -> FileEntry {
+        let since_epoch = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+        FileEntry {
+            name,
+            file_len: metadata.len(),
+            mode: metadata_mode(metadata),
+            mtime: since_epoch.map_or(0, |d| d.as_secs() as u32),
+            mtime_nsec: since_epoch.map_or(0, |d| d.subsec_nanos()),
+            link_target: None,
+            uid: None,
+            gid: None,
+        }
+    }
+
+    /// Construct an entry with the given attributes.
+    pub(crate) fn new(name: Vec<u8>, mode: u32, file_len: u64, mtime: u32) -> FileEntry {
+        FileEntry {
+            name,
+            file_len,
+            mode,
+            mtime,
+            mtime_nsec: 0,
+            link_target: None,
+            uid: None,
+            gid: None,
+        }
+    }
+
+    /// Construct an entry with the given attributes, for testing.
+    #[cfg(test)]
+    pub(crate) fn for_test(name: &[u8], mode: u32, file_len: u64, mtime: u32) -> FileEntry {
+        FileEntry::new(name.to_vec(), mode, file_len, mtime)
+    }
+
     /// Returns the file name, as a byte string, in the (remote) OS's encoding.
     ///
     /// rsync doesn't constrain the encoding, so this will typically, but not
@@ -82,6 +138,21 @@ impl FileEntry {
         String::from_utf8_lossy(&self.name)
     }
 
+    /// Returns the file name, escaped so that it's safe to print to a
+    /// terminal, in the same way as rsync.
+    ///
+    /// Control characters other than tab, and bytes that aren't valid UTF-8,
+    /// are shown as a backslash, `#`, and three octal digits, like `\#012`
+    /// for a newline. A backslash is escaped too if it would otherwise look
+    /// like the start of an escape.
+    ///
+    /// With `eight_bit_output`, as with rsync's `--8-bit-output`, only ASCII
+    /// control characters are escaped, and invalid UTF-8 is shown as
+    /// Unicode replacement characters.
+    pub fn name_escaped(&self, eight_bit_output: bool) -> String {
+        escape_name(&self.name, eight_bit_output)
+    }
+
     /// Returns true if this entry describes a plain file.
     pub fn is_file(&self) -> bool {
         unix_mode::is_file(self.mode)
@@ -97,15 +168,126 @@ impl FileEntry {
         unix_mode::is_symlink(self.mode)
     }
 
+    /// If this is a symlink, returns its target.
+    ///
+    /// Targets are only sent with the `preserve_links` option.
+    pub fn link_target(&self) -> Option<&[u8]> {
+        self.link_target.as_deref()
+    }
+
+    /// Returns true if this is a symlink whose target might be outside the
+    /// transferred tree, like rsync's `unsafe_symlink`.
+    ///
+    /// This is decided from the names alone: absolute targets are unsafe,
+    /// and so are relative targets with more `..` components than the
+    /// link's depth in the tree allows.
+    pub(crate) fn is_unsafe_symlink(&self) -> bool {
+        match &self.link_target {
+            Some(target) => unsafe_symlink(&self.name, target),
+            None => false,
+        }
+    }
+
+    /// Returns the owner's user id, if the `preserve_uid` option is set.
+    ///
+    /// Unless `numeric_ids` is set, this is the id of the local user with
+    /// the same name as the owner on the server, if there is one.
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    /// Returns the group id, if the `preserve_gid` option is set.
+    ///
+    /// Unless `numeric_ids` is set, this is the id of the local group with
+    /// the same name as the group on the server, if there is one.
+    pub fn gid(&self) -> Option<u32> {
+        self.gid
+    }
+
     /// Returns the modification time, in seconds since the Unix epoch.
     pub fn unix_mtime(&self) -> u32 {
         self.mtime
     }
 
+    /// Returns the nanoseconds of the modification time, after
+    /// `unix_mtime`, for entries from a local tree.
+    ///
+    /// Entries received from a server never carry nanoseconds, so this is
+    /// always 0 for them: servers only send nanoseconds from protocol 31,
+    /// and rsyn speaks 27.
+    pub fn mtime_nanos(&self) -> u32 {
+        self.mtime_nsec
+    }
+
     /// Returns the modification time as a chrono::DateTime associated to the
     /// local timezone.
     pub fn mtime(&self) -> chrono::DateTime<Local> {
-        Local.timestamp(self.mtime as i64, 0)
+        Local.timestamp(self.mtime as i64, self.mtime_nsec)
+    }
+
+    /// Format this entry like `ls`, as its `Display` does, but with a choice
+    /// of whether sizes are `human_readable` and names are escaped with
+    /// `eight_bit_output`.
+    pub fn to_ls_line(&self, human_readable: bool, eight_bit_output: bool) -> String {
+        let size = if human_readable {
+            human_bytes(self.file_len as i64)
+        } else {
+            self.file_len.to_string()
+        };
+        format!(
+            "{:08} {:>11} {:19} {}",
+            unix_mode::to_string(self.mode),
+            size,
+            self.mtime().format("%Y-%m-%d %H:%M:%S"),
+            self.name_escaped(eight_bit_output),
+        )
+    }
+
+    /// Format this entry as one line of a listing meant to be read by
+    /// other programs, like
+    ///
+    /// ```text
+    /// drwxr-x---         420 2020-05-02T14:25:17Z rsyn
+    /// ```
+    ///
+    /// The mode and size columns have a fixed width, and the size is always
+    /// exact. The modification time is in ISO 8601 format: in UTC if `tz_utc`
+    /// is set, so that the output doesn't depend on the local timezone, and
+    /// otherwise in the local timezone with its offset. The name is escaped,
+    /// so that each entry is on a single line.
+    pub fn to_listing_line(&self, tz_utc: bool) -> String {
+        let mtime = if tz_utc {
+            Utc.timestamp(self.mtime as i64, 0)
+                .format("%Y-%m-%dT%H:%M:%SZ")
+                .to_string()
+        } else {
+            self.mtime().format("%Y-%m-%dT%H:%M:%S%:z").to_string()
+        };
+        format!(
+            "{:08} {:>11} {} {}",
+            unix_mode::to_string(self.mode),
+            self.file_len,
+            mtime,
+            self.name_escaped(false),
+        )
+    }
+}
+
+#[cfg(unix)]
+fn metadata_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.mode()
+}
+
+#[cfg(not(unix))]
+fn metadata_mode(metadata: &fs::Metadata) -> u32 {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        0o040755
+    } else if file_type.is_symlink() {
+        0o120777
+    } else {
+        0o100644
     }
 }
 
@@ -116,84 +298,172 @@ impl FileEntry {
 /// drwxr-x---         420 2020-05-02 07:25:17 rsyn
 /// ```
 ///
-/// The modification time is shown in the local timezone.
+/// The modification time is shown in the local timezone; see
+/// [`FileEntry::to_listing_line`] for a format that doesn't depend on it.
+/// The name is escaped by [`FileEntry::name_escaped`].
+///
+/// With `{:#}`, the size is shown like `1.2M`.
 impl fmt::Display for FileEntry {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{:08} {:11} {:19} {}",
-            unix_mode::to_string(self.mode),
-            self.file_len,
-            self.mtime().format("%Y-%m-%d %H:%M:%S"),
-            self.name_lossy_string(),
-        )
+        f.write_str(&self.to_ls_line(f.alternate(), false))
+    }
+}
+
+/// Escape a file name for printing, as described for
+/// [`FileEntry::name_escaped`]. Compare to rsync's `filtered_fwrite`.
+pub(crate) fn escape_name(name: &[u8], eight_bit_output: bool) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut rest = name;
+    while !rest.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(valid) => (valid, &rest[rest.len()..]),
+            Err(err) => {
+                let (valid, tail) = rest.split_at(err.valid_up_to());
+                let bad_len = err.error_len().unwrap_or(tail.len());
+                (std::str::from_utf8(valid).unwrap(), &tail[..bad_len])
+            }
+        };
+        for (i, c) in valid.char_indices() {
+            let looks_like_escape = c == '\\' && {
+                let next = &valid.as_bytes()[i + 1..];
+                next.len() >= 4 && next[0] == b'#' && next[1..4].iter().all(u8::is_ascii_digit)
+            };
+            let unprintable = c != '\t' && (c < ' ' || (!eight_bit_output && c.is_control()));
+            if looks_like_escape || unprintable {
+                for &b in c.to_string().as_bytes() {
+                    out.push_str(&format!("\\#{:03o}", b));
+                }
+            } else {
+                out.push(c);
+            }
+        }
+        if !invalid.is_empty() {
+            if eight_bit_output {
+                out.push(std::char::REPLACEMENT_CHARACTER);
+            } else {
+                for &b in invalid {
+                    out.push_str(&format!("\\#{:03o}", b));
+                }
+            }
+        }
+        rest = &rest[valid.len() + invalid.len()..];
     }
+    out
 }
 
 /// A list of files returned from a server.
 pub type FileList = Vec<FileEntry>;
 
 /// Reads a file list, and then cleans and sorts it.
-pub(crate) fn read_file_list(rv: &mut ReadVarint) -> Result<FileList> {
+///
+/// With `preserve_uid` or `preserve_gid`, this also reads the user and group
+/// names that follow the list, and maps the ids to local ids.
+///
+/// Also returns the server's IO errors while building the list, which come
+/// after the list and the user and group names.
+///
+/// Both ends sort the list in the same way, and files are identified by
+/// their index in the sorted list.
+pub(crate) fn read_file_list(rv: &mut ReadVarint, options: &Options) -> Result<(FileList, i32)> {
+    let (mut file_list, io_error) = read_file_list_raw(rv, options)?;
+    sort_and_dedupe(&mut file_list);
+    Ok((file_list, io_error))
+}
+
+/// Reads a file list like `read_file_list`, but returns the entries in the
+/// order they were sent, including any duplicates.
+pub(crate) fn read_file_list_raw(
+    rv: &mut ReadVarint,
+    options: &Options,
+) -> Result<(FileList, i32)> {
     // Corresponds to rsync |receive_file_entry|.
-    // TODO: Support receipt of uid and gid with -o, -g.
-    // TODO: Support devices, links, etc.
+    // TODO: Support devices, etc.
     // TODO: Sort order changes in different protocol versions.
+    //
+    // The file list is never compressed, even with `-z`: rsync only
+    // compresses file data, as deflate tokens. The list is already kept
+    // small by sending each name as a suffix of the previous one, and by
+    // omitting fields that repeat.
 
     let mut file_list = Vec::new();
-    while let Some(entry) = receive_file_entry(rv, file_list.last())? {
-        file_list.push(entry)
+    loop {
+        options.check_cancelled()?;
+        let status = read_status(rv).map_err(|err| keep_partial(err, &file_list))?;
+        if status == 0 {
+            break;
+        }
+        let entry = receive_file_entry(rv, status, file_list.last(), options)
+            .map_err(|err| keep_partial(err, &file_list))?;
+        file_list.push(entry);
     }
     debug!("End of file list");
-    sort_and_dedupe(&mut file_list);
-    Ok(file_list)
+    receive_id_lists(rv, &mut file_list, options)?;
+    override_ids(&mut file_list, options)?;
+    let io_error = rv.read_i32().context("Failed to read server error count")?;
+    Ok((file_list, io_error))
 }
 
-fn receive_file_entry(
-    rv: &mut ReadVarint,
-    previous: Option<&FileEntry>,
-) -> Result<Option<FileEntry>> {
+/// If the connection was interrupted after some entries were received,
+/// return them in `Error::PartialFileList`, along with the original error.
+///
+/// Other errors, such as unexpected data, are returned unchanged, since the
+/// entries before them can't be trusted.
+fn keep_partial(err: anyhow::Error, file_list: &[FileEntry]) -> anyhow::Error {
+    const INTERRUPTIONS: &[io::ErrorKind] = &[
+        io::ErrorKind::UnexpectedEof,
+        io::ErrorKind::TimedOut,
+        io::ErrorKind::BrokenPipe,
+        io::ErrorKind::ConnectionReset,
+        io::ErrorKind::ConnectionAborted,
+    ];
+    let interrupted = err.chain().any(|cause| {
+        matches!(cause.downcast_ref::<io::Error>(),
+            Some(io_err) if INTERRUPTIONS.contains(&io_err.kind()))
+    });
+    if file_list.is_empty() || !interrupted {
+        return err;
+    }
+    let mut entries = file_list.to_vec();
+    sort_and_dedupe(&mut entries);
+    warn!("File list was interrupted after {} entries", entries.len());
+    Error::PartialFileList {
+        entries,
+        source: err,
+    }
+    .into()
+}
+
+/// Read the status byte at the start of a file entry, which is zero at the
+/// end of the list.
+///
+/// At protocol 27 this is the only byte of flags: the second byte of
+/// extended flags comes from protocol 28.
+fn read_status(rv: &mut ReadVarint) -> Result<u8> {
     let status = rv
         .read_u8()
         .context("Failed to read file entry status byte")?;
     trace!("File list status {:#x}", status);
-    if status == 0 {
-        return Ok(None);
-    }
-
-    let inherit_name_bytes = if (status & STATUS_REPEAT_PARTIAL_NAME) != 0 {
-        rv.read_u8()
-            .context("Failed to read inherited name bytes")? as usize
-    } else {
-        0
-    };
+    Ok(status)
+}
 
-    let name_len = if status & STATUS_LONG_NAME != 0 {
-        rv.read_i32()? as usize
-    } else {
-        rv.read_u8()? as usize
-    };
-    let mut name = rv.read_byte_string(name_len)?;
-    if inherit_name_bytes > 0 {
-        let mut new_name = previous.unwrap().name.clone();
-        new_name.truncate(inherit_name_bytes);
-        new_name.append(&mut name);
-        name = new_name;
-    }
+fn receive_file_entry(
+    rv: &mut ReadVarint,
+    status: u8,
+    previous: Option<&FileEntry>,
+    options: &Options,
+) -> Result<FileEntry> {
+    let name = receive_name(rv, status, previous)?;
     trace!("  filename: {:?}", String::from_utf8_lossy(&name));
-    assert!(!name.is_empty());
     validate_name(&name)?;
 
-    let file_len: u64 = rv
-        .read_i64()?
-        .try_into()
-        .context("Received negative file_len")?;
+    let file_len = rv.read_i64()?;
+    let file_len: u64 = file_len.try_into().context("Received negative file_len")?;
     trace!("  file_len: {}", file_len);
 
-    let mtime = if status & STATUS_REPEAT_MTIME == 0 {
-        rv.read_i32()? as u32
-    } else {
+    let mtime = if status & STATUS_REPEAT_MTIME != 0 {
         previous.unwrap().mtime
+    } else {
+        rv.read_i32()? as u32
     };
     trace!("  mtime: {}", mtime);
 
@@ -204,15 +474,112 @@ fn receive_file_entry(
     };
     trace!("  mode: {:#o}", mode);
 
-    // TODO: If the relevant options are set, read uid, gid, device, link target.
+    let uid = if !options.preserve_uid {
+        None
+    } else if status & STATUS_REPEAT_UID == 0 {
+        Some(rv.read_i32()? as u32)
+    } else {
+        previous.unwrap().uid
+    };
+    let gid = if !options.preserve_gid {
+        None
+    } else if status & STATUS_REPEAT_GID == 0 {
+        Some(rv.read_i32()? as u32)
+    } else {
+        previous.unwrap().gid
+    };
+    trace!("  uid: {:?}, gid: {:?}", uid, gid);
+
+    // TODO: If the relevant options are set, read device.
+
+    let link_target = if options.preserve_links && unix_mode::is_symlink(mode) {
+        let len = rv.read_i32()?;
+        let len = len
+            .try_into()
+            .with_context(|| format!("Received negative symlink target length {}", len))?;
+        let target = rv
+            .read_byte_string(len)
+            .context("Failed to read symlink target")?;
+        trace!("  link_target: {:?}", String::from_utf8_lossy(&target));
+        Some(target)
+    } else {
+        None
+    };
 
-    Ok(Some(FileEntry {
+    Ok(FileEntry {
         name,
         file_len,
         mtime,
+        // Servers only send nanoseconds from protocol 31, so received
+        // entries never have them.
+        mtime_nsec: 0,
         mode,
-        link_target: None,
-    }))
+        link_target,
+        uid,
+        gid,
+    })
+}
+
+/// Returns the name, relative to the top of the tree, of what a symlink
+/// called `name` pointing to `target` refers to, or None if it might be
+/// outside the tree.
+pub(crate) fn link_referent(name: &[u8], target: &[u8]) -> Option<Vec<u8>> {
+    if unsafe_symlink(name, target) {
+        return None;
+    }
+    let mut components: Vec<&[u8]> = name
+        .split(|&c| c == b'/')
+        .filter(|c| !c.is_empty() && *c != b".")
+        .collect();
+    components.pop();
+    for component in target.split(|&c| c == b'/') {
+        match component {
+            b".." => {
+                components.pop()?;
+            }
+            b"." | b"" => (),
+            _ => components.push(component),
+        }
+    }
+    if components.is_empty() {
+        Some(b".".to_vec())
+    } else {
+        Some(components.join(&b'/'))
+    }
+}
+
+/// Returns true if a symlink called `name`, relative to the top of the tree,
+/// pointing to `target`, might point outside the tree.
+///
+/// Compare to rsync's `unsafe_symlink`.
+fn unsafe_symlink(name: &[u8], target: &[u8]) -> bool {
+    if target.is_empty() || target[0] == b'/' {
+        return true;
+    }
+    // The number of directories the link is below the top of the tree.
+    let mut depth: i32 = 0;
+    let mut dirs: Vec<&[u8]> = name.split(|&c| c == b'/').collect();
+    dirs.pop();
+    for dir in dirs {
+        match dir {
+            b".." => depth = 0,
+            b"." | b"" => (),
+            _ => depth += 1,
+        }
+    }
+    for component in target.split(|&c| c == b'/') {
+        match component {
+            b".." => {
+                depth -= 1;
+                if depth < 0 {
+                    return true;
+                }
+            }
+            b"." | b"" => (),
+            _ => depth += 1,
+        }
+    }
+    false
 }
 
 /// Check that this name is safe to handle, and doesn't seem to include an escape from the
@@ -220,7 +587,58 @@ fn receive_file_entry(
 ///
 /// The resulting path should only ever be used as relative to a destination directory.
 ///
-fn validate_name(name: &[u8]) -> Result<()> {
+/// Read the name of a file list entry, which can start with some bytes of
+/// the previous entry's name.
+///
+/// As in rsync's `send_file_entry`, up to 255 bytes are inherited, and the
+/// rest of the name is sent with a one-byte length if it's up to 255 bytes
+/// long, or with `STATUS_LONG_NAME` and a longer length otherwise. The
+/// boundary is on the length of the part that's sent, not the whole name.
+fn receive_name(rv: &mut ReadVarint, status: u8, previous: Option<&FileEntry>) -> Result<Vec<u8>> {
+    let inherit_name_bytes = if (status & STATUS_REPEAT_PARTIAL_NAME) != 0 {
+        rv.read_u8()
+            .context("Failed to read inherited name bytes")? as usize
+    } else {
+        0
+    };
+    let suffix_len = if status & STATUS_LONG_NAME != 0 {
+        let len = rv.read_i32()?;
+        len.try_into().context("Received negative name length")?
+    } else {
+        rv.read_u8()? as usize
+    };
+    // Like rsync, check the length before reading the name, so a bad length
+    // can't cause a huge allocation.
+    if inherit_name_bytes + suffix_len >= MAXPATHLEN {
+        bail!(
+            "Received name of {} bytes, longer than the limit of {}",
+            inherit_name_bytes + suffix_len,
+            MAXPATHLEN - 1
+        );
+    }
+    let mut name = Vec::with_capacity(inherit_name_bytes + suffix_len);
+    if inherit_name_bytes > 0 {
+        let previous = match previous {
+            Some(previous) => &previous.name,
+            None => bail!(
+                "First file list entry inherits {} bytes of the previous name",
+                inherit_name_bytes
+            ),
+        };
+        if inherit_name_bytes > previous.len() {
+            bail!(
+                "Entry inherits {} bytes of the previous name, {:?}, which is shorter",
+                inherit_name_bytes,
+                String::from_utf8_lossy(previous)
+            );
+        }
+        name.extend_from_slice(&previous[..inherit_name_bytes]);
+    }
+    name.extend_from_slice(&rv.read_byte_string(suffix_len)?);
+    Ok(name)
+}
+
+pub(crate) fn validate_name(name: &[u8]) -> Result<()> {
     // Compare to rsync |clean_fname| and |sanitize_path|, although this does not
     // yet have the behavior of mapping into a pseudo-chroot directory, and it
     // only treats bad names as errors.
@@ -244,7 +662,7 @@ fn validate_name(name: &[u8]) -> Result<()> {
     Ok(())
 }
 
-fn sort_and_dedupe(file_list: &mut Vec<FileEntry>) {
+pub(crate) fn sort_and_dedupe(file_list: &mut Vec<FileEntry>) {
     // Compare to rsync `file_compare`.
 
     // In the rsync protocol the receiver gets a list of files from the server in
@@ -279,8 +697,11 @@ mod test {
             mode: 0o0040750,
             file_len: 420,
             mtime: 1588429517,
+            mtime_nsec: 0,
             name: b"rsyn".to_vec(),
             link_target: None,
+            uid: None,
+            gid: None,
         };
         // The mtime is in the local timezone, and we need the tests to pass
         // regardless of timezone. Rust Chrono doesn't seem to provide a way
@@ -299,6 +720,88 @@ mod test {
         );
     }
 
+    #[test]
+    fn listing_line_in_utc() {
+        let entry = FileEntry::for_test(b"rsyn", 0o0040750, 420, 1588429517);
+        assert_eq!(
+            entry.to_listing_line(true),
+            "drwxr-x---         420 2020-05-02T14:25:17Z rsyn"
+        );
+        let entry = FileEntry::for_test(b"big", 0o100644, 3 << 30, 0);
+        assert_eq!(
+            entry.to_listing_line(true),
+            "-rw-r--r--  3221225472 1970-01-01T00:00:00Z big"
+        );
+        assert!(Regex::new(
+            r"^drwxr-x---         420 2020-05-0[123]T\d\d:\d\d:17[+-]\d\d:\d\d rsyn$"
+        )
+        .unwrap()
+        .is_match(
+            &FileEntry::for_test(b"rsyn", 0o0040750, 420, 1588429517).to_listing_line(false)
+        ));
+    }
+
+    #[test]
+    fn display_escapes_control_characters() {
+        let entry = FileEntry::for_test(b"new\nline\x1b[31m", 0o100644, 1, 1588429517);
+        let display = entry.to_string();
+        assert!(
+            display.ends_with(r" new\#012line\#033[31m"),
+            "{:?} isn't escaped",
+            display
+        );
+        assert_eq!(display.lines().count(), 1);
+    }
+
+    #[test]
+    fn escape_names_like_rsync() {
+        assert_eq!(escape_name(b"plain name.txt", false), "plain name.txt");
+        assert_eq!(escape_name(b"tab\tok", false), "tab\tok");
+        assert_eq!(escape_name(b"del\x7f", false), r"del\#177");
+        assert_eq!(escape_name("caf\u{e9}".as_bytes(), false), "caf\u{e9}");
+        assert_eq!(escape_name(b"latin1 caf\xe9", false), r"latin1 caf\#351");
+        assert_eq!(escape_name(b"c1 \xc2\x85", false), r"c1 \#302\#205");
+        assert_eq!(escape_name(br"not\#12 \#123", false), r"not\#12 \#134#123");
+
+        // With --8-bit-output, only ASCII control characters are escaped.
+        assert_eq!(escape_name(b"c1 \xc2\x85\n", true), "c1 \u{85}\\#012");
+        assert_eq!(escape_name(b"del\x7f", true), "del\x7f");
+        assert_eq!(escape_name(b"latin1 caf\xe9", true), "latin1 caf\u{fffd}");
+    }
+
+    #[test]
+    fn link_referents() {
+        assert_eq!(link_referent(b"a", b"b").unwrap(), b"b");
+        assert_eq!(link_referent(b"dir/a", b"b").unwrap(), b"dir/b");
+        assert_eq!(link_referent(b"dir/a", b"./b/../c").unwrap(), b"dir/c");
+        assert_eq!(link_referent(b"dir/sub/a", b"../../b").unwrap(), b"b");
+        assert_eq!(link_referent(b"dir/a", b"..").unwrap(), b".");
+        assert_eq!(link_referent(b"dir/a", b"../../b"), None);
+        assert_eq!(link_referent(b"a", b"/etc/passwd"), None);
+    }
+
+    #[test]
+    fn unsafe_symlinks() {
+        assert!(!unsafe_symlink(b"a", b"b"));
+        assert!(!unsafe_symlink(b"a", b"./b/c"));
+        assert!(!unsafe_symlink(b"dir/a", b"../b"));
+        assert!(!unsafe_symlink(b"dir/sub/a", b"../../b"));
+        assert!(!unsafe_symlink(b"a", b"b/../c"));
+
+        assert!(unsafe_symlink(b"a", b"/etc/passwd"));
+        assert!(unsafe_symlink(b"a", b""));
+        assert!(unsafe_symlink(b"a", b"../b"));
+        assert!(unsafe_symlink(b"dir/a", b"../../b"));
+        assert!(unsafe_symlink(b"a", b"b/../../c"));
+        assert!(unsafe_symlink(b"dir/../a", b"../b"));
+    }
+
+    #[test]
+    fn file_entry_display_human_readable() {
+        let entry = FileEntry::for_test(b"big", 0o100644, 3 << 30, 1588429517);
+        assert!(format!("{:#}", entry).starts_with("-rw-r--r--        3.0G "));
+    }
+
     // TODO: Test reading and decoding from an varint stream.
 
     /// Examples from verbose output of rsync 2.6.1.
@@ -322,8 +825,11 @@ mod test {
                 mode: 0o0040750,
                 file_len: 420,
                 mtime: 1588429517,
+                mtime_nsec: 0,
                 name: name.to_vec(),
                 link_target: None,
+                uid: None,
+                gid: None,
             })
             .collect();
         let mut messy = clean.clone();
@@ -342,4 +848,114 @@ mod test {
         assert!(validate_name(b"../../naughty").is_err());
         assert!(validate_name(b"still/not/../ok").is_err());
     }
+
+    /// Encode a name as rsync's `send_file_entry` does, following the
+    /// status byte, given the previous name.
+    fn send_name(previous: &[u8], name: &[u8]) -> Vec<u8> {
+        let inherit = previous
+            .iter()
+            .zip(name)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(255);
+        let suffix = &name[inherit..];
+        let mut status = 0;
+        let mut buf = Vec::new();
+        if inherit > 0 {
+            status |= STATUS_REPEAT_PARTIAL_NAME;
+            buf.push(inherit as u8);
+        }
+        if suffix.len() > 255 {
+            status |= STATUS_LONG_NAME;
+            buf.extend_from_slice(&(suffix.len() as i32).to_le_bytes());
+        } else {
+            buf.push(suffix.len() as u8);
+        }
+        buf.extend_from_slice(suffix);
+        [&[status][..], &buf].concat()
+    }
+
+    /// Names are sent with a one-byte length up to 255 bytes, and a long
+    /// length from 256, counting only the part not inherited from the
+    /// previous name.
+    #[test]
+    fn name_lengths_around_the_long_name_boundary() {
+        let receive = |previous: Option<&FileEntry>, encoded: Vec<u8>| {
+            let status = encoded[0];
+            let mut rv = ReadVarint::new(Box::new(std::io::Cursor::new(encoded)));
+            rv.read_u8().unwrap();
+            let name = receive_name(&mut rv, status, previous).unwrap();
+            rv.check_for_eof().unwrap();
+            (status, name)
+        };
+
+        for (len, long) in [
+            (1, false),
+            (254, false),
+            (255, false),
+            (256, true),
+            (4000, true),
+        ] {
+            let name = vec![b'n'; len];
+            let encoded = send_name(b"", &name);
+            let header_len = if long { 4 } else { 1 };
+            assert_eq!(encoded.len(), 1 + header_len + len, "length {}", len);
+            let (status, received) = receive(None, encoded);
+            assert_eq!(status & STATUS_LONG_NAME != 0, long, "length {}", len);
+            assert_eq!(received, name, "length {}", len);
+        }
+
+        // At most 255 bytes are inherited, so the last 46 bytes of the
+        // shared prefix are sent again, and count towards the length of
+        // the part that's sent.
+        let prefix = vec![b'd'; 300];
+        let previous_name = [&prefix[..], b"/a"].concat();
+        let previous = FileEntry::for_test(&previous_name, 0o100644, 0, 0);
+        for (suffix_len, long) in [(1, false), (209, false), (210, true)] {
+            let name = [&prefix[..], b"/", &vec![b'b'; suffix_len]].concat();
+            let encoded = send_name(&previous_name, &name);
+            assert_eq!(encoded[1], 255);
+            let (status, received) = receive(Some(&previous), encoded);
+            assert_ne!(status & STATUS_REPEAT_PARTIAL_NAME, 0);
+            assert_eq!(status & STATUS_LONG_NAME != 0, long);
+            assert_eq!(received, name);
+        }
+    }
+
+    #[test]
+    fn bad_name_lengths_are_errors() {
+        let receive = |status: u8, encoded: &[u8], previous: Option<&FileEntry>| {
+            let mut rv = ReadVarint::new(Box::new(std::io::Cursor::new(encoded.to_vec())));
+            receive_name(&mut rv, status, previous)
+                .unwrap_err()
+                .to_string()
+        };
+        let previous = FileEntry::for_test(b"abc", 0o100644, 0, 0);
+        assert_eq!(
+            receive(STATUS_REPEAT_PARTIAL_NAME, &[2, 1, b'x'], None),
+            "First file list entry inherits 2 bytes of the previous name"
+        );
+        assert_eq!(
+            receive(STATUS_REPEAT_PARTIAL_NAME, &[4, 1, b'x'], Some(&previous)),
+            "Entry inherits 4 bytes of the previous name, \"abc\", which is shorter"
+        );
+        assert_eq!(
+            receive(STATUS_LONG_NAME, &(-1i32).to_le_bytes(), None),
+            "Received negative name length"
+        );
+        assert_eq!(
+            receive(STATUS_LONG_NAME, &4096i32.to_le_bytes(), None),
+            "Received name of 4096 bytes, longer than the limit of 4095"
+        );
+    }
+
+    #[test]
+    fn io_error_count_at_end_of_list() {
+        // The list ends with a zero byte, and the count follows the id lists
+        // as an i32.
+        let mut rv = ReadVarint::new(Box::new(std::io::Cursor::new(vec![0, 5, 0, 0, 0])));
+        let (file_list, io_error) = read_file_list(&mut rv, &Options::default()).unwrap();
+        assert!(file_list.is_empty());
+        assert_eq!(io_error, 5);
+    }
 }
@@ -0,0 +1,248 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Map user and group ids from the server to local ids, by name.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::varint::ReadVarint;
+use crate::{FileEntry, Options, Result, Tree};
+
+/// Maps the server's ids to local ids.
+type IdMap = HashMap<u32, u32>;
+
+/// Read the user and group names that follow the file list, and change the
+/// ids of the entries to the local ids with the same names, like rsync's
+/// `recv_uid_list`.
+///
+/// Names are only sent with `preserve_uid` or `preserve_gid`, and not with
+/// `numeric_ids`. Ids without a name, including root's, are kept, and so are
+/// ids whose names aren't known locally.
+pub(crate) fn receive_id_lists(
+    rv: &mut ReadVarint,
+    file_list: &mut [FileEntry],
+    options: &Options,
+) -> Result<()> {
+    if options.numeric_ids {
+        return Ok(());
+    }
+    if options.preserve_uid {
+        let uids = read_id_map(rv, "user", local_uid).context("Failed to read user names")?;
+        for entry in file_list.iter_mut() {
+            entry.uid = entry.uid.map(|uid| *uids.get(&uid).unwrap_or(&uid));
+        }
+    }
+    if options.preserve_gid {
+        let gids = read_id_map(rv, "group", local_gid).context("Failed to read group names")?;
+        for entry in file_list.iter_mut() {
+            entry.gid = entry.gid.map(|gid| *gids.get(&gid).unwrap_or(&gid));
+        }
+    }
+    Ok(())
+}
+
+/// With `options.chown` or `options.copy_as`, replace the owner and group of
+/// every entry, whether or not they were received.
+pub(crate) fn override_ids(file_list: &mut [FileEntry], options: &Options) -> Result<()> {
+    if let Some(copy_as) = &options.copy_as {
+        let (uid, gid) = parse_copy_as(copy_as)?;
+        for entry in file_list.iter_mut() {
+            entry.uid = Some(uid);
+            entry.gid = Some(gid);
+        }
+    } else if let Some(chown) = &options.chown {
+        let (uid, gid) = parse_chown(chown)?;
+        for entry in file_list.iter_mut() {
+            entry.uid = uid.or(entry.uid);
+            entry.gid = gid.or(entry.gid);
+        }
+    }
+    Ok(())
+}
+
+/// Parse an owner and group like rsync's `--chown=USER:GROUP`.
+///
+/// Either part can be empty, and the colon can be left out if there's only a
+/// user. Each part is a numeric id, or a name that's looked up locally.
+pub(crate) fn parse_chown(chown: &str) -> Result<(Option<u32>, Option<u32>)> {
+    let (user, group) = match chown.find(':') {
+        Some(pos) => (&chown[..pos], &chown[pos + 1..]),
+        None => (chown, ""),
+    };
+    Ok((
+        parse_id(user, "user", local_uid)?,
+        parse_id(group, "group", local_gid)?,
+    ))
+}
+
+/// Parse a user and optional group like rsync's `--copy-as=USER[:GROUP]`.
+///
+/// Unlike `--chown`, the user is required, and the group defaults to the
+/// user's primary group.
+pub(crate) fn parse_copy_as(copy_as: &str) -> Result<(u32, u32)> {
+    match parse_chown(copy_as)? {
+        (None, _) => bail!("No user in copy_as {:?}", copy_as),
+        (Some(uid), Some(gid)) => Ok((uid, gid)),
+        (Some(uid), None) => match primary_gid(uid) {
+            Some(gid) => Ok((uid, gid)),
+            None => bail!(
+                "Can't find the primary group of user {}: give one as USER:GROUP",
+                uid
+            ),
+        },
+    }
+}
+
+fn parse_id(s: &str, kind: &str, lookup: fn(&[u8]) -> Option<u32>) -> Result<Option<u32>> {
+    if s.is_empty() {
+        Ok(None)
+    } else if let Ok(id) = s.parse() {
+        Ok(Some(id))
+    } else {
+        match lookup(s.as_bytes()) {
+            Some(id) => Ok(Some(id)),
+            None => bail!("Unknown {} {:?}", kind, s),
+        }
+    }
+}
+
+/// Set the owner and group of everything in the file list that's in the
+/// tree, if they're known.
+///
+/// This usually needs root. If it fails, a warning is logged and no more
+/// owners are changed.
+pub(crate) fn set_owners(tree: &mut dyn Tree, file_list: &[FileEntry]) {
+    for entry in file_list {
+        if (entry.uid.is_none() && entry.gid.is_none()) || tree.stat(entry.name_bytes()).is_none() {
+            continue;
+        }
+        if let Err(err) = tree.set_owner(entry.name_bytes(), entry.uid, entry.gid) {
+            warn!("{:#}; not changing the owners of any more files", err);
+            return;
+        }
+    }
+}
+
+/// Read a list of ids and names, ending with a zero id, and look up each name.
+fn read_id_map(rv: &mut ReadVarint, kind: &str, lookup: fn(&[u8]) -> Option<u32>) -> Result<IdMap> {
+    let mut map = IdMap::new();
+    loop {
+        let id = rv.read_i32()? as u32;
+        if id == 0 {
+            return Ok(map);
+        }
+        let len = rv.read_u8()? as usize;
+        let name = rv.read_byte_string(len)?;
+        match lookup(&name) {
+            Some(local_id) => {
+                debug!(
+                    "Map remote {} {:?} from {} to {}",
+                    kind,
+                    String::from_utf8_lossy(&name),
+                    id,
+                    local_id
+                );
+                map.insert(id, local_id);
+            }
+            None => warn!(
+                "Remote {} {:?} isn't known locally; using id {}",
+                kind,
+                String::from_utf8_lossy(&name),
+                id
+            ),
+        }
+    }
+}
+
+/// Returns the local uid of a user name.
+#[cfg(unix)]
+pub(crate) fn local_uid(name: &[u8]) -> Option<u32> {
+    use std::os::unix::ffi::OsStrExt;
+    users::get_user_by_name(std::ffi::OsStr::from_bytes(name)).map(|user| user.uid())
+}
+
+/// Returns the local gid of a group name.
+#[cfg(unix)]
+pub(crate) fn local_gid(name: &[u8]) -> Option<u32> {
+    use std::os::unix::ffi::OsStrExt;
+    users::get_group_by_name(std::ffi::OsStr::from_bytes(name)).map(|group| group.gid())
+}
+
+/// Returns the primary group of a local user.
+#[cfg(unix)]
+fn primary_gid(uid: u32) -> Option<u32> {
+    users::get_user_by_uid(uid).map(|user| user.primary_group_id())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn local_uid(_name: &[u8]) -> Option<u32> {
+    None
+}
+
+#[cfg(not(unix))]
+pub(crate) fn local_gid(_name: &[u8]) -> Option<u32> {
+    None
+}
+
+#[cfg(not(unix))]
+fn primary_gid(_uid: u32) -> Option<u32> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_chown_ids_and_names() {
+        assert_eq!(parse_chown("1000:1000").unwrap(), (Some(1000), Some(1000)));
+        assert_eq!(parse_chown("1000").unwrap(), (Some(1000), None));
+        assert_eq!(parse_chown("root:").unwrap(), (Some(0), None));
+        // Not every system has a group called staff.
+        assert_eq!(
+            parse_chown(":staff").ok(),
+            local_gid(b"staff").map(|gid| (None, Some(gid)))
+        );
+        assert!(parse_chown("no-such-user-for-rsyn:0").is_err());
+    }
+
+    #[test]
+    fn parse_copy_as_user_and_group() {
+        assert_eq!(parse_copy_as("1000:1001").unwrap(), (1000, 1001));
+        // root's primary group is 0 on Linux and macOS alike.
+        assert_eq!(parse_copy_as("root").unwrap(), (0, 0));
+        assert_eq!(parse_copy_as("0:").unwrap(), (0, 0));
+        assert_eq!(
+            parse_copy_as(":0").unwrap_err().to_string(),
+            "No user in copy_as \":0\""
+        );
+        assert!(parse_copy_as("no-such-user-for-rsyn").is_err());
+        // No user has this id, so it has no primary group.
+        assert_eq!(
+            parse_copy_as("4000000000").unwrap_err().to_string(),
+            "Can't find the primary group of user 4000000000: give one as USER:GROUP"
+        );
+    }
+
+    #[test]
+    fn look_up_local_names() {
+        assert_eq!(local_uid(b"root"), Some(0));
+        assert_eq!(local_uid(b"no-such-user-for-rsyn"), None);
+        assert_eq!(local_gid(b"no-such-group-for-rsyn"), None);
+    }
+}
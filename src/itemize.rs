@@ -0,0 +1,229 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Descriptions of what changed for each entry, like rsync's
+//! `--itemize-changes`.
+
+use std::fmt;
+
+use crate::{FileEntry, Summary, Tree};
+
+/// What differs between an entry from the server and the local tree.
+///
+/// This is formatted like rsync's `%i` escape, for example `>f.st......`
+/// for a received file whose size and time changed, or `>f+++++++++` for a
+/// new file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ItemizedChange {
+    /// Type of the entry, as in rsync: `f` for a file, `d` for a directory,
+    /// `L` for a symlink, `D` for a device, or `S` for another special file.
+    pub file_type: char,
+
+    /// The entry didn't exist locally, or had a different type.
+    pub created: bool,
+
+    /// The file's content is requested from the server.
+    pub transferred: bool,
+
+    /// The size of a regular file differs.
+    pub size: bool,
+
+    /// The modification time differs.
+    pub mtime: bool,
+
+    /// The permission bits differ.
+    pub permissions: bool,
+}
+
+impl ItemizedChange {
+    /// Compare a remote entry to the local entry of the same name, if there
+    /// is one.
+    pub(crate) fn compare(
+        local: Option<&FileEntry>,
+        remote: &FileEntry,
+        transferred: bool,
+    ) -> ItemizedChange {
+        let file_type = file_type_char(remote.mode);
+        match local {
+            Some(local) if file_type_char(local.mode) == file_type => ItemizedChange {
+                file_type,
+                created: false,
+                transferred,
+                size: remote.is_file() && local.file_len != remote.file_len,
+                mtime: !remote.is_symlink() && local.unix_mtime() != remote.unix_mtime(),
+                permissions: !remote.is_symlink() && local.mode & 0o7777 != remote.mode & 0o7777,
+            },
+            _ => ItemizedChange {
+                file_type,
+                created: true,
+                transferred,
+                size: false,
+                mtime: false,
+                permissions: false,
+            },
+        }
+    }
+
+    /// True if nothing about the entry changes.
+    pub fn is_unchanged(&self) -> bool {
+        !(self.created || self.transferred || self.size || self.mtime || self.permissions)
+    }
+}
+
+impl fmt::Display for ItemizedChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let update = if self.transferred {
+            '>'
+        } else if self.created {
+            'c'
+        } else {
+            '.'
+        };
+        if self.created {
+            return write!(f, "{}{}+++++++++", update, self.file_type);
+        }
+        let flag = |changed: bool, c: char| if changed { c } else { '.' };
+        write!(
+            f,
+            "{}{}.{}{}{}.....",
+            update,
+            self.file_type,
+            flag(self.size, 's'),
+            flag(self.mtime, 't'),
+            flag(self.permissions, 'p'),
+        )
+    }
+}
+
+/// Returns the changes for every entry in the file list that differs from
+/// the local tree, given the sorted indexes of files that will be requested.
+pub(crate) fn itemize(
+    local_tree: &dyn Tree,
+    file_list: &[FileEntry],
+    wanted: &[usize],
+) -> Vec<(FileEntry, ItemizedChange)> {
+    debug_assert!(wanted.windows(2).all(|w| w[0] < w[1]));
+    let mut wanted = wanted.iter().peekable();
+    file_list
+        .iter()
+        .enumerate()
+        .map(|(idx, entry)| {
+            let local = local_tree.stat(entry.name_bytes());
+            let transferred = wanted.next_if_eq(&&idx).is_some();
+            let change = ItemizedChange::compare(local.as_ref(), entry, transferred);
+            (entry.clone(), change)
+        })
+        .filter(|(_entry, change)| !change.is_unchanged())
+        .collect()
+}
+
+/// Count the entries that are created, updated, or have only their metadata
+/// changed, into the summary.
+pub(crate) fn count_changes(changes: &[(FileEntry, ItemizedChange)], summary: &mut Summary) {
+    for (_entry, change) in changes {
+        if change.created {
+            summary.files_created += 1;
+        } else if change.transferred {
+            summary.files_updated += 1;
+        } else if !change.is_unchanged() {
+            summary.files_metadata_only += 1;
+        }
+    }
+}
+
+/// Format a list of changes as a JSON array, with the name and the rsync-style
+/// change string of each entry, like
+/// `[{"name":"a","change":">f.st......"}]`.
+///
+/// Names that aren't UTF-8 are converted lossily.
+pub fn changes_to_json(changes: &[(FileEntry, ItemizedChange)]) -> String {
+    let items: Vec<String> = changes
+        .iter()
+        .map(|(entry, change)| {
+            format!(
+                "{{\"name\":{},\"change\":{}}}",
+                json_string(&entry.name_lossy_string()),
+                json_string(&change.to_string())
+            )
+        })
+        .collect();
+    format!("[{}]", items.join(","))
+}
+
+/// Quote and escape a string for JSON.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn file_type_char(mode: u32) -> char {
+    match mode & 0o170000 {
+        0o100000 => 'f',
+        0o040000 => 'd',
+        0o120000 => 'L',
+        0o020000 | 0o060000 => 'D',
+        _ => 'S',
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_like_rsync() {
+        let remote = FileEntry::for_test(b"a", 0o100644, 10, 1000);
+        assert_eq!(
+            ItemizedChange::compare(None, &remote, true).to_string(),
+            ">f+++++++++"
+        );
+        let local = FileEntry::for_test(b"a", 0o100600, 12, 900);
+        assert_eq!(
+            ItemizedChange::compare(Some(&local), &remote, true).to_string(),
+            ">f.stp....."
+        );
+        let dir = FileEntry::for_test(b"d", 0o040755, 0, 1000);
+        assert_eq!(
+            ItemizedChange::compare(None, &dir, false).to_string(),
+            "cd+++++++++"
+        );
+        let older_dir = FileEntry::for_test(b"d", 0o040755, 0, 900);
+        assert_eq!(
+            ItemizedChange::compare(Some(&older_dir), &dir, false).to_string(),
+            ".d..t......"
+        );
+        assert!(ItemizedChange::compare(Some(&dir), &dir, false).is_unchanged());
+    }
+
+    #[test]
+    fn json_escapes_names() {
+        let remote = FileEntry::for_test(b"say \"hi\"\n", 0o100644, 10, 1000);
+        let change = ItemizedChange::compare(None, &remote, true);
+        assert_eq!(
+            changes_to_json(&[(remote, change)]),
+            r#"[{"name":"say \"hi\"\u000a","change":">f+++++++++"}]"#
+        );
+        assert_eq!(changes_to_json(&[]), "[]");
+    }
+}
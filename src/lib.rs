@@ -46,21 +46,42 @@
 //! # rsyn::Result::Ok(())
 //! ```
 
+mod auth;
+mod cancel;
 mod client;
 mod connection;
+mod counting;
+mod daemon;
+mod deflate;
+mod delete;
+mod error;
+mod filter;
 mod flist;
+mod ids;
+mod itemize;
 mod localtree;
 mod mux;
 mod options;
+mod outformat;
+mod quickcheck;
+mod redact;
 mod statistics;
 mod sums;
+mod timeout;
+mod tree;
 mod varint;
 
-pub use client::Client;
+pub use cancel::CancelToken;
+pub use client::{Client, Transport};
+pub use connection::{Connection, ProtocolInfo};
+pub use error::Error;
+pub use filter::{FilterRule, FilterSet, FilterSource};
 pub use flist::{FileEntry, FileList};
+pub use itemize::{changes_to_json, ItemizedChange};
 pub use localtree::LocalTree;
-pub use options::Options;
+pub use options::{DeleteMode, Options, SymlinkFallback, TransferMode};
 pub use statistics::{ServerStatistics, Summary};
+pub use tree::{FileWriter, MemoryTree, PendingFile, Tree};
 
 /// General Result type from rsyn APIs.
 pub type Result<T> = anyhow::Result<T>;
@@ -14,22 +14,40 @@
 
 //! Facade for local-filesystem operations.
 
+use std::fs;
+use std::io::{Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
-use anyhow::Context;
-use tempfile::NamedTempFile;
+use anyhow::bail;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use tempfile::{NamedTempFile, TempPath};
 
-use crate::Result;
+use crate::error::LocalIoContext;
+use crate::flist::{sort_and_dedupe, validate_name};
+use crate::{FileEntry, FileList, FileWriter, Options, PendingFile, Result, Tree};
 
 /// A filesystem tree local to this process.
 ///
 /// The local tree is the destination for downloads and the source for uploads.
 ///
 /// All local IO is funneled through this layer so that it can be observed
-/// and so filenames can be checked. (And perhaps later, applications can provide
-/// new implementations that don't literally use the local filesystem.)
+/// and so filenames can be checked. Applications can provide other
+/// destinations that don't literally use the local filesystem by implementing
+/// [`Tree`](trait.Tree.html).
 pub struct LocalTree {
     root: PathBuf,
+
+    /// The root is a file to which the only entry is written, rather than a
+    /// directory.
+    single_file: bool,
+
+    /// Whether the root is a single file is decided from the file list of
+    /// each transfer.
+    file_if_single: bool,
+
+    /// The root is canonical, and writes that resolve outside it are refused.
+    confined: bool,
 }
 
 /// A file being written into the local tree.
@@ -39,12 +57,126 @@ pub struct LocalTree {
 pub struct WriteFile {
     final_path: PathBuf,
     temp: NamedTempFile,
+    /// True if the last thing added was a hole, so the length must be set.
+    ends_in_hole: bool,
 }
 
 impl LocalTree {
     /// Construct a new LocalTree addressing a local directory.
     pub fn new<P: Into<PathBuf>>(root: P) -> LocalTree {
-        LocalTree { root: root.into() }
+        LocalTree {
+            root: root.into(),
+            single_file: false,
+            file_if_single: false,
+            confined: false,
+        }
+    }
+
+    /// Construct a LocalTree for an existing directory, which is resolved
+    /// now to its canonical path, and which refuses writes that would resolve
+    /// to somewhere outside it.
+    ///
+    /// If `root` is a symlink, files are written into its target, even if
+    /// the symlink is changed later. Before each change to the tree, the
+    /// directory containing the entry is resolved again, so a subdirectory
+    /// that's swapped for a symlink can't redirect writes out of the tree.
+    pub fn confined<P: AsRef<Path>>(root: P) -> Result<LocalTree> {
+        let root = root.as_ref();
+        let canonical = root
+            .canonicalize()
+            .local_context(|| format!("Failed to resolve destination {:?}", root))?;
+        if !canonical.is_dir() {
+            bail!("Destination {:?} is not a directory", root);
+        }
+        debug!("Destination {:?} resolves to {:?}", root, canonical);
+        Ok(LocalTree {
+            root: canonical,
+            single_file: false,
+            file_if_single: false,
+            confined: true,
+        })
+    }
+
+    /// Construct a LocalTree for a transfer of a single file, which is written
+    /// to `path` whatever its name on the source.
+    ///
+    /// This is like rsync copying one file to a destination that names a file
+    /// rather than a directory.
+    ///
+    /// It's an error to transfer anything other than a single regular file
+    /// into this tree.
+    pub fn single_file<P: Into<PathBuf>>(path: P) -> LocalTree {
+        LocalTree {
+            root: path.into(),
+            single_file: true,
+            file_if_single: false,
+            confined: false,
+        }
+    }
+
+    /// Construct a LocalTree that's a single file, as for
+    /// [`single_file`](#method.single_file), if the transfer turns out to be
+    /// a single regular file, and is otherwise a directory.
+    ///
+    /// This is how rsync treats a destination that has no trailing slash and
+    /// isn't an existing directory. It's decided from the file list that's
+    /// received, so there's no need to list the source beforehand.
+    pub fn file_or_directory<P: Into<PathBuf>>(path: P) -> LocalTree {
+        LocalTree {
+            root: path.into(),
+            single_file: false,
+            file_if_single: true,
+            confined: false,
+        }
+    }
+
+    /// Returns the local path for a name relative to the root.
+    fn local_path(&self, name: &Path) -> PathBuf {
+        if self.single_file || name == Path::new(".") {
+            self.root.clone()
+        } else {
+            self.root.join(name)
+        }
+    }
+
+    /// Returns the local path for a name that's about to be changed, checking
+    /// that in a confined tree it doesn't resolve to outside the root.
+    ///
+    /// The directory containing the entry is resolved. With `follow`, the
+    /// entry itself is also resolved, if it exists, for operations that would
+    /// follow a symlink there.
+    fn checked_path(&self, name: &Path, follow: bool) -> Result<PathBuf> {
+        let path = self.local_path(name);
+        if !self.confined || path == self.root {
+            return Ok(path);
+        }
+        let resolved = if follow && fs::symlink_metadata(&path).is_ok() {
+            path.canonicalize()
+        } else {
+            let parent = path.parent().expect("Path in the tree has a parent");
+            let file_name = path.file_name().expect("Path in the tree has a name");
+            parent.canonicalize().map(|parent| parent.join(file_name))
+        }
+        .local_context(|| format!("Failed to resolve {:?}", path))?;
+        if !resolved.starts_with(&self.root) {
+            bail!(
+                "Refusing to change {:?}, which resolves to {:?}, outside the destination {:?}",
+                path,
+                resolved,
+                self.root
+            );
+        }
+        Ok(resolved)
+    }
+
+    /// Returns the local path where `entry` would be written, without
+    /// creating anything.
+    ///
+    /// The name is checked in the same way as names received from a server,
+    /// so an error is returned for names that could escape the tree.
+    pub fn destination_path(&self, entry: &FileEntry) -> Result<PathBuf> {
+        validate_name(entry.name_bytes())?;
+        Ok(self.local_path(&bytes_path(entry.name_bytes())))
     }
 
     /// Open a file for write.
@@ -54,20 +186,256 @@ impl LocalTree {
     ///
     /// `path` is the relative path.
     pub fn write_file<P: AsRef<Path>>(&self, path: &P) -> Result<WriteFile> {
-        let final_path = self.root.join(path.as_ref());
+        let final_path = self.checked_path(path.as_ref(), false)?;
         // Store the temporary file in its subdirectory, not in the root.
-        let temp = NamedTempFile::new_in(final_path.parent().unwrap())?;
-        Ok(WriteFile { final_path, temp })
+        let dir = final_path.parent().unwrap();
+        let temp = NamedTempFile::new_in(dir)
+            .local_context(|| format!("Failed to create a temporary file in {:?}", dir))?;
+        Ok(WriteFile {
+            final_path,
+            temp,
+            ends_in_hole: false,
+        })
+    }
+
+    /// Scan the whole local tree, returning a sorted list of its contents.
+    ///
+    /// Names are relative to the root, which is itself included as `"."`.
+    /// Symlinks are not followed.
+    ///
+    /// With `options.one_file_system`, directories on a different filesystem
+    /// from the root are listed but not descended into.
+    ///
+    /// Directories that can't be read are logged and skipped.
+    ///
+    /// A single file destination can't be scanned, since its name doesn't
+    /// correspond to any name on the source.
+    pub fn scan(&self, options: &Options) -> Result<FileList> {
+        self.scan_with_devices(options, |_rel_path, metadata| device(metadata))
+    }
+
+    /// Scan the tree, getting the device of each directory, given its path
+    /// relative to the root, from `device`.
+    fn scan_with_devices<D>(&self, options: &Options, device: D) -> Result<FileList>
+    where
+        D: Fn(&Path, &fs::Metadata) -> Option<u64>,
+    {
+        if self.single_file {
+            bail!("Can't scan single file destination {:?}", self.root);
+        }
+        let root_metadata = fs::symlink_metadata(&self.root)
+            .local_context(|| format!("Failed to stat local tree root {:?}", self.root))?;
+        let root_device = device(Path::new(""), &root_metadata);
+        let mut file_list = vec![FileEntry::from_metadata(b".".to_vec(), &root_metadata)];
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(rel_dir) = dirs.pop() {
+            let read_dir = match fs::read_dir(self.root.join(&rel_dir)) {
+                Ok(read_dir) => read_dir,
+                Err(err) => {
+                    warn!("Failed to read local directory {:?}: {}", rel_dir, err);
+                    continue;
+                }
+            };
+            for dirent in read_dir {
+                // Symlinks are listed as themselves and never followed, so
+                // that a link to a directory elsewhere isn't scanned, or its
+                // contents deleted, as if it were part of this tree.
+                let (rel_path, metadata) = match dirent.and_then(|dirent| {
+                    let rel_path = rel_dir.join(dirent.file_name());
+                    let metadata = fs::symlink_metadata(self.root.join(&rel_path))?;
+                    Ok((rel_path, metadata))
+                }) {
+                    Ok(x) => x,
+                    Err(err) => {
+                        warn!(
+                            "Failed to read entry in local directory {:?}: {}",
+                            rel_dir, err
+                        );
+                        continue;
+                    }
+                };
+                if metadata.is_dir() {
+                    if options.one_file_system && device(&rel_path, &metadata) != root_device {
+                        debug!("Not descending into {:?} on another filesystem", rel_path);
+                    } else {
+                        dirs.push(rel_path.clone());
+                    }
+                }
+                file_list.push(FileEntry::from_metadata(path_bytes(&rel_path), &metadata));
+            }
+        }
+        sort_and_dedupe(&mut file_list);
+        Ok(file_list)
+    }
+}
+
+impl Tree for LocalTree {
+    fn stat(&self, name: &[u8]) -> Option<FileEntry> {
+        fs::symlink_metadata(self.local_path(&bytes_path(name)))
+            .ok()
+            .map(|metadata| FileEntry::from_metadata(name.to_vec(), &metadata))
+    }
+
+    fn scan(&self, options: &Options) -> Result<FileList> {
+        LocalTree::scan(self, options)
+    }
+
+    fn create_file(&mut self, entry: &FileEntry) -> Result<Box<dyn FileWriter>> {
+        Ok(Box::new(self.write_file(&bytes_path(entry.name_bytes()))?))
+    }
+
+    fn create_dir(&mut self, name: &[u8]) -> Result<()> {
+        let path = self.checked_path(&bytes_path(name), false)?;
+        fs::create_dir(&path).local_context(|| format!("Failed to create directory {:?}", path))
+    }
+
+    #[cfg(unix)]
+    fn symlink(&mut self, name: &[u8], target: &[u8]) -> Result<()> {
+        let path = self.checked_path(&bytes_path(name), false)?;
+        std::os::unix::fs::symlink(bytes_path(target), &path)
+            .local_context(|| format!("Failed to create symlink {:?}", path))
+    }
+
+    #[cfg(not(unix))]
+    fn symlink(&mut self, name: &[u8], _target: &[u8]) -> Result<()> {
+        bail!(
+            "Can't create symlink {:?} on this platform",
+            self.local_path(&bytes_path(name))
+        )
+    }
+
+    fn rename(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+        let from = self.checked_path(&bytes_path(from), false)?;
+        let to = self.checked_path(&bytes_path(to), false)?;
+        fs::rename(&from, &to).local_context(|| format!("Failed to rename {:?} to {:?}", from, to))
     }
+
+    fn copy_file(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+        let from = self.checked_path(&bytes_path(from), true)?;
+        let to = self.checked_path(&bytes_path(to), true)?;
+        fs::copy(&from, &to)
+            .map(|_| ())
+            .local_context(|| format!("Failed to copy {:?} to {:?}", from, to))
+    }
+
+    fn remove(&mut self, name: &[u8], is_dir: bool) -> Result<()> {
+        let path = self.checked_path(&bytes_path(name), false)?;
+        if is_dir {
+            fs::remove_dir(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+        .local_context(|| format!("Failed to remove {:?}", path))
+    }
+
+    fn remove_all(&mut self, name: &[u8], is_dir: bool) -> Result<()> {
+        let path = self.checked_path(&bytes_path(name), false)?;
+        if is_dir {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+        .local_context(|| format!("Failed to remove {:?}", path))
+    }
+
+    #[cfg(unix)]
+    fn set_owner(&mut self, name: &[u8], uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        let path = self.checked_path(&bytes_path(name), false)?;
+        std::os::unix::fs::lchown(&path, uid, gid)
+            .local_context(|| format!("Failed to set the owner of {:?}", path))
+    }
+
+    #[cfg(unix)]
+    fn set_mode(&mut self, name: &[u8], mode: u32) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let path = self.checked_path(&bytes_path(name), true)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))
+            .local_context(|| format!("Failed to set the permissions of {:?}", path))
+    }
+
+    fn is_single_file(&self) -> bool {
+        self.single_file
+    }
+
+    fn prepare(&mut self, file_list: &[FileEntry]) -> Result<()> {
+        let one_file = matches!(file_list, [entry] if entry.is_file());
+        if self.file_if_single {
+            self.single_file = one_file;
+            debug!(
+                "Destination {:?} is a single file: {}",
+                self.root, self.single_file
+            );
+        } else if self.single_file && !one_file && !file_list.is_empty() {
+            bail!(
+                "Can't write {} entries to the single file destination {:?}",
+                file_list.len(),
+                self.root
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Returns the id of the device holding a file, if the platform has them.
+#[cfg(unix)]
+fn device(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+pub(crate) fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().replace('\\', "/").into_bytes()
+}
+
+/// Convert a name from a file list to a relative path.
+#[cfg(unix)]
+fn bytes_path(name: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(name).into()
+}
+
+#[cfg(not(unix))]
+fn bytes_path(name: &[u8]) -> PathBuf {
+    String::from_utf8_lossy(name).into_owned().into()
 }
 
 impl WriteFile {
     /// Finish writing to this file and store it to its permanent location.
     pub fn finalize(self) -> Result<()> {
-        let WriteFile { temp, final_path } = self;
-        temp.persist(&final_path)
-            .with_context(|| format!("Failed to persist temporary file to {:?}", final_path))?;
-        Ok(())
+        self.close_temp()?.persist()
+    }
+
+    /// Finish writing, and close the temporary file, which is kept until
+    /// it's persisted or dropped.
+    fn close_temp(self) -> Result<ClosedFile> {
+        let WriteFile {
+            temp,
+            final_path,
+            ends_in_hole,
+        } = self;
+        if ends_in_hole {
+            // Seeking past the end doesn't extend the file by itself.
+            let mut file = temp.as_file();
+            file.stream_position()
+                .and_then(|len| file.set_len(len))
+                .local_context(|| format!("Failed to set length of {:?}", final_path))?;
+        }
+        Ok(ClosedFile {
+            temp_path: temp.into_temp_path(),
+            final_path,
+        })
     }
 
     /// The full path to which this file will eventually be written.
@@ -76,9 +444,54 @@ impl WriteFile {
     }
 }
 
+impl FileWriter for WriteFile {
+    fn commit(self: Box<Self>) -> Result<()> {
+        self.finalize()
+    }
+
+    fn close(self: Box<Self>) -> Result<Box<dyn PendingFile>> {
+        Ok(Box::new(self.close_temp()?))
+    }
+
+    fn skip(&mut self, len: usize) -> std::io::Result<()> {
+        self.temp.seek(SeekFrom::Current(len as i64))?;
+        self.ends_in_hole |= len > 0;
+        Ok(())
+    }
+}
+
+/// A temporary file that's written and closed, waiting to be moved to its
+/// final path.
+struct ClosedFile {
+    temp_path: TempPath,
+    final_path: PathBuf,
+}
+
+impl ClosedFile {
+    fn persist(self) -> Result<()> {
+        let ClosedFile {
+            temp_path,
+            final_path,
+        } = self;
+        temp_path
+            .persist(&final_path)
+            .map_err(|err| err.error)
+            .local_context(|| format!("Failed to persist temporary file to {:?}", final_path))?;
+        Ok(())
+    }
+}
+
+impl PendingFile for ClosedFile {
+    fn commit(self: Box<Self>) -> Result<()> {
+        self.persist()
+    }
+}
+
 impl std::io::Write for WriteFile {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.temp.write(buf)
+        let len = self.temp.write(buf)?;
+        self.ends_in_hole &= len == 0;
+        Ok(len)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -86,11 +499,15 @@ impl std::io::Write for WriteFile {
     }
 
     fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
-        self.temp.write_vectored(bufs)
+        let len = self.temp.write_vectored(bufs)?;
+        self.ends_in_hole &= len == 0;
+        Ok(len)
     }
 
     fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        self.temp.write_all(buf)
+        self.temp.write_all(buf)?;
+        self.ends_in_hole &= buf.is_empty();
+        Ok(())
     }
 }
 
@@ -101,6 +518,105 @@ mod test {
     use std::fs::File;
     use std::io::prelude::*;
 
+    #[cfg(unix)]
+    #[test]
+    fn destination_paths() {
+        let tree = LocalTree::new("/dest");
+        let entry = FileEntry::for_test(b"sub/dir/file", 0o100644, 0, 0);
+        assert_eq!(
+            tree.destination_path(&entry).unwrap(),
+            Path::new("/dest/sub/dir/file")
+        );
+        let root = FileEntry::for_test(b".", 0o040755, 0, 0);
+        assert_eq!(tree.destination_path(&root).unwrap(), Path::new("/dest"));
+        let unsafe_entry = FileEntry::for_test(b"sub/../../etc/passwd", 0o100644, 0, 0);
+        assert!(tree.destination_path(&unsafe_entry).is_err());
+        assert!(LocalTree::single_file("/dest/renamed")
+            .destination_path(&entry)
+            .unwrap()
+            .ends_with("renamed"));
+    }
+
+    #[test]
+    fn write_single_file_to_destination_name() {
+        let tempdir = tempfile::Builder::new()
+            .prefix("rsyn_localtree_single_file")
+            .tempdir()
+            .unwrap();
+        let dest = tempdir.path().join("renamed");
+        let lt = LocalTree::single_file(&dest);
+        assert!(lt.is_single_file());
+        assert!(lt.stat(b"hello").is_none());
+        let mut f = lt.write_file(&"hello").unwrap();
+        assert_eq!(f.final_path(), dest);
+        writeln!(f, "renamed").unwrap();
+        f.finalize().unwrap();
+
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "renamed\n");
+        assert!(!tempdir.path().join("hello").exists());
+        assert_eq!(lt.stat(b"hello").unwrap().file_len, 8);
+        assert!(lt.scan(&Options::default()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn confined_tree_stays_in_its_canonical_root() {
+        use std::os::unix::fs::symlink;
+
+        let tempdir = tempfile::Builder::new()
+            .prefix("rsyn_localtree_confined")
+            .tempdir()
+            .unwrap();
+        let real = tempdir.path().join("real");
+        let other = tempdir.path().join("other");
+        let link = tempdir.path().join("link");
+        fs::create_dir(&real).unwrap();
+        fs::create_dir(&other).unwrap();
+        symlink(&real, &link).unwrap();
+
+        let mut lt = LocalTree::confined(&link).unwrap();
+        let write = |lt: &mut LocalTree, name: &[u8]| -> Result<()> {
+            let mut f = lt.create_file(&FileEntry::for_test(name, 0o100644, 7, 0))?;
+            f.write_all(b"content")?;
+            f.commit()
+        };
+        write(&mut lt, b"a").unwrap();
+        assert!(real.join("a").is_file());
+
+        // Pointing the symlink somewhere else after construction doesn't move
+        // the tree.
+        fs::remove_file(&link).unwrap();
+        symlink(&other, &link).unwrap();
+        write(&mut lt, b"b").unwrap();
+        assert!(real.join("b").is_file());
+        assert!(!other.join("b").exists());
+
+        // A subdirectory swapped for a symlink out of the tree can't be
+        // written through.
+        lt.create_dir(b"sub").unwrap();
+        fs::remove_dir(real.join("sub")).unwrap();
+        symlink(&other, real.join("sub")).unwrap();
+        let err = write(&mut lt, b"sub/c").unwrap_err();
+        assert!(
+            err.to_string().starts_with("Refusing to change"),
+            "{:#}",
+            err
+        );
+        assert!(lt.create_dir(b"sub/d").is_err());
+        assert!(lt.remove(b"sub/a", false).is_err());
+        assert!(lt.set_mode(b"sub", 0o777).is_err());
+        assert!(!other.join("c").exists());
+        assert!(!other.join("d").exists());
+
+        // An unconfined tree does follow the symlinks.
+        let mut lt = LocalTree::new(&link);
+        write(&mut lt, b"e").unwrap();
+        assert!(other.join("e").is_file());
+
+        assert!(LocalTree::confined(tempdir.path().join("missing")).is_err());
+        assert!(LocalTree::confined(real.join("a")).is_err());
+    }
+
     #[test]
     fn write_a_file() {
         let tempdir = tempfile::Builder::new()
@@ -125,6 +641,31 @@ mod test {
         assert_eq!(content, "The answer is: 42\n");
     }
 
+    /// A closed file holds no descriptor while it waits to be committed, so
+    /// that many can wait at once.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn closed_file_holds_no_descriptor() {
+        let tempdir = tempfile::Builder::new()
+            .prefix("rsyn_localtree_closed_file")
+            .tempdir()
+            .unwrap();
+        let lt = LocalTree::new(tempdir.path());
+        let mut f = lt.write_file(&"hello").unwrap();
+        f.write_all(b"hello").unwrap();
+        let closed = f.close_temp().unwrap();
+        let temp_path = closed.temp_path.to_path_buf();
+        assert_eq!(fs::read(&temp_path).unwrap(), b"hello");
+        for fd in fs::read_dir("/proc/self/fd").unwrap() {
+            if let Ok(target) = fs::read_link(fd.unwrap().path()) {
+                assert_ne!(target, temp_path);
+            }
+        }
+        Box::new(closed).commit().unwrap();
+        assert!(fs::metadata(&temp_path).is_err());
+        assert_eq!(fs::read(tempdir.path().join("hello")).unwrap(), b"hello");
+    }
+
     #[test]
     fn dropped_file_is_discarded() {
         let tempdir = tempfile::Builder::new()
@@ -144,4 +685,55 @@ mod test {
         assert!(fs::metadata(tempdir.path()).unwrap().is_dir());
         assert!(fs::metadata(&final_path).is_err());
     }
+
+    #[test]
+    fn scan_nested_tree() {
+        let tempdir = tempfile::Builder::new()
+            .prefix("rsyn_localtree_scan_nested_tree")
+            .tempdir()
+            .unwrap();
+        fs::create_dir(tempdir.path().join("sub")).unwrap();
+        fs::write(tempdir.path().join("sub").join("b"), b"bee").unwrap();
+        fs::write(tempdir.path().join("a"), b"").unwrap();
+        let file_list = LocalTree::new(tempdir.path())
+            .scan(&Options::default())
+            .unwrap();
+        let names: Vec<_> = file_list.iter().map(|e| e.name_lossy_string()).collect();
+        assert_eq!(names, [".", "a", "sub", "sub/b"]);
+        assert!(file_list[0].is_dir());
+        assert!(file_list[1].is_file());
+        assert!(file_list[2].is_dir());
+        assert_eq!(file_list[3].file_len, 3);
+    }
+
+    /// A directory on another device is listed but not descended into,
+    /// while one on the root's device is.
+    #[test]
+    fn scan_one_file_system() {
+        let tempdir = tempfile::Builder::new()
+            .prefix("rsyn_localtree_scan_one_file_system")
+            .tempdir()
+            .unwrap();
+        for dir in &["mnt", "same"] {
+            fs::create_dir(tempdir.path().join(dir)).unwrap();
+            fs::write(tempdir.path().join(dir).join("f"), b"f").unwrap();
+        }
+        let device = |rel_path: &Path, _: &fs::Metadata| {
+            Some(if rel_path == Path::new("mnt") { 2 } else { 1 })
+        };
+        let tree = LocalTree::new(tempdir.path());
+        let scan = |one_file_system| {
+            let options = Options {
+                one_file_system,
+                ..Options::default()
+            };
+            tree.scan_with_devices(&options, device)
+                .unwrap()
+                .iter()
+                .map(|e| e.name_lossy_string().into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(scan(true), [".", "mnt", "same", "same/f"]);
+        assert_eq!(scan(false), [".", "mnt", "mnt/f", "same", "same/f"]);
+    }
 }
@@ -21,19 +21,38 @@
 
 use std::io;
 use std::io::prelude::*;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
+use crate::varint::check_alloc;
+
 // TODO: Handle other message types from rsync `read_a_msg`.
 const TAG_DATA: u8 = 7;
 const TAG_FATAL: u8 = 1;
+/// `MSG_IO_ERROR`, carrying a 4-byte bitmask of the server's IO errors.
+const TAG_IO_ERROR: u8 = TAG_DATA + 22;
+/// `MSG_NOOP`, with no content.
+const TAG_NOOP: u8 = TAG_DATA + 42;
+/// `MSG_SUCCESS`, carrying the 4-byte index of a file that was received.
+const TAG_SUCCESS: u8 = TAG_DATA + 100;
+/// `MSG_NO_SEND`, carrying the 4-byte index of a file the sender won't
+/// send, typically because it couldn't open it.
+pub(crate) const TAG_NO_SEND: u8 = TAG_DATA + 102;
 
 pub struct DemuxRead {
     /// Underlying stream.
     r: Box<dyn Read + Send>,
     /// Amount of data from previous packet remaining to read out.
     current_packet_len: usize,
+    /// IO errors reported by the server in `MSG_IO_ERROR`, ORed together.
+    io_error: Arc<AtomicI32>,
+    /// The longest message that will be read, if limited.
+    max_alloc: Option<usize>,
+    /// Indexes of files the server says it won't send, in `MSG_NO_SEND`.
+    no_send: Arc<Mutex<Vec<i32>>>,
 }
 
 impl Read for DemuxRead {
@@ -55,9 +74,29 @@ impl DemuxRead {
         DemuxRead {
             r,
             current_packet_len: 0,
+            io_error: Arc::default(),
+            max_alloc: None,
+            no_send: Arc::default(),
         }
     }
 
+    /// Limit the size of messages that will be read into memory.
+    pub fn set_max_alloc(&mut self, max_alloc: Option<usize>) {
+        self.max_alloc = max_alloc;
+    }
+
+    /// Returns a handle on the IO errors the server has reported in
+    /// messages so far, which is updated as more messages are read.
+    pub fn io_error(&self) -> Arc<AtomicI32> {
+        self.io_error.clone()
+    }
+
+    /// Returns a handle on the indexes of files the server has said it
+    /// won't send, which is updated as more messages are read.
+    pub fn no_send(&self) -> Arc<Mutex<Vec<i32>>> {
+        self.no_send.clone()
+    }
+
     /// Return the length of the next real data block.
     ///
     /// Read and print out any messages from the remote end, without returning
@@ -93,10 +132,45 @@ impl DemuxRead {
                 return Ok(len);
             }
 
-            // A human-readable message: read and display it here.
+            check_alloc(len, self.max_alloc)?;
             let mut message = vec![0; len];
             self.r.read_exact(&mut message)?;
-            info!("REMOTE: {}", String::from_utf8_lossy(&message).trim_end());
+            // Some messages carry an integer rather than text.
+            match tag {
+                TAG_IO_ERROR => {
+                    let io_error = int_message("IO error", &message)?;
+                    warn!("Server reports IO error {:#x}", io_error);
+                    self.io_error.fetch_or(io_error, Ordering::Relaxed);
+                    continue;
+                }
+                TAG_NO_SEND => {
+                    let idx = int_message("No-send", &message)?;
+                    if idx < 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Server won't send negative file index {}", idx),
+                        ));
+                    }
+                    warn!("Server won't send file {}", idx);
+                    self.no_send.lock().unwrap().push(idx);
+                    continue;
+                }
+                TAG_SUCCESS => {
+                    let idx = int_message("Success", &message)?;
+                    debug!("Server reports success for file {}", idx);
+                    continue;
+                }
+                TAG_NOOP => continue,
+                _ => (),
+            }
+
+            // A human-readable message: display it here. Some servers pad
+            // messages with NULs.
+            info!(
+                "REMOTE: {}",
+                String::from_utf8_lossy(&message)
+                    .trim_end_matches(|c: char| c == '\0' || c.is_whitespace())
+            );
             if tag == TAG_FATAL {
                 return Err(io::Error::new(
                     io::ErrorKind::ConnectionAborted,
@@ -107,6 +181,17 @@ impl DemuxRead {
     }
 }
 
+/// Decode a message that holds a single little-endian i32.
+fn int_message(what: &str, message: &[u8]) -> io::Result<i32> {
+    match *message {
+        [a, b, c, d] => Ok(i32::from_le_bytes([a, b, c, d])),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} message has length {}", what, message.len()),
+        )),
+    }
+}
+
 // MAYBE: Add buffering and flushing, so that every single write is
 // not sent as a single packet.
 
@@ -151,3 +236,73 @@ impl Write for MuxWrite {
         self.w.flush()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn packet(tag: u8, payload: &[u8]) -> Vec<u8> {
+        let h = payload.len() as u32 | ((tag as u32) << 24);
+        [&h.to_le_bytes()[..], payload].concat()
+    }
+
+    #[test]
+    fn io_error_messages_are_collected() {
+        let input = [
+            packet(TAG_DATA, b"abc"),
+            packet(TAG_IO_ERROR, &1i32.to_le_bytes()),
+            packet(TAG_IO_ERROR, &4i32.to_le_bytes()),
+            packet(TAG_DATA, b"def"),
+        ]
+        .concat();
+        let mut demux = DemuxRead::new(Box::new(io::Cursor::new(input)));
+        let io_error = demux.io_error();
+        let mut data = Vec::new();
+        demux.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"abcdef");
+        assert_eq!(io_error.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn integer_messages_are_decoded_not_logged() {
+        let input = [
+            packet(TAG_DATA, b"abc"),
+            packet(TAG_NO_SEND, &3i32.to_le_bytes()),
+            packet(TAG_SUCCESS, &2i32.to_le_bytes()),
+            packet(TAG_NOOP, b""),
+            packet(TAG_DATA + 2, b"hello\n\0\0"),
+            packet(TAG_NO_SEND, &7i32.to_le_bytes()),
+            packet(TAG_DATA, b"def"),
+        ]
+        .concat();
+        let mut demux = DemuxRead::new(Box::new(io::Cursor::new(input)));
+        let no_send = demux.no_send();
+        let mut data = Vec::new();
+        demux.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"abcdef");
+        assert_eq!(*no_send.lock().unwrap(), [3, 7]);
+    }
+
+    #[test]
+    fn integer_messages_of_the_wrong_length_are_errors() {
+        let input = packet(TAG_NO_SEND, b"\x01\x00");
+        let err = DemuxRead::new(Box::new(io::Cursor::new(input)))
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "No-send message has length 2");
+    }
+
+    #[test]
+    fn messages_longer_than_max_alloc_are_refused() {
+        let input = [packet(TAG_FATAL, &[b'x'; 100]), packet(TAG_DATA, b"abc")].concat();
+        let mut demux = DemuxRead::new(Box::new(io::Cursor::new(input)));
+        demux.set_max_alloc(Some(99));
+        let err = demux.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Refusing to allocate 100 bytes for data from the server, more than max_alloc of 99"
+        );
+    }
+}
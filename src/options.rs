@@ -14,9 +14,20 @@
 
 //! Command-line options controlling the local and remote processes.
 
+use std::path::{Component, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
+use crate::cancel::CancelToken;
+use crate::filter::FilterSource;
+use crate::ids::{parse_chown, parse_copy_as};
+use crate::localtree::path_bytes;
+use crate::sums::max_block_len;
+use crate::{Error, Result};
+
 /// Command-line options controlling the local and remote processes.
 ///
 /// These are held inside a [`Client`](struct.Client.html),
@@ -51,13 +62,941 @@ pub struct Options {
     /// If unset, just "ssh".
     pub ssh_command: Option<Vec<String>>,
 
-    /// Only list files, don't transfer contents.
+    /// Environment variables to set for the ssh or rsync subprocess, such as
+    /// `SSH_AUTH_SOCK`, in addition to those it inherits.
+    pub subprocess_env: Vec<(String, String)>,
+
+    /// Start the ssh or rsync subprocess with no inherited environment, so
+    /// that it has only `subprocess_env`.
     ///
-    /// In some cases the server will infer this.
-    pub list_only: bool,
+    /// The command is then not looked up on the `PATH`, unless one is given
+    /// in `subprocess_env`, so it may need to be an absolute path.
+    pub subprocess_env_clear: bool,
 
     /// Be verbose.
     ///
     /// (This is passed to the server to encourage it to be verbose too.)
     pub verbose: u32,
+
+    /// Don't cross filesystem boundaries when recursing.
+    ///
+    /// This is passed to the server, and also limits scans of the local tree.
+    /// Mount point directories are included, but not their contents.
+    pub one_file_system: bool,
+
+    /// Keep the path given to the server in the names it sends, as with
+    /// rsync's `-R`, so that `a/b/c` is received as `a/b/c` rather than `c`.
+    ///
+    /// Parent directories of received entries that aren't in the file list
+    /// are created in the destination if they're missing.
+    pub relative: bool,
+
+    /// With `relative`, ask the server not to send the parent directories
+    /// implied by the path, as with rsync's `--no-implied-dirs`.
+    ///
+    /// Parents that already exist locally keep their permissions and
+    /// times, rather than being set from the source, and missing ones are
+    /// created with default permissions. This has no effect without
+    /// `relative`.
+    pub no_implied_dirs: bool,
+
+    /// Receive the owner of each file, as with rsync's `-o`.
+    ///
+    /// The owner's name is used to find the local user id, unless
+    /// `numeric_ids` is set. The ids are available from `FileEntry::uid`.
+    pub preserve_uid: bool,
+
+    /// Receive the group of each file, as with rsync's `-g`.
+    ///
+    /// The group's name is used to find the local group id, unless
+    /// `numeric_ids` is set. The ids are available from `FileEntry::gid`.
+    pub preserve_gid: bool,
+
+    /// Use the server's numeric user and group ids, rather than mapping them
+    /// by name, as with rsync's `--numeric-ids`.
+    pub numeric_ids: bool,
+
+    /// Receive symlinks as symlinks, with their targets, as with rsync's
+    /// `-l`. The target is available from `FileEntry::link_target`.
+    pub preserve_links: bool,
+
+    /// What to do when a symlink can't be created in the destination, for
+    /// example on Windows without the privilege to create them.
+    ///
+    /// By default, the transfer fails.
+    pub symlink_fallback: SymlinkFallback,
+
+    /// Give received entries the permissions they have on the server, as with
+    /// rsync's `-p`.
+    ///
+    /// The `.` entry describes the top of the transfer, so its permissions are
+    /// set on the destination directory itself. Symlinks are left alone.
+    pub preserve_perms: bool,
+
+    /// Receive symlinks that point outside the transferred tree as the files
+    /// they point to, as with rsync's `--copy-unsafe-links`, while keeping
+    /// other symlinks as links.
+    ///
+    /// The server follows these links before sending the file list. Any
+    /// unsafe symlinks it still sends are kept in the file list, but not
+    /// created.
+    pub copy_unsafe_links: bool,
+
+    /// Rules controlling which files are transferred, in order, and files to
+    /// read them from.
+    ///
+    /// Each rule is a pattern prefixed by `- ` to exclude matching files or
+    /// `+ ` to include them, as in rsync's `--filter`. The long forms
+    /// `exclude PATTERN` and `include PATTERN` are also accepted. The first
+    /// matching rule has effect, so the order matters. See
+    /// [`FilterRule`](struct.FilterRule.html) for the pattern syntax, and
+    /// [`FilterSource`](enum.FilterSource.html) for files of rules.
+    ///
+    /// These are sent to the server, which applies them when building the
+    /// file list.
+    pub filter_rules: Vec<FilterSource>,
+
+    /// Exclude files that CVS would ignore, like `*.o`, `core` and `.git/`,
+    /// as for rsync's `-C`.
+    ///
+    /// These rules are added after all the others. Per-directory
+    /// `.cvsignore` files aren't read yet.
+    pub cvs_exclude: bool,
+
+    /// Log a line in this format for each file received, as with rsync's
+    /// `--out-format`.
+    ///
+    /// Lines are logged at `info` level. Escapes include `%n` for the name,
+    /// `%l` for the length, `%b` for the bytes transferred, and `%M` for
+    /// the modification time.
+    pub out_format: Option<String>,
+
+    /// Show sizes in `out_format` lines like `1.2M`, rather than as a
+    /// number of bytes, as with rsync's `--human-readable`.
+    ///
+    /// To show `Summary`, `ServerStatistics` and `FileEntry` this way, format
+    /// them with `{:#}`.
+    pub human_readable: bool,
+
+    /// Show non-ASCII characters in file names unescaped, and only escape
+    /// ASCII control characters, as with rsync's `--8-bit-output`.
+    ///
+    /// This affects `out_format` lines; to list files this way, use
+    /// `FileEntry::to_ls_line`.
+    pub eight_bit_output: bool,
+
+    /// Describe what changed for each entry in `Summary::changes`, as with
+    /// rsync's `--itemize-changes`.
+    ///
+    /// Entries that are already up to date are left out. This doesn't log
+    /// anything by itself: each `ItemizedChange` formats like rsync's `%i`.
+    pub itemize: bool,
+
+    /// Skip files whose size matches the destination, ignoring the
+    /// modification time, as with rsync's `--size-only`.
+    ///
+    /// By default, a file is only skipped if both its size and modification
+    /// time match.
+    pub size_only: bool,
+
+    /// Use blocks of this many bytes for delta transfers, as with rsync's
+    /// `--block-size`, rather than choosing a size for each file.
+    ///
+    /// By default, blocks are about the square root of the file's length,
+    /// with a maximum that's much smaller from protocol 30. This is also
+    /// passed to the server, and it's an error if it's more than the
+    /// negotiated protocol allows.
+    pub block_size: Option<u32>,
+
+    /// Ask the server to use this checksum seed, as with rsync's
+    /// `--checksum-seed`, rather than one based on the time.
+    ///
+    /// The seed is mixed into every strong checksum, so fixing it makes
+    /// transfers reproducible byte for byte, which is mostly useful for
+    /// tests. It's an error if the server sends a different seed. Zero
+    /// isn't allowed, because rsync takes it to mean the time.
+    pub checksum_seed: Option<i32>,
+
+    /// Transfer files even if their size and modification time match the
+    /// destination, as with rsync's `--ignore-times`.
+    ///
+    /// `size_only` takes precedence, as in rsync.
+    pub ignore_times: bool,
+
+    /// Skip files that are newer on the destination than on the server, as
+    /// with rsync's `--update`.
+    pub update: bool,
+
+    /// Replace local entries of a different type from the source, such as a
+    /// directory where the source has a file, as with rsync's `--force`.
+    ///
+    /// Directories are removed along with everything in them. Without this,
+    /// such entries are skipped, and counted in
+    /// `Summary::type_conflict_count`.
+    pub force: bool,
+
+    /// Before a file in the destination is replaced or deleted, rename it
+    /// with `backup_suffix` added to its name, as with rsync's `--backup`.
+    ///
+    /// Existing backups are not deleted by `delete`.
+    pub backup: bool,
+
+    /// The suffix for backups, as with rsync's `--suffix`. By default, `~`,
+    /// or nothing with `backup_dir`.
+    pub backup_suffix: Option<String>,
+
+    /// Put backups in this directory, at the same relative path as the file
+    /// they replace, as with rsync's `--backup-dir`. This implies `backup`.
+    ///
+    /// A relative path is relative to the destination. A backup directory
+    /// inside the destination is never deleted by `delete`.
+    pub backup_dir: Option<PathBuf>,
+
+    /// Keep each received file aside until the whole transfer succeeds, and
+    /// then put them all in place, as with rsync's `--delay-updates`.
+    ///
+    /// This makes the time during which the destination is partly updated as
+    /// short as possible. If the transfer fails, the received files are
+    /// discarded.
+    pub delay_updates: bool,
+
+    /// Leave holes in received files where they contain runs of zeros,
+    /// rather than writing the zeros, as with rsync's `-S`.
+    ///
+    /// This saves space for files like disk images, where the local tree
+    /// supports it.
+    pub sparse: bool,
+
+    /// Don't change anything in the destination, or request any files, as
+    /// with rsync's `-n`.
+    ///
+    /// With `itemize`, `Summary::changes` still says what would change.
+    pub dry_run: bool,
+
+    /// Delete extraneous files even if the server reports IO errors, as with
+    /// rsync's `--ignore-errors`.
+    ///
+    /// Otherwise, `delete` is skipped after errors, since the file list might
+    /// be missing files that the server couldn't read.
+    pub ignore_errors: bool,
+
+    /// Give received files this owner and group, as with rsync's
+    /// `--chown=USER:GROUP`.
+    ///
+    /// Either part can be left out, and each can be a name or a numeric id.
+    /// These replace the ids in the file list, even with `preserve_uid` or
+    /// `preserve_gid`. Changing owners usually needs root: if it fails, a
+    /// warning is logged and owners are left unchanged.
+    pub chown: Option<String>,
+
+    /// Make everything received owned by this user and group, as with
+    /// rsync's `--copy-as=USER[:GROUP]`, whatever the owners on the server.
+    ///
+    /// This is meant for a client running as root that should leave files
+    /// owned by a service account. Unlike `chown`, the user is required, and
+    /// the group defaults to the user's primary group. It can't be combined
+    /// with `chown`.
+    pub copy_as: Option<String>,
+
+    /// Delete files from the destination that aren't on the source, as with
+    /// rsync's `--delete`, and if so when.
+    ///
+    /// Only the contents of directories that are transferred are deleted.
+    /// As in rsync, this needs `recursive`: otherwise the file list has
+    /// directories without their contents, and connecting fails.
+    pub delete: Option<DeleteMode>,
+
+    /// Compression algorithm to use, as for rsync's `--compress-choice`.
+    ///
+    /// rsyn only implements `zlib`, which `-z` chooses, and `none`. rsync's
+    /// other names, `zstd`, `lz4` and `zlibx`, are accepted, but rsyn can't
+    /// use them, so the transfer is uncompressed, with a warning. Unknown
+    /// names are an error.
+    pub compress_choice: Option<String>,
+
+    /// Algorithm for the whole-file checksums that verify each received
+    /// file, as for rsync's `--checksum-choice`: one of `xxh128`, `xxh3`,
+    /// `xxh64` (also called `xxhash`), `md5`, `md4`, `sha1`, or `none`.
+    ///
+    /// Algorithms are negotiated by name from protocol 30, but rsyn uses
+    /// protocol 27, where the only checksum is MD4. So for now, choosing any
+    /// other algorithm fails when connecting, with an error that says what
+    /// the server offers. Unknown names are an error before connecting.
+    pub checksum_choice: Option<String>,
+
+    /// Send the server's arguments, including the path, over the connection
+    /// rather than on its command line, as with rsync's `--protect-args`.
+    ///
+    /// This stops a remote shell splitting or expanding paths that contain
+    /// spaces or wildcards. It has no effect for daemons, which always get
+    /// their arguments this way.
+    pub protect_args: bool,
+
+    /// Extra arguments for the server, added verbatim after the ones rsyn
+    /// generates and before the path.
+    ///
+    /// This allows experimenting with rsync options that rsyn doesn't model
+    /// yet. Options that change what's sent on the wire will probably break
+    /// the protocol, because rsyn won't expect the difference.
+    pub extra_server_args: Vec<String>,
+
+    /// Ask the server to send no more than this many KiB per second, as with
+    /// rsync's `--bwlimit`.
+    ///
+    /// `Summary::rate_bytes_per_sec` shows the rate that was achieved.
+    pub bwlimit: Option<u32>,
+
+    /// Refuse to allocate more than this many bytes at once for data from
+    /// the server, such as a name in the file list or a message, as a
+    /// defense against a server that sends huge lengths. This is also
+    /// passed to the server, as rsync's `--max-alloc`.
+    ///
+    /// rsync requires at least 1 MiB, and servers before 3.2.2 don't
+    /// understand the option.
+    pub max_alloc: Option<usize>,
+
+    /// Fail if no data is received for this long.
+    ///
+    /// This applies to all transports, and is also passed to the server,
+    /// rounded up to whole seconds.
+    ///
+    /// A zero timeout is the same as none, as in rsync.
+    pub timeout: Option<Duration>,
+
+    /// Fail if no data is received for this long while the server builds
+    /// and sends the file list, which can take much longer than any later
+    /// wait for a large tree.
+    ///
+    /// If this is None, `timeout` applies; zero means no timeout in this
+    /// phase. This isn't passed to the server.
+    pub flist_timeout: Option<Duration>,
+
+    /// Fail if no data is received for this long while files are
+    /// transferred, after the file list.
+    ///
+    /// If this is None, `timeout` applies; zero means no timeout in this
+    /// phase. This isn't passed to the server.
+    pub transfer_timeout: Option<Duration>,
+
+    /// Stop the transfer, and kill the server, when this token is
+    /// cancelled.
+    ///
+    /// This is checked while the file list is read, which can take a long
+    /// time for a large tree, and between files.
+    pub cancel: Option<CancelToken>,
+
+    /// Read the password for an rsync daemon from the first line of this
+    /// file, as for rsync's `--password-file`.
+    ///
+    /// On Unix, the file must not be readable by group or other users. If
+    /// this is None, the password is taken from `$RSYNC_PASSWORD`.
+    pub password_file: Option<PathBuf>,
+
+    /// Include passwords and authentication responses in log messages.
+    ///
+    /// By default they're replaced by `***`. This should only be turned on
+    /// for debugging.
+    pub log_secrets: bool,
+}
+
+/// Names accepted by `compress_choice`.
+const COMPRESS_CHOICES: &[&str] = &["zstd", "lz4", "zlibx", "zlib", "none"];
+
+/// Names accepted by `checksum_choice`.
+const CHECKSUM_CHOICES: &[&str] = &[
+    "xxh128", "xxh3", "xxh64", "xxhash", "md5", "md4", "sha1", "none",
+];
+
+/// The smallest `max_alloc` that rsync accepts.
+const MIN_MAX_ALLOC: usize = 1 << 20;
+
+type SetOption = fn(&mut Options);
+
+/// rsync flags accepted by `Options::from_args`: the short name, if any, the
+/// long name, and how the flag changes the options.
+const FLAGS: &[(Option<char>, &str, SetOption)] = &[
+    (Some('r'), "recursive", |o| o.recursive = true),
+    (Some('v'), "verbose", |o| o.verbose += 1),
+    (Some('x'), "one-file-system", |o| o.one_file_system = true),
+    (Some('R'), "relative", |o| o.relative = true),
+    (None, "no-implied-dirs", |o| o.no_implied_dirs = true),
+    (Some('o'), "owner", |o| o.preserve_uid = true),
+    (Some('g'), "group", |o| o.preserve_gid = true),
+    (None, "numeric-ids", |o| o.numeric_ids = true),
+    (Some('l'), "links", |o| o.preserve_links = true),
+    (Some('p'), "perms", |o| o.preserve_perms = true),
+    (None, "copy-unsafe-links", |o| o.copy_unsafe_links = true),
+    (Some('C'), "cvs-exclude", |o| o.cvs_exclude = true),
+    (Some('h'), "human-readable", |o| o.human_readable = true),
+    (Some('8'), "8-bit-output", |o| o.eight_bit_output = true),
+    (Some('i'), "itemize-changes", |o| o.itemize = true),
+    (None, "size-only", |o| o.size_only = true),
+    (Some('I'), "ignore-times", |o| o.ignore_times = true),
+    (Some('u'), "update", |o| o.update = true),
+    (None, "force", |o| o.force = true),
+    (Some('b'), "backup", |o| o.backup = true),
+    (None, "delay-updates", |o| o.delay_updates = true),
+    (Some('S'), "sparse", |o| o.sparse = true),
+    (Some('n'), "dry-run", |o| o.dry_run = true),
+    (None, "ignore-errors", |o| o.ignore_errors = true),
+    (Some('s'), "protect-args", |o| o.protect_args = true),
+    (Some('z'), "compress", |o| {
+        o.compress_choice = Some("zlib".to_owned())
+    }),
+    (None, "delete", |o| {
+        o.delete.get_or_insert(DeleteMode::Before);
+    }),
+    (None, "delete-before", |o| {
+        o.delete = Some(DeleteMode::Before)
+    }),
+    (None, "delete-after", |o| o.delete = Some(DeleteMode::After)),
+    (None, "delete-excluded", |o| {
+        o.delete = Some(DeleteMode::Excluded)
+    }),
+    // The mode is chosen by the `Client` method that's called.
+    (None, "list-only", |_| ()),
+];
+
+/// rsync flags that rsyn knows, but doesn't support yet.
+const UNSUPPORTED_FLAGS: &[(Option<char>, &str)] = &[
+    (Some('a'), "archive"),
+    (Some('t'), "times"),
+    (Some('D'), "devices"),
+    (Some('H'), "hard-links"),
+    (Some('c'), "checksum"),
+    // TODO: Once rsyn reads local file content, as the sender or for the
+    // basis of a delta transfer, open those files with `O_NOATIME` on Linux.
+    // Scanning the destination only reads metadata, which never changes
+    // access times.
+    (None, "open-noatime"),
+];
+
+impl Options {
+    /// Parse rsync-style flags, like `["-rv", "--delete"]`, into options.
+    ///
+    /// This accepts short flags, which can be combined, and long flags that
+    /// turn on an option, such as `-r`, `-v`, `-l`, `-p`, `-n`,
+    /// `--delete-after` and `--list-only`. `-z` sets `compress_choice` to
+    /// `zlib`. `--list-only` is accepted, but has no effect here: the mode
+    /// is chosen by the `Client` method that's called.
+    ///
+    /// Options that take a value, paths, and flags that rsyn doesn't support,
+    /// such as `-t`, are errors.
+    ///
+    /// ```
+    /// use rsyn::Options;
+    /// let options = Options::from_args(&["-rv", "--size-only"]).unwrap();
+    /// assert!(options.recursive);
+    /// assert!(options.size_only);
+    /// assert_eq!(options.verbose, 1);
+    /// ```
+    pub fn from_args(args: &[&str]) -> Result<Options> {
+        let mut options = Options::default();
+        for arg in args {
+            if let Some(long) = arg.strip_prefix("--") {
+                match FLAGS.iter().find(|(_, name, _)| *name == long) {
+                    Some((_, _, set)) => set(&mut options),
+                    None if UNSUPPORTED_FLAGS.iter().any(|(_, name)| *name == long) => {
+                        bail!("Option {:?} is not supported by rsyn yet", arg)
+                    }
+                    None => bail!("Unknown option {:?}", arg),
+                }
+            } else if let Some(shorts) = arg.strip_prefix('-').filter(|s| !s.is_empty()) {
+                for c in shorts.chars() {
+                    match FLAGS.iter().find(|(short, _, _)| *short == Some(c)) {
+                        Some((_, _, set)) => set(&mut options),
+                        None if UNSUPPORTED_FLAGS.iter().any(|(short, _)| *short == Some(c)) => {
+                            bail!("Option -{} in {:?} is not supported by rsyn yet", c, arg)
+                        }
+                        None => bail!("Unknown option -{} in {:?}", c, arg),
+                    }
+                }
+            } else {
+                bail!("Expected an option, not {:?}", arg);
+            }
+        }
+        Ok(options)
+    }
+
+    /// Check for option values that can never work, before connecting.
+    pub(crate) fn check(&self) -> Result<()> {
+        if let Some(choice) = &self.compress_choice {
+            if !COMPRESS_CHOICES.contains(&choice.as_str()) {
+                bail!(
+                    "Unknown compression algorithm {:?}: expected one of {}",
+                    choice,
+                    COMPRESS_CHOICES.join(", ")
+                );
+            }
+        }
+        if let Some(choice) = &self.checksum_choice {
+            if !CHECKSUM_CHOICES.contains(&choice.as_str()) {
+                bail!(
+                    "Unknown checksum algorithm {:?}: expected one of {}",
+                    choice,
+                    CHECKSUM_CHOICES.join(", ")
+                );
+            }
+        }
+        if self.backs_up() {
+            let suffix = self.effective_backup_suffix();
+            if (suffix.is_empty() && self.backup_dir.is_none()) || suffix.contains('/') {
+                bail!("Invalid backup suffix {:?}", suffix);
+            }
+        }
+        if self.delete.is_some() && !self.recursive {
+            bail!("delete needs recursive, or it would delete the contents of every directory");
+        }
+        if self.block_size == Some(0) {
+            bail!("block_size must be more than zero");
+        }
+        if self.checksum_seed == Some(0) {
+            bail!("checksum_seed must not be zero, which rsync takes to mean the time");
+        }
+        if let Some(max_alloc) = self.max_alloc {
+            if max_alloc < MIN_MAX_ALLOC {
+                bail!(
+                    "max_alloc of {} is too small: rsync requires at least {}",
+                    max_alloc,
+                    MIN_MAX_ALLOC
+                );
+            }
+        }
+        if let Some(chown) = &self.chown {
+            parse_chown(chown)?;
+        }
+        if let Some(copy_as) = &self.copy_as {
+            if self.chown.is_some() {
+                bail!("copy_as and chown can't be used together");
+            }
+            parse_copy_as(copy_as)?;
+        }
+        if let Some(backup_dir) = &self.backup_dir {
+            // Backups in the destination itself would replace the files they
+            // back up, or be backed up in turn.
+            if backup_dir.components().all(|c| c == Component::CurDir) {
+                bail!(
+                    "Backup directory {:?} can't be the destination itself",
+                    backup_dir
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if files are backed up before they're replaced or
+    /// deleted, with either `backup` or `backup_dir`.
+    pub(crate) fn backs_up(&self) -> bool {
+        self.backup || self.backup_dir.is_some()
+    }
+
+    /// Returns the suffix added to the names of backups.
+    pub(crate) fn effective_backup_suffix(&self) -> &str {
+        match (&self.backup_suffix, &self.backup_dir) {
+            (Some(suffix), _) => suffix,
+            (None, Some(_)) => "",
+            (None, None) => "~",
+        }
+    }
+
+    /// Returns the name of the backup directory relative to the destination,
+    /// without any leading `./` or trailing `/`, if it's set.
+    pub(crate) fn backup_dir_name(&self) -> Option<Vec<u8>> {
+        self.backup_dir.as_ref().map(|dir| {
+            let dir: PathBuf = dir
+                .components()
+                .filter(|c| *c != Component::CurDir)
+                .collect();
+            path_bytes(&dir)
+        })
+    }
+
+    /// Returns the compression algorithm to use with the agreed protocol
+    /// version, or None to transfer uncompressed.
+    ///
+    /// Only `zlib` is implemented, so anything else is None, with a warning.
+    pub(crate) fn negotiate_compression(&self) -> Option<&str> {
+        match self.compress_choice.as_deref() {
+            None | Some("none") => None,
+            Some("zlib") => Some("zlib"),
+            Some(choice) => {
+                warn!(
+                    "rsyn doesn't implement {:?} compression; transferring uncompressed",
+                    choice
+                );
+                None
+            }
+        }
+    }
+
+    /// Returns the checksum algorithm to use, from the names `offered` by
+    /// the server in its order of preference.
+    ///
+    /// Without a `checksum_choice`, this is the server's first choice. It's
+    /// an error if the chosen algorithm isn't offered.
+    pub(crate) fn negotiate_checksum<'a>(&self, offered: &[&'a str]) -> Result<&'a str> {
+        let choice = match self.checksum_choice.as_deref() {
+            None => {
+                return offered
+                    .first()
+                    .copied()
+                    .ok_or_else(|| anyhow!("Server offers no checksum algorithms"))
+            }
+            Some("xxhash") => "xxh64",
+            Some(choice) => choice,
+        };
+        match offered.iter().find(|name| **name == choice) {
+            Some(name) => Ok(name),
+            None => bail!(
+                "Checksum algorithm {:?} is not supported by the server, which offers: {}",
+                self.checksum_choice.as_deref().unwrap(),
+                offered.join(", ")
+            ),
+        }
+    }
+
+    /// Check that `block_size`, if set, is allowed in the negotiated protocol
+    /// version.
+    pub(crate) fn check_block_size(&self, protocol_version: i32) -> Result<()> {
+        let max = max_block_len(protocol_version) as u32;
+        match self.block_size {
+            Some(block_size) if block_size > max => bail!(
+                "block_size of {} is too large for protocol {}: the most is {}",
+                block_size,
+                protocol_version,
+                max
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with `Error::Cancelled` if the `cancel` token is set.
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        match &self.cancel {
+            Some(cancel) if cancel.is_cancelled() => Err(Error::Cancelled.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the timeout, if there is one, treating zero as none.
+    pub(crate) fn effective_timeout(&self) -> Option<Duration> {
+        nonzero(self.timeout)
+    }
+
+    /// Returns the timeout while receiving the file list.
+    pub(crate) fn effective_flist_timeout(&self) -> Option<Duration> {
+        nonzero(self.flist_timeout.or(self.timeout))
+    }
+
+    /// Returns the timeout while transferring files.
+    pub(crate) fn effective_transfer_timeout(&self) -> Option<Duration> {
+        nonzero(self.transfer_timeout.or(self.timeout))
+    }
+
+    /// Returns true if any timeout is set, so reads must be watched.
+    pub(crate) fn has_timeouts(&self) -> bool {
+        self.effective_timeout().is_some()
+            || self.effective_flist_timeout().is_some()
+            || self.effective_transfer_timeout().is_some()
+    }
+}
+
+fn nonzero(timeout: Option<Duration>) -> Option<Duration> {
+    timeout.filter(|timeout| *timeout > Duration::from_secs(0))
+}
+
+/// The kind of transfer a connection is opened for.
+///
+/// This is chosen by the `Client` method that's called, such as
+/// [`Client::list_files`](struct.Client.html#method.list_files), and
+/// determines both the arguments passed to the server and whether file
+/// contents are requested.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransferMode {
+    /// List files, without transferring their contents, as with rsync's
+    /// `--list-only`.
+    ///
+    /// Without `recursive`, the server lists just the source directory
+    /// and, if its name has a trailing slash, its immediate children.
+    List,
+
+    /// Copy files from the server into a local tree.
+    Download,
+
+    /// Copy files from a local tree to the server.
+    ///
+    /// This is not implemented yet.
+    Upload,
+}
+
+/// When, and which, extraneous files are deleted from the destination.
+///
+/// Files are extraneous if they're in a directory being transferred, but not
+/// on the source.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeleteMode {
+    /// Delete extraneous files before transferring any files, as with rsync's
+    /// `--delete-before`.
+    ///
+    /// This is rsync's behavior for `--delete` with the protocol version
+    /// used by rsyn.
+    Before,
+
+    /// Delete extraneous files after all files are transferred, as with
+    /// rsync's `--delete-after`.
+    After,
+
+    /// Also delete files excluded by the filter rules, before the transfer,
+    /// as with rsync's `--delete-excluded`.
+    ///
+    /// Otherwise, excluded files are kept.
+    Excluded,
+}
+
+/// What to do when a received symlink can't be created, as set by
+/// `Options::symlink_fallback`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SymlinkFallback {
+    /// Fail the transfer.
+    #[default]
+    Error,
+
+    /// Log a warning, and carry on without the symlink.
+    SkipWithWarning,
+
+    /// After the transfer, put a copy of the file that the symlink points to
+    /// in its place.
+    ///
+    /// This only works for a link to a regular file in the transfer: other
+    /// links are skipped with a warning.
+    CopyReferent,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_args() {
+        let options = Options::from_args(&["-rvz", "--delete"]).unwrap();
+        assert_eq!(
+            options,
+            Options {
+                recursive: true,
+                verbose: 1,
+                compress_choice: Some("zlib".to_owned()),
+                delete: Some(DeleteMode::Before),
+                ..Options::default()
+            }
+        );
+
+        let options = Options::from_args(&["-vv", "--delete-after", "--delete", "-lpn"]).unwrap();
+        assert_eq!(options.verbose, 2);
+        assert_eq!(options.delete, Some(DeleteMode::After));
+        assert!(options.preserve_links && options.preserve_perms && options.dry_run);
+        assert_eq!(
+            Options::from_args(&["--list-only"]).unwrap(),
+            Options::default()
+        );
+
+        for (args, message) in &[
+            (&["-rq"][..], "Unknown option -q in \"-rq\""),
+            (&["--frobnicate"], "Unknown option \"--frobnicate\""),
+            (
+                &["-rt"],
+                "Option -t in \"-rt\" is not supported by rsyn yet",
+            ),
+            (&["--bwlimit=10"], "Unknown option \"--bwlimit=10\""),
+            (&["src/"], "Expected an option, not \"src/\""),
+            (&["-"], "Expected an option, not \"-\""),
+        ] {
+            let err = Options::from_args(args).unwrap_err();
+            assert_eq!(err.to_string(), *message);
+        }
+    }
+
+    #[test]
+    fn check_delete_needs_recursive() {
+        let mut options = Options {
+            delete: Some(DeleteMode::After),
+            ..Options::default()
+        };
+        assert!(options.check().is_err());
+        options.recursive = true;
+        assert!(options.check().is_ok());
+    }
+
+    #[test]
+    fn check_compress_choice() {
+        for (choice, negotiated) in &[("zstd", None), ("zlib", Some("zlib")), ("none", None)] {
+            let options = Options {
+                compress_choice: Some(choice.to_string()),
+                ..Options::default()
+            };
+            assert!(options.check().is_ok());
+            assert_eq!(options.negotiate_compression(), *negotiated);
+        }
+        let options = Options {
+            compress_choice: Some("bzip2".to_owned()),
+            ..Options::default()
+        };
+        assert!(options.check().is_err());
+    }
+
+    #[test]
+    fn negotiate_checksum_choice() {
+        let options = Options {
+            checksum_choice: Some("xxhash".to_owned()),
+            ..Options::default()
+        };
+        assert!(options.check().is_ok());
+        assert_eq!(
+            options
+                .negotiate_checksum(&["xxh128", "xxh64", "md5"])
+                .unwrap(),
+            "xxh64"
+        );
+        let err = options.negotiate_checksum(&["md5"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Checksum algorithm \"xxhash\" is not supported by the server, which offers: md5"
+        );
+
+        assert_eq!(
+            Options::default()
+                .negotiate_checksum(&["md5", "md4"])
+                .unwrap(),
+            "md5"
+        );
+        assert!(Options::default().negotiate_checksum(&[]).is_err());
+
+        let options = Options {
+            checksum_choice: Some("crc32".to_owned()),
+            ..Options::default()
+        };
+        assert!(options.check().is_err());
+    }
+
+    #[test]
+    fn check_block_size() {
+        let options = |block_size| Options {
+            block_size,
+            ..Options::default()
+        };
+        assert!(options(Some(0)).check().is_err());
+        assert!(options(Some(700)).check().is_ok());
+        assert!(options(None).check_block_size(30).is_ok());
+        // Blocks can be much larger before protocol 30.
+        assert!(options(Some(1 << 20)).check_block_size(29).is_ok());
+        assert_eq!(
+            options(Some(1 << 20))
+                .check_block_size(30)
+                .unwrap_err()
+                .to_string(),
+            "block_size of 1048576 is too large for protocol 30: the most is 131072"
+        );
+    }
+
+    #[test]
+    fn check_checksum_seed() {
+        let options = |checksum_seed| Options {
+            checksum_seed,
+            ..Options::default()
+        };
+        assert!(options(None).check().is_ok());
+        assert!(options(Some(-1)).check().is_ok());
+        assert!(options(Some(0)).check().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_copy_as() {
+        let options = |copy_as: &str, chown: Option<&str>| Options {
+            copy_as: Some(copy_as.to_owned()),
+            chown: chown.map(str::to_owned),
+            ..Options::default()
+        };
+        assert!(options("root", None).check().is_ok());
+        assert!(options(":0", None).check().is_err());
+        assert_eq!(
+            options("root", Some("0:0"))
+                .check()
+                .unwrap_err()
+                .to_string(),
+            "copy_as and chown can't be used together"
+        );
+    }
+
+    #[test]
+    fn check_max_alloc() {
+        for (max_alloc, ok) in [(None, true), (Some(1 << 20), true), (Some(1000), false)] {
+            let options = Options {
+                max_alloc,
+                ..Options::default()
+            };
+            assert_eq!(options.check().is_ok(), ok, "{:?}", max_alloc);
+        }
+    }
+
+    #[test]
+    fn check_backup_suffix() {
+        let mut options = Options {
+            backup: true,
+            ..Options::default()
+        };
+        assert!(options.check().is_ok());
+        assert_eq!(options.effective_backup_suffix(), "~");
+        for suffix in &["", "a/b"] {
+            options.backup_suffix = Some(suffix.to_string());
+            assert!(options.check().is_err());
+        }
+    }
+
+    #[test]
+    fn check_backup_dir() {
+        let mut options = Options {
+            backup_dir: Some("./old/".into()),
+            ..Options::default()
+        };
+        assert!(options.check().is_ok());
+        assert!(options.backs_up());
+        assert_eq!(options.effective_backup_suffix(), "");
+        assert_eq!(options.backup_dir_name().unwrap(), b"old");
+        for dir in &[".", "./", ""] {
+            options.backup_dir = Some(dir.into());
+            assert!(options.check().is_err(), "{:?}", dir);
+        }
+    }
+
+    #[test]
+    fn phase_timeouts_default_to_timeout() {
+        let mut options = Options {
+            timeout: Some(Duration::from_secs(30)),
+            ..Options::default()
+        };
+        assert_eq!(
+            options.effective_flist_timeout(),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            options.effective_transfer_timeout(),
+            Some(Duration::from_secs(30))
+        );
+
+        options.flist_timeout = Some(Duration::from_secs(600));
+        options.transfer_timeout = Some(Duration::from_secs(0));
+        assert_eq!(
+            options.effective_flist_timeout(),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(options.effective_transfer_timeout(), None);
+
+        let options = Options {
+            flist_timeout: Some(Duration::from_secs(600)),
+            ..Options::default()
+        };
+        assert_eq!(options.effective_timeout(), None);
+        assert!(options.has_timeouts());
+        assert!(!Options::default().has_timeouts());
+    }
 }
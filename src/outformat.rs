@@ -0,0 +1,153 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-file log lines, like those from rsync's `--out-format`.
+
+use crate::statistics::human_bytes;
+use crate::{FileEntry, Options};
+
+/// Format a line describing a transferred entry, from an rsync
+/// `--out-format` template.
+///
+/// Supported escapes are:
+///
+/// * `%n`: the file name, with a trailing `/` on directories
+/// * `%f`: the file name
+/// * `%L`: ` -> TARGET` for symlinks, otherwise nothing
+/// * `%l`: the length of the file in bytes
+/// * `%b`: the number of bytes transferred
+/// * `%M`: the modification time, like `2020/05/02-07:25:17`
+/// * `%%`: a literal `%`
+///
+/// As in rsync, an escape can have a width, like `%10l`, or `%-10n` to align
+/// to the left. Other escapes are copied unchanged.
+///
+/// With `options.human_readable`, `%l` and `%b` are shown like `1.2M`.
+/// Names are escaped, unless `options.eight_bit_output` is set.
+pub(crate) fn format_entry(
+    template: &str,
+    entry: &FileEntry,
+    bytes_transferred: u64,
+    options: &Options,
+) -> String {
+    let size = |n: i64| {
+        if options.human_readable {
+            human_bytes(n)
+        } else {
+            n.to_string()
+        }
+    };
+    let name = entry.name_escaped(options.eight_bit_output);
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(pos) = rest.find('%') {
+        out.push_str(&rest[..pos]);
+        let escape = &rest[pos + 1..];
+        let left_align = escape.starts_with('-');
+        let digits = &escape[left_align as usize..];
+        let width_len = digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(digits.len());
+        let width: usize = digits[..width_len].parse().unwrap_or(0);
+        let mut after = digits[width_len..].chars();
+        let code = after.next();
+        let value = match code {
+            Some('n') if entry.is_dir() => Some(format!("{}/", name)),
+            Some('n') | Some('f') => Some(name.clone()),
+            Some('L') => Some(match entry.link_target() {
+                Some(target) => format!(" -> {}", String::from_utf8_lossy(target)),
+                None => String::new(),
+            }),
+            Some('l') => Some(size(entry.file_len as i64)),
+            Some('b') => Some(size(bytes_transferred as i64)),
+            Some('M') => Some(entry.mtime().format("%Y/%m/%d-%H:%M:%S").to_string()),
+            Some('%') => Some("%".to_owned()),
+            _ => None,
+        };
+        match value {
+            Some(value) if left_align => out.push_str(&format!("{:<1$}", value, width)),
+            Some(value) => out.push_str(&format!("{:>1$}", value, width)),
+            None => {
+                // Copy the unknown escape, or a trailing `%`, unchanged.
+                out.push_str(&rest[pos..rest.len() - after.as_str().len()]);
+            }
+        }
+        rest = after.as_str();
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_file_entry() {
+        let entry = FileEntry::for_test(b"src/lib.rs", 0o100644, 1234, 1588429517);
+        let mtime = entry.mtime().format("%Y/%m/%d-%H:%M:%S").to_string();
+        assert_eq!(
+            format_entry("recv %n %l %b %M", &entry, 1000, &Options::default()),
+            format!("recv src/lib.rs 1234 1000 {}", mtime)
+        );
+        assert_eq!(
+            format_entry("[%-12n][%6l]%L 100%%", &entry, 0, &Options::default()),
+            "[src/lib.rs  ][  1234] 100%"
+        );
+    }
+
+    #[test]
+    fn directory_names_have_trailing_slash() {
+        let entry = FileEntry::for_test(b"src", 0o040755, 4096, 1588429517);
+        assert_eq!(
+            format_entry("%n %f", &entry, 0, &Options::default()),
+            "src/ src"
+        );
+    }
+
+    #[test]
+    fn human_readable_sizes() {
+        let entry = FileEntry::for_test(b"big", 0o100644, 3 << 20, 1588429517);
+        assert_eq!(
+            format_entry(
+                "%n %l %b",
+                &entry,
+                1536,
+                &Options {
+                    human_readable: true,
+                    ..Options::default()
+                }
+            ),
+            "big 3.0M 1.5K"
+        );
+    }
+
+    #[test]
+    fn names_are_escaped() {
+        let entry = FileEntry::for_test(b"caf\xc3\xa9\x01", 0o100644, 1, 1588429517);
+        assert_eq!(
+            format_entry("%n", &entry, 0, &Options::default()),
+            "caf\u{e9}\\#001"
+        );
+    }
+
+    #[test]
+    fn unknown_escapes_are_unchanged() {
+        let entry = FileEntry::for_test(b"a", 0o100644, 1, 1588429517);
+        assert_eq!(
+            format_entry("%q %5z %n%", &entry, 0, &Options::default()),
+            "%q %5z a%"
+        );
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decide whether to request a file from the server, by comparing it to the
+//! local file of the same name, like rsync's `quick_check_ok` and the checks
+//! around it in `recv_generator`.
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{FileEntry, Options};
+
+/// How many seconds in the future a file's mtime can be before it's taken as
+/// a sign that the server's clock is ahead of ours.
+const CLOCK_SKEW_THRESHOLD: i64 = 5 * 60;
+
+/// Whether to request a regular file from the server.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Decision {
+    Transfer,
+    Skip(SkipReason),
+}
+
+/// Why a file isn't requested.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum SkipReason {
+    /// The local file has the same size and modification time, or with
+    /// `size_only`, just the same size.
+    UpToDate,
+
+    /// With `update`, the local file is newer than the server's.
+    NewerOnDestination,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::UpToDate => write!(f, "up to date"),
+            SkipReason::NewerOnDestination => write!(f, "newer on the destination"),
+        }
+    }
+}
+
+/// Decide whether to request the regular file `remote`, given the `local`
+/// entry of the same name, if there is one.
+///
+/// As in rsync, with `update`, a local file that's newer is skipped before
+/// anything else is compared. Otherwise, a file whose size differs is always
+/// transferred, and `size_only` takes precedence over `ignore_times`.
+pub(crate) fn should_transfer(
+    remote: &FileEntry,
+    local: Option<&FileEntry>,
+    options: &Options,
+) -> Decision {
+    let local = match local {
+        Some(local) if local.is_file() => local,
+        _ => return Decision::Transfer,
+    };
+    if options.update && local.unix_mtime() > remote.unix_mtime() {
+        Decision::Skip(SkipReason::NewerOnDestination)
+    } else if local.file_len != remote.file_len {
+        Decision::Transfer
+    } else if options.size_only {
+        Decision::Skip(SkipReason::UpToDate)
+    } else if options.ignore_times || local.unix_mtime() != remote.unix_mtime() {
+        Decision::Transfer
+    } else {
+        Decision::Skip(SkipReason::UpToDate)
+    }
+}
+
+/// Returns a warning if the newest mtime in the file list is further ahead of
+/// `now` than `CLOCK_SKEW_THRESHOLD`.
+///
+/// The server's clock can only be compared to ours through the file list:
+/// `flist_build_time` is how long the list took to build, not when. A file
+/// modified in the future most likely means the server's clock is ahead,
+/// which can make the quick check transfer or skip files it shouldn't.
+/// Skew the other way can't be seen.
+pub(crate) fn clock_skew_warning(file_list: &[FileEntry], now: SystemTime) -> Option<String> {
+    let now = now.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+    let newest = file_list
+        .iter()
+        .filter(|entry| !entry.is_symlink())
+        .max_by_key(|entry| entry.unix_mtime())?;
+    let skew = newest.unix_mtime() as i64 - now;
+    if skew <= CLOCK_SKEW_THRESHOLD {
+        return None;
+    }
+    Some(format!(
+        "{:?} was modified {} seconds in the future: the server's clock may be \
+        ahead of this one, which makes modification times unreliable for \
+        deciding what to transfer; sync the clocks, or use --size-only",
+        newest.name_lossy_string(),
+        skew
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn warn_about_mtimes_in_the_future() {
+        let now = UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        let list = |mtime| {
+            vec![
+                FileEntry::for_test(b"a", 0o100644, 5, 1_000_000),
+                FileEntry::for_test(b"b", 0o100644, 5, mtime),
+            ]
+        };
+        assert_eq!(clock_skew_warning(&list(999_000), now), None);
+        assert_eq!(clock_skew_warning(&list(1_000_300), now), None);
+        assert_eq!(
+            clock_skew_warning(&list(1_000_301), now).unwrap(),
+            "\"b\" was modified 301 seconds in the future: the server's clock may \
+            be ahead of this one, which makes modification times unreliable for \
+            deciding what to transfer; sync the clocks, or use --size-only"
+        );
+        assert_eq!(clock_skew_warning(&[], now), None);
+    }
+
+    #[test]
+    fn decisions_for_combinations_of_options() {
+        use Decision::*;
+        use SkipReason::*;
+
+        let remote = FileEntry::for_test(b"a", 0o100644, 5, 1000);
+        let same = FileEntry::for_test(b"a", 0o100644, 5, 1000);
+        let older = FileEntry::for_test(b"a", 0o100644, 5, 900);
+        let longer = FileEntry::for_test(b"a", 0o100644, 6, 1000);
+        let dir = FileEntry::for_test(b"a", 0o040755, 5, 1000);
+        let default = Options::default();
+        let size_only = Options {
+            size_only: true,
+            ..Options::default()
+        };
+        let ignore_times = Options {
+            ignore_times: true,
+            ..Options::default()
+        };
+        let both = Options {
+            size_only: true,
+            ignore_times: true,
+            ..Options::default()
+        };
+        let update = Options {
+            update: true,
+            ..Options::default()
+        };
+        let update_ignore_times = Options {
+            update: true,
+            ignore_times: true,
+            ..Options::default()
+        };
+        let newer = FileEntry::for_test(b"a", 0o100644, 5, 1100);
+        let newer_and_longer = FileEntry::for_test(b"a", 0o100644, 6, 1100);
+
+        let table: &[(Option<&FileEntry>, &Options, Decision)] = &[
+            (None, &default, Transfer),
+            (Some(&dir), &default, Transfer),
+            (Some(&same), &default, Skip(UpToDate)),
+            (Some(&older), &default, Transfer),
+            (Some(&longer), &default, Transfer),
+            (None, &size_only, Transfer),
+            (Some(&same), &size_only, Skip(UpToDate)),
+            (Some(&older), &size_only, Skip(UpToDate)),
+            (Some(&longer), &size_only, Transfer),
+            (None, &ignore_times, Transfer),
+            (Some(&same), &ignore_times, Transfer),
+            (Some(&older), &ignore_times, Transfer),
+            (Some(&same), &both, Skip(UpToDate)),
+            (Some(&longer), &both, Transfer),
+            (Some(&newer), &default, Transfer),
+            (None, &update, Transfer),
+            (Some(&newer), &update, Skip(NewerOnDestination)),
+            (Some(&newer_and_longer), &update, Skip(NewerOnDestination)),
+            (Some(&newer), &update_ignore_times, Skip(NewerOnDestination)),
+            (Some(&same), &update, Skip(UpToDate)),
+            (Some(&older), &update, Transfer),
+            (Some(&longer), &update, Transfer),
+        ];
+        for (i, (local, options, expected)) in table.iter().enumerate() {
+            assert_eq!(
+                should_transfer(&remote, *local, options),
+                *expected,
+                "row {}",
+                i
+            );
+        }
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Keep passwords and other secrets out of log messages.
+
+use crate::Options;
+
+/// Replaces secrets with this.
+pub(crate) const REDACTED: &str = "***";
+
+/// Environment variable from which rsync takes the daemon password.
+pub(crate) const RSYNC_PASSWORD_ENV: &str = "RSYNC_PASSWORD";
+
+/// Removes known secrets from text before it's logged.
+///
+/// All logging of command lines and handshake traffic, which might contain
+/// passwords or authentication responses, should go through a `Redactor`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Redactor {
+    secrets: Vec<String>,
+}
+
+impl Redactor {
+    /// Construct a Redactor that hides the given secrets.
+    pub(crate) fn new<I, S>(secrets: I) -> Redactor
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut redactor = Redactor::default();
+        for secret in secrets {
+            redactor.add_secret(secret);
+        }
+        redactor
+    }
+
+    /// Construct a Redactor that hides any password given in the environment,
+    /// unless the options say to log secrets.
+    pub(crate) fn for_options(options: &Options) -> Redactor {
+        Redactor::for_password(options, std::env::var(RSYNC_PASSWORD_ENV).ok())
+    }
+
+    /// Construct a Redactor that hides `password`, unless the options say to
+    /// log secrets.
+    pub(crate) fn for_password(options: &Options, password: Option<String>) -> Redactor {
+        if options.log_secrets {
+            Redactor::default()
+        } else {
+            Redactor::new(password)
+        }
+    }
+
+    /// Also hide this secret from now on.
+    pub(crate) fn add_secret<S: Into<String>>(&mut self, secret: S) {
+        let secret = secret.into();
+        // Replacing an empty string would mangle everything, and there's
+        // nothing to hide anyhow.
+        if !secret.is_empty() && !self.secrets.contains(&secret) {
+            self.secrets.push(secret);
+        }
+    }
+
+    /// Returns `text` with every occurrence of a secret replaced by `***`.
+    pub(crate) fn redact(&self, text: &str) -> String {
+        let mut text = text.to_owned();
+        for secret in &self.secrets {
+            text = text.replace(secret.as_str(), REDACTED);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redact_secrets() {
+        let redactor = Redactor::new(vec!["hunter2", "", "s3kr1t"]);
+        assert_eq!(
+            redactor.redact("ssh -p hunter2 host s3kr1t s3kr1t"),
+            "ssh -p *** host *** ***"
+        );
+        assert_eq!(redactor.redact("nothing to hide"), "nothing to hide");
+    }
+
+    #[test]
+    fn empty_redactor_changes_nothing() {
+        assert_eq!(Redactor::default().redact("a b c"), "a b c");
+    }
+}
@@ -14,9 +14,15 @@
 
 //! Statistics/counter structs.
 
+use std::fmt;
+use std::time::Duration;
+
 /// Description of what happened during a transfer.
 #[derive(Clone, Eq, PartialEq, Debug, Default)]
 pub struct Summary {
+    /// How the connection reached the server.
+    pub transport: crate::Transport,
+
     /// Server reported this many errors while building the file count.
     /// (Typically, "permission denied" on a subdirectory.)
     pub server_flist_io_error_count: i32,
@@ -37,8 +43,198 @@ pub struct Summary {
     /// Number of literal bytes (rather than references to the old file) received.
     pub literal_bytes_received: usize,
 
+    /// Number of bytes copied from matching blocks of the old file.
+    ///
+    /// This is always 0 until delta transfers are implemented.
+    pub matched_bytes: usize,
+
     /// Number of files received.
     pub files_received: usize,
+
+    /// Indexes in the file list of files the server said it wouldn't send,
+    /// typically because it couldn't read them.
+    ///
+    /// rsync only says so from protocol 30, so this is empty while rsyn
+    /// speaks 27.
+    pub files_not_sent: Vec<usize>,
+
+    /// Number of files and directories deleted from the destination.
+    pub files_deleted: usize,
+
+    /// Number of entries of any type that didn't exist locally, or had a
+    /// different type.
+    pub files_created: usize,
+
+    /// Number of existing files whose content was requested from the server.
+    pub files_updated: usize,
+
+    /// Number of existing entries whose content wasn't requested, but whose
+    /// size, modification time, or permissions differ.
+    pub files_metadata_only: usize,
+
+    /// Number of entries skipped because a local entry of a different type
+    /// was in the way, and `Options::force` wasn't set.
+    pub type_conflict_count: usize,
+
+    /// Entries that differ from the local tree, and what changed, if
+    /// `Options::itemize` is set.
+    pub changes: Vec<(crate::FileEntry, crate::ItemizedChange)>,
+
+    /// Bytes read from the transport, as measured by the client.
+    ///
+    /// This includes protocol overhead, and any text exchanged with a daemon.
+    pub bytes_read: u64,
+
+    /// Bytes written to the transport, as measured by the client.
+    pub bytes_written: u64,
+
+    /// Bytes read from the transport while receiving the file list, including
+    /// the user and group names that follow it, and multiplexing overhead.
+    pub file_list_bytes: u64,
+
+    /// Wall-clock time taken by the transfer, from sending the filter rules
+    /// until the server's statistics were received.
+    pub elapsed: Duration,
+}
+
+impl Summary {
+    /// Returns the rate at which file content was received, in bytes per
+    /// second, counting both literal and matched bytes.
+    ///
+    /// This is 0 if no time elapsed.
+    pub fn rate_bytes_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            (self.literal_bytes_received + self.matched_bytes) as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns something that formats statistics like rsync's `--stats`,
+    /// with the same labels, given the file list from the same transfer.
+    ///
+    /// With `{:#}`, byte counts are shown like `1.2M`.
+    pub fn rsync_stats<'a>(&'a self, file_list: &'a [crate::FileEntry]) -> impl fmt::Display + 'a {
+        RsyncStats {
+            summary: self,
+            file_list,
+        }
+    }
+}
+
+struct RsyncStats<'a> {
+    summary: &'a Summary,
+    file_list: &'a [crate::FileEntry],
+}
+
+impl fmt::Display for RsyncStats<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let summary = self.summary;
+        writeln!(f, "Number of files: {}", self.file_list.len())?;
+        writeln!(
+            f,
+            "Number of regular files transferred: {}",
+            summary.files_received
+        )?;
+        writeln!(
+            f,
+            "Total file size: {} bytes",
+            bytes(f, summary.server_stats.total_file_size)
+        )?;
+        writeln!(
+            f,
+            "Literal data: {} bytes",
+            bytes(f, summary.literal_bytes_received as i64)
+        )?;
+        writeln!(
+            f,
+            "Matched data: {} bytes",
+            bytes(f, summary.matched_bytes as i64)
+        )?;
+        writeln!(
+            f,
+            "File list size: {}",
+            bytes(f, summary.file_list_bytes as i64)
+        )?;
+        writeln!(
+            f,
+            "Total bytes sent: {}",
+            bytes(f, summary.bytes_written as i64)
+        )?;
+        write!(
+            f,
+            "Total bytes received: {}",
+            bytes(f, summary.bytes_read as i64)
+        )
+    }
+}
+
+/// Format a number of bytes like `1.2M`, in powers of 1024, or as a plain
+/// number if it's less than 1K.
+pub(crate) fn human_bytes(n: i64) -> String {
+    const UNITS: &[&str] = &["K", "M", "G", "T", "P", "E"];
+    if n.unsigned_abs() < 1024 {
+        return n.to_string();
+    }
+    let mut value = n as f64 / 1024.0;
+    let mut unit = 0;
+    // Move up a unit rather than rounding up to, say, `1024.0K`.
+    while value.abs() >= 1023.95 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+/// Format a number of bytes, in human-readable form if the formatter has the
+/// alternate flag, as from `{:#}`.
+fn bytes(f: &fmt::Formatter<'_>, n: i64) -> String {
+    if f.alternate() {
+        human_bytes(n)
+    } else {
+        n.to_string()
+    }
+}
+
+/// Shows a few lines describing the transfer.
+///
+/// With `{:#}`, byte counts are shown like `1.2M`.
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Files received: {}", self.files_received)?;
+        writeln!(
+            f,
+            "Literal bytes received: {}",
+            bytes(f, self.literal_bytes_received as i64)
+        )?;
+        writeln!(f, "Matched bytes: {}", bytes(f, self.matched_bytes as i64))?;
+        writeln!(f, "Files deleted: {}", self.files_deleted)?;
+        writeln!(f, "Type conflicts: {}", self.type_conflict_count)?;
+        writeln!(f, "Bytes read: {}", bytes(f, self.bytes_read as i64))?;
+        writeln!(f, "Bytes written: {}", bytes(f, self.bytes_written as i64))?;
+        writeln!(f, "Elapsed: {:.3}s", self.elapsed.as_secs_f64())?;
+        writeln!(
+            f,
+            "Transfer rate: {}/s",
+            bytes(f, self.rate_bytes_per_sec().round() as i64)
+        )?;
+        writeln!(
+            f,
+            "Whole-file checksum mismatches: {}",
+            self.whole_file_sum_mismatch_count
+        )?;
+        writeln!(
+            f,
+            "Server file list IO errors: {}",
+            self.server_flist_io_error_count
+        )?;
+        write!(
+            f,
+            "Total file size: {}",
+            bytes(f, self.server_stats.total_file_size)
+        )
+    }
 }
 
 /// Statistics from a remote server about how much work it did.
@@ -55,9 +251,110 @@ pub struct ServerStatistics {
     /// symlinks.
     pub total_file_size: i64,
     /// The number of seconds spent by the server building a file list.
+    ///
+    /// Only sent from protocol 29, so always None from rsyn, which speaks 27.
     pub flist_build_time: Option<i64>,
     /// The number of seconds the server spent sending the file list to the
     /// client.
+    ///
+    /// Only sent from protocol 29, so always None from rsyn, which speaks 27.
     pub flist_xfer_time: Option<i64>,
     // TODO: More fields in at least some protocol versions.
 }
+
+/// Shows the server's statistics, one per line.
+///
+/// With `{:#}`, byte counts are shown like `1.2M`.
+impl fmt::Display for ServerStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Total bytes read: {}", bytes(f, self.total_bytes_read))?;
+        writeln!(
+            f,
+            "Total bytes written: {}",
+            bytes(f, self.total_bytes_written)
+        )?;
+        write!(f, "Total file size: {}", bytes(f, self.total_file_size))?;
+        if let Some(secs) = self.flist_build_time {
+            write!(f, "\nFile list build time: {}s", secs)?;
+        }
+        if let Some(secs) = self.flist_xfer_time {
+            write!(f, "\nFile list transfer time: {}s", secs)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display_summary() {
+        let summary = Summary {
+            files_received: 3,
+            literal_bytes_received: 1024,
+            files_deleted: 2,
+            ..Summary::default()
+        };
+        let display = summary.to_string();
+        assert!(display.contains("Files received: 3\n"));
+        assert!(display.contains("Literal bytes received: 1024\n"));
+        assert!(display.contains("Files deleted: 2\n"));
+        assert!(format!("{:#}", summary).contains("Literal bytes received: 1.0K\n"));
+        assert!(display.contains("Transfer rate: 0/s\n"));
+    }
+
+    #[test]
+    fn transfer_rate() {
+        let summary = Summary {
+            literal_bytes_received: 3000,
+            matched_bytes: 1000,
+            elapsed: Duration::from_millis(500),
+            ..Summary::default()
+        };
+        assert_eq!(summary.rate_bytes_per_sec(), 8000.0);
+        assert!(summary.to_string().contains("Transfer rate: 8000/s\n"));
+        assert!(format!("{:#}", summary).contains("Transfer rate: 7.8K/s\n"));
+    }
+
+    #[test]
+    fn display_server_statistics() {
+        let stats = ServerStatistics {
+            total_bytes_read: 120,
+            total_bytes_written: 5 << 30,
+            total_file_size: 5_000_000,
+            flist_build_time: Some(2),
+            flist_xfer_time: None,
+        };
+        assert_eq!(
+            stats.to_string(),
+            "Total bytes read: 120\n\
+             Total bytes written: 5368709120\n\
+             Total file size: 5000000\n\
+             File list build time: 2s"
+        );
+        assert_eq!(
+            format!("{:#}", stats),
+            "Total bytes read: 120\n\
+             Total bytes written: 5.0G\n\
+             Total file size: 4.8M\n\
+             File list build time: 2s"
+        );
+    }
+
+    #[test]
+    fn human_bytes_units() {
+        assert_eq!(human_bytes(0), "0");
+        assert_eq!(human_bytes(1023), "1023");
+        assert_eq!(human_bytes(1024), "1.0K");
+        assert_eq!(human_bytes(1536), "1.5K");
+        assert_eq!(human_bytes((1 << 20) - 1), "1.0M");
+        assert_eq!(human_bytes(1 << 20), "1.0M");
+        assert_eq!(human_bytes(1_258_291), "1.2M");
+        assert_eq!(human_bytes((1 << 30) - 1), "1.0G");
+        assert_eq!(human_bytes(3_650_722_202), "3.4G");
+        assert_eq!(human_bytes(1 << 40), "1.0T");
+        assert_eq!(human_bytes(-2048), "-2.0K");
+        assert_eq!(human_bytes(i64::MAX), "8.0E");
+    }
+}
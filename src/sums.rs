@@ -15,10 +15,38 @@
 //! A collection of strong and weak sums for a single file, from which deltas
 //! can be generated.
 
-use crate::varint::{ReadVarint, WriteVarint};
+pub(crate) mod rolling;
+
+use anyhow::{bail, Context};
+use md4::{Digest, Md4};
+
+use crate::varint::{check_alloc, ReadVarint, WriteVarint};
 use crate::Result;
 
-#[derive(Debug)]
+/// The longest strong sum, an MD4, as in rsync's `SUM_LENGTH`.
+pub(crate) const SUM_LENGTH: usize = 16;
+
+/// The shortest strong sum sent in the first pass, as in rsync's
+/// `SHORT_SUM_LENGTH`. Files that fail their whole-file checksum are redone
+/// with `SUM_LENGTH`.
+#[allow(dead_code)] // Not used until delta transfers are implemented.
+pub(crate) const SHORT_SUM_LENGTH: usize = 2;
+
+/// The block length for files up to `BLOCK_SIZE * BLOCK_SIZE` bytes.
+const BLOCK_SIZE: i32 = 700;
+
+/// The longest block before protocol 30.
+const OLD_MAX_BLOCK_SIZE: i32 = 1 << 29;
+
+/// The longest block from protocol 30.
+const MAX_BLOCK_SIZE: i32 = 1 << 17;
+
+/// Bits added to the estimate of how long the strong sums need to be.
+const BLOCKSUM_BIAS: i32 = 10;
+
+/// Describes the block sums of one file: how many blocks there are, and
+/// how long they and their sums are.
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub(crate) struct SumHead {
     // like rsync |sum_struct|.
     count: i32,
@@ -38,15 +66,88 @@ impl SumHead {
         }
     }
 
+    /// Describe `count` blocks of `blength` bytes, with strong sums of
+    /// `s2length` bytes, where the last block is `remainder` bytes, or a full
+    /// block if that's 0.
+    #[allow(dead_code)] // Not used until delta transfers are implemented.
+    pub(crate) fn new(count: i32, blength: i32, s2length: i32, remainder: i32) -> Self {
+        SumHead {
+            count,
+            blength,
+            s2length,
+            remainder,
+        }
+    }
+
+    /// Choose the block and strong sum lengths for a file of `file_len` bytes,
+    /// like rsync's `sum_sizes_sqroot`.
+    ///
+    /// Unless `block_size` is given, blocks are about the square root of the
+    /// file length, so there are about as many of them as there are bytes in
+    /// each, up to a maximum that depends on the protocol version. The strong
+    /// sums are at least `csum_length` bytes, and are longer when there are
+    /// more blocks.
+    #[allow(dead_code)] // Not used until delta transfers are implemented.
+    pub(crate) fn for_file(
+        file_len: u64,
+        protocol_version: i32,
+        csum_length: usize,
+        block_size: Option<u32>,
+    ) -> Self {
+        let blength = match block_size {
+            Some(block_size) => block_size as i32,
+            None => block_len(file_len, protocol_version),
+        };
+        let s2length = strong_sum_len(file_len, blength, protocol_version, csum_length);
+        let blength64 = blength as u64;
+        SumHead {
+            count: file_len.div_ceil(blength64) as i32,
+            blength,
+            s2length,
+            remainder: (file_len % blength64) as i32,
+        }
+    }
+
+    /// The number of blocks.
+    pub(crate) fn count(&self) -> i32 {
+        self.count
+    }
+
+    /// The length of each block, except perhaps the last.
+    #[allow(dead_code)]
+    pub(crate) fn block_len(&self) -> i32 {
+        self.blength
+    }
+
+    /// The number of bytes of each strong sum that are sent.
+    pub(crate) fn strong_len(&self) -> i32 {
+        self.s2length
+    }
+
+    /// The length of the last block, or 0 if it's a full block.
+    #[allow(dead_code)]
+    pub(crate) fn remainder(&self) -> i32 {
+        self.remainder
+    }
+
     pub fn read(rv: &mut ReadVarint) -> Result<Self> {
         // TODO: Encoding varies per protocol version.
-        // TODO: Assertions about the values?
-        Ok(SumHead {
+        let head = SumHead {
             count: rv.read_i32()?,
             blength: rv.read_i32()?,
             s2length: rv.read_i32()?,
             remainder: rv.read_i32()?,
-        })
+        };
+        if head.count < 0
+            || head.blength < 0
+            || head.s2length < 0
+            || head.s2length as usize > SUM_LENGTH
+            || head.remainder < 0
+            || head.remainder > head.blength
+        {
+            bail!("Invalid sum head {:?}", head);
+        }
+        Ok(head)
     }
 
     pub fn write(&self, wv: &mut WriteVarint) -> Result<()> {
@@ -57,3 +158,323 @@ impl SumHead {
         Ok(())
     }
 }
+
+/// Returns the longest block allowed in a protocol version.
+pub(crate) fn max_block_len(protocol_version: i32) -> i32 {
+    if protocol_version < 30 {
+        OLD_MAX_BLOCK_SIZE
+    } else {
+        MAX_BLOCK_SIZE
+    }
+}
+
+/// Returns the block length for a file, about the square root of its length,
+/// rounded down to a multiple of 8.
+fn block_len(file_len: u64, protocol_version: i32) -> i32 {
+    let max_blength = max_block_len(protocol_version);
+    if file_len <= (BLOCK_SIZE * BLOCK_SIZE) as u64 {
+        return BLOCK_SIZE;
+    }
+    // Start from the highest power of two no more than the square root.
+    let mut c: i64 = 1;
+    let mut l = file_len >> 2;
+    while l != 0 {
+        c <<= 1;
+        l >>= 2;
+    }
+    if c >= max_blength as i64 {
+        return max_blength;
+    }
+    let mut blength: i64 = 0;
+    while c >= 8 {
+        blength |= c;
+        if (file_len as i128) < (blength as i128) * (blength as i128) {
+            blength &= !c;
+        }
+        c >>= 1;
+    }
+    std::cmp::max(blength as i32, BLOCK_SIZE)
+}
+
+/// Returns the number of bytes of each block's strong sum to send, like
+/// rsync's `s2length`.
+///
+/// With more blocks there are more chances of a false match, so the sums get
+/// longer: the estimate is the bits needed to distinguish every block of the
+/// file from every offset of the other file, plus `BLOCKSUM_BIAS`, less the
+/// 32 bits of the weak sum.
+fn strong_sum_len(file_len: u64, blength: i32, protocol_version: i32, csum_length: usize) -> i32 {
+    if protocol_version < 27 {
+        return csum_length as i32;
+    } else if csum_length == SUM_LENGTH {
+        return SUM_LENGTH as i32;
+    }
+    let mut b = BLOCKSUM_BIAS;
+    let mut l = file_len >> 1;
+    while l != 0 {
+        b += 2;
+        l >>= 1;
+    }
+    let mut c = blength >> 1;
+    while c != 0 && b != 0 {
+        b -= 1;
+        c >>= 1;
+    }
+    // Add a bit, subtract the weak sum, and round up to whole bytes. Like
+    // C, this division rounds towards zero.
+    let s2length = (b + 1 - 32 + 7) / 8;
+    s2length.clamp(csum_length as i32, SUM_LENGTH as i32)
+}
+
+/// The weak and strong sums of one block.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct BlockSum {
+    /// The rolling checksum, from `rolling::weak_sum`.
+    pub(crate) weak: u32,
+    /// The strong sum. Only the first `SumHead::strong_len` bytes are sent;
+    /// the rest are zero.
+    pub(crate) strong: [u8; SUM_LENGTH],
+}
+
+/// The sums of every block of a file, as sent by the receiver so that the
+/// sender can find matching blocks.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[allow(dead_code)] // Not used until delta transfers are implemented.
+pub(crate) struct BlockSums {
+    pub(crate) head: SumHead,
+    pub(crate) sums: Vec<BlockSum>,
+}
+
+#[allow(dead_code)]
+impl BlockSums {
+    /// Compute the sums of each block of `data`, with block and strong sum
+    /// lengths from `SumHead::for_file`, like rsync's `generate_and_send_sums`.
+    pub(crate) fn generate(
+        data: &[u8],
+        protocol_version: i32,
+        csum_length: usize,
+        block_size: Option<u32>,
+        checksum_seed: i32,
+    ) -> Self {
+        let head = SumHead::for_file(data.len() as u64, protocol_version, csum_length, block_size);
+        let sums = data
+            .chunks(head.block_len() as usize)
+            .map(|block| BlockSum {
+                weak: rolling::weak_sum(block),
+                strong: strong_sum(block, checksum_seed),
+            })
+            .collect();
+        BlockSums { head, sums }
+    }
+
+    /// Returns true if block `i` has this weak sum, and the same strong sum
+    /// as far as it was sent.
+    pub(crate) fn block_matches(&self, i: usize, weak: u32, strong: &[u8; SUM_LENGTH]) -> bool {
+        let strong_len = self.head.strong_len() as usize;
+        let sum = &self.sums[i];
+        sum.weak == weak && sum.strong[..strong_len] == strong[..strong_len]
+    }
+
+    /// Read a sum head followed by the sums it describes, like rsync's
+    /// `receive_sums`.
+    pub(crate) fn read(rv: &mut ReadVarint) -> Result<Self> {
+        let head = SumHead::read(rv)?;
+        let strong_len = head.strong_len() as usize;
+        check_alloc(
+            (head.count() as usize).saturating_mul(std::mem::size_of::<BlockSum>()),
+            rv.max_alloc(),
+        )?;
+        let mut sums = Vec::with_capacity(head.count() as usize);
+        for i in 0..head.count() {
+            let weak = rv
+                .read_i32()
+                .with_context(|| format!("Failed to read weak sum of block {}", i))?
+                as u32;
+            let mut strong = [0; SUM_LENGTH];
+            strong[..strong_len].copy_from_slice(
+                &rv.read_byte_string(strong_len)
+                    .with_context(|| format!("Failed to read strong sum of block {}", i))?,
+            );
+            sums.push(BlockSum { weak, strong });
+        }
+        Ok(BlockSums { head, sums })
+    }
+
+    /// Write the sum head and then each block's sums.
+    pub(crate) fn write(&self, wv: &mut WriteVarint) -> Result<()> {
+        if self.sums.len() != self.head.count() as usize {
+            bail!(
+                "Sum head describes {} blocks, but there are {} sums",
+                self.head.count(),
+                self.sums.len()
+            );
+        }
+        self.head.write(wv)?;
+        let strong_len = self.head.strong_len() as usize;
+        for sum in &self.sums {
+            wv.write_i32(sum.weak as i32)?;
+            wv.write_byte_string(&sum.strong[..strong_len])?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the strong sum of a block, like rsync's `get_checksum2`: the MD4
+/// of the block followed by the checksum seed, if it's not zero.
+#[allow(dead_code)] // Not used until delta transfers are implemented.
+pub(crate) fn strong_sum(block: &[u8], checksum_seed: i32) -> [u8; SUM_LENGTH] {
+    let mut hasher = Md4::new();
+    hasher.input(block);
+    if checksum_seed != 0 {
+        hasher.input(checksum_seed.to_le_bytes());
+    }
+    let mut strong = [0; SUM_LENGTH];
+    strong.copy_from_slice(&hasher.result());
+    strong
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::*;
+    use crate::connection::test::SharedBuf;
+
+    fn write_and_read(sums: &BlockSums) -> Result<BlockSums> {
+        let buf = SharedBuf::default();
+        sums.write(&mut WriteVarint::new(Box::new(buf.clone())))?;
+        let written = buf.0.lock().unwrap().clone();
+        let mut rv = ReadVarint::new(Box::new(io::Cursor::new(written)));
+        let read = BlockSums::read(&mut rv)?;
+        rv.check_for_eof()?;
+        Ok(read)
+    }
+
+    #[test]
+    fn block_sums_round_trip() {
+        let strong = |b: u8| {
+            let mut strong = [0; SUM_LENGTH];
+            strong[..2].copy_from_slice(&[b, b + 1]);
+            strong
+        };
+        let sums = BlockSums {
+            head: SumHead::new(3, 700, 2, 100),
+            sums: vec![
+                BlockSum {
+                    weak: 0x1a00_045c,
+                    strong: strong(1),
+                },
+                BlockSum {
+                    weak: 0xffff_ffff,
+                    strong: strong(3),
+                },
+                BlockSum {
+                    weak: 0,
+                    strong: strong(5),
+                },
+            ],
+        };
+        let read = write_and_read(&sums).unwrap();
+        assert_eq!(read, sums);
+        assert_eq!(read.head.block_len(), 700);
+        assert_eq!(read.head.remainder(), 100);
+    }
+
+    /// Block and strong sum lengths, as computed by rsync's
+    /// `sum_sizes_sqroot` for the first pass at protocol 27.
+    #[test]
+    fn sum_sizes_like_rsync() {
+        for &(file_len, count, blength, s2length, remainder) in &[
+            (0, 0, 700, 2, 0),
+            (1, 1, 700, 2, 1),
+            (700, 1, 700, 2, 0),
+            (490_000, 700, 700, 2, 0),
+            (490_001, 701, 700, 2, 1),
+            (1 << 20, 1024, 1024, 2, 0),
+            (100 << 20, 10240, 10240, 3, 0),
+            (1 << 30, 32768, 32768, 3, 0),
+            (10 << 30, 103_628, 103_616, 4, 3008),
+            (1 << 40, 1 << 20, 1 << 20, 5, 0),
+        ] {
+            assert_eq!(
+                SumHead::for_file(file_len, 27, SHORT_SUM_LENGTH, None),
+                SumHead::new(count, blength, s2length, remainder),
+                "file_len {}",
+                file_len
+            );
+        }
+    }
+
+    #[test]
+    fn sum_sizes_for_redo_and_new_protocols() {
+        // Redone files always get whole MD4s.
+        assert_eq!(
+            SumHead::for_file(1 << 20, 27, SUM_LENGTH, None).strong_len(),
+            SUM_LENGTH as i32
+        );
+        // From protocol 30, blocks are limited to 128kB, where before they
+        // could be much longer.
+        assert_eq!(
+            SumHead::for_file(1 << 40, 27, SHORT_SUM_LENGTH, None).block_len(),
+            1 << 20
+        );
+        assert_eq!(
+            SumHead::for_file(1 << 40, 30, SHORT_SUM_LENGTH, None).block_len(),
+            1 << 17
+        );
+        // A fixed block size replaces the choice from the file length, and
+        // the strong sums are sized for the resulting number of blocks.
+        assert_eq!(
+            SumHead::for_file(1 << 20, 27, SHORT_SUM_LENGTH, Some(4096)),
+            SumHead::new(256, 4096, 2, 0)
+        );
+        assert_eq!(
+            SumHead::for_file(1000, 30, SHORT_SUM_LENGTH, Some(300)),
+            SumHead::new(4, 300, 2, 100)
+        );
+        // Before protocol 27, the strong sums are always the minimum length.
+        assert_eq!(
+            SumHead::for_file(1 << 40, 26, SHORT_SUM_LENGTH, None).strong_len(),
+            2
+        );
+    }
+
+    #[test]
+    fn generate_and_match_block_sums() {
+        let data: Vec<u8> = (0..2000u32).map(|i| (i * 7) as u8).collect();
+        let sums = BlockSums::generate(&data, 27, SHORT_SUM_LENGTH, None, 0x1234);
+        assert_eq!(sums.head, SumHead::new(3, 700, 2, 600));
+        assert_eq!(sums.sums.len(), 3);
+        let block = &data[700..1400];
+        let weak = rolling::weak_sum(block);
+        let mut strong = strong_sum(block, 0x1234);
+        assert!(sums.block_matches(1, weak, &strong));
+        assert!(!sums.block_matches(0, weak, &strong));
+        // Only the bytes that were sent are compared.
+        strong[SHORT_SUM_LENGTH] ^= 0xff;
+        assert!(sums.block_matches(1, weak, &strong));
+        strong[0] ^= 0xff;
+        assert!(!sums.block_matches(1, weak, &strong));
+        // The seed changes the strong sum.
+        assert_ne!(strong_sum(block, 0x1234), strong_sum(block, 0));
+        assert_eq!(strong_sum(b"", 0), Md4::digest(b"")[..]);
+    }
+
+    #[test]
+    fn mismatched_count_is_an_error() {
+        let sums = BlockSums {
+            head: SumHead::new(1, 700, 2, 0),
+            sums: Vec::new(),
+        };
+        assert!(write_and_read(&sums).is_err());
+    }
+
+    #[test]
+    fn overlong_strong_sum_is_an_error() {
+        let sums = BlockSums {
+            head: SumHead::new(0, 700, 17, 0),
+            sums: Vec::new(),
+        };
+        assert!(write_and_read(&sums).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! rsync's weak rolling checksum, used to find blocks of the basis file
+//! in the new file.
+
+// Not used until delta transfers are implemented.
+#![allow(dead_code)]
+
+/// The weak checksum of a window of bytes, which can be moved along by one
+/// byte in constant time.
+///
+/// Like rsync's `get_checksum1`, this is an Adler-like pair of sums, in
+/// which the bytes are treated as *signed*, and `CHAR_OFFSET` is 0. Only the
+/// low 16 bits of each sum are used.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Rolling {
+    /// Sum of the bytes.
+    s1: u32,
+    /// Sum of `s1` after each byte, so each byte is weighted by its distance
+    /// from the end of the window.
+    s2: u32,
+    /// Number of bytes in the window.
+    len: u32,
+}
+
+impl Rolling {
+    /// Returns the checksum of a whole window.
+    pub(crate) fn new(window: &[u8]) -> Rolling {
+        let mut rolling = Rolling::default();
+        rolling.update(window);
+        rolling
+    }
+
+    /// Add bytes to the end of the window.
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.s1 = self.s1.wrapping_add(signed(b));
+            self.s2 = self.s2.wrapping_add(self.s1);
+        }
+        self.len += data.len() as u32;
+    }
+
+    /// Move the window along by one byte: remove `out_byte` from the start,
+    /// and add `in_byte` at the end.
+    pub(crate) fn roll(&mut self, out_byte: u8, in_byte: u8) {
+        self.roll_out(out_byte);
+        self.update(&[in_byte]);
+    }
+
+    /// Remove `out_byte` from the start of the window, shrinking it, as at
+    /// the end of the file.
+    pub(crate) fn roll_out(&mut self, out_byte: u8) {
+        let out = signed(out_byte);
+        self.s1 = self.s1.wrapping_sub(out);
+        self.s2 = self.s2.wrapping_sub(self.len.wrapping_mul(out));
+        self.len -= 1;
+    }
+
+    /// Returns the checksum of the current window, as sent in block sums.
+    pub(crate) fn digest(&self) -> u32 {
+        (self.s1 & 0xffff) | (self.s2 << 16)
+    }
+}
+
+/// Returns the weak checksum of a block, like rsync's `get_checksum1`.
+pub(crate) fn weak_sum(block: &[u8]) -> u32 {
+    Rolling::new(block).digest()
+}
+
+/// Extend a byte as rsync's `schar`, wrapping into the unsigned sums.
+fn signed(b: u8) -> u32 {
+    b as i8 as i32 as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Expected values follow rsync's `get_checksum1`, including its
+    /// treatment of bytes over 0x7f as negative.
+    #[test]
+    fn weak_sums_match_rsync() {
+        assert_eq!(weak_sum(b""), 0);
+        assert_eq!(weak_sum(b"a"), 0x0061_0061);
+        assert_eq!(weak_sum(b"abcd"), 0x03d4_018a);
+        assert_eq!(weak_sum(b"hello world"), 0x1a00_045c);
+        assert_eq!(weak_sum(&[0xff]), 0xffff_ffff);
+        assert_eq!(weak_sum(&[0x80, 0x7f, 0xff, 0x00, 0xc3, 0xa9]), 0xfea6_ff6a);
+        assert_eq!(
+            weak_sum(b"The quick brown fox jumps over the lazy dog"),
+            0x5ba2_0fd9
+        );
+        let all_bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(weak_sum(&all_bytes), 0x6a80_ff80);
+    }
+
+    #[test]
+    fn rolling_matches_fresh_sum() {
+        let data: Vec<u8> = (0..2000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let window = 700;
+        let mut rolling = Rolling::new(&data[..window]);
+        for start in 1..=(data.len() - window) {
+            rolling.roll(data[start - 1], data[start + window - 1]);
+            assert_eq!(
+                rolling.digest(),
+                weak_sum(&data[start..start + window]),
+                "window at {}",
+                start
+            );
+        }
+    }
+
+    #[test]
+    fn roll_out_shrinks_window() {
+        let data = b"\xfehello world\x80";
+        let mut rolling = Rolling::new(data);
+        for start in 1..=data.len() {
+            rolling.roll_out(data[start - 1]);
+            assert_eq!(rolling.digest(), weak_sum(&data[start..]));
+        }
+        assert_eq!(rolling, Rolling::default());
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Inactivity timeouts on reads from any transport.
+
+use std::io;
+use std::io::prelude::*;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// Size of reads from the underlying stream.
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// Maximum number of chunks read ahead of the consumer.
+const READ_AHEAD_CHUNKS: usize = 16;
+
+/// How long dropping a `TimeoutReader` waits for its thread to finish.
+const JOIN_GRACE: Duration = Duration::from_secs(1);
+
+/// The timeout of a `TimeoutReader`, which can be changed while it's in use,
+/// for example between phases of the protocol.
+///
+/// Clones share the same setting. None means reads can wait forever.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TimeoutSetting(Arc<Mutex<Option<Duration>>>);
+
+impl TimeoutSetting {
+    pub(crate) fn new(timeout: Option<Duration>) -> TimeoutSetting {
+        TimeoutSetting(Arc::new(Mutex::new(timeout)))
+    }
+
+    pub(crate) fn get(&self) -> Option<Duration> {
+        *self.0.lock().unwrap()
+    }
+
+    pub(crate) fn set(&self, timeout: Option<Duration>) {
+        *self.0.lock().unwrap() = timeout;
+    }
+}
+
+/// Wraps a `Read`, and fails with `ErrorKind::TimedOut` if no data arrives
+/// for too long.
+///
+/// Pipes to subprocesses have no timeout option like sockets do, so the
+/// underlying stream is read by a watchdog thread, which passes data back
+/// over a channel that can be waited on with a timeout. This gives the
+/// same behavior for every transport.
+///
+/// The thread stays blocked until the underlying stream is closed, typically
+/// by the other end exiting or being killed, so that should happen before
+/// this is dropped. Dropping it then joins the thread, but if the stream is
+/// still open after `JOIN_GRACE`, the thread is left to finish by itself.
+pub(crate) struct TimeoutReader {
+    rx: Receiver<io::Result<Vec<u8>>>,
+    thread: Option<JoinHandle<()>>,
+    timeout: TimeoutSetting,
+    /// Data received but not yet returned to the caller.
+    buf: Vec<u8>,
+    /// Position of the next byte to return from `buf`.
+    pos: usize,
+    /// True after the underlying stream has ended.
+    eof: bool,
+}
+
+impl TimeoutReader {
+    pub(crate) fn new(mut r: Box<dyn Read + Send>, timeout: TimeoutSetting) -> TimeoutReader {
+        let (tx, rx) = sync_channel(READ_AHEAD_CHUNKS);
+        let thread = std::thread::Builder::new()
+            .name("rsyn_timeout_reader".to_owned())
+            .spawn(move || loop {
+                let mut buf = vec![0; CHUNK_SIZE];
+                let result = match r.read(&mut buf) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Ok(len) => {
+                        buf.truncate(len);
+                        Ok(buf)
+                    }
+                    Err(e) => Err(e),
+                };
+                let done = !matches!(result, Ok(ref buf) if !buf.is_empty());
+                if tx.send(result).is_err() || done {
+                    return;
+                }
+            })
+            .expect("Failed to spawn timeout reader thread");
+        TimeoutReader {
+            rx,
+            thread: Some(thread),
+            timeout,
+            buf: Vec::new(),
+            pos: 0,
+            eof: false,
+        }
+    }
+}
+
+impl Read for TimeoutReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() && !self.eof {
+            let timeout = self.timeout.get();
+            let received = match timeout {
+                Some(timeout) => self.rx.recv_timeout(timeout),
+                None => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(Ok(data)) => {
+                    self.eof = data.is_empty();
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("No data received for {:?}", timeout.unwrap()),
+                    ))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "Timeout reader thread stopped unexpectedly",
+                    ))
+                }
+            }
+        }
+        let len = std::cmp::min(buf.len(), self.buf.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl Drop for TimeoutReader {
+    fn drop(&mut self) {
+        // Discard anything the thread is still trying to send, so that it
+        // can get back to reading, and find the end of the stream.
+        let deadline = Instant::now() + JOIN_GRACE;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.rx.recv_timeout(left) {
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {
+                    debug!("Timeout reader thread is still blocked; not waiting for it");
+                    return;
+                }
+            }
+        }
+        // Panicking here could abort the process, if this is dropped while
+        // unwinding.
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Timeout reader thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{channel, Sender};
+
+    use super::*;
+
+    /// A reader that blocks until the corresponding sender is dropped, and
+    /// then reports EOF.
+    struct Stall(Receiver<()>);
+
+    impl Read for Stall {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            let _ = self.0.recv();
+            Ok(0)
+        }
+    }
+
+    fn stall() -> (Sender<()>, Box<dyn Read + Send>) {
+        let (tx, rx) = channel();
+        (tx, Box::new(Stall(rx)))
+    }
+
+    /// A reader that sets a flag when it's dropped.
+    struct DropFlag<R>(R, Arc<AtomicBool>);
+
+    impl<R: Read> Read for DropFlag<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl<R> Drop for DropFlag<R> {
+        fn drop(&mut self) {
+            self.1.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn stalled_read_times_out() {
+        let (unstall, r) = stall();
+        let timeout = Duration::from_millis(100);
+        let mut tr = TimeoutReader::new(r, TimeoutSetting::new(Some(timeout)));
+        let start = Instant::now();
+        let err = tr.read(&mut [0; 10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= timeout);
+        // Let the thread finish.
+        drop(unstall);
+    }
+
+    #[test]
+    fn data_passes_through() {
+        let r: Box<dyn Read + Send> = Box::new(&b"hello world"[..]);
+        let mut tr = TimeoutReader::new(r, TimeoutSetting::new(Some(Duration::from_secs(60))));
+        let mut content = String::new();
+        tr.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello world");
+        // Still at EOF after the end.
+        assert_eq!(tr.read(&mut [0; 10]).unwrap(), 0);
+    }
+
+    #[test]
+    fn change_timeout_while_reading() {
+        let (unstall, r) = stall();
+        let setting = TimeoutSetting::new(None);
+        let mut tr = TimeoutReader::new(r, setting.clone());
+        setting.set(Some(Duration::from_millis(50)));
+        let err = tr.read(&mut [0; 10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "No data received for 50ms");
+        drop(unstall);
+    }
+
+    /// Once the stream is closed after a timeout, dropping the reader waits
+    /// for its thread to finish.
+    #[test]
+    fn drop_joins_thread_after_timeout() {
+        let (unstall, r) = stall();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let r = Box::new(DropFlag(r, dropped.clone()));
+        let mut tr = TimeoutReader::new(r, TimeoutSetting::new(Some(Duration::from_millis(50))));
+        let err = tr.read(&mut [0; 10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        drop(unstall);
+        drop(tr);
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    /// A panic in the reader thread is logged when the reader is dropped,
+    /// rather than panicking again.
+    #[test]
+    fn drop_survives_panicked_thread() {
+        struct Panic;
+
+        impl Read for Panic {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                panic!("read failed");
+            }
+        }
+
+        let mut tr = TimeoutReader::new(Box::new(Panic), TimeoutSetting::new(None));
+        assert!(tr.read(&mut [0; 10]).is_err());
+        drop(tr);
+    }
+
+    /// If the stream is still open, dropping the reader doesn't wait forever.
+    #[test]
+    fn drop_gives_up_on_blocked_thread() {
+        let (unstall, r) = stall();
+        let tr = TimeoutReader::new(r, TimeoutSetting::new(None));
+        let start = Instant::now();
+        drop(tr);
+        assert!(start.elapsed() < JOIN_GRACE * 2);
+        drop(unstall);
+    }
+}
@@ -0,0 +1,476 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Abstract trees of files that can be the destination of a transfer.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::flist::sort_and_dedupe;
+use crate::{FileEntry, FileList, Options, Result};
+
+/// A tree of files that can receive a transfer.
+///
+/// [`LocalTree`](struct.LocalTree.html) stores files on the local
+/// filesystem, and [`MemoryTree`](struct.MemoryTree.html) keeps them in
+/// memory. Applications can implement this to store files elsewhere.
+///
+/// Names are relative to the root of the tree, as in a file list, and the
+/// root itself is `"."`.
+pub trait Tree: Send {
+    /// Describes what's currently at `name`, or returns None if there's
+    /// nothing there.
+    ///
+    /// Symlinks are not followed.
+    fn stat(&self, name: &[u8]) -> Option<FileEntry>;
+
+    /// Returns a sorted list of everything in the tree, including the root.
+    fn scan(&self, options: &Options) -> Result<FileList>;
+
+    /// Start writing the regular file described by `entry`, which replaces
+    /// anything at its name when it's committed.
+    ///
+    /// `entry` comes from the source, so trees that keep modification times
+    /// can take the file's from it.
+    fn create_file(&mut self, entry: &FileEntry) -> Result<Box<dyn FileWriter>>;
+
+    /// Create a directory, whose parent must already exist.
+    fn create_dir(&mut self, name: &[u8]) -> Result<()>;
+
+    /// Create a symlink pointing to `target`.
+    fn symlink(&mut self, name: &[u8], target: &[u8]) -> Result<()>;
+
+    /// Rename an entry, replacing anything at `to` that isn't a directory.
+    fn rename(&mut self, from: &[u8], to: &[u8]) -> Result<()>;
+
+    /// Remove a file, symlink, or empty directory.
+    fn remove(&mut self, name: &[u8], is_dir: bool) -> Result<()>;
+
+    /// Remove a file, symlink, or directory and everything in it.
+    fn remove_all(&mut self, name: &[u8], is_dir: bool) -> Result<()>;
+
+    /// Copy the regular file at `from` to `to`, whose parent must already
+    /// exist.
+    ///
+    /// By default, this isn't supported.
+    fn copy_file(&mut self, from: &[u8], _to: &[u8]) -> Result<()> {
+        bail!(
+            "Can't copy {:?} in this tree",
+            String::from_utf8_lossy(from)
+        )
+    }
+
+    /// Set the owner and group of an entry, leaving either unchanged if it's
+    /// None. Symlinks are not followed.
+    ///
+    /// By default, this isn't supported.
+    fn set_owner(&mut self, name: &[u8], _uid: Option<u32>, _gid: Option<u32>) -> Result<()> {
+        bail!(
+            "Can't set the owner of {:?} in this tree",
+            String::from_utf8_lossy(name)
+        )
+    }
+
+    /// Set the permission bits of an entry, which isn't a symlink.
+    ///
+    /// By default, this isn't supported.
+    fn set_mode(&mut self, name: &[u8], _mode: u32) -> Result<()> {
+        bail!(
+            "Can't set the permissions of {:?} in this tree",
+            String::from_utf8_lossy(name)
+        )
+    }
+
+    /// True if this tree is a single file, to which the only entry of a
+    /// transfer is written whatever its name.
+    fn is_single_file(&self) -> bool {
+        false
+    }
+
+    /// Called with the file list of a transfer into the tree, before
+    /// anything is changed.
+    ///
+    /// By default, this does nothing.
+    fn prepare(&mut self, _file_list: &[FileEntry]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A file being written into a [`Tree`](trait.Tree.html).
+///
+/// It becomes visible under its name only when it's committed, and is
+/// discarded if it's dropped before that.
+pub trait FileWriter: Write + Send {
+    /// Finish writing, and store the file under its name.
+    fn commit(self: Box<Self>) -> Result<()>;
+
+    /// Finish writing, and return the file to be stored under its name
+    /// later.
+    ///
+    /// Many files can be waiting at once, so this should release anything
+    /// that's limited, like open file descriptors.
+    fn close(self: Box<Self>) -> Result<Box<dyn PendingFile>>;
+
+    /// Add `len` zero bytes to the file.
+    ///
+    /// Writers that can make sparse files should leave a hole, rather than
+    /// writing the zeros. By default, they're written.
+    fn skip(&mut self, len: usize) -> std::io::Result<()> {
+        self.write_all(&vec![0; len])
+    }
+}
+
+/// A file that's completely written into a [`Tree`](trait.Tree.html), but
+/// isn't yet stored under its name.
+///
+/// It's discarded if it's dropped without being committed.
+pub trait PendingFile: Send {
+    /// Store the file under its name.
+    fn commit(self: Box<Self>) -> Result<()>;
+}
+
+/// The size of the chunks of a sparse file that are checked for zeros, as in
+/// rsync.
+const SPARSE_WRITE_SIZE: usize = 1024;
+
+/// Write `data`, but skip zeros at the start and end of every chunk, leaving
+/// holes where the writer supports them, like rsync's `write_sparse`.
+pub(crate) fn write_sparse(writer: &mut dyn FileWriter, data: &[u8]) -> std::io::Result<()> {
+    for chunk in data.chunks(SPARSE_WRITE_SIZE) {
+        let leading = chunk.iter().take_while(|&&b| b == 0).count();
+        if leading == chunk.len() {
+            writer.skip(leading)?;
+            continue;
+        }
+        let trailing = chunk.iter().rev().take_while(|&&b| b == 0).count();
+        if leading > 0 {
+            writer.skip(leading)?;
+        }
+        writer.write_all(&chunk[leading..chunk.len() - trailing])?;
+        if trailing > 0 {
+            writer.skip(trailing)?;
+        }
+    }
+    Ok(())
+}
+
+/// A tree held in memory, for example to receive files in tests, or to
+/// process them without writing them to disk.
+///
+/// Clones share the same contents, so a clone can be kept to look at the
+/// files after the tree is passed to a transfer.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryTree {
+    entries: Arc<Mutex<BTreeMap<Vec<u8>, MemoryEntry>>>,
+}
+
+#[derive(Clone, Debug)]
+enum MemoryEntry {
+    File { content: Vec<u8>, mtime: u32 },
+    Dir,
+    Symlink { target: Vec<u8> },
+}
+
+impl MemoryTree {
+    /// Construct an empty tree, containing only the root directory.
+    pub fn new() -> MemoryTree {
+        MemoryTree::default()
+    }
+
+    /// Returns the content of the regular file at `name`, if there is one.
+    pub fn file_content(&self, name: &[u8]) -> Option<Vec<u8>> {
+        match self.entries.lock().unwrap().get(name) {
+            Some(MemoryEntry::File { content, .. }) => Some(content.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns the target of the symlink at `name`, if there is one.
+    pub fn link_target(&self, name: &[u8]) -> Option<Vec<u8>> {
+        match self.entries.lock().unwrap().get(name) {
+            Some(MemoryEntry::Symlink { target }) => Some(target.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, name: &[u8], entry: MemoryEntry) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(parent) = parent(name) {
+            if parent != b"." && !matches!(entries.get(parent), Some(MemoryEntry::Dir)) {
+                bail!(
+                    "Parent directory of {:?} doesn't exist",
+                    String::from_utf8_lossy(name)
+                );
+            }
+        }
+        entries.insert(name.to_vec(), entry);
+        Ok(())
+    }
+}
+
+impl Tree for MemoryTree {
+    fn stat(&self, name: &[u8]) -> Option<FileEntry> {
+        if name == b"." {
+            return Some(FileEntry::new(name.to_vec(), 0o040755, 0, 0));
+        }
+        self.entries
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|entry| match entry {
+                MemoryEntry::File { content, mtime } => {
+                    FileEntry::new(name.to_vec(), 0o100644, content.len() as u64, *mtime)
+                }
+                MemoryEntry::Dir => FileEntry::new(name.to_vec(), 0o040755, 0, 0),
+                MemoryEntry::Symlink { target } => {
+                    FileEntry::new(name.to_vec(), 0o120777, target.len() as u64, 0)
+                }
+            })
+    }
+
+    fn scan(&self, _options: &Options) -> Result<FileList> {
+        let names: Vec<Vec<u8>> = self.entries.lock().unwrap().keys().cloned().collect();
+        let mut file_list: FileList = std::iter::once(b".".to_vec())
+            .chain(names)
+            .filter_map(|name| self.stat(&name))
+            .collect();
+        sort_and_dedupe(&mut file_list);
+        Ok(file_list)
+    }
+
+    fn create_file(&mut self, entry: &FileEntry) -> Result<Box<dyn FileWriter>> {
+        Ok(Box::new(MemoryFileWriter {
+            tree: self.clone(),
+            name: entry.name_bytes().to_vec(),
+            mtime: entry.unix_mtime(),
+            content: Vec::new(),
+        }))
+    }
+
+    fn create_dir(&mut self, name: &[u8]) -> Result<()> {
+        if self.stat(name).is_some() {
+            bail!("{:?} already exists", String::from_utf8_lossy(name));
+        }
+        self.insert(name, MemoryEntry::Dir)
+    }
+
+    fn symlink(&mut self, name: &[u8], target: &[u8]) -> Result<()> {
+        if self.stat(name).is_some() {
+            bail!("{:?} already exists", String::from_utf8_lossy(name));
+        }
+        self.insert(
+            name,
+            MemoryEntry::Symlink {
+                target: target.to_vec(),
+            },
+        )
+    }
+
+    fn rename(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+        let entry = match self.entries.lock().unwrap().get(from) {
+            Some(MemoryEntry::Dir) => bail!("Renaming directories isn't supported"),
+            Some(entry) => entry.clone(),
+            None => bail!("{:?} doesn't exist", String::from_utf8_lossy(from)),
+        };
+        self.insert(to, entry)?;
+        self.entries.lock().unwrap().remove(from);
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &[u8], is_dir: bool) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(name) {
+            None => bail!("{:?} doesn't exist", String::from_utf8_lossy(name)),
+            Some(MemoryEntry::Dir) if !is_dir => {
+                bail!("{:?} is a directory", String::from_utf8_lossy(name))
+            }
+            Some(MemoryEntry::Dir) if entries.keys().any(|other| is_inside(other, name)) => {
+                bail!("Directory {:?} isn't empty", String::from_utf8_lossy(name))
+            }
+            _ => {
+                entries.remove(name);
+                Ok(())
+            }
+        }
+    }
+
+    fn remove_all(&mut self, name: &[u8], _is_dir: bool) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.remove(name).is_none() {
+            bail!("{:?} doesn't exist", String::from_utf8_lossy(name));
+        }
+        entries.retain(|other, _| !is_inside(other, name));
+        Ok(())
+    }
+
+    fn copy_file(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+        let entry = match self.entries.lock().unwrap().get(from) {
+            Some(entry @ MemoryEntry::File { .. }) => entry.clone(),
+            _ => bail!("{:?} isn't a regular file", String::from_utf8_lossy(from)),
+        };
+        self.insert(to, entry)
+    }
+}
+
+/// If backups are enabled and there's a file or symlink at `name`, rename it
+/// to its backup name before it's replaced or deleted.
+///
+/// With `options.backup_dir`, the backup goes at the same relative path under
+/// that directory, and any missing directories are created.
+pub(crate) fn backup(tree: &mut dyn Tree, name: &[u8], options: &Options) -> Result<()> {
+    if !options.backs_up() {
+        return Ok(());
+    }
+    match tree.stat(name) {
+        Some(entry) if !entry.is_dir() => {
+            let suffix = options.effective_backup_suffix().as_bytes();
+            let backup_name = match options.backup_dir_name() {
+                Some(dir) => {
+                    let backup_name = [&dir, &b"/"[..], name, suffix].concat();
+                    create_parents(tree, &backup_name)?;
+                    backup_name
+                }
+                None => [name, suffix].concat(),
+            };
+            debug!(
+                "Back up {:?} to {:?}",
+                entry.name_lossy_string(),
+                String::from_utf8_lossy(&backup_name)
+            );
+            tree.rename(name, &backup_name)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Create any directories containing `name` that don't exist yet.
+fn create_parents(tree: &mut dyn Tree, name: &[u8]) -> Result<()> {
+    for (pos, _) in name
+        .iter()
+        .enumerate()
+        .filter(|(pos, &c)| c == b'/' && *pos > 0)
+    {
+        let dir = &name[..pos];
+        if tree.stat(dir).is_none() {
+            tree.create_dir(dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// A file being written into a `MemoryTree`.
+struct MemoryFileWriter {
+    tree: MemoryTree,
+    name: Vec<u8>,
+    /// The source's mtime, which the file keeps, so that the quick check
+    /// finds it up to date.
+    mtime: u32,
+    content: Vec<u8>,
+}
+
+impl Write for MemoryFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.content.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FileWriter for MemoryFileWriter {
+    fn commit(self: Box<Self>) -> Result<()> {
+        let MemoryFileWriter {
+            tree,
+            name,
+            mtime,
+            content,
+        } = *self;
+        tree.insert(&name, MemoryEntry::File { content, mtime })
+    }
+
+    fn close(self: Box<Self>) -> Result<Box<dyn PendingFile>> {
+        // Nothing is held open, so the writer can wait as it is.
+        Ok(self)
+    }
+}
+
+impl PendingFile for MemoryFileWriter {
+    fn commit(self: Box<Self>) -> Result<()> {
+        FileWriter::commit(self)
+    }
+}
+
+/// Returns the directory containing `name`, or None for the root.
+fn parent(name: &[u8]) -> Option<&[u8]> {
+    if name == b"." {
+        None
+    } else {
+        Some(match name.iter().rposition(|&c| c == b'/') {
+            Some(pos) => &name[..pos],
+            None => b".",
+        })
+    }
+}
+
+/// True if `name` is somewhere inside the directory `dir`.
+fn is_inside(name: &[u8], dir: &[u8]) -> bool {
+    name.len() > dir.len() && name.starts_with(dir) && name[dir.len()] == b'/'
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_tree_files_and_dirs() {
+        let mut tree = MemoryTree::new();
+        assert!(tree.stat(b".").unwrap().is_dir());
+        tree.create_dir(b"sub").unwrap();
+        let mut file = tree
+            .create_file(&FileEntry::for_test(b"sub/a", 0o100644, 5, 1588429517))
+            .unwrap();
+        file.write_all(b"hello").unwrap();
+        // Not visible until it's committed.
+        assert!(tree.stat(b"sub/a").is_none());
+        file.commit().unwrap();
+        assert_eq!(tree.file_content(b"sub/a").unwrap(), b"hello");
+        let entry = tree.stat(b"sub/a").unwrap();
+        assert_eq!(entry.file_len, 5);
+        // The mtime is the source's, not when it was written.
+        assert_eq!(entry.unix_mtime(), 1588429517);
+
+        assert!(tree
+            .create_file(&FileEntry::for_test(b"nodir/a", 0o100644, 0, 0))
+            .unwrap()
+            .commit()
+            .is_err());
+        assert!(tree.remove(b"sub", true).is_err());
+        let names: Vec<Vec<u8>> = tree
+            .scan(&Options::default())
+            .unwrap()
+            .iter()
+            .map(|entry| entry.name_bytes().to_vec())
+            .collect();
+        assert_eq!(names, [&b"."[..], b"sub", b"sub/a"]);
+
+        tree.remove_all(b"sub", true).unwrap();
+        assert_eq!(tree.scan(&Options::default()).unwrap().len(), 1);
+    }
+}
@@ -21,14 +21,42 @@ use anyhow::{Context, Error, Result};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
+/// Fails if `len` bytes, with a length that came from the server, would be
+/// more than `max_alloc`, like rsync's `--max-alloc`.
+pub(crate) fn check_alloc(len: usize, max_alloc: Option<usize>) -> io::Result<()> {
+    match max_alloc {
+        Some(max_alloc) if len > max_alloc => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Refusing to allocate {} bytes for data from the server, \
+                more than max_alloc of {}",
+                len, max_alloc
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
 /// Read rsync data types from a wrapped stream.
 pub struct ReadVarint {
     r: Box<dyn Read + Send>,
+    /// The largest byte string that can be read, if limited.
+    max_alloc: Option<usize>,
 }
 
 impl ReadVarint {
     pub fn new(r: Box<dyn Read + Send>) -> ReadVarint {
-        ReadVarint { r }
+        ReadVarint { r, max_alloc: None }
+    }
+
+    /// Limit the size of allocations for data read from this stream.
+    pub fn set_max_alloc(&mut self, max_alloc: Option<usize>) {
+        self.max_alloc = max_alloc;
+    }
+
+    /// Returns the limit on allocations, if there is one.
+    pub fn max_alloc(&self) -> Option<usize> {
+        self.max_alloc
     }
 
     pub fn read_u8(&mut self) -> io::Result<u8> {
@@ -38,8 +66,10 @@ impl ReadVarint {
 
     /// Read a known-length byte string into a newly allocated buffer.
     ///
-    /// Always returns the exact size, or an error.
+    /// Always returns the exact size, or an error, including if `len` is
+    /// more than the `max_alloc` limit.
     pub fn read_byte_string(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        check_alloc(len, self.max_alloc)?;
         let mut buf = vec![0; len];
         self.r.read_exact(&mut buf).and(Ok(buf))
     }
@@ -105,6 +135,12 @@ impl WriteVarint {
         trace!("Send {:#x}u8", v);
         self.w.write_all(&[v])
     }
+
+    /// Write a byte string, without any length prefix.
+    pub fn write_byte_string(&mut self, b: &[u8]) -> io::Result<()> {
+        trace!("Send {} bytes", b.len());
+        self.w.write_all(b)
+    }
 }
 
 #[cfg(test)]
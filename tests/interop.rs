@@ -18,11 +18,12 @@
 
 use std::fmt;
 use std::fs::{create_dir, File};
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use chrono::prelude::*;
 
-use rsyn::{Client, Options};
+use rsyn::{Client, MemoryTree, Options, TransferMode, Transport};
 
 /// List files from a newly-created temporary directory.
 #[test]
@@ -38,7 +39,7 @@ fn list_files() {
     create_dir(tmp.path().join("subdir")).unwrap();
     File::create(tmp.path().join("subdir").join("galah")).unwrap();
 
-    let mut client = Client::local(tmp.path());
+    let mut client = Client::local(contents_of(tmp.path()));
     client.set_recursive(true);
     let (flist, summary) = client.list_files().unwrap();
 
@@ -77,6 +78,369 @@ fn list_files() {
     assert_eq!(summary.server_stats.total_file_size, 0);
 }
 
+/// Listing an empty directory returns just the directory, and finishes
+/// promptly.
+#[test]
+fn list_empty_directory() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_list_empty_directory")
+        .tempdir()
+        .unwrap();
+    let client = Client::local(contents_of(tmp.path()));
+    let (flist, _summary) = with_timeout(move || client.list_files()).unwrap();
+    assert_eq!(flist.len(), 1);
+    assert_eq!(flist[0].name_lossy_string(), ".");
+}
+
+/// A local client's summary says it used the local transport.
+#[test]
+fn local_listing_reports_local_transport() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_local_transport")
+        .tempdir()
+        .unwrap();
+    let client = Client::local(contents_of(tmp.path()));
+    let (_flist, summary) = with_timeout(move || client.list_files()).unwrap();
+    assert_eq!(summary.transport, Transport::Local);
+}
+
+/// When the source doesn't exist, the server sends an empty file list and
+/// stops, and so should we.
+#[test]
+fn list_nonexistent_source() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_list_nonexistent_source")
+        .tempdir()
+        .unwrap();
+    let client = Client::local(tmp.path().join("nothing"));
+    let (flist, summary) = with_timeout(move || client.list_files()).unwrap();
+    assert!(flist.is_empty());
+    assert!(!summary.child_exit_status.unwrap().success());
+}
+
+/// A trailing slash lists the contents of the directory; without it, the
+/// directory itself.
+#[test]
+fn list_with_and_without_trailing_slash() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_list_with_and_without_trailing_slash")
+        .tempdir()
+        .unwrap();
+    create_dir(tmp.path().join("src")).unwrap();
+    File::create(tmp.path().join("src").join("a")).unwrap();
+    let list_names = |path: PathBuf| -> Vec<String> {
+        let mut client = Client::local(path);
+        client.set_recursive(true);
+        let (flist, _summary) = client.list_files().unwrap();
+        flist
+            .iter()
+            .map(|fe| fe.name_lossy_string().into_owned())
+            .collect()
+    };
+
+    assert_eq!(list_names(tmp.path().join("src/")), [".", "a"]);
+    assert_eq!(list_names(tmp.path().join("src")), ["src", "src/a"]);
+}
+
+/// With `protect_args`, a path containing spaces reaches the server intact.
+#[test]
+fn list_path_with_spaces_protect_args() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_protect_args")
+        .tempdir()
+        .unwrap();
+    File::create(tmp.path().join("a file")).unwrap();
+    File::create(tmp.path().join("a")).unwrap();
+
+    let mut client = Client::local(tmp.path().join("a file"));
+    client.mut_options().protect_args = true;
+    let (flist, _summary) = client.list_files().unwrap();
+    let names: Vec<_> = flist.iter().map(|e| e.name_lossy_string()).collect();
+    assert_eq!(names, ["a file"]);
+}
+
+/// Probe a local server, without listing anything.
+#[test]
+fn probe() {
+    install_test_logger();
+    let info = Client::local("./src").probe().unwrap();
+    // The server offers a newer version, but we always agree to 27.
+    assert_eq!(info.protocol_version, 27);
+    assert!(info.remote_protocol_version >= 27);
+    assert!(info.motd.is_empty());
+}
+
+/// The version of the local rsync can be asked for, by running it the same
+/// way as the server.
+#[test]
+fn remote_rsync_version() {
+    install_test_logger();
+    let version = Client::local("./src").remote_rsync_version().unwrap();
+    assert!(version.starts_with("rsync  version "), "{:?}", version);
+    assert!(!version.contains('\n'));
+}
+
+/// The client's own count of bytes moved is close to the server's.
+#[test]
+fn client_measured_bytes_match_server_stats() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_wire_bytes")
+        .tempdir()
+        .unwrap();
+    File::create(tmp.path().join("a")).unwrap();
+
+    let (_flist, summary) = Client::local(contents_of(tmp.path())).list_files().unwrap();
+    assert!(summary.bytes_read > 0);
+    assert!(summary.bytes_written > 0);
+    // The server's numbers don't include the statistics themselves, or some
+    // other small parts of the protocol.
+    let server_written = summary.server_stats.total_bytes_written as u64;
+    let server_read = summary.server_stats.total_bytes_read as u64;
+    assert!(
+        summary.bytes_read >= server_written && summary.bytes_read < server_written + 100,
+        "{:#?}",
+        summary
+    );
+    assert!(
+        (summary.bytes_written as i64 - server_read as i64).abs() < 100,
+        "{:#?}",
+        summary
+    );
+}
+
+/// Without `recursive`, list only the immediate children of the directory.
+#[test]
+fn list_files_shallow() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_list_files_shallow")
+        .tempdir()
+        .unwrap();
+    File::create(tmp.path().join("a")).unwrap();
+    create_dir(tmp.path().join("subdir")).unwrap();
+    File::create(tmp.path().join("subdir").join("galah")).unwrap();
+
+    let (flist, _summary) = Client::local(contents_of(tmp.path())).list_files().unwrap();
+    let names: Vec<String> = flist
+        .iter()
+        .map(|fe| fe.name_lossy_string().into_owned())
+        .collect();
+    assert_eq!(names, [".", "a", "subdir"]);
+    assert!(flist[2].is_dir());
+}
+
+/// With `cvs_exclude`, object files and the like aren't listed.
+#[test]
+fn list_files_cvs_exclude() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_list_files_cvs_exclude")
+        .tempdir()
+        .unwrap();
+    File::create(tmp.path().join("foo.c")).unwrap();
+    File::create(tmp.path().join("foo.o")).unwrap();
+    create_dir(tmp.path().join(".git")).unwrap();
+
+    let options = Options {
+        cvs_exclude: true,
+        ..Options::default()
+    };
+    let (flist, _summary) = Client::local(contents_of(tmp.path()))
+        .set_options(options)
+        .list_files()
+        .unwrap();
+    let names: Vec<String> = flist
+        .iter()
+        .map(|fe| fe.name_lossy_string().into_owned())
+        .collect();
+    assert_eq!(names, [".", "foo.c"]);
+}
+
+/// Fetch just the start of one file.
+#[test]
+fn fetch_range() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_fetch_range")
+        .tempdir()
+        .unwrap();
+    let content: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+    std::fs::write(tmp.path().join("big"), &content).unwrap();
+
+    let client = Client::local(contents_of(tmp.path()));
+    let (flist, _summary) = client.clone().list_files().unwrap();
+    assert_eq!(flist[1].name_lossy_string(), "big");
+    let head = client
+        .connect(TransferMode::Download)
+        .unwrap()
+        .fetch_range(&flist[1], 0, 16)
+        .unwrap();
+    assert_eq!(head, &content[..16]);
+}
+
+/// List several directories, two at a time.
+#[test]
+fn list_many() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_list_many")
+        .tempdir()
+        .unwrap();
+    let paths: Vec<String> = ["a", "b", "c"]
+        .iter()
+        .map(|name| {
+            let dir = tmp.path().join(name);
+            create_dir(&dir).unwrap();
+            File::create(dir.join(format!("{}.txt", name))).unwrap();
+            contents_of(&dir).to_str().unwrap().to_owned()
+        })
+        .collect();
+    let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+
+    let file_lists = Client::local(tmp.path()).list_many(&paths, 2).unwrap();
+    assert_eq!(file_lists.len(), 3);
+    for (path, name) in paths.iter().zip(&["a.txt", "b.txt", "c.txt"]) {
+        let names: Vec<String> = file_lists[*path]
+            .iter()
+            .map(|entry| entry.name_lossy_string().into_owned())
+            .collect();
+        assert_eq!(names, [".", name]);
+    }
+}
+
+/// The server's total file size agrees with the sizes in the listing.
+#[test]
+fn total_file_size_matches_listing() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_total_file_size")
+        .tempdir()
+        .unwrap();
+    create_dir(tmp.path().join("sub")).unwrap();
+    std::fs::write(tmp.path().join("a"), vec![b'a'; 1000]).unwrap();
+    std::fs::write(tmp.path().join("sub/b"), vec![b'b'; 234]).unwrap();
+
+    let mut client = Client::local(contents_of(tmp.path()));
+    client.set_recursive(true);
+    let (file_list, summary) = client.list_files().unwrap();
+    let listed: u64 = file_list
+        .iter()
+        .filter(|entry| entry.is_file())
+        .map(|entry| entry.file_len)
+        .sum();
+    assert_eq!(listed, 1234);
+    assert_eq!(summary.server_stats.total_file_size, 1234);
+}
+
+/// Fetch one whole file into memory.
+#[test]
+fn fetch_file() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_fetch_file")
+        .tempdir()
+        .unwrap();
+    std::fs::create_dir(tmp.path().join("sub")).unwrap();
+    std::fs::write(tmp.path().join("sub/small"), b"small content\n").unwrap();
+    std::fs::write(tmp.path().join("other"), b"not this one").unwrap();
+
+    let mut client = Client::local(contents_of(tmp.path()));
+    client.set_recursive(true);
+    let content = client
+        .connect(TransferMode::Download)
+        .unwrap()
+        .fetch_file(b"sub/small")
+        .unwrap();
+    assert_eq!(
+        content,
+        std::fs::read(tmp.path().join("sub/small")).unwrap()
+    );
+}
+
+/// Fetch one text file into a string.
+#[test]
+fn download_file_to_string() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_download_file_to_string")
+        .tempdir()
+        .unwrap();
+    std::fs::write(tmp.path().join("config.txt"), "name = \"rsyn\"\n").unwrap();
+    std::fs::write(tmp.path().join("other"), b"not this one").unwrap();
+
+    let content = Client::local(contents_of(tmp.path()))
+        .download_file_to_string("config.txt")
+        .unwrap();
+    assert_eq!(content, "name = \"rsyn\"\n");
+}
+
+/// The manifest has the MD4 of every file, the same as `md4sum` would give.
+#[test]
+fn checksum_manifest() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_checksum_manifest")
+        .tempdir()
+        .unwrap();
+    create_dir(tmp.path().join("sub")).unwrap();
+    std::fs::write(tmp.path().join("a"), b"abc").unwrap();
+    std::fs::write(tmp.path().join("sub/b"), b"").unwrap();
+
+    let mut client = Client::local(contents_of(tmp.path()));
+    client.set_recursive(true);
+    let manifest: Vec<(String, String)> = client
+        .checksum_manifest()
+        .unwrap()
+        .into_iter()
+        .map(|(entry, digest)| (entry.name_lossy_string().into_owned(), hex::encode(digest)))
+        .collect();
+    // Test vectors from RFC 1320.
+    assert_eq!(
+        manifest,
+        [
+            (
+                "a".to_owned(),
+                "a448017aaf21d8525fc10ae87aa6729d".to_owned()
+            ),
+            (
+                "sub/b".to_owned(),
+                "31d6cfe0d16ae931b73c59d7e0c089c0".to_owned()
+            ),
+        ]
+    );
+}
+
+/// Download a text file compressed with `-z`.
+#[test]
+fn download_compressed() {
+    install_test_logger();
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_download_compressed")
+        .tempdir()
+        .unwrap();
+    let text: String = (0..5000)
+        .map(|i| format!("{} green bottles, hanging on the wall\n", i))
+        .collect();
+    std::fs::write(tmp.path().join("song.txt"), &text).unwrap();
+
+    let mut client = Client::local(contents_of(tmp.path()));
+    client.mut_options().compress_choice = Some("zlib".to_owned());
+    let mut tree = MemoryTree::new();
+    let (_file_list, summary) = client.download(&mut tree).unwrap();
+    assert_eq!(tree.file_content(b"song.txt").unwrap(), text.as_bytes());
+    assert_eq!(summary.files_received, 1);
+    assert_eq!(summary.whole_file_sum_mismatch_count, 0);
+    // The literal data is counted uncompressed, but much less crossed the wire.
+    assert_eq!(summary.literal_bytes_received, text.len());
+    assert!(summary.bytes_read < text.len() as u64 / 4);
+}
+
 /// Only on Unix, check we can list a directory containing a symlink, and see
 /// the symlink.
 #[cfg(unix)]
@@ -89,8 +453,7 @@ fn list_symlink() -> rsyn::Result<()> {
         .tempdir()?;
     std::os::unix::fs::symlink("dangling link", tmp.path().join("a link"))?;
 
-    let mut client = Client::local(tmp.path());
-    client.mut_options().list_only = true;
+    let client = Client::local(contents_of(tmp.path()));
     let (flist, _summary) = client.list_files()?;
 
     assert_eq!(flist.len(), 2);
@@ -103,6 +466,38 @@ fn list_symlink() -> rsyn::Result<()> {
     Ok(())
 }
 
+/// Only on Unix: with `--copy-unsafe-links`, a symlink within the tree stays
+/// a link, but one pointing outside it is sent as the file it points to.
+#[cfg(unix)]
+#[test]
+fn copy_unsafe_links() -> rsyn::Result<()> {
+    install_test_logger();
+
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_copy_unsafe_links")
+        .tempdir()?;
+    let src = tmp.path().join("src");
+    std::fs::create_dir(&src)?;
+    std::fs::write(src.join("file"), b"inside")?;
+    std::fs::write(tmp.path().join("secret"), b"outside")?;
+    std::os::unix::fs::symlink("file", src.join("in"))?;
+    std::os::unix::fs::symlink("../secret", src.join("out"))?;
+
+    let mut client = Client::local(contents_of(&src));
+    client.mut_options().preserve_links = true;
+    client.mut_options().copy_unsafe_links = true;
+    let (flist, _summary) = client.list_files()?;
+
+    let names: Vec<_> = flist.iter().map(|e| e.name_lossy_string()).collect();
+    assert_eq!(names, [".", "file", "in", "out"]);
+    assert!(flist[2].is_symlink());
+    assert_eq!(flist[2].link_target(), Some(&b"file"[..]));
+    assert!(flist[3].is_file());
+    assert_eq!(flist[3].file_len, 7);
+
+    Ok(())
+}
+
 /// Only on Unix: list `/etc`, a good natural source of files with different
 /// permissions, including some probably not readable to the non-root
 /// user running this test.
@@ -110,10 +505,9 @@ fn list_symlink() -> rsyn::Result<()> {
 #[test]
 fn list_files_etc() -> Result<()> {
     install_test_logger();
-    let mut client = Client::local("/etc");
+    let mut client = Client::local("/etc/");
     client.set_options(Options {
         recursive: true,
-        list_only: true,
         ..Options::default()
     });
     let (flist, _summary) = client.list_files()?;
@@ -134,10 +528,9 @@ fn list_files_etc() -> Result<()> {
 #[test]
 fn list_files_dev() -> Result<()> {
     install_test_logger();
-    let mut client = Client::local("/dev");
+    let mut client = Client::local("/dev/");
     client.set_options(Options {
         recursive: true,
-        list_only: true,
         ..Options::default()
     });
     let (flist, _summary) = client.list_files()?;
@@ -154,6 +547,48 @@ fn list_files_dev() -> Result<()> {
     Ok(())
 }
 
+/// Returns the path with a trailing slash, which tells rsync to transfer the
+/// contents of the directory, rather than the directory itself.
+/// The hidden `--benchmark` option of the command-line tool lists and
+/// downloads a small tree.
+#[test]
+fn benchmark_small_tree() {
+    let tmp = tempfile::Builder::new()
+        .prefix("rsyn_interop_benchmark")
+        .tempdir()
+        .unwrap();
+    for i in 0..10 {
+        std::fs::write(tmp.path().join(format!("f{}", i)), vec![b'x'; 1000]).unwrap();
+    }
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rsyn"))
+        .arg("--benchmark")
+        .arg("-r")
+        .arg(contents_of(tmp.path()))
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(stdout.contains("Listed 11 entries in "), "{}", stdout);
+    assert!(stdout.contains("Downloaded 10000 bytes in "), "{}", stdout);
+}
+
+fn contents_of(path: &Path) -> PathBuf {
+    path.join("")
+}
+
+/// Run `f` on another thread, and panic if it doesn't finish within a generous
+/// time limit, rather than hanging the test run.
+fn with_timeout<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || tx.send(f()).unwrap());
+    rx.recv_timeout(std::time::Duration::from_secs(60))
+        .expect("Operation did not finish in time")
+}
+
 fn install_test_logger() {
     // This works, but leaks out of the normally-captured test stdout, because
     // the way Rust catches output only affects the main thread.